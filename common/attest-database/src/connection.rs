@@ -4,60 +4,498 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! The pool of connections behind a [`MsgDB`].
+//!
+//! There is a single writer, checked out by [`MsgDB::get_handle_all`], and a
+//! set of readers opened read only, checked out by
+//! [`MsgDB::get_handle_read`]. Both are handed out first come first served: a
+//! reader goes to whoever has waited longest, as soon as any reader is idle.
+//!
+//! SQLite in WAL mode lets the readers run alongside the writer, but the WAL
+//! can only be checkpointed back into the DB up to the oldest open read, so
+//! the pool also checkpoints on a timer to keep the WAL from growing while
+//! readers are busy.
+
 use super::db_handle::MsgDBHandle;
 use crate::db_handle::handle_type::{self, All};
 use rusqlite::Connection;
-use sapio_bitcoin::secp256k1::rand::{seq::SliceRandom, thread_rng};
-use std::{marker::PhantomData, pin::Pin, sync::Arc};
-use tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Display;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Weak;
+use std::time::{Duration, Instant};
+use std::{marker::PhantomData, sync::Arc};
+use tokio::sync::{Mutex, OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, warn};
+
+/// How many times a statement waits out another connection's lock before
+/// failing with `SQLITE_BUSY`, see [`on_busy`].
+const BUSY_RETRIES: i32 = 500;
+const BUSY_SLEEP: Duration = Duration::from_millis(10);
+
+// SQLite's busy handler is a plain fn, so these count for every pool in the
+// process
+static BUSY_WAITS: AtomicU64 = AtomicU64::new(0);
+static BUSY_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Installed as the busy handler of every pooled connection. Waits about as
+/// long as SQLite's default busy timeout would, but counts how often it has
+/// to.
+fn on_busy(attempt: i32) -> bool {
+    if attempt >= BUSY_RETRIES {
+        BUSY_ERRORS.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    BUSY_WAITS.fetch_add(1, Ordering::Relaxed);
+    std::thread::sleep(BUSY_SLEEP);
+    true
+}
+
+fn default_readers() -> usize {
+    9
+}
+
+fn default_checkout_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+const fn default_wal_autocheckpoint() -> u32 {
+    1000
+}
+
+fn default_checkpoint_frequency() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+/// Settings for the connections of a [`MsgDB`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PoolConfig {
+    /// how many read only connections to open alongside the writer
+    #[serde(default = "default_readers")]
+    pub readers: usize,
+    /// how long [`MsgDB::try_get_handle_read`] and
+    /// [`MsgDB::try_get_handle_all`] wait for a connection
+    #[serde(default = "default_checkout_timeout")]
+    pub checkout_timeout: Duration,
+    /// WAL size, in pages, past which a commit checkpoints what it can
+    #[serde(default = "default_wal_autocheckpoint")]
+    pub wal_autocheckpoint: u32,
+    /// how often to checkpoint the whole WAL and truncate it
+    #[serde(default = "default_checkpoint_frequency")]
+    pub checkpoint_frequency: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            readers: default_readers(),
+            checkout_timeout: default_checkout_timeout(),
+            wal_autocheckpoint: default_wal_autocheckpoint(),
+            checkpoint_frequency: default_checkpoint_frequency(),
+        }
+    }
+}
+
+/// No connection became free within [`PoolConfig::checkout_timeout`].
+#[derive(Debug)]
+pub struct CheckoutTimeout;
+
+impl Display for CheckoutTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for CheckoutTimeout {}
+
+/// Counters for a [`MsgDB`], see [`MsgDB::stats`]. Wait times are in
+/// microseconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub readers: usize,
+    pub idle_readers: usize,
+    pub read_checkouts: u64,
+    pub read_wait_us: u64,
+    pub max_read_wait_us: u64,
+    pub write_checkouts: u64,
+    pub write_wait_us: u64,
+    pub max_write_wait_us: u64,
+    /// checkouts that gave up, see [`CheckoutTimeout`]
+    pub timeouts: u64,
+    /// times any connection in the process waited on another's lock
+    pub busy_waits: u64,
+    /// times any connection in the process gave up with `SQLITE_BUSY`
+    pub busy_errors: u64,
+    pub checkpoints: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    read_checkouts: AtomicU64,
+    read_wait_us: AtomicU64,
+    max_read_wait_us: AtomicU64,
+    write_checkouts: AtomicU64,
+    write_wait_us: AtomicU64,
+    max_write_wait_us: AtomicU64,
+    timeouts: AtomicU64,
+    checkpoints: AtomicU64,
+}
+
+fn record_wait(checkouts: &AtomicU64, total: &AtomicU64, max: &AtomicU64, waited: Duration) {
+    let us = waited.as_micros() as u64;
+    checkouts.fetch_add(1, Ordering::Relaxed);
+    total.fetch_add(us, Ordering::Relaxed);
+    max.fetch_max(us, Ordering::Relaxed);
+}
+
+struct Pool {
+    writer: Arc<Mutex<Connection>>,
+    readers: Vec<Arc<Mutex<Connection>>>,
+    /// indexes into `readers` not checked out, with a permit in `available`
+    /// for each
+    idle: std::sync::Mutex<Vec<usize>>,
+    available: Arc<Semaphore>,
+    config: PoolConfig,
+    counters: Counters,
+}
+
+/// Returns a reader to its pool when its handle is dropped, or when a
+/// checkout is abandoned before the handle is built.
+struct Checkin {
+    pool: Arc<Pool>,
+    reader: usize,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for Checkin {
+    fn drop(&mut self) {
+        // the reader goes back on the idle list before its permit is
+        // released, so that a permit always finds one
+        self.pool
+            .idle
+            .lock()
+            .expect("idle list is never poisoned")
+            .push(self.reader);
+    }
+}
+
+/// A connection checked out of a [`MsgDB`].
+pub struct PooledConnection {
+    conn: OwnedMutexGuard<Connection>,
+    _checkin: Option<Checkin>,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+    fn deref(&self) -> &Connection {
+        &self.conn
+    }
+}
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+}
+
+/// The result of [`MsgDB::checkpoint`], in WAL frames.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalCheckpoint {
+    /// a reader kept the checkpoint from finishing
+    pub busy: bool,
+    pub log: i64,
+    pub checkpointed: i64,
+}
 
 #[derive(Clone)]
-pub struct MsgDB(Arc<Vec<Arc<Mutex<Connection>>>>);
+pub struct MsgDB(Arc<Pool>);
 
 impl MsgDB {
-    pub fn new(db: Vec<Arc<Mutex<Connection>>>) -> Self {
-        if db.len() < 2 {
-            panic!("Expected at least two connections, one read one write")
+    /// `readers` may share connections with each other and with `writer`, as
+    /// an in memory DB must.
+    pub fn new(
+        writer: Arc<Mutex<Connection>>,
+        readers: Vec<Arc<Mutex<Connection>>>,
+        config: PoolConfig,
+    ) -> Self {
+        if readers.is_empty() {
+            panic!("Expected at least one reader")
         }
-        MsgDB(Arc::new(db))
+        MsgDB(Arc::new(Pool {
+            writer,
+            idle: std::sync::Mutex::new((0..readers.len()).rev().collect()),
+            available: Arc::new(Semaphore::new(readers.len())),
+            readers,
+            config,
+            counters: Default::default(),
+        }))
     }
 
-    pub async fn map_all_sequential<F>(&self, f: F)
-    where
-        F: Fn(MsgDBHandle<All>) -> Pin<Box<dyn std::future::Future<Output = ()> + Send>>,
-    {
-        for conn in self.0.iter() {
-            let h = MsgDBHandle(conn.clone().lock_owned().await, PhantomData::default());
-            f(h).await;
+    /// Creates the tables with the writer and readies the statements of every
+    /// connection.
+    pub async fn setup(&self) {
+        let mut w = self.get_handle_all().await;
+        w.setup_tables();
+        w.0.busy_handler(Some(on_busy))
+            .expect("Setting a busy handler can't fail");
+        w.0.pragma_update(None, "wal_autocheckpoint", self.0.config.wal_autocheckpoint)
+            .expect("Setting the WAL autocheckpoint can't fail");
+        drop(w);
+        for conn in self.0.readers.iter() {
+            let mut h = MsgDBHandle::<handle_type::ReadOnly>(
+                PooledConnection {
+                    conn: conn.clone().lock_owned().await,
+                    _checkin: None,
+                },
+                PhantomData::default(),
+            );
+            h.prepare_cached_statements();
+            h.0.busy_handler(Some(on_busy))
+                .expect("Setting a busy handler can't fail");
         }
     }
 
     pub async fn get_handle_all(&self) -> MsgDBHandle<handle_type::All> {
-        let conns = &self.0;
         tracing::trace!("Getting Write Handle to DB...");
-        let first = conns[0].clone().lock_owned().await;
-        tracing::trace!("Write Handle Acquired");
-        MsgDBHandle(first, PhantomData::default())
+        let start = Instant::now();
+        let conn = self.0.writer.clone().lock_owned().await;
+        self.writer_checked_out(conn, start)
+    }
+
+    /// Like [`MsgDB::get_handle_all`], but gives up after
+    /// [`PoolConfig::checkout_timeout`].
+    pub async fn try_get_handle_all(&self) -> Result<MsgDBHandle<All>, CheckoutTimeout> {
+        let start = Instant::now();
+        let lock = self.0.writer.clone().lock_owned();
+        match tokio::time::timeout(self.0.config.checkout_timeout, lock).await {
+            Ok(conn) => Ok(self.writer_checked_out(conn, start)),
+            Err(_) => Err(self.timed_out("write")),
+        }
     }
 
     pub async fn get_handle_read(&self) -> MsgDBHandle<handle_type::ReadOnly> {
-        let conns = &self.0;
         tracing::trace!("Getting Read Handle to DB");
-        // try N random locks
-        for _lock in 1..conns.len() {
-            let lock = SliceRandom::choose(&conns[1..], &mut thread_rng())
-                .expect("conns known to be >= 2 in length");
+        let start = Instant::now();
+        let permit = self
+            .0
+            .available
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Pool semaphore is never closed");
+        self.reader_checked_out(permit, start).await
+    }
+
+    /// Like [`MsgDB::get_handle_read`], but gives up after
+    /// [`PoolConfig::checkout_timeout`], whether waiting for a free reader or
+    /// for the reader's lock.
+    pub async fn try_get_handle_read(
+        &self,
+    ) -> Result<MsgDBHandle<handle_type::ReadOnly>, CheckoutTimeout> {
+        let start = Instant::now();
+        let checkout = async {
+            let permit = self
+                .0
+                .available
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("Pool semaphore is never closed");
+            self.reader_checked_out(permit, start).await
+        };
+        match tokio::time::timeout(self.0.config.checkout_timeout, checkout).await {
+            Ok(handle) => Ok(handle),
+            Err(_) => Err(self.timed_out("read")),
+        }
+    }
+
+    fn writer_checked_out(
+        &self,
+        conn: OwnedMutexGuard<Connection>,
+        start: Instant,
+    ) -> MsgDBHandle<All> {
+        let c = &self.0.counters;
+        record_wait(
+            &c.write_checkouts,
+            &c.write_wait_us,
+            &c.max_write_wait_us,
+            start.elapsed(),
+        );
+        tracing::trace!("Write Handle Acquired");
+        MsgDBHandle(
+            PooledConnection {
+                conn,
+                _checkin: None,
+            },
+            PhantomData::default(),
+        )
+    }
+
+    async fn reader_checked_out(
+        &self,
+        permit: OwnedSemaphorePermit,
+        start: Instant,
+    ) -> MsgDBHandle<handle_type::ReadOnly> {
+        let reader = self
+            .0
+            .idle
+            .lock()
+            .expect("idle list is never poisoned")
+            .pop()
+            .expect("There is an idle reader for every permit");
+        // from here on the reader goes back if this future is dropped
+        let checkin = Checkin {
+            pool: self.0.clone(),
+            reader,
+            _permit: permit,
+        };
+        // only waits if the reader is shared, e.g. with the writer in memory
+        let conn = self.0.readers[reader].clone().lock_owned().await;
+        let c = &self.0.counters;
+        record_wait(
+            &c.read_checkouts,
+            &c.read_wait_us,
+            &c.max_read_wait_us,
+            start.elapsed(),
+        );
+        tracing::trace!("Read Handle Acquired");
+        MsgDBHandle(
+            PooledConnection {
+                conn,
+                _checkin: Some(checkin),
+            },
+            PhantomData::default(),
+        )
+    }
+
+    fn timed_out(&self, kind: &str) -> CheckoutTimeout {
+        self.0.counters.timeouts.fetch_add(1, Ordering::Relaxed);
+        warn!(kind, timeout=?self.0.config.checkout_timeout, "Timed Out Waiting for DB Connection");
+        CheckoutTimeout
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let c = &self.0.counters;
+        let get = |a: &AtomicU64| a.load(Ordering::Relaxed);
+        PoolStats {
+            readers: self.0.readers.len(),
+            idle_readers: self.0.available.available_permits(),
+            read_checkouts: get(&c.read_checkouts),
+            read_wait_us: get(&c.read_wait_us),
+            max_read_wait_us: get(&c.max_read_wait_us),
+            write_checkouts: get(&c.write_checkouts),
+            write_wait_us: get(&c.write_wait_us),
+            max_write_wait_us: get(&c.max_write_wait_us),
+            timeouts: get(&c.timeouts),
+            busy_waits: get(&BUSY_WAITS),
+            busy_errors: get(&BUSY_ERRORS),
+            checkpoints: get(&c.checkpoints),
+        }
+    }
+
+    /// Copies the whole WAL back into the DB and truncates it, unless a
+    /// reader is still using part of it.
+    pub async fn checkpoint(&self) -> Result<WalCheckpoint, rusqlite::Error> {
+        let w = self.get_handle_all().await;
+        let checkpoint = tokio::task::spawn_blocking(move || {
+            w.0.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |r| {
+                Ok(WalCheckpoint {
+                    busy: r.get::<_, i64>(0)? != 0,
+                    log: r.get(1)?,
+                    checkpointed: r.get(2)?,
+                })
+            })
+        })
+        .await
+        .expect("Checkpoint should not panic")?;
+        self.0.counters.checkpoints.fetch_add(1, Ordering::Relaxed);
+        Ok(checkpoint)
+    }
+
+    /// Checkpoints every [`PoolConfig::checkpoint_frequency`] for as long as
+    /// the pool is in use.
+    pub(crate) fn start_checkpointing(&self) {
+        let pool: Weak<Pool> = Arc::downgrade(&self.0);
+        let frequency = self.0.config.checkpoint_frequency;
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(frequency).await;
+                let db = match pool.upgrade() {
+                    Some(pool) => MsgDB(pool),
+                    None => break,
+                };
+                match db.checkpoint().await {
+                    Ok(c) if c.busy => debug!(?c, "WAL Checkpoint Held Up by a Reader"),
+                    Ok(c) => debug!(?c, "WAL Checkpointed"),
+                    Err(e) => warn!(err=?e, "WAL Checkpoint Failed"),
+                }
+            }
+        });
+    }
+}
+
+/// The mutex list [`MsgDB`] used to be, kept only to benchmark the pool
+/// against. Every connection can write and is in the default journal mode,
+/// the first is the writer and readers are picked at random from the rest.
+#[cfg(test)]
+pub(crate) struct MutexList(Vec<Arc<Mutex<Connection>>>);
+
+#[cfg(test)]
+impl MutexList {
+    pub(crate) async fn open(db_file: &std::path::Path, conns: usize) -> Self {
+        let list = MutexList(
+            (0..conns.max(2))
+                .map(|_| Arc::new(Mutex::new(Connection::open(db_file).unwrap())))
+                .collect(),
+        );
+        for conn in list.0.iter() {
+            let mut h = MsgDBHandle::<All>(
+                PooledConnection {
+                    conn: conn.clone().lock_owned().await,
+                    _checkin: None,
+                },
+                PhantomData::default(),
+            );
+            h.setup_tables();
+        }
+        list
+    }
+
+    pub(crate) async fn get_handle_all(&self) -> MsgDBHandle<All> {
+        MsgDBHandle(
+            PooledConnection {
+                conn: self.0[0].clone().lock_owned().await,
+                _checkin: None,
+            },
+            PhantomData::default(),
+        )
+    }
+
+    pub(crate) async fn get_handle_read(&self) -> MsgDBHandle<handle_type::ReadOnly> {
+        use sapio_bitcoin::secp256k1::rand::{seq::SliceRandom, thread_rng};
+        let readers = &self.0[1..];
+        // try N random locks, then sleep on a random one
+        let mut conn = None;
+        for _ in 0..readers.len() {
+            let lock = readers.choose(&mut thread_rng()).unwrap();
             if let Ok(l) = lock.clone().try_lock_owned() {
-                tracing::trace!("Read Handle Acquired");
-                return MsgDBHandle(l, PhantomData::default());
+                conn = Some(l);
+                break;
             }
         }
-        // pick a random lock to sleep on
-        let l = SliceRandom::choose(&conns[1..], &mut thread_rng())
-            .expect("conns known to be >= 2 in length")
-            .clone();
-        let l = l.lock_owned().await;
-        tracing::trace!("Read Handle Acquired");
-        MsgDBHandle(l, PhantomData::default())
+        let conn = match conn {
+            Some(conn) => conn,
+            None => {
+                let lock = readers.choose(&mut thread_rng()).unwrap().clone();
+                lock.lock_owned().await
+            }
+        };
+        MsgDBHandle(
+            PooledConnection {
+                conn,
+                _checkin: None,
+            },
+            PhantomData::default(),
+        )
     }
 }
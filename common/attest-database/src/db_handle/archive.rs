@@ -0,0 +1,295 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Moving old envelopes out of the DB and back.
+//!
+//! Once a chain goes quiet, or the game it was for is over, its history is
+//! rarely looked at again but still kept at full size on every node. Archiving
+//! writes the envelopes between a chain's genesis and its last few envelopes
+//! to a compressed file and deletes them from the DB:
+//!
+//! - the genesis stays, as everything else about a chain hangs off of it;
+//! - the tip and the envelopes just below it stay, so the chain can still be
+//!   extended, served, and checked against new envelopes;
+//! - the lowest envelope kept, the checkpoint, commits to the last archived
+//!   envelope as its `prev_msg`, so the archive can be checked against it
+//!   when restored.
+//!
+//! While archived, those heights are refused on insert so that peers don't
+//! sync them right back in.
+
+use super::get::ancestry::stored_chain_ending_at;
+use super::get::archive::{archived_chains_for, ArchivedChain};
+use super::insert::try_insert_authenticated_envelope_with_txn;
+use super::{handle_type, MsgDBHandle};
+use crate::db_handle::sql::get::archive::SQL_GET_ARCHIVE_CHAIN_TIP;
+use crate::db_handle::sql::get::messages::SQL_GET_MESSAGE_BY_HASH;
+use crate::db_handle::sql::insert::SQL_INSERT_ARCHIVED_CHAIN;
+use crate::db_handle::sql::update::*;
+use crate::sql_error::SqliteFail;
+use attest_messages::binary::BinaryError;
+use attest_messages::{AttestEnvelopable, CanonicalEnvelopeHash, GenericEnvelope};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use rusqlite::{named_params, OptionalExtension};
+use sapio_bitcoin::hashes::hex::ToHex;
+use sapio_bitcoin::hashes::{sha256, Hash};
+use sapio_bitcoin::secp256k1::{Secp256k1, Verification};
+use std::error::Error;
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    UnknownChain,
+    Io(std::io::Error),
+    /// the file changed since it was archived
+    DigestMismatch {
+        path: PathBuf,
+    },
+    Corrupt(BinaryError),
+    /// an envelope in the archive is not authentic or doesn't belong where
+    /// it was archived from
+    Invalid(String),
+    /// the DB refused an envelope from the archive
+    Rejected(SqliteFail, Option<String>),
+}
+
+impl Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for ArchiveError {}
+
+impl From<std::io::Error> for ArchiveError {
+    fn from(e: std::io::Error) -> Self {
+        ArchiveError::Io(e)
+    }
+}
+
+/// Compresses `data` into a new file, returning its path and digest.
+fn write_archive(
+    dir: &Path,
+    name: String,
+    data: &[u8],
+) -> Result<(PathBuf, sha256::Hash), std::io::Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(name);
+    let mut file = std::fs::File::create(&path)?;
+    file.write_all(&compressed)?;
+    file.sync_all()?;
+    Ok((path, sha256::Hash::hash(&compressed)))
+}
+
+fn read_archive<M: AttestEnvelopable>(
+    archive: &ArchivedChain,
+) -> Result<Vec<GenericEnvelope<M>>, ArchiveError> {
+    let compressed = std::fs::read(&archive.path)?;
+    if sha256::Hash::hash(&compressed) != archive.digest {
+        return Err(ArchiveError::DigestMismatch {
+            path: archive.path.clone(),
+        });
+    }
+    let mut data = vec![];
+    GzDecoder::new(&compressed[..]).read_to_end(&mut data)?;
+    let mut input = &data[..];
+    let mut envelopes = vec![];
+    while !input.is_empty() {
+        envelopes.push(GenericEnvelope::decode_binary(&mut input).map_err(ArchiveError::Corrupt)?);
+    }
+    Ok(envelopes)
+}
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get + handle_type::Insert,
+{
+    /// Archives the connected envelopes of a chain more than `keep` (at least
+    /// one) below its tip into a new file in `dir`, and deletes them.
+    ///
+    /// Returns `None` if there was nothing to archive.
+    pub fn archive_chain<M>(
+        &mut self,
+        genesis: CanonicalEnvelopeHash,
+        keep: u64,
+        dir: &Path,
+    ) -> Result<Result<Option<ArchivedChain>, ArchiveError>, rusqlite::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        let keep = keep.max(1) as i64;
+        let tx = self.0.transaction()?;
+        let tip: Option<CanonicalEnvelopeHash> = tx
+            .prepare_cached(SQL_GET_ARCHIVE_CHAIN_TIP)?
+            .query_row(named_params! {":genesis": genesis}, |r| r.get(0))
+            .optional()?;
+        let tip = match tip {
+            Some(tip) => tip,
+            None => return Ok(Err(ArchiveError::UnknownChain)),
+        };
+        // stops at the genesis, or at the checkpoint of the last archive
+        let chain = stored_chain_ending_at(&tx, tip)?;
+        let cutoff = chain.last().map_or(0, |(height, _)| height - keep);
+        let archived: Vec<_> = chain
+            .iter()
+            .filter(|(height, _)| 0 < *height && *height <= cutoff)
+            .collect();
+        let (first_height, last_height) = match (archived.first(), archived.last()) {
+            (Some((first, _)), Some((last, _))) => (*first, *last),
+            _ => return Ok(Ok(None)),
+        };
+        let checkpoint = chain
+            .iter()
+            .find(|(height, _)| *height == last_height + 1)
+            .map(|(_, hash)| *hash)
+            .expect("keep is at least one, so the tip is above the cutoff");
+
+        let mut data = vec![];
+        {
+            let mut stmt = tx.prepare_cached(SQL_GET_MESSAGE_BY_HASH)?;
+            for (_, hash) in &archived {
+                let envelope: GenericEnvelope<M> = stmt.query_row([hash], |r| r.get(0))?;
+                if let Err(e) = envelope.encode_binary(&mut data) {
+                    return Ok(Err(ArchiveError::Corrupt(e)));
+                }
+            }
+        }
+        let name = format!("{}-{}-{}.gz", genesis.to_hex(), first_height, last_height);
+        let (path, digest) = match write_archive(dir, name, &data) {
+            Ok(written) => written,
+            Err(e) => return Ok(Err(e.into())),
+        };
+
+        tx.prepare_cached(SQL_UPDATE_MARK_PREV_ARCHIVED)?
+            .execute(named_params! {":hash": checkpoint, ":prev_archived": true})?;
+        {
+            // top down, so no stored envelope is ever left without its parent
+            let mut stmt = tx.prepare_cached(SQL_UPDATE_PRUNE_MESSAGE)?;
+            for (_, hash) in archived.iter().rev() {
+                stmt.execute(named_params! {":hash": hash})?;
+            }
+        }
+        let archive = ArchivedChain {
+            genesis,
+            checkpoint,
+            first_height,
+            last_height,
+            path,
+            digest,
+            archived_time: attest_util::now(),
+        };
+        tx.prepare_cached(SQL_INSERT_ARCHIVED_CHAIN)?
+            .execute(named_params! {
+                ":genesis": archive.genesis,
+                ":checkpoint": archive.checkpoint,
+                ":first_height": archive.first_height,
+                ":last_height": archive.last_height,
+                ":path": archive.path.to_string_lossy().into_owned(),
+                ":digest": archive.digest.to_hex(),
+                ":archived_time": archive.archived_time,
+            })?;
+        tx.commit()?;
+        info!(?genesis, first_height, last_height, path=?archive.path, "Archived Chain");
+        Ok(Ok(Some(archive)))
+    }
+
+    /// Puts every archived envelope of a chain back in the DB, checking each
+    /// against the envelopes around it, and deletes the archive files.
+    ///
+    /// Returns how many envelopes were restored.
+    pub fn restore_chain<M, C>(
+        &mut self,
+        secp: &Secp256k1<C>,
+        genesis: CanonicalEnvelopeHash,
+    ) -> Result<Result<usize, ArchiveError>, rusqlite::Error>
+    where
+        M: AttestEnvelopable,
+        C: Verification,
+    {
+        let tx = self.0.transaction()?;
+        let archives = archived_chains_for(&tx, genesis)?;
+        // so the archived heights may be inserted again, without counting
+        // towards the chain's statistics twice
+        tx.prepare_cached(SQL_UPDATE_MARK_ARCHIVED_CHAINS_RESTORING)?
+            .execute(named_params! {":genesis": genesis})?;
+        let mut restored = 0;
+        // lowest first, as a checkpoint may itself be in a later archive
+        let mut lasts = vec![];
+        for archive in &archives {
+            let envelopes = match read_archive::<M>(archive) {
+                Ok(envelopes) => envelopes,
+                Err(e) => return Ok(Err(e)),
+            };
+            let heights = envelopes
+                .iter()
+                .map(|e| e.header().height())
+                .eq(archive.first_height..=archive.last_height);
+            if !heights {
+                return Ok(Err(ArchiveError::Invalid(format!(
+                    "Archive {} is missing envelopes",
+                    archive.path.display()
+                ))));
+            }
+            lasts.push(envelopes.last().map(|e| e.canonicalized_hash_ref()));
+            for envelope in envelopes {
+                if envelope.get_genesis_hash() != genesis {
+                    return Ok(Err(ArchiveError::Invalid(
+                        "Envelope from another chain".into(),
+                    )));
+                }
+                let authentic = match envelope.into_authenticated(secp) {
+                    Ok(authentic) => authentic,
+                    Err(e) => return Ok(Err(ArchiveError::Invalid(e.to_string()))),
+                };
+                match try_insert_authenticated_envelope_with_txn(authentic, &tx)? {
+                    Ok(()) => restored += 1,
+                    // e.g. if it was received again before being archived
+                    Err((SqliteFail::SqliteConstraintUnique, _)) => {}
+                    Err((fail, msg)) => return Ok(Err(ArchiveError::Rejected(fail, msg))),
+                }
+            }
+        }
+        for (archive, last) in archives.iter().zip(lasts) {
+            let checkpoint: GenericEnvelope<M> = tx
+                .prepare_cached(SQL_GET_MESSAGE_BY_HASH)?
+                .query_row([archive.checkpoint], |r| r.get(0))?;
+            if last != checkpoint.header().ancestors().map(|a| a.prev_msg()) {
+                return Ok(Err(ArchiveError::Invalid(format!(
+                    "Archive {} does not link to its checkpoint",
+                    archive.path.display()
+                ))));
+            }
+            tx.prepare_cached(SQL_UPDATE_MARK_PREV_ARCHIVED)?
+                .execute(named_params! {":hash": archive.checkpoint, ":prev_archived": false})?;
+        }
+        tx.prepare_cached(SQL_UPDATE_DELETE_ARCHIVED_CHAINS)?
+            .execute(named_params! {":genesis": genesis})?;
+        tx.commit()?;
+        for archive in &archives {
+            if let Err(e) = std::fs::remove_file(&archive.path) {
+                warn!(path=?archive.path, err=?e, "Could not remove restored archive");
+            }
+        }
+        info!(?genesis, restored, "Restored Chain");
+        Ok(Ok(restored))
+    }
+
+    /// Reclaims the space freed up by archiving.
+    ///
+    /// Blocks all other use of the DB while it runs, so should be called
+    /// sparingly.
+    pub fn compact(&self) -> Result<(), rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_UPDATE_COMPACT)?;
+        stmt.execute([])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Online snapshots of the whole DB, and restoring from them.
+//!
+//! Copying the DB file of a running node is not safe, as any of its
+//! connections may be mid write. Snapshots are taken with SQLite's backup API
+//! instead, which copies a consistent view of the DB while writers carry on.
+//!
+//! A snapshot is only restored after checking every envelope in it: its
+//! signature, that it is stored under its own hash, and that each connected
+//! envelope's parent is in the snapshot one height below it in the same chain.
+//! Restoring replaces the whole DB, users, keys and groups included.
+//!
+//! Nonces must never sign twice, so a snapshot that is behind the DB on any
+//! chain we sign for is refused, and nonces the DB has used stay used in the
+//! restored one.
+
+use super::sql::get::backup::{
+    SQL_GET_LOCAL_CHAIN_HEIGHTS, SQL_GET_SNAPSHOT_MESSAGES, SQL_GET_SPENT_NONCES,
+};
+use super::sql::update::{SQL_UPDATE_RETIRE_NONCE, SQL_UPDATE_USE_NONCE};
+use super::{handle_type, MsgDBHandle};
+use attest_messages::nonce::PrecomittedPublicNonce;
+use attest_messages::{AttestEnvelopable, CanonicalEnvelopeHash, GenericEnvelope};
+use fallible_iterator::FallibleIterator;
+use rusqlite::backup::Backup;
+use rusqlite::{Connection, OpenFlags};
+use sapio_bitcoin::secp256k1::{Secp256k1, Verification};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::info;
+
+#[derive(Debug)]
+pub enum BackupError {
+    Io(std::io::Error),
+    /// SQLite's own integrity check failed on the snapshot
+    Integrity(String),
+    /// an envelope in the snapshot is not authentic or not where it claims
+    /// to be
+    Invalid {
+        hash: CanonicalEnvelopeHash,
+        reason: String,
+    },
+    /// the snapshot is missing envelopes of a chain we sign for, restoring it
+    /// would let us sign at heights we already signed at
+    Behind {
+        genesis: CanonicalEnvelopeHash,
+        snapshot: Option<i64>,
+        live: i64,
+    },
+}
+
+impl Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for BackupError {}
+
+impl From<std::io::Error> for BackupError {
+    fn from(e: std::io::Error) -> Self {
+        BackupError::Io(e)
+    }
+}
+
+/// What a snapshot was found to hold when verified.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedSnapshot {
+    pub path: PathBuf,
+    pub envelopes: usize,
+    pub chains: usize,
+}
+
+/// Pages copied per step when restoring, between which other connections may
+/// get a look in.
+const RESTORE_PAGES_PER_STEP: i32 = 1024;
+const BUSY_WAIT: Duration = Duration::from_millis(50);
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// Writes a consistent snapshot of the DB to `path`.
+    ///
+    /// The snapshot is written next to `path` and only moved there once
+    /// complete, so `path` never holds a partial snapshot.
+    pub fn backup_to(&self, path: &Path) -> Result<Result<(), BackupError>, rusqlite::Error> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        {
+            let mut dst = Connection::open(&partial)?;
+            // a single step copies everything under one read transaction, so
+            // writes that land mid backup can't restart it
+            Backup::new(&self.0, &mut dst)?.run_to_completion(i32::MAX, BUSY_WAIT, None)?;
+            // leave a single self contained file behind
+            dst.pragma_update(None, "journal_mode", "DELETE")?;
+        }
+        if let Err(e) = std::fs::rename(&partial, path) {
+            return Ok(Err(e.into()));
+        }
+        info!(path=?path, "Wrote DB Snapshot");
+        Ok(Ok(()))
+    }
+}
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get + handle_type::Insert,
+{
+    /// Replaces the contents of the DB with the snapshot at `path`, if it
+    /// verifies and is not behind the DB on any chain we have the key of.
+    /// Nonces used in the DB are then marked used in the restored one too.
+    ///
+    /// Other connections to the DB see the restored contents from their next
+    /// transaction.
+    pub fn restore_snapshot<M, C>(
+        &mut self,
+        secp: &Secp256k1<C>,
+        path: &Path,
+    ) -> Result<Result<VerifiedSnapshot, BackupError>, rusqlite::Error>
+    where
+        M: AttestEnvelopable,
+        C: Verification,
+    {
+        let verified = match verify_snapshot::<M, C>(secp, path)? {
+            Ok(verified) => verified,
+            Err(e) => return Ok(Err(e)),
+        };
+        let src = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let snapshot_heights = local_chain_heights(&src)?;
+        for (genesis, live) in local_chain_heights(&self.0)? {
+            let snapshot = snapshot_heights.get(&genesis).copied();
+            if snapshot.map_or(true, |h| h < live) {
+                return Ok(Err(BackupError::Behind {
+                    genesis,
+                    snapshot,
+                    live,
+                }));
+            }
+        }
+        // (nonce, whether its secret was erased)
+        let spent: Vec<(PrecomittedPublicNonce, bool)> = self
+            .0
+            .prepare_cached(SQL_GET_SPENT_NONCES)?
+            .query([])?
+            .map(|r| Ok((r.get(0)?, r.get(1)?)))
+            .collect()?;
+        Backup::new(&src, &mut self.0)?.run_to_completion(
+            RESTORE_PAGES_PER_STEP,
+            BUSY_WAIT,
+            None,
+        )?;
+        let tx = self.0.transaction()?;
+        {
+            let mut retire = tx.prepare_cached(SQL_UPDATE_RETIRE_NONCE)?;
+            let mut use_nonce = tx.prepare_cached(SQL_UPDATE_USE_NONCE)?;
+            for (nonce, erased) in spent {
+                let stmt = if erased { &mut retire } else { &mut use_nonce };
+                stmt.execute(rusqlite::named_params! {":nonce": nonce})?;
+            }
+        }
+        tx.commit()?;
+        info!(path=?path, envelopes = verified.envelopes, "Restored DB Snapshot");
+        Ok(Ok(verified))
+    }
+}
+
+/// The height of every chain with a key in `conn`'s keyset.
+fn local_chain_heights(
+    conn: &Connection,
+) -> Result<HashMap<CanonicalEnvelopeHash, i64>, rusqlite::Error> {
+    conn.prepare_cached(SQL_GET_LOCAL_CHAIN_HEIGHTS)?
+        .query([])?
+        .map(|r| Ok((r.get(0)?, r.get(1)?)))
+        .collect()
+}
+
+/// Checks every envelope of the snapshot at `path`, without modifying it.
+pub fn verify_snapshot<M, C>(
+    secp: &Secp256k1<C>,
+    path: &Path,
+) -> Result<Result<VerifiedSnapshot, BackupError>, rusqlite::Error>
+where
+    M: AttestEnvelopable,
+    C: Verification,
+{
+    if !path.is_file() {
+        return Ok(Err(BackupError::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            path.display().to_string(),
+        ))));
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let integrity: String = conn.query_row("PRAGMA integrity_check", [], |r| r.get(0))?;
+    if integrity != "ok" {
+        return Ok(Err(BackupError::Integrity(integrity)));
+    }
+    let mut stmt = conn.prepare(SQL_GET_SNAPSHOT_MESSAGES)?;
+    let rows: Vec<(
+        GenericEnvelope<M>,
+        CanonicalEnvelopeHash,
+        CanonicalEnvelopeHash,
+        i64,
+        bool,
+        bool,
+    )> = stmt
+        .query([])?
+        .map(|r| {
+            Ok((
+                r.get(0)?,
+                r.get(1)?,
+                r.get(2)?,
+                r.get(3)?,
+                r.get(4)?,
+                r.get(5)?,
+            ))
+        })
+        .collect()?;
+    // (genesis, height) of every envelope, for checking linkage
+    let mut positions: HashMap<CanonicalEnvelopeHash, (CanonicalEnvelopeHash, i64)> =
+        HashMap::with_capacity(rows.len());
+    for (envelope, hash, genesis, height, _, _) in &rows {
+        let invalid = |reason: &str| BackupError::Invalid {
+            hash: *hash,
+            reason: reason.into(),
+        };
+        if envelope.canonicalized_hash_ref() != *hash {
+            return Ok(Err(invalid("stored under the wrong hash")));
+        }
+        if envelope.get_genesis_hash() != *genesis || envelope.header().height() != *height {
+            return Ok(Err(invalid("stored in the wrong place")));
+        }
+        if let Err(e) = envelope.clone().into_authenticated(secp) {
+            return Ok(Err(invalid(&e.to_string())));
+        }
+        positions.insert(*hash, (*genesis, *height));
+    }
+    let mut chains = 0;
+    for (envelope, hash, genesis, height, connected, prev_archived) in &rows {
+        let invalid = |reason: &str| BackupError::Invalid {
+            hash: *hash,
+            reason: reason.into(),
+        };
+        if positions.get(genesis) != Some(&(*genesis, 0)) {
+            return Ok(Err(invalid("genesis missing")));
+        }
+        if *height == 0 {
+            chains += 1;
+        }
+        if *height == 0 || !*connected || *prev_archived {
+            continue;
+        }
+        let parent = envelope.header().ancestors().map(|a| a.prev_msg());
+        if parent.and_then(|p| positions.get(&p)) != Some(&(*genesis, *height - 1)) {
+            return Ok(Err(invalid("connected, but parent missing")));
+        }
+    }
+    Ok(Ok(VerifiedSnapshot {
+        path: path.into(),
+        envelopes: rows.len(),
+        chains,
+    }))
+}
+
+/// Deletes all but the newest `keep` snapshots in `dir` whose file names
+/// start with `prefix`, returning the deleted paths.
+///
+/// Snapshots are ordered by file name, so names should sort by age.
+pub fn rotate_snapshots(
+    dir: &Path,
+    prefix: &str,
+    keep: usize,
+) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut snapshots = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_snapshot = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.starts_with(prefix) && !n.ends_with(".partial"))
+            .unwrap_or(false);
+        if is_snapshot && path.is_file() {
+            snapshots.push(path);
+        }
+    }
+    snapshots.sort();
+    let stale = snapshots.len().saturating_sub(keep);
+    let removed: Vec<PathBuf> = snapshots.into_iter().take(stale).collect();
+    for path in &removed {
+        std::fs::remove_file(path)?;
+    }
+    Ok(removed)
+}
@@ -6,10 +6,19 @@
 
 use crate::sql_error::SqliteFail;
 
+use super::get::ancestry::ancestry_for_child_of;
+use super::get::nonces::NonceState;
 use super::handle_type;
+use super::insert::save_partial_signature;
 
+use super::sql::update::SQL_UPDATE_USE_NONCE;
 use super::MsgDBHandle;
+use attest_messages::authority::KeyGrant;
+use attest_messages::blobs::BlobRef;
 use attest_messages::checkpoints::BitcoinCheckPoints;
+use attest_messages::ephemeral::Ephemeral;
+use attest_messages::musig::{GroupError, GroupNonce, PartialSignature, SigningGroup};
+use attest_messages::nonce::PrecomittedPublicNonce;
 use attest_messages::Ancestors;
 use attest_messages::AttestEnvelopable;
 use attest_messages::Authenticated;
@@ -20,7 +29,9 @@ use attest_messages::SigningError;
 use attest_messages::Unsigned;
 use attest_messages::WrappedJson;
 
-use sapio_bitcoin::secp256k1::Verification;
+use rusqlite::named_params;
+use sapio_bitcoin::hashes::hex::ToHex;
+use sapio_bitcoin::secp256k1::{Message, Verification};
 use sapio_bitcoin::{
     secp256k1::{Secp256k1, Signing},
     KeyPair, XOnlyPublicKey,
@@ -33,6 +44,7 @@ pub enum TipControl {
     AllTips,
 }
 use tracing::debug;
+use tracing::error;
 use tracing::warn;
 impl<T> MsgDBHandle<T>
 where
@@ -40,7 +52,9 @@ where
 {
     /// given an arbitrary inner message, generates an envelope and signs it.
     ///
-    /// Calling multiple times with a given nonce would result in nonce reuse.
+    /// Signing uses up the tip's `next_nonce`, so calling again before the
+    /// envelope is stored fails with [`SigningError::NonceAlreadyUsed`]
+    /// rather than reuse it.
     pub fn wrap_message_in_envelope_for_user_by_key<
         C: Signing,
         M: AttestEnvelopable,
@@ -54,6 +68,124 @@ where
         dangerous_bypass_tip: Option<Envelope>,
         tip_groups: TipControl,
     ) -> Result<Result<GenericEnvelope<M>, SigningError>, rusqlite::Error> {
+        self.wrap_message_in_envelope_with_grant(
+            msg,
+            keypair,
+            secp,
+            bitcoin_tipcache,
+            dangerous_bypass_tip,
+            tip_groups,
+            None,
+            vec![],
+            None,
+        )
+    }
+
+    /// Like [`Self::wrap_message_in_envelope_for_user_by_key`], but the
+    /// envelope also references `attachments`, see
+    /// [`attest_messages::blobs`].
+    ///
+    /// The attachments should be stored with [`MsgDBHandle::save_blob`] first
+    /// so that peers can fetch them from us.
+    pub fn wrap_attachments_in_envelope_for_user_by_key<
+        C: Signing,
+        M: AttestEnvelopable,
+        Im: Into<M>,
+    >(
+        &self,
+        msg: Im,
+        keypair: &KeyPair,
+        secp: &Secp256k1<C>,
+        bitcoin_tipcache: Option<BitcoinCheckPoints>,
+        attachments: Vec<BlobRef>,
+    ) -> Result<Result<GenericEnvelope<M>, SigningError>, rusqlite::Error> {
+        self.wrap_message_in_envelope_with_grant(
+            msg,
+            keypair,
+            secp,
+            bitcoin_tipcache,
+            None,
+            TipControl::NoTips,
+            None,
+            attachments,
+            None,
+        )
+    }
+
+    /// Like [`Self::wrap_message_in_envelope_for_user_by_key`], but the
+    /// envelope is ephemeral: its message may be pruned `ttl_ms` after it is
+    /// sent, see [`attest_messages::ephemeral`].
+    pub fn wrap_ephemeral_in_envelope_for_user_by_key<
+        C: Signing,
+        M: AttestEnvelopable,
+        Im: Into<M>,
+    >(
+        &self,
+        msg: Im,
+        keypair: &KeyPair,
+        secp: &Secp256k1<C>,
+        bitcoin_tipcache: Option<BitcoinCheckPoints>,
+        ttl_ms: u64,
+    ) -> Result<Result<GenericEnvelope<M>, SigningError>, rusqlite::Error> {
+        self.wrap_message_in_envelope_with_grant(
+            msg,
+            keypair,
+            secp,
+            bitcoin_tipcache,
+            None,
+            TipControl::NoTips,
+            None,
+            vec![],
+            Some(ttl_ms),
+        )
+    }
+
+    /// Like [`Self::wrap_message_in_envelope_for_user_by_key`], but the
+    /// envelope also carries a [`KeyGrant`] rotating or delegating the chain's
+    /// authority, see [`attest_messages::authority`].
+    ///
+    /// `keypair` must be the chain's current root key for the envelope to be
+    /// accepted.
+    pub fn wrap_grant_in_envelope_for_user_by_key<C: Signing, M: AttestEnvelopable, Im: Into<M>>(
+        &self,
+        msg: Im,
+        keypair: &KeyPair,
+        secp: &Secp256k1<C>,
+        bitcoin_tipcache: Option<BitcoinCheckPoints>,
+        grant: KeyGrant,
+    ) -> Result<Result<GenericEnvelope<M>, SigningError>, rusqlite::Error> {
+        self.wrap_message_in_envelope_with_grant(
+            msg,
+            keypair,
+            secp,
+            bitcoin_tipcache,
+            None,
+            TipControl::NoTips,
+            Some(grant),
+            vec![],
+            None,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn wrap_message_in_envelope_with_grant<C: Signing, M: AttestEnvelopable, Im: Into<M>>(
+        &self,
+        msg: Im,
+        keypair: &KeyPair,
+        secp: &Secp256k1<C>,
+        bitcoin_tipcache: Option<BitcoinCheckPoints>,
+        dangerous_bypass_tip: Option<Envelope>,
+        tip_groups: TipControl,
+        grant: Option<KeyGrant>,
+        attachments: Vec<BlobRef>,
+        ttl_ms: Option<u64>,
+    ) -> Result<Result<GenericEnvelope<M>, SigningError>, rusqlite::Error> {
+        let msg: M = msg.into();
+        let ephemeral = match ttl_ms.map(|ttl| msg.as_canonical().map(|m| Ephemeral::of(ttl, &m))) {
+            Some(Err(_)) => return Ok(Err(SigningError::HashingError)),
+            Some(Ok(e)) => Some(e),
+            None => None,
+        };
         let key: XOnlyPublicKey = keypair.x_only_public_key().0;
         debug!(key=%key, "Creating new Envelope");
         // Side effect free...
@@ -67,7 +199,15 @@ where
             }
             TipControl::NoTips => vec![],
         };
-        if let Some(p) = tips.iter().position(|x| x.header().key() == key) {
+        let my_tip = if let Some(envelope) = dangerous_bypass_tip {
+            envelope
+        } else {
+            self.get_tip_for_user_by_key(key)?.inner()
+        };
+        // match on the chain rather than the key, our tip may have been
+        // signed by a key the chain has since rotated away from
+        let my_genesis = my_tip.get_genesis_hash();
+        if let Some(p) = tips.iter().position(|x| x.get_genesis_hash() == my_genesis) {
             tips.swap_remove(p);
         }
         debug!(?tips, "Tip Envelopes");
@@ -80,32 +220,48 @@ where
             })
             .collect();
         debug!(?tips, "Extracted Tip Hashes");
-        let my_tip = if let Some(envelope) = dangerous_bypass_tip {
-            envelope
-        } else {
-            self.get_tip_for_user_by_key(key)?.inner()
-        };
         let sent_time_ms = attest_util::now();
-        let secret = self.get_secret_for_public_nonce(my_tip.header().next_nonce())?;
+        let nonce = my_tip.header().next_nonce();
+        if self.get_nonce_state(nonce)? == Some(NonceState::Used) {
+            error!(key=%key, ?nonce, "Refusing to sign a second envelope with a used nonce");
+            return Ok(Err(SigningError::NonceAlreadyUsed(nonce)));
+        }
+        let secret = self.get_secret_for_public_nonce(nonce)?;
+        let ancestry = ancestry_for_child_of(&self.0, &my_tip)?.unwrap_or_default();
         // Has side effects!
-        let next_nonce = self.generate_fresh_nonce_for_user_by_key(secp, key)?;
-        let mut msg = GenericEnvelope::new(
-            Header::new(
-                key,
-                next_nonce,
-                Some(Ancestors::new(
-                    my_tip.canonicalized_hash_ref(),
-                    my_tip.get_genesis_hash(),
-                )),
-                tips,
-                my_tip.header().height() + 1,
-                sent_time_ms,
-                Unsigned::new(Default::default()),
-                bitcoin_tipcache.unwrap_or_default(),
-            ),
-            msg.into(),
+        let next_nonce = self.claim_nonce_for_user_by_key(secp, key)?;
+        let header = Header::new(
+            key,
+            next_nonce,
+            Some(Ancestors::new(
+                my_tip.canonicalized_hash_ref(),
+                my_tip.get_genesis_hash(),
+            )),
+            tips,
+            my_tip.header().height() + 1,
+            sent_time_ms,
+            Unsigned::new(Default::default()),
+            bitcoin_tipcache.unwrap_or_default(),
+            ancestry,
         );
-        Ok(msg.sign_with(keypair, secp, secret).map(move |_| msg))
+        let header = match grant {
+            Some(grant) => header.with_grant(grant),
+            None => header,
+        }
+        .with_attachments(attachments);
+        let header = match ephemeral {
+            Some(e) => header.with_ephemeral(e),
+            None => header,
+        };
+        let mut msg = GenericEnvelope::new(header, msg);
+        if let Err(e) = msg.sign_with(keypair, secp, secret) {
+            return Ok(Err(e));
+        }
+        // the secret itself is erased once the envelope is stored
+        self.0
+            .prepare_cached(SQL_UPDATE_USE_NONCE)?
+            .execute(named_params! {":nonce": nonce})?;
+        Ok(Ok(msg))
     }
 
     pub fn retry_insert_authenticated_envelope_atomic<M, C, Im>(
@@ -122,16 +278,25 @@ where
         Im: Into<M> + Clone,
     {
         loop {
-            let wrapped = self
-                .wrap_message_in_envelope_for_user_by_key::<_, M, Im>(
-                    msg.clone(),
-                    keypair,
-                    secp,
-                    bitcoin_tipcache.clone(),
-                    None,
-                    tip_groups,
-                )??
-                .self_authenticate(secp)?;
+            let wrapped = self.wrap_message_in_envelope_for_user_by_key::<_, M, Im>(
+                msg.clone(),
+                keypair,
+                secp,
+                bitcoin_tipcache.clone(),
+                None,
+                tip_groups,
+            )??;
+            let nonce = wrapped.extract_used_nonce();
+            let wrapped = match wrapped.self_authenticate(secp) {
+                Ok(wrapped) => wrapped,
+                Err(e) => {
+                    // never stored, so the nonce may sign another
+                    if let Some(nonce) = nonce {
+                        self.release_unstored_nonce(nonce)?;
+                    }
+                    return Err(e.into());
+                }
+            };
             match self
                 .try_insert_authenticated_envelope(wrapped, true)?
                 .map_err(|(a, sqlite_error_extra)| {
@@ -145,3 +310,188 @@ where
         }
     }
 }
+
+/// Building and signing envelopes for chains owned by a [`SigningGroup`], see
+/// [`attest_messages::musig`].
+///
+/// A round at height `h` goes:
+///
+/// 1. every member makes a nonce share for `h + 1` with
+///    [`MsgDBHandle::generate_fresh_nonce_for_user_by_key`], the shares are
+///    summed with [`SigningGroup::aggregate_nonce`] and saved everywhere with
+///    [`MsgDBHandle::save_group_nonce`].
+/// 2. one member builds the envelope with [`MsgDBHandle::new_group_envelope`]
+///    and hands it around.
+/// 3. every member signs it with [`MsgDBHandle::partially_sign_group_envelope`]
+///    and the partial signatures are collected with
+///    [`MsgDBHandle::add_group_partial_signature`].
+/// 4. [`MsgDBHandle::finish_group_envelope`] combines them.
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get + handle_type::Insert,
+{
+    /// Builds the next envelope of `group`'s chain, unsigned, committing to
+    /// `next_nonce` for the envelope after it.
+    ///
+    /// Builds the chain's genesis if it has no envelopes yet.
+    pub fn new_group_envelope<M: AttestEnvelopable, Im: Into<M>>(
+        &self,
+        group: &SigningGroup,
+        msg: Im,
+        next_nonce: &GroupNonce,
+        bitcoin_tipcache: Option<BitcoinCheckPoints>,
+    ) -> Result<GenericEnvelope<M>, rusqlite::Error> {
+        let tip = match self.get_tip_for_user_by_key::<WrappedJson>(group.key()) {
+            Ok(tip) => Some(tip.inner()),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e),
+        };
+        let (ancestors, height, ancestry) = match &tip {
+            Some(tip) => (
+                Some(Ancestors::new(
+                    tip.canonicalized_hash_ref(),
+                    tip.get_genesis_hash(),
+                )),
+                tip.header().height() + 1,
+                ancestry_for_child_of(&self.0, tip)?.unwrap_or_default(),
+            ),
+            None => (None, 0, Default::default()),
+        };
+        let header = Header::new(
+            group.key(),
+            next_nonce.public(),
+            ancestors,
+            vec![],
+            height,
+            attest_util::now(),
+            Unsigned::new(Default::default()),
+            bitcoin_tipcache.unwrap_or_default(),
+            ancestry,
+        );
+        Ok(GenericEnvelope::new(header, msg.into()))
+    }
+
+    /// The group nonce `envelope` must be signed with and the digest to sign.
+    ///
+    /// That is the nonce its parent committed to, or `genesis_nonce` for the
+    /// first envelope of a chain.
+    fn group_signing_session<M: AttestEnvelopable>(
+        &self,
+        group: &SigningGroup,
+        envelope: &GenericEnvelope<M>,
+        genesis_nonce: Option<PrecomittedPublicNonce>,
+    ) -> Result<Result<(GroupNonce, Message), GroupError>, rusqlite::Error> {
+        if envelope.header().key() != group.key() {
+            return Ok(Err(GroupError::NotGroupKey));
+        }
+        let committed = match envelope.header().ancestors() {
+            Some(a) => self
+                .messages_by_hash::<_, Authenticated<Envelope>, WrappedJson>([a.prev_msg()].iter())?
+                .first()
+                .map(|parent| parent.header().next_nonce()),
+            None => genesis_nonce,
+        };
+        let nonce = match committed {
+            Some(committed) => self.get_group_nonce(group, committed)?,
+            None => None,
+        };
+        let digest = Message::from(envelope.clone().signature_digest_mut());
+        Ok(nonce
+            .map(|nonce| (nonce, digest))
+            .ok_or(GroupError::UncommittedNonce))
+    }
+
+    /// Partially signs `envelope` as the local member `keypair`.
+    ///
+    /// A member signs only one digest per nonce share: asking again for the
+    /// same envelope returns the same partial signature, any other envelope is
+    /// refused with [`GroupError::NonceAlreadyUsed`].
+    pub fn partially_sign_group_envelope<C: Signing, M: AttestEnvelopable>(
+        &self,
+        secp: &Secp256k1<C>,
+        group: &SigningGroup,
+        envelope: &GenericEnvelope<M>,
+        keypair: &KeyPair,
+        genesis_nonce: Option<PrecomittedPublicNonce>,
+    ) -> Result<Result<PartialSignature, GroupError>, rusqlite::Error> {
+        let (nonce, digest) = match self.group_signing_session(group, envelope, genesis_nonce)? {
+            Ok(session) => session,
+            Err(e) => return Ok(Err(e)),
+        };
+        let member = keypair.x_only_public_key().0;
+        if let Some((signed, partial)) = self
+            .get_all_partial_signatures(nonce.public())?
+            .remove(&member)
+        {
+            if signed == digest.as_ref().to_hex() {
+                return Ok(Ok(partial));
+            }
+            warn!(?member, "Refusing to reuse a group nonce share");
+            return Ok(Err(GroupError::NonceAlreadyUsed));
+        }
+        let share = match nonce.shares().get(&member) {
+            Some(share) => *share,
+            None => return Ok(Err(GroupError::MissingNonceShare(member))),
+        };
+        let secret = self.get_secret_for_public_nonce(share)?;
+        let partial = match group.sign_partial(secp, &nonce, &digest, keypair, secret) {
+            Ok(partial) => partial,
+            Err(e) => return Ok(Err(e)),
+        };
+        self.0
+            .prepare_cached(SQL_UPDATE_USE_NONCE)?
+            .execute(named_params! {":nonce": share})?;
+        // only hand out the partial signature once it is durably recorded
+        save_partial_signature(&self.0, nonce.public(), member, &digest, &partial)?;
+        Ok(Ok(partial))
+    }
+
+    /// Records another member's partial signature of `envelope`, after
+    /// checking it.
+    pub fn add_group_partial_signature<C: Signing + Verification, M: AttestEnvelopable>(
+        &self,
+        secp: &Secp256k1<C>,
+        group: &SigningGroup,
+        envelope: &GenericEnvelope<M>,
+        member: XOnlyPublicKey,
+        partial: PartialSignature,
+        genesis_nonce: Option<PrecomittedPublicNonce>,
+    ) -> Result<Result<(), GroupError>, rusqlite::Error> {
+        let (nonce, digest) = match self.group_signing_session(group, envelope, genesis_nonce)? {
+            Ok(session) => session,
+            Err(e) => return Ok(Err(e)),
+        };
+        if let Err(e) = group.verify_partial(secp, &nonce, &digest, &member, &partial) {
+            return Ok(Err(e));
+        }
+        match self
+            .get_all_partial_signatures(nonce.public())?
+            .remove(&member)
+        {
+            Some((_, known)) if known == partial => Ok(Ok(())),
+            Some(_) => Ok(Err(GroupError::NonceAlreadyUsed)),
+            None => {
+                save_partial_signature(&self.0, nonce.public(), member, &digest, &partial)?;
+                Ok(Ok(()))
+            }
+        }
+    }
+
+    /// Signs `envelope` once every member's partial signature is in.
+    pub fn finish_group_envelope<C: Signing + Verification, M: AttestEnvelopable>(
+        &self,
+        secp: &Secp256k1<C>,
+        group: &SigningGroup,
+        mut envelope: GenericEnvelope<M>,
+        genesis_nonce: Option<PrecomittedPublicNonce>,
+    ) -> Result<Result<GenericEnvelope<M>, GroupError>, rusqlite::Error> {
+        let (nonce, digest) = match self.group_signing_session(group, &envelope, genesis_nonce)? {
+            Ok(session) => session,
+            Err(e) => return Ok(Err(e)),
+        };
+        let partials = self.get_partial_signatures(nonce.public(), &digest)?;
+        Ok(group
+            .sign_envelope(secp, &mut envelope, &nonce, &partials)
+            .map(|()| envelope))
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::db_handle::sql::get::ancestry::*;
+use crate::db_handle::sql::get::messages::SQL_GET_MESSAGE_BY_HASH;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use attest_messages::ancestry::{path_for, AncestryPeaks, AncestryProof};
+use attest_messages::{AttestEnvelopable, CanonicalEnvelopeHash, GenericEnvelope};
+use fallible_iterator::FallibleIterator;
+use rusqlite::{named_params, Connection, OptionalExtension};
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// get the hash of every envelope from the genesis up to and including
+    /// `tip`, following `prev_msg` links.
+    ///
+    /// Returns `None` if the chain is not connected all the way to its
+    /// genesis.
+    pub fn get_chain_ending_at(
+        &self,
+        tip: CanonicalEnvelopeHash,
+    ) -> Result<Option<Vec<CanonicalEnvelopeHash>>, rusqlite::Error> {
+        chain_ending_at(&self.0, tip)
+    }
+
+    /// Builds a proof that `ancestor` precedes `tip` in `tip`'s chain.
+    ///
+    /// Returns `None` if `ancestor` is not an ancestor of `tip`, if the chain
+    /// is missing envelopes, or if `tip` does not commit to its ancestry.
+    pub fn get_ancestry_proof<M>(
+        &self,
+        ancestor: CanonicalEnvelopeHash,
+        tip: CanonicalEnvelopeHash,
+    ) -> Result<Option<AncestryProof<M>>, rusqlite::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        let mut stmt = self.0.prepare_cached(SQL_GET_MESSAGE_BY_HASH)?;
+        let tip_envelope: GenericEnvelope<M> = stmt.query_row([tip], |r| r.get(0))?;
+        let height = tip_envelope.header().height();
+        if !tip_envelope.header().ancestry().well_formed_for(height) {
+            return Ok(None);
+        }
+        let chain = match self.get_chain_ending_at(tip)? {
+            Some(chain) => chain,
+            None => return Ok(None),
+        };
+        let ancestors = &chain[..height as usize];
+        let ancestor_height = match ancestors.iter().position(|h| *h == ancestor) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        Ok(
+            path_for(ancestors, ancestor_height as u64).map(|path| AncestryProof {
+                tip: tip_envelope,
+                ancestor,
+                ancestor_height: ancestor_height as i64,
+                path,
+            }),
+        )
+    }
+
+    /// Checks that a stored envelope's ancestry commitment matches the chain
+    /// we have for it.
+    ///
+    /// Returns `None` if the envelope has no commitment or the chain is not
+    /// connected, as there is nothing to check.
+    pub fn check_ancestry_commitment<M>(
+        &self,
+        hash: CanonicalEnvelopeHash,
+    ) -> Result<Option<bool>, rusqlite::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        let mut stmt = self.0.prepare_cached(SQL_GET_MESSAGE_BY_HASH)?;
+        let envelope: GenericEnvelope<M> = stmt.query_row([hash], |r| r.get(0))?;
+        if envelope.header().ancestry().is_empty() && envelope.header().height() > 0 {
+            return Ok(None);
+        }
+        Ok(self.get_chain_ending_at(hash)?.map(|chain| {
+            &AncestryPeaks::from_chain(&chain[..chain.len() - 1]) == envelope.header().ancestry()
+        }))
+    }
+}
+
+/// The height and hash of every stored envelope from `tip` down, following
+/// `prev_msg` links for as long as they resolve, lowest first.
+pub(crate) fn stored_chain_ending_at(
+    conn: &Connection,
+    tip: CanonicalEnvelopeHash,
+) -> Result<Vec<(i64, CanonicalEnvelopeHash)>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(SQL_GET_CHAIN_ENDING_AT)?;
+    let rows = stmt
+        .query(named_params! {":tip": tip})?
+        .map(|r| Ok((r.get(0)?, r.get(1)?)))
+        .collect()?;
+    Ok(rows)
+}
+
+pub(crate) fn chain_ending_at(
+    conn: &Connection,
+    tip: CanonicalEnvelopeHash,
+) -> Result<Option<Vec<CanonicalEnvelopeHash>>, rusqlite::Error> {
+    let rows = stored_chain_ending_at(conn, tip)?;
+    let connected = !rows.is_empty()
+        && rows
+            .iter()
+            .enumerate()
+            .all(|(i, (height, _))| *height == i as i64);
+    Ok(connected.then(|| rows.into_iter().map(|(_, h)| h).collect()))
+}
+
+/// The ancestry commitment for a new child of `parent`, extending the
+/// parent's own commitment if it has one and otherwise rebuilding it from the
+/// chain.
+///
+/// Returns `None` if the parent has no commitment and its chain is not
+/// connected.
+pub(crate) fn ancestry_for_child_of<M: AttestEnvelopable>(
+    conn: &Connection,
+    parent: &GenericEnvelope<M>,
+) -> Result<Option<AncestryPeaks>, rusqlite::Error> {
+    if let Some(peaks) = AncestryPeaks::for_child_of(parent) {
+        return Ok(Some(peaks));
+    }
+    Ok(chain_ending_at(conn, parent.canonicalized_hash_ref())?
+        .map(|chain| AncestryPeaks::from_chain(&chain)))
+}
+
+/// The commitment a child of the envelope `prev` must make, if it can be
+/// determined from what is stored.
+pub(crate) fn expected_ancestry_after<M: AttestEnvelopable>(
+    conn: &Connection,
+    prev: CanonicalEnvelopeHash,
+) -> Result<Option<AncestryPeaks>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(SQL_GET_MESSAGE_BY_HASH)?;
+    let parent: Option<GenericEnvelope<M>> = stmt.query_row([prev], |r| r.get(0)).optional()?;
+    match parent {
+        Some(parent) => ancestry_for_child_of(conn, &parent),
+        None => Ok(None),
+    }
+}
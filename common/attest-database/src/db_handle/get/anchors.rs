@@ -0,0 +1,92 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::db_handle::sql::get::anchors::*;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use attest_messages::anchor::{merkle_branch, AnchorProof};
+use attest_messages::CanonicalEnvelopeHash;
+use fallible_iterator::FallibleIterator;
+use rusqlite::types::Type;
+use rusqlite::{named_params, OptionalExtension};
+use sapio_bitcoin::consensus::encode::{deserialize, Decodable};
+use sapio_bitcoin::hashes::hex::FromHex;
+use sapio_bitcoin::Txid;
+
+fn parse_consensus_hex<D: Decodable>(idx: usize, hex: String) -> Result<D, rusqlite::Error> {
+    Vec::<u8>::from_hex(&hex)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+        .and_then(|b| {
+            deserialize(&b).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e))
+            })
+        })
+}
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// The txids of anchors that have been broadcast but not yet confirmed,
+    /// oldest first.
+    pub fn get_unconfirmed_anchors(&self) -> Result<Vec<Txid>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_UNCONFIRMED_ANCHORS)?;
+        let txids = stmt
+            .query([])?
+            .map(|r| {
+                Txid::from_hex(&r.get::<_, String>(0)?).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e))
+                })
+            })
+            .collect()?;
+        Ok(txids)
+    }
+
+    /// Whether the envelope is part of any anchor, confirmed or not.
+    pub fn is_anchored(&self, envelope: CanonicalEnvelopeHash) -> Result<bool, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_MESSAGE_IS_ANCHORED)?;
+        stmt.query_row(named_params! {":message_hash": envelope}, |r| r.get(0))
+    }
+
+    /// The proof from the earliest confirmed anchor of the envelope, if any,
+    /// see [`attest_messages::anchor`].
+    ///
+    /// Only envelopes that were anchored themselves have a proof, the proof
+    /// of a descendant covers the rest.
+    pub fn get_anchor_proof(
+        &self,
+        envelope: CanonicalEnvelopeHash,
+    ) -> Result<Option<AnchorProof>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_EARLIEST_ANCHOR_FOR_MESSAGE)?;
+        let anchor = stmt
+            .query_row(named_params! {":message_hash": envelope}, |r| {
+                Ok((
+                    r.get::<_, i64>(0)?,
+                    parse_consensus_hex(1, r.get(1)?)?,
+                    parse_consensus_hex(2, r.get(2)?)?,
+                ))
+            })
+            .optional()?;
+        let (anchor_id, tx, tx_proof) = match anchor {
+            Some(a) => a,
+            None => return Ok(None),
+        };
+        let mut stmt = self.0.prepare_cached(SQL_GET_ANCHOR_LEAVES)?;
+        let leaves: Vec<CanonicalEnvelopeHash> = stmt
+            .query(named_params! {":anchor_id": anchor_id})?
+            .map(|r| r.get(0))
+            .collect()?;
+        Ok(leaves
+            .iter()
+            .position(|l| *l == envelope)
+            .and_then(|i| merkle_branch(&leaves, i))
+            .map(|branch| AnchorProof {
+                envelope,
+                branch,
+                tx,
+                tx_proof,
+            }))
+    }
+}
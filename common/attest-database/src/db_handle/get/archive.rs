@@ -0,0 +1,118 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::db_handle::sql::get::archive::*;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use attest_messages::CanonicalEnvelopeHash;
+use fallible_iterator::FallibleIterator;
+use rusqlite::types::Type;
+use rusqlite::{named_params, Connection, Row};
+use sapio_bitcoin::hashes::hex::FromHex;
+use sapio_bitcoin::hashes::sha256;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// A range of a chain that was moved out of the DB into an archive file, see
+/// [`crate::db_handle::archive`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchivedChain {
+    pub genesis: CanonicalEnvelopeHash,
+    /// the lowest envelope still stored above the range, which commits to
+    /// the last archived envelope as its `prev_msg`
+    pub checkpoint: CanonicalEnvelopeHash,
+    pub first_height: i64,
+    pub last_height: i64,
+    pub path: PathBuf,
+    /// sha256 of the archive file
+    pub digest: sha256::Hash,
+    pub archived_time: i64,
+}
+
+impl ArchivedChain {
+    fn from_row(r: &Row) -> Result<Self, rusqlite::Error> {
+        let path: String = r.get(4)?;
+        let digest: String = r.get(5)?;
+        Ok(ArchivedChain {
+            genesis: r.get(0)?,
+            checkpoint: r.get(1)?,
+            first_height: r.get(2)?,
+            last_height: r.get(3)?,
+            path: path.into(),
+            digest: sha256::Hash::from_hex(&digest).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(5, Type::Text, Box::new(e))
+            })?,
+            archived_time: r.get(6)?,
+        })
+    }
+}
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// Every archived range, grouped by chain, lowest first.
+    pub fn get_archived_chains(&self) -> Result<Vec<ArchivedChain>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_ALL_ARCHIVED_CHAINS)?;
+        let archives = stmt.query([])?.map(ArchivedChain::from_row).collect()?;
+        Ok(archives)
+    }
+
+    /// The archived ranges of one chain, lowest first.
+    pub fn get_archived_chains_for(
+        &self,
+        genesis: CanonicalEnvelopeHash,
+    ) -> Result<Vec<ArchivedChain>, rusqlite::Error> {
+        archived_chains_for(&self.0, genesis)
+    }
+
+    /// Chains with envelopes more than `keep` below their tip that are due
+    /// for archiving, either because nothing new was received for them since
+    /// `older_than` (in ms) or, if `finished_groups`, because they are in a
+    /// finished chain commit group.
+    pub fn get_archive_candidates(
+        &self,
+        older_than: i64,
+        finished_groups: bool,
+        keep: u64,
+    ) -> Result<Vec<CanonicalEnvelopeHash>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_ARCHIVE_CANDIDATES)?;
+        let chains = stmt
+            .query(named_params! {
+                ":older_than": older_than,
+                ":finished_groups": finished_groups,
+                ":keep": keep as i64,
+            })?
+            .map(|r| r.get(0))
+            .collect()?;
+        Ok(chains)
+    }
+}
+
+pub(crate) fn archived_chains_for(
+    conn: &Connection,
+    genesis: CanonicalEnvelopeHash,
+) -> Result<Vec<ArchivedChain>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(SQL_GET_ARCHIVED_CHAINS_FOR_CHAIN)?;
+    let archives = stmt
+        .query(named_params! {":genesis": genesis})?
+        .map(ArchivedChain::from_row)
+        .collect()?;
+    Ok(archives)
+}
+
+/// Whether the envelope at `height` in the chain was archived, in which case
+/// it should not be stored again until the chain is restored.
+pub(crate) fn is_archived(
+    conn: &Connection,
+    genesis: CanonicalEnvelopeHash,
+    height: i64,
+) -> Result<bool, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(SQL_GET_IS_ARCHIVED)?;
+    stmt.query_row(
+        named_params! {":genesis": genesis, ":height": height},
+        |r| r.get(0),
+    )
+}
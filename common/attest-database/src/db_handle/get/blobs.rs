@@ -0,0 +1,81 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::db_handle::sql::get::blobs::*;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use attest_messages::blobs::BlobRef;
+use attest_messages::CanonicalEnvelopeHash;
+use fallible_iterator::FallibleIterator;
+use rusqlite::types::Type;
+use rusqlite::{named_params, OptionalExtension};
+use sapio_bitcoin::hashes::hex::{FromHex, ToHex};
+use sapio_bitcoin::hashes::sha256;
+
+fn parse_hash(idx: usize, hex: String) -> Result<sha256::Hash, rusqlite::Error> {
+    sha256::Hash::from_hex(&hex)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+}
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// The attachment with the given hash, if we have it.
+    pub fn get_blob(&self, hash: sha256::Hash) -> Result<Option<Vec<u8>>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_BLOB_BY_HASH)?;
+        stmt.query_row(named_params! {":hash": hash.to_hex()}, |r| r.get(0))
+            .optional()
+    }
+
+    /// The size of the attachment with the given hash, if we have it, without
+    /// reading it.
+    pub fn get_blob_size(&self, hash: sha256::Hash) -> Result<Option<u64>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_BLOB_SIZE_BY_HASH)?;
+        stmt.query_row(named_params! {":hash": hash.to_hex()}, |r| r.get(0))
+            .optional()
+    }
+
+    /// Like [`Self::get_blob`], but only if some chain the peer may see
+    /// references it, see [`Self::get_chains_hidden_from`].
+    pub fn get_blob_visible_to(
+        &self,
+        hash: sha256::Hash,
+        service_url: &str,
+        port: u16,
+    ) -> Result<Option<Vec<u8>>, rusqlite::Error> {
+        let hidden = self.get_chains_hidden_from(service_url, port)?;
+        let mut stmt = self.0.prepare_cached(SQL_GET_BLOB_REFERENCING_CHAINS)?;
+        let chains: Vec<CanonicalEnvelopeHash> = stmt
+            .query(named_params! {":hash": hash.to_hex()})?
+            .map(|r| r.get(0))
+            .collect()?;
+        if chains.iter().all(|c| hidden.contains(c)) {
+            return Ok(None);
+        }
+        self.get_blob(hash)
+    }
+
+    /// Attachments no larger than `max_size` referenced by envelopes we have
+    /// but which we don't have ourselves, at most `limit` of them. They come
+    /// in random order so that ones no peer has don't starve the rest.
+    pub fn get_missing_blobs(
+        &self,
+        max_size: u32,
+        limit: u32,
+    ) -> Result<Vec<BlobRef>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_MISSING_BLOBS)?;
+        let missing = stmt
+            .query(named_params! {":max_size": max_size, ":limit": limit})?
+            .map(|r| {
+                Ok(BlobRef {
+                    hash: parse_hash(0, r.get(0)?)?,
+                    size: r.get(1)?,
+                })
+            })
+            .collect()?;
+        Ok(missing)
+    }
+}
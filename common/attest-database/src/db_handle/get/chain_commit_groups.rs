@@ -17,12 +17,77 @@ use attest_messages::Authenticated;
 use attest_messages::CanonicalEnvelopeHash;
 
 use attest_messages::GenericEnvelope;
+use attest_messages::WrappedJson;
 use fallible_iterator::FallibleIterator;
 use rusqlite::named_params;
-use rusqlite::types::FromSql;
+use rusqlite::types::{FromSql, FromSqlError, ToSqlOutput};
+use rusqlite::{OptionalExtension, ToSql};
 use sapio_bitcoin::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
 use tracing::warn;
 
+/// Which tips of a group's members go into the envelopes of chains
+/// subscribed to it, when they are made with `TipControl::GroupsOnly`.
+///
+/// A member of several groups a chain subscribes to follows the most
+/// inclusive of their policies.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupTipPolicy {
+    /// the latest tip of every member
+    All,
+    /// only tips the subscriber's latest envelope doesn't already reference
+    Changed,
+    /// no tips at all, e.g. while a game is on hold
+    Paused,
+}
+
+impl Default for GroupTipPolicy {
+    fn default() -> Self {
+        GroupTipPolicy::All
+    }
+}
+
+impl ToSql for GroupTipPolicy {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            GroupTipPolicy::All => "All",
+            GroupTipPolicy::Changed => "Changed",
+            GroupTipPolicy::Paused => "Paused",
+        }
+        .into())
+    }
+}
+impl FromSql for GroupTipPolicy {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        match value.as_str()? {
+            "All" => Ok(GroupTipPolicy::All),
+            "Changed" => Ok(GroupTipPolicy::Changed),
+            "Paused" => Ok(GroupTipPolicy::Paused),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainCommitGroup {
+    pub id: ChainCommitGroupID,
+    pub name: String,
+    pub finished: bool,
+    pub tip_policy: GroupTipPolicy,
+}
+
+/// How one of a key's chains takes part in a group. A chain may both be a
+/// member (others commit to its tips) and subscribe (it commits to theirs).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainCommitGroupMembership {
+    pub group_id: ChainCommitGroupID,
+    pub name: String,
+    pub genesis: CanonicalEnvelopeHash,
+    pub member: bool,
+    pub subscriber: bool,
+    pub tip_policy: GroupTipPolicy,
+}
+
 impl<T> MsgDBHandle<T>
 where
     T: handle_type::Get,
@@ -71,6 +136,59 @@ where
         .collect()
     }
 
+    /// get the genesis hash of every chain that is a member of the named group
+    pub fn get_chain_commit_group_member_chains_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Vec<CanonicalEnvelopeHash>, rusqlite::Error> {
+        let mut stmt = self
+            .0
+            .prepare_cached(SQL_GET_CHAIN_COMMIT_GROUP_MEMBER_CHAINS_BY_GROUP_NAME)?;
+        let q = stmt.query(named_params! {":name": name})?;
+        q.mapped(|row| row.get(0)).collect()
+    }
+
+    pub fn get_chain_commit_group_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Option<ChainCommitGroup>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_CHAIN_COMMIT_GROUP_BY_NAME)?;
+        stmt.query_row(named_params! {":name": name}, |row| {
+            Ok(ChainCommitGroup {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                finished: row.get(2)?,
+                tip_policy: row.get(3)?,
+            })
+        })
+        .optional()
+    }
+
+    /// every group that a chain created by `key` is a member of or subscribes
+    /// to
+    pub fn get_chain_commit_group_memberships_for_key(
+        &self,
+        key: XOnlyPublicKey,
+    ) -> Result<Vec<ChainCommitGroupMembership>, rusqlite::Error> {
+        let mut stmt = self
+            .0
+            .prepare_cached(SQL_GET_CHAIN_COMMIT_GROUP_MEMBERSHIPS_FOR_KEY)?;
+        let q = stmt.query(named_params! {":key": PK(key)})?;
+        q.mapped(|row| {
+            Ok(ChainCommitGroupMembership {
+                group_id: row.get(0)?,
+                name: row.get(1)?,
+                genesis: row.get(2)?,
+                member: row.get(3)?,
+                subscriber: row.get(4)?,
+                tip_policy: row.get(5)?,
+            })
+        })
+        .collect()
+    }
+
+    /// the tips of the members of every group the chain of `key` subscribes
+    /// to, as allowed by each group's [`GroupTipPolicy`]
     pub fn get_all_chain_commit_group_members_tips_for_chain<M>(
         &self,
         key: XOnlyPublicKey,
@@ -84,12 +202,14 @@ where
             .prepare_cached(SQL_GET_ALL_CHAIN_COMMIT_GROUP_MEMBERS_TIPS_FOR_CHAIN)?;
         let mut q = stmt.query(named_params! {":key": PK(key)})?;
         let mut v: Vec<Authenticated<GenericEnvelope<M>>> = vec![];
+        let mut changed_only = vec![];
         loop {
             match q.next() {
                 Ok(o) => match o {
                     Some(row) => match row.get(0) {
                         Ok(r1) => {
                             v.push(r1);
+                            changed_only.push(row.get::<_, bool>(2)?);
                         }
                         Err(error) => {
                             warn!(?error, "Corrupt Row");
@@ -103,6 +223,18 @@ where
                 Err(e) => return Err(e),
             }
         }
+        if changed_only.contains(&true) {
+            let referenced: Vec<CanonicalEnvelopeHash> = self
+                .get_tip_for_user_by_key::<WrappedJson>(key)
+                .optional()?
+                .map(|tip| tip.header().tips().iter().map(|t| t.2).collect())
+                .unwrap_or_default();
+            let mut changed_only = changed_only.into_iter();
+            v.retain(|tip| {
+                !(changed_only.next() == Some(true)
+                    && referenced.contains(&tip.canonicalized_hash_ref()))
+            });
+        }
         Ok(v)
     }
 
@@ -0,0 +1,195 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::db_handle::sql::get::chain_keys::*;
+use crate::db_handle::sql::insert::SQL_INSERT_CHAIN_KEY;
+use crate::db_handle::sql::update::SQL_UPDATE_END_CHAIN_KEY;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use crate::sql_serializers::PK;
+use attest_messages::authority::{AuthorityError, KeyGrant, Role};
+use attest_messages::{CanonicalEnvelopeHash, Header};
+use rusqlite::{named_params, Connection, OptionalExtension};
+use sapio_bitcoin::XOnlyPublicKey;
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// the key with root authority over the chain at `height`, following any
+    /// rotations.
+    ///
+    /// Returns `None` if the chain's genesis is not known.
+    pub fn get_root_key_at(
+        &self,
+        genesis: CanonicalEnvelopeHash,
+        height: i64,
+    ) -> Result<Option<XOnlyPublicKey>, rusqlite::Error> {
+        root_key_at(&self.0, genesis, height)
+    }
+
+    /// the role `key` has in the chain at `height`, if any.
+    pub fn get_chain_role(
+        &self,
+        genesis: CanonicalEnvelopeHash,
+        key: XOnlyPublicKey,
+        height: i64,
+    ) -> Result<Option<Role>, rusqlite::Error> {
+        if root_key_at(&self.0, genesis, height)? == Some(key) {
+            return Ok(Some(Role::Root));
+        }
+        Ok(is_delegate_at(&self.0, genesis, key, height)?.then(|| Role::Delegate))
+    }
+}
+
+/// The root recorded in the chain_keys table, chains from before rotations
+/// were tracked have none.
+fn tracked_root_key_at(
+    conn: &Connection,
+    genesis: CanonicalEnvelopeHash,
+    height: i64,
+) -> Result<Option<XOnlyPublicKey>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(SQL_GET_CHAIN_ROOT_KEY_AT)?;
+    Ok(stmt
+        .query_row(
+            named_params! {":genesis": genesis, ":height": height},
+            |r| r.get::<_, PK>(0),
+        )
+        .optional()?
+        .map(|k| k.0))
+}
+
+pub(crate) fn root_key_at(
+    conn: &Connection,
+    genesis: CanonicalEnvelopeHash,
+    height: i64,
+) -> Result<Option<XOnlyPublicKey>, rusqlite::Error> {
+    if let Some(root) = tracked_root_key_at(conn, genesis, height)? {
+        return Ok(Some(root));
+    }
+    let mut stmt = conn.prepare_cached(SQL_GET_CHAIN_GENESIS_KEY)?;
+    Ok(stmt
+        .query_row(named_params! {":genesis": genesis}, |r| r.get::<_, PK>(0))
+        .optional()?
+        .map(|k| k.0))
+}
+
+fn is_delegate_at(
+    conn: &Connection,
+    genesis: CanonicalEnvelopeHash,
+    key: XOnlyPublicKey,
+    height: i64,
+) -> Result<bool, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(SQL_GET_CHAIN_KEY_IS_DELEGATE_AT)?;
+    stmt.query_row(
+        named_params! {":genesis": genesis, ":key": PK(key), ":height": height},
+        |r| r.get(0),
+    )
+}
+
+/// Checks that the signer of `header` may post to the chain at its height.
+///
+/// If the chain's genesis hasn't been seen yet its root is unknown, the
+/// signer is then treated as the root as was the case before rotations: the
+/// envelope is only accepted if its key belongs to a known user.
+pub(crate) fn check_chain_authority(
+    conn: &Connection,
+    header: &Header,
+    genesis: CanonicalEnvelopeHash,
+) -> Result<Result<Role, AuthorityError>, rusqlite::Error> {
+    let height = header.height();
+    if height == 0 {
+        return Ok(Ok(Role::Root));
+    }
+    let role = match root_key_at(conn, genesis, height)? {
+        None => Role::Root,
+        Some(root) if root == header.key() => Role::Root,
+        Some(_) if is_delegate_at(conn, genesis, header.key(), height)? => Role::Delegate,
+        Some(_) => return Ok(Err(AuthorityError::UnauthorizedKey)),
+    };
+    if header.grant().is_some() && role != Role::Root {
+        return Ok(Err(AuthorityError::GrantFromDelegate));
+    }
+    Ok(Ok(role))
+}
+
+/// Records the keys a newly inserted envelope hands authority to.
+pub(crate) fn record_chain_keys(
+    conn: &Connection,
+    header: &Header,
+    genesis: CanonicalEnvelopeHash,
+    hash: CanonicalEnvelopeHash,
+) -> Result<(), rusqlite::Error> {
+    let mut insert = conn.prepare_cached(SQL_INSERT_CHAIN_KEY)?;
+    let mut end = conn.prepare_cached(SQL_UPDATE_END_CHAIN_KEY)?;
+    let height = header.height();
+    if height == 0 {
+        insert.execute(named_params! {
+            ":genesis": genesis,
+            ":key": PK(header.key()),
+            ":granted_in": hash,
+            ":role": Role::Root,
+            ":from_height": 0i64,
+            ":until_height": None::<i64>,
+        })?;
+    }
+    // grants apply from the next envelope on
+    let effective = height + 1;
+    match header.grant() {
+        Some(KeyGrant::Rotate { to }) => {
+            if tracked_root_key_at(conn, genesis, height)?.is_none() {
+                // a chain from before rotations were tracked
+                insert.execute(named_params! {
+                    ":genesis": genesis,
+                    ":key": PK(header.key()),
+                    ":granted_in": hash,
+                    ":role": Role::Root,
+                    ":from_height": 0i64,
+                    ":until_height": None::<i64>,
+                })?;
+            }
+            end.execute(named_params! {
+                ":genesis": genesis,
+                ":key": PK(header.key()),
+                ":role": Role::Root,
+                ":height": effective,
+            })?;
+            end.execute(named_params! {
+                ":genesis": genesis,
+                ":key": PK(*to),
+                ":role": Role::Delegate,
+                ":height": effective,
+            })?;
+            insert.execute(named_params! {
+                ":genesis": genesis,
+                ":key": PK(*to),
+                ":granted_in": hash,
+                ":role": Role::Root,
+                ":from_height": effective,
+                ":until_height": None::<i64>,
+            })?;
+        }
+        Some(KeyGrant::Delegate { to, until_height }) => {
+            insert.execute(named_params! {
+                ":genesis": genesis,
+                ":key": PK(*to),
+                ":granted_in": hash,
+                ":role": Role::Delegate,
+                ":from_height": effective,
+                ":until_height": until_height,
+            })?;
+        }
+        Some(KeyGrant::Revoke { key }) => {
+            end.execute(named_params! {
+                ":genesis": genesis,
+                ":key": PK(*key),
+                ":role": Role::Delegate,
+                ":height": effective,
+            })?;
+        }
+        None => {}
+    }
+    Ok(())
+}
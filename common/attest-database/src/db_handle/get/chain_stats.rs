@@ -0,0 +1,106 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Per chain statistics, for diagnosing slow players and flaky peers.
+//!
+//! They are kept up to date by a trigger as envelopes are stored rather than
+//! computed on demand, so reading them is cheap. Every stored envelope counts
+//! once, envelopes restored from an archive aren't counted again.
+//!
+//! Envelopes stored before the statistics were kept are counted when the DB is
+//! migrated, but not towards the propagation delay, as they may have been
+//! replicated long after they were sent.
+
+use crate::db_handle::sql::get::chain_stats::*;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use crate::sql_serializers::PK;
+use attest_messages::CanonicalEnvelopeHash;
+use fallible_iterator::FallibleIterator;
+use rusqlite::Row;
+use sapio_bitcoin::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
+
+/// Upper bounds, in ms, of the propagation delay histogram buckets of
+/// [`ChainStats::delay_histogram`] after the first. The first bucket counts
+/// negative delays and the last delays of at least a minute.
+pub const DELAY_BUCKETS_MS: [i64; 4] = [100, 1_000, 10_000, 60_000];
+
+/// Statistics of a chain's stored envelopes.
+///
+/// The propagation delay of an envelope is the time between its
+/// `sent_time_ms` and it being stored here. Negative delays come from a
+/// sender whose clock is ahead of ours.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChainStats {
+    pub genesis: CanonicalEnvelopeHash,
+    pub key: XOnlyPublicKey,
+    pub nickname: Option<String>,
+    pub messages: u64,
+    /// local time the first and last envelopes were stored
+    pub first_seen: i64,
+    pub last_seen: i64,
+    /// the earliest and latest `sent_time_ms`
+    pub first_sent: i64,
+    pub last_sent: i64,
+    /// envelopes sent per minute between `first_sent` and `last_sent`
+    pub messages_per_minute: f64,
+    /// over the envelopes in `delay_histogram`
+    pub mean_delay_ms: i64,
+    pub max_delay_ms: i64,
+    /// negative, then below each of [`DELAY_BUCKETS_MS`], then the rest
+    pub delay_histogram: [u64; 6],
+}
+
+impl ChainStats {
+    fn from_row(r: &Row) -> Result<Self, rusqlite::Error> {
+        let messages: u64 = r.get(3)?;
+        let first_sent: i64 = r.get(6)?;
+        let last_sent: i64 = r.get(7)?;
+        let total_delay: i64 = r.get(8)?;
+        let delay_histogram: [u64; 6] = [
+            r.get(10)?,
+            r.get(11)?,
+            r.get(12)?,
+            r.get(13)?,
+            r.get(14)?,
+            r.get(15)?,
+        ];
+        let delayed: u64 = delay_histogram.iter().sum();
+        let span = last_sent.saturating_sub(first_sent);
+        Ok(ChainStats {
+            genesis: r.get(0)?,
+            key: r.get::<_, PK>(1)?.0,
+            nickname: r.get(2)?,
+            messages,
+            first_seen: r.get(4)?,
+            last_seen: r.get(5)?,
+            first_sent,
+            last_sent,
+            messages_per_minute: if span > 0 {
+                // the first envelope starts the span rather than being sent in it
+                (messages - 1) as f64 * 60_000.0 / span as f64
+            } else {
+                0.0
+            },
+            mean_delay_ms: total_delay / delayed.max(1) as i64,
+            max_delay_ms: r.get(9)?,
+            delay_histogram,
+        })
+    }
+}
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// Statistics of every chain with stored envelopes, most recently active
+    /// first.
+    pub fn get_chain_stats(&self) -> Result<Vec<ChainStats>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_ALL_CHAIN_STATS)?;
+        let stats = stmt.query([])?.map(ChainStats::from_row).collect()?;
+        Ok(stats)
+    }
+}
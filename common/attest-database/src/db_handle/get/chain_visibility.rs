@@ -0,0 +1,74 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::db_handle::sql::get::chain_visibility::*;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use attest_messages::CanonicalEnvelopeHash;
+use fallible_iterator::FallibleIterator;
+use rusqlite::named_params;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Who a chain may be shared with.
+///
+/// Chains are `Public` unless set otherwise. A `Private` chain is only served
+/// to, and pushed to, the listed peers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ChainVisibility {
+    Public,
+    Private { allowed: Vec<(String, u16)> },
+}
+
+impl Default for ChainVisibility {
+    fn default() -> Self {
+        ChainVisibility::Public
+    }
+}
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// get the visibility policy of a chain
+    pub fn get_chain_visibility(
+        &self,
+        genesis: CanonicalEnvelopeHash,
+    ) -> Result<ChainVisibility, rusqlite::Error> {
+        let mut stmt = self
+            .0
+            .prepare_cached(SQL_GET_CHAIN_VISIBILITY_ALLOWED_PEERS)?;
+        let rows: Vec<Option<(String, u16)>> = stmt
+            .query(named_params! {":genesis": genesis})?
+            .map(|r| {
+                let url: Option<String> = r.get(0)?;
+                let port: Option<u16> = r.get(1)?;
+                Ok(url.zip(port))
+            })
+            .collect()?;
+        if rows.is_empty() {
+            Ok(ChainVisibility::Public)
+        } else {
+            Ok(ChainVisibility::Private {
+                allowed: rows.into_iter().flatten().collect(),
+            })
+        }
+    }
+
+    /// get the genesis hash of every chain that must not be shared with the
+    /// given peer
+    pub fn get_chains_hidden_from(
+        &self,
+        service_url: &str,
+        port: u16,
+    ) -> Result<BTreeSet<CanonicalEnvelopeHash>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_CHAINS_HIDDEN_FROM_SERVICE)?;
+        let results = stmt
+            .query(named_params! {":service_url": service_url, ":port": port})?
+            .map(|r| r.get(0))
+            .collect()?;
+        Ok(results)
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::db_handle::sql::get::messages::SQL_GET_MESSAGE_BY_HASH;
+use crate::db_handle::sql::get::users::SQL_GET_SECRET_KEY_BY_KEY;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use crate::sql_serializers::{PK, SK};
+use attest_messages::encrypted::{EncryptedPayload, EncryptionError};
+use attest_messages::{CanonicalEnvelopeHash, GenericEnvelope};
+use ruma_serde::CanonicalJsonValue;
+use rusqlite::OptionalExtension;
+use sapio_bitcoin::secp256k1::{Secp256k1, Signing};
+use sapio_bitcoin::KeyPair;
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// Opens `payload` with the first of its recipients whose secret key we
+    /// hold.
+    pub fn decrypt_for_local_keys<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        payload: &EncryptedPayload,
+    ) -> Result<Result<CanonicalJsonValue, EncryptionError>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_SECRET_KEY_BY_KEY)?;
+        for to in payload.recipients() {
+            if let Some(sk) = stmt.query_row([PK(to)], |r| r.get::<_, SK>(0)).optional()? {
+                let kp = KeyPair::from_secret_key(secp, &sk.0);
+                return Ok(payload.open(secp, &kp));
+            }
+        }
+        Ok(Err(EncryptionError::NotARecipient))
+    }
+
+    /// Decrypts the message of the envelope `hash`, which must be an
+    /// [`EncryptedPayload`] sealed by the key that signed the envelope.
+    pub fn get_decrypted_message_by_hash<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        hash: CanonicalEnvelopeHash,
+    ) -> Result<Result<CanonicalJsonValue, EncryptionError>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_MESSAGE_BY_HASH)?;
+        let envelope: GenericEnvelope<EncryptedPayload> = stmt.query_row([hash], |r| r.get(0))?;
+        if envelope.msg().sender() != envelope.header().key() {
+            return Ok(Err(EncryptionError::SenderMismatch));
+        }
+        self.decrypt_for_local_keys(secp, envelope.msg())
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::db_handle::sql::get::gaps::*;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use attest_messages::{AttestEnvelopable, CanonicalEnvelopeHash, GenericEnvelope};
+use fallible_iterator::FallibleIterator;
+use rusqlite::named_params;
+use rusqlite::types::FromSql;
+use serde::{Deserialize, Serialize};
+
+/// A run of heights missing from a chain, just below an envelope we have but
+/// can't connect until they arrive.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Gap {
+    pub first_height: i64,
+    pub last_height: i64,
+    /// the envelope at `last_height`, as committed to by the one above the gap
+    pub needed: CanonicalEnvelopeHash,
+}
+
+/// The gaps of one chain, lowest first.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainGaps {
+    pub genesis: CanonicalEnvelopeHash,
+    pub gaps: Vec<Gap>,
+}
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// Every chain with envelopes whose ancestors are missing, and exactly
+    /// which heights are missing. Archived ranges are not gaps.
+    pub fn get_chain_gaps(&self) -> Result<Vec<ChainGaps>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_GAPS_MISSING_PARENTS)?;
+        let rows: Vec<(CanonicalEnvelopeHash, i64, CanonicalEnvelopeHash, i64)> = stmt
+            .query([])?
+            .map(|r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))
+            .collect()?;
+        let mut chains: Vec<ChainGaps> = vec![];
+        for (genesis, height, needed, below) in rows {
+            let gap = Gap {
+                // if a different envelope is stored right below, only the
+                // parent itself is missing
+                first_height: (below + 1).min(height - 1),
+                last_height: height - 1,
+                needed,
+            };
+            match chains.last_mut() {
+                Some(c) if c.genesis == genesis => c.gaps.push(gap),
+                _ => chains.push(ChainGaps {
+                    genesis,
+                    gaps: vec![gap],
+                }),
+            }
+        }
+        Ok(chains)
+    }
+
+    /// The stored envelopes of a chain from `first_height` to `last_height`
+    /// (inclusive), lowest first and at most `limit` of them.
+    pub fn get_envelopes_in_range<E, M>(
+        &self,
+        genesis: CanonicalEnvelopeHash,
+        first_height: i64,
+        last_height: i64,
+        limit: u32,
+    ) -> Result<Vec<E>, rusqlite::Error>
+    where
+        E: AsRef<GenericEnvelope<M>> + FromSql,
+        M: AttestEnvelopable,
+    {
+        let mut stmt = self.0.prepare_cached(SQL_GET_GAPS_RANGE)?;
+        let envelopes = stmt
+            .query(named_params! {
+                ":genesis": genesis,
+                ":first_height": first_height,
+                ":last_height": last_height,
+                ":limit": limit,
+            })?
+            .map(|r| r.get(0))
+            .collect()?;
+        Ok(envelopes)
+    }
+}
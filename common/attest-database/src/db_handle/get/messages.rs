@@ -28,6 +28,7 @@ use std::collections::HashMap;
 use tracing::debug;
 
 use tracing::trace;
+use tracing::warn;
 
 impl<T> MsgDBHandle<T>
 where
@@ -188,7 +189,9 @@ where
                 Err(e) if !skip_invalid => {
                     return Err(e);
                 }
-                _ => {}
+                Err(e) => {
+                    warn!(id, error=?e, "Skipping Invalid Message");
+                }
             }
         }
         Ok(())
@@ -232,6 +235,19 @@ where
         Ok(vs)
     }
 
+    /// get the (genesis, height, hash) of every message we hold, sorted.
+    ///
+    /// Used to reconcile our set of messages against a peer's without
+    /// loading any bodies.
+    pub fn get_all_sync_keys(
+        &self,
+    ) -> Result<Vec<(CanonicalEnvelopeHash, i64, CanonicalEnvelopeHash)>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_MESSAGE_SYNC_KEYS)?;
+        let rows = stmt.query([])?;
+        rows.map(|r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .collect()
+    }
+
     /// loads all the messages from a given user
     pub fn load_all_messages_for_user_by_key_connected<M, E>(
         &self,
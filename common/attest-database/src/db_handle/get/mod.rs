@@ -5,10 +5,23 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use serde::{Deserialize, Serialize};
+pub mod ancestry;
+pub mod anchors;
+pub mod archive;
+pub mod blobs;
 pub mod chain_commit_groups;
+pub mod chain_keys;
+pub mod chain_stats;
+pub mod chain_visibility;
+pub mod encrypted;
+pub mod gaps;
 pub mod hidden_services;
 pub mod messages;
 pub mod nonces;
+pub mod peer_acknowledgements;
+pub mod query;
+pub mod signing_groups;
+pub mod storage_encoding;
 pub mod users;
 #[derive(Serialize, Deserialize)]
 pub struct PeerInfo {
@@ -7,6 +7,7 @@
 use super::super::handle_type;
 use super::super::MsgDBHandle;
 use crate::db_handle::sql::get::nonces::*;
+use crate::sql_serializers::PK;
 use attest_messages::nonce::PrecomittedNonce;
 use attest_messages::nonce::PrecomittedPublicNonce;
 use attest_messages::Authenticated;
@@ -15,14 +16,53 @@ use fallible_iterator::FallibleIterator;
 use num_bigint::BigInt;
 use num_bigint::Sign;
 use num_integer::Integer;
+use rusqlite::types::{FromSql, FromSqlError, ToSqlOutput};
+use rusqlite::{named_params, OptionalExtension, ToSql};
 use sapio_bitcoin::hashes::sha256;
 use sapio_bitcoin::hashes::Hash;
 use sapio_bitcoin::hashes::HashEngine;
 use sapio_bitcoin::secp256k1::Message;
 use sapio_bitcoin::secp256k1::SecretKey;
 use sapio_bitcoin::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+/// Where a nonce is in its life.
+///
+/// A nonce may sign exactly one envelope, so its secret is only worth keeping
+/// until then. Once the envelope it signed is stored the secret is erased,
+/// leaving the public nonce behind to catch any attempt to sign with it again.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonceState {
+    /// made ahead of time, not yet committed to by any envelope
+    Generated,
+    /// committed to as the `next_nonce` of an envelope, free to sign its child
+    Committed,
+    /// has signed an envelope, the secret is erased once that is stored
+    Used,
+}
+
+impl ToSql for NonceState {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            NonceState::Generated => "Generated",
+            NonceState::Committed => "Committed",
+            NonceState::Used => "Used",
+        }
+        .into())
+    }
+}
+impl FromSql for NonceState {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        match value.as_str()? {
+            "Generated" => Ok(NonceState::Generated),
+            "Committed" => Ok(NonceState::Committed),
+            "Used" => Ok(NonceState::Used),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
 impl<T> MsgDBHandle<T>
 where
     T: handle_type::Get,
@@ -36,6 +76,25 @@ where
         stmt.query_row([nonce], |r| r.get::<_, PrecomittedNonce>(0))
     }
 
+    /// Returns the state of a nonce, if we have it
+    pub fn get_nonce_state(
+        &self,
+        nonce: PrecomittedPublicNonce,
+    ) -> Result<Option<NonceState>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_NONCE_STATE)?;
+        stmt.query_row(named_params! {":nonce": nonce}, |r| r.get(0))
+            .optional()
+    }
+
+    /// Returns how many pregenerated nonces are left for the given user
+    pub fn get_pregenerated_nonce_count(
+        &self,
+        key: XOnlyPublicKey,
+    ) -> Result<usize, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_PREGENERATED_NONCE_COUNT)?;
+        stmt.query_row(named_params! {":key": PK(key)}, |r| r.get(0))
+    }
+
     /// finds a reused nonce
     pub fn get_reused_nonces(
         &self,
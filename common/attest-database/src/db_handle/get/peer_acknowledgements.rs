@@ -0,0 +1,85 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::db_handle::sql::get::peer_acknowledgements::*;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use attest_messages::CanonicalEnvelopeHash;
+use fallible_iterator::FallibleIterator;
+use rusqlite::named_params;
+use serde::{Deserialize, Serialize};
+
+/// The highest envelope of a chain that a peer is known to have persisted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PeerAcknowledgement {
+    pub genesis: CanonicalEnvelopeHash,
+    pub height: i64,
+    pub hash: CanonicalEnvelopeHash,
+    pub updated_time: i64,
+}
+
+/// How far along a given chain a push peer is known to be.
+///
+/// `acknowledged` is None if the peer has never confirmed any envelope of the
+/// chain.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PeerReplication {
+    pub service_url: String,
+    pub port: u16,
+    pub acknowledged: Option<(i64, CanonicalEnvelopeHash)>,
+    pub updated_time: Option<i64>,
+}
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// get the high-water mark of every chain the peer has acknowledged
+    pub fn get_peer_acknowledgements(
+        &self,
+        service_url: &str,
+        port: u16,
+    ) -> Result<Vec<PeerAcknowledgement>, rusqlite::Error> {
+        let mut stmt = self
+            .0
+            .prepare_cached(SQL_GET_PEER_ACKNOWLEDGEMENTS_FOR_SERVICE)?;
+        let results = stmt
+            .query(named_params! {":service_url": service_url, ":port": port})?
+            .map(|r| {
+                Ok(PeerAcknowledgement {
+                    genesis: r.get(0)?,
+                    height: r.get(1)?,
+                    hash: r.get(2)?,
+                    updated_time: r.get(3)?,
+                })
+            })
+            .collect()?;
+        Ok(results)
+    }
+
+    /// get the replication state of a chain across all peers we push to
+    pub fn get_replication_status_for_chain(
+        &self,
+        genesis: CanonicalEnvelopeHash,
+    ) -> Result<Vec<PeerReplication>, rusqlite::Error> {
+        let mut stmt = self
+            .0
+            .prepare_cached(SQL_GET_PEER_ACKNOWLEDGEMENTS_FOR_CHAIN)?;
+        let results = stmt
+            .query(named_params! {":genesis": genesis})?
+            .map(|r| {
+                let height: Option<i64> = r.get(2)?;
+                let hash: Option<CanonicalEnvelopeHash> = r.get(3)?;
+                Ok(PeerReplication {
+                    service_url: r.get(0)?,
+                    port: r.get(1)?,
+                    acknowledged: height.zip(hash),
+                    updated_time: r.get(4)?,
+                })
+            })
+            .collect()?;
+        Ok(results)
+    }
+}
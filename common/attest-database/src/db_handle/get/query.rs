@@ -0,0 +1,363 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Filtering envelopes by the contents of their messages.
+//!
+//! Rather than loading every envelope of a chain and picking through the
+//! messages in Rust, an [`EnvelopeQuery`] is run against the stored bodies with
+//! SQLite's JSON functions, e.g. all `trade` moves by some key after some
+//! height:
+//!
+//! ```json
+//! {"key": "...", "after_height": 10, "filters": [{"path": "data.trade", "op": "exists"}]}
+//! ```
+//!
+//! Envelopes stored in the binary encoding (see
+//! [`super::storage_encoding::StorageEncoding`]) can't be inspected from SQL,
+//! so they are let through and the filters are checked against them after
+//! decoding. Every result is checked that way, so both encodings give the
+//! same answers.
+
+use crate::db_handle::sql::get::messages::SQL_GET_MESSAGES_QUERY;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use crate::sql_serializers::PK;
+use attest_messages::{AttestEnvelopable, CanonicalEnvelopeHash, GenericEnvelope};
+use ruma_serde::CanonicalJsonValue;
+use rusqlite::types::FromSql;
+use rusqlite::ToSql;
+use sapio_bitcoin::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Display;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// A path into a message, like `data.trade.amount` or `players[0].key`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct MsgPath(pub Vec<PathSegment>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    EmptyKey,
+    /// keys can't contain any of `."[]`
+    InvalidKey(String),
+    InvalidIndex(String),
+}
+
+impl Display for PathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for PathError {}
+
+impl FromStr for MsgPath {
+    type Err = PathError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix('$').unwrap_or(s);
+        let s = s.strip_prefix('.').unwrap_or(s);
+        let mut segments = vec![];
+        if s.is_empty() {
+            return Ok(MsgPath(segments));
+        }
+        for part in s.split('.') {
+            let (key, indexes) = part.split_at(part.find('[').unwrap_or(part.len()));
+            if key.contains(['"', ']']) {
+                return Err(PathError::InvalidKey(key.into()));
+            }
+            match (
+                key.is_empty(),
+                segments.is_empty() && indexes.starts_with('['),
+            ) {
+                (false, _) => segments.push(PathSegment::Key(key.into())),
+                // e.g. `[0].key`, for messages that are arrays
+                (true, true) => {}
+                (true, false) => return Err(PathError::EmptyKey),
+            }
+            if indexes.is_empty() {
+                continue;
+            }
+            let inner = indexes
+                .strip_prefix('[')
+                .and_then(|i| i.strip_suffix(']'))
+                .ok_or_else(|| PathError::InvalidIndex(indexes.into()))?;
+            for index in inner.split("][") {
+                let index = index
+                    .parse()
+                    .map_err(|_| PathError::InvalidIndex(index.into()))?;
+                segments.push(PathSegment::Index(index));
+            }
+        }
+        Ok(MsgPath(segments))
+    }
+}
+
+impl TryFrom<String> for MsgPath {
+    type Error = PathError;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<MsgPath> for String {
+    fn from(p: MsgPath) -> Self {
+        let mut s = String::new();
+        for segment in &p.0 {
+            match segment {
+                PathSegment::Key(k) if s.is_empty() => s.push_str(k),
+                PathSegment::Key(k) => {
+                    s.push('.');
+                    s.push_str(k)
+                }
+                PathSegment::Index(i) => s.push_str(&format!("[{}]", i)),
+            }
+        }
+        s
+    }
+}
+
+impl MsgPath {
+    /// The path in SQLite's syntax, to be appended to `$.msg`. Keys are quoted
+    /// so that e.g. `@type` can be used.
+    fn to_sqlite(&self) -> String {
+        self.0
+            .iter()
+            .map(|segment| match segment {
+                PathSegment::Key(k) => format!(".\"{}\"", k),
+                PathSegment::Index(i) => format!("[{}]", i),
+            })
+            .collect()
+    }
+
+    /// The value at this path in `msg`, if any
+    pub fn lookup<'a>(&self, msg: &'a CanonicalJsonValue) -> Option<&'a CanonicalJsonValue> {
+        self.0
+            .iter()
+            .try_fold(msg, |v, segment| match (segment, v) {
+                (PathSegment::Key(k), CanonicalJsonValue::Object(o)) => o.get(k),
+                (PathSegment::Index(i), CanonicalJsonValue::Array(a)) => a.get(*i),
+                _ => None,
+            })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "op", content = "value")]
+pub enum FilterOp {
+    Exists,
+    Eq(CanonicalJsonValue),
+    /// only matches integers
+    Gt(i64),
+    /// only matches integers
+    Lt(i64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MsgFilter {
+    pub path: MsgPath,
+    #[serde(flatten)]
+    pub op: FilterOp,
+}
+
+impl MsgFilter {
+    pub fn matches(&self, msg: &CanonicalJsonValue) -> bool {
+        let value = self.path.lookup(msg);
+        match (&self.op, value) {
+            (FilterOp::Exists, v) => v.is_some(),
+            (FilterOp::Eq(expected), Some(v)) => v == expected,
+            (FilterOp::Gt(n), Some(CanonicalJsonValue::Integer(v))) => i64::from(*v) > *n,
+            (FilterOp::Lt(n), Some(CanonicalJsonValue::Integer(v))) => i64::from(*v) < *n,
+            _ => false,
+        }
+    }
+
+    /// The form the query's SQL expects, see `get/messages/query.sql`
+    fn to_sql_filter(&self) -> CanonicalJsonValue {
+        let (op, value) = match &self.op {
+            FilterOp::Exists => ("exists", CanonicalJsonValue::Null),
+            FilterOp::Eq(v) => ("eq", v.clone()),
+            FilterOp::Gt(n) => ("gt", saturating_integer(*n)),
+            FilterOp::Lt(n) => ("lt", saturating_integer(*n)),
+        };
+        CanonicalJsonValue::Object(
+            [
+                ("op".to_string(), CanonicalJsonValue::String(op.into())),
+                (
+                    "path".to_string(),
+                    CanonicalJsonValue::String(self.path.to_sqlite()),
+                ),
+                ("value".to_string(), value),
+            ]
+            .into_iter()
+            .collect(),
+        )
+    }
+}
+
+/// The largest integer canonical json can hold, 2^53 - 1
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+/// `n` as a canonical json integer, clamped to the range canonical json can
+/// hold. Stored integers lie within that range, so only one sitting exactly
+/// on the end of it can compare differently against a clamped bound.
+fn saturating_integer(n: i64) -> CanonicalJsonValue {
+    CanonicalJsonValue::Integer(
+        n.clamp(-MAX_SAFE_INTEGER, MAX_SAFE_INTEGER)
+            .try_into()
+            .expect("Clamped to the safe range"),
+    )
+}
+
+/// Which envelopes to return, every field narrows the results down further.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvelopeQuery {
+    /// only envelopes from the chain of this user
+    #[serde(default)]
+    pub key: Option<XOnlyPublicKey>,
+    #[serde(default)]
+    pub genesis: Option<CanonicalEnvelopeHash>,
+    #[serde(default)]
+    pub after_height: Option<i64>,
+    /// only messages tagged with this type, see [`attest_messages::registry`]
+    #[serde(default)]
+    pub msg_type: Option<String>,
+    #[serde(default)]
+    pub filters: Vec<MsgFilter>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl EnvelopeQuery {
+    fn matches(&self, msg: &CanonicalJsonValue) -> bool {
+        let tagged = match (&self.msg_type, msg) {
+            (None, _) => true,
+            (Some(t), CanonicalJsonValue::Object(o)) => {
+                o.get("@type") == Some(&CanonicalJsonValue::String(t.clone()))
+            }
+            _ => false,
+        };
+        tagged && self.filters.iter().all(|f| f.matches(msg))
+    }
+}
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// The envelopes matching `query`, ordered by height.
+    pub fn query_envelopes<E, M>(&self, query: &EnvelopeQuery) -> Result<Vec<E>, rusqlite::Error>
+    where
+        E: AsRef<GenericEnvelope<M>> + FromSql,
+        M: AttestEnvelopable,
+    {
+        let filters =
+            CanonicalJsonValue::Array(query.filters.iter().map(MsgFilter::to_sql_filter).collect())
+                .to_string();
+        let key = query.key.map(PK);
+        let mut sql = SQL_GET_MESSAGES_QUERY.to_string();
+        let mut params: Vec<(&str, &dyn ToSql)> = vec![(":filters", &filters)];
+        if let Some(key) = &key {
+            sql.push_str("\nAND U.key = :key");
+            params.push((":key", key));
+        }
+        if let Some(genesis) = &query.genesis {
+            sql.push_str("\nAND M.genesis = :genesis");
+            params.push((":genesis", genesis));
+        }
+        if let Some(after_height) = &query.after_height {
+            sql.push_str("\nAND M.height > :after_height");
+            params.push((":after_height", after_height));
+        }
+        if let Some(msg_type) = &query.msg_type {
+            sql.push_str("\nAND (M.msg_type = :msg_type OR M.binary_body)");
+            params.push((":msg_type", msg_type));
+        }
+        sql.push_str("\nORDER BY M.height, M.message_id");
+        let mut stmt = self.0.prepare_cached(&sql)?;
+        let mut rows = stmt.query(&params[..])?;
+        let mut results = vec![];
+        while let Some(row) = rows.next()? {
+            let envelope: E = row.get(0)?;
+            let msg = envelope.as_ref().canonical_msg().map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    0,
+                    rusqlite::types::Type::Text,
+                    Box::new(e),
+                )
+            })?;
+            if !query.matches(&msg) {
+                continue;
+            }
+            results.push(envelope);
+            if Some(results.len()) == query.limit {
+                break;
+            }
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_msg_path() {
+        let p: MsgPath = "$.data.trade[1][0].@type".parse().unwrap();
+        assert_eq!(
+            p.0,
+            vec![
+                PathSegment::Key("data".into()),
+                PathSegment::Key("trade".into()),
+                PathSegment::Index(1),
+                PathSegment::Index(0),
+                PathSegment::Key("@type".into()),
+            ]
+        );
+        assert_eq!(p.to_sqlite(), ".\"data\".\"trade\"[1][0].\"@type\"");
+        assert_eq!(String::from(p.clone()).parse::<MsgPath>().unwrap(), p);
+        assert_eq!("[2].a".parse::<MsgPath>().unwrap().to_sqlite(), "[2].\"a\"");
+        assert_eq!("".parse::<MsgPath>().unwrap().0, vec![]);
+        assert_eq!("a..b".parse::<MsgPath>(), Err(PathError::EmptyKey));
+        assert!(matches!(
+            "a\"b".parse::<MsgPath>(),
+            Err(PathError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            "a[x]".parse::<MsgPath>(),
+            Err(PathError::InvalidIndex(_))
+        ));
+    }
+
+    #[test]
+    fn test_out_of_range_bounds_saturate() {
+        let value = |op| match (MsgFilter {
+            path: "a".parse().unwrap(),
+            op,
+        })
+        .to_sql_filter()
+        {
+            CanonicalJsonValue::Object(o) => o["value"].clone(),
+            _ => unreachable!(),
+        };
+        let max = CanonicalJsonValue::Integer(MAX_SAFE_INTEGER.try_into().unwrap());
+        let min = CanonicalJsonValue::Integer((-MAX_SAFE_INTEGER).try_into().unwrap());
+        assert_eq!(value(FilterOp::Gt(i64::MAX)), max);
+        assert_eq!(value(FilterOp::Lt(i64::MAX)), max);
+        assert_eq!(value(FilterOp::Gt(i64::MIN)), min);
+        assert_eq!(value(FilterOp::Lt(i64::MIN)), min);
+        assert_eq!(
+            value(FilterOp::Gt(7)),
+            CanonicalJsonValue::Integer(7u32.into())
+        );
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::db_handle::sql::get::signing_groups::*;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use crate::sql_serializers::{PK, SK};
+use attest_messages::musig::{GroupNonce, PartialSignature, SigningGroup};
+use attest_messages::nonce::PrecomittedPublicNonce;
+use fallible_iterator::FallibleIterator;
+use rusqlite::named_params;
+use sapio_bitcoin::hashes::hex::ToHex;
+use sapio_bitcoin::secp256k1::{Message, Secp256k1, Verification};
+use sapio_bitcoin::XOnlyPublicKey;
+use std::collections::BTreeMap;
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// The signing group whose aggregate key is `key`, if it was saved.
+    pub fn get_signing_group<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        key: XOnlyPublicKey,
+    ) -> Result<Option<SigningGroup>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_SIGNING_GROUP_MEMBERS)?;
+        let members: Vec<XOnlyPublicKey> = stmt
+            .query(named_params! {":group_key": PK(key)})?
+            .map(|r| Ok(r.get::<_, PK>(0)?.0))
+            .collect()?;
+        Ok(SigningGroup::new(secp, members)
+            .ok()
+            .filter(|g| g.key() == key))
+    }
+
+    /// The group nonce of `group` which sums to `aggregate`, if every share of
+    /// it was saved.
+    pub fn get_group_nonce(
+        &self,
+        group: &SigningGroup,
+        aggregate: PrecomittedPublicNonce,
+    ) -> Result<Option<GroupNonce>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_GROUP_NONCE_SHARES)?;
+        let shares: BTreeMap<XOnlyPublicKey, PrecomittedPublicNonce> = stmt
+            .query(named_params! {
+                ":group_key": PK(group.key()),
+                ":aggregate_nonce": aggregate,
+            })?
+            .map(|r| Ok((r.get::<_, PK>(0)?.0, r.get(1)?)))
+            .collect()?;
+        Ok(group
+            .aggregate_nonce(shares)
+            .ok()
+            .filter(|n| n.public() == aggregate))
+    }
+
+    /// The partial signatures made with the group nonce `aggregate` over
+    /// `digest`.
+    pub fn get_partial_signatures(
+        &self,
+        aggregate: PrecomittedPublicNonce,
+        digest: &Message,
+    ) -> Result<BTreeMap<XOnlyPublicKey, PartialSignature>, rusqlite::Error> {
+        let digest = digest.as_ref().to_hex();
+        Ok(self
+            .get_all_partial_signatures(aggregate)?
+            .into_iter()
+            .filter(|(_, (d, _))| *d == digest)
+            .map(|(m, (_, p))| (m, p))
+            .collect())
+    }
+
+    /// Every partial signature made with the group nonce `aggregate`, with the
+    /// hex of the digest each signed.
+    pub(crate) fn get_all_partial_signatures(
+        &self,
+        aggregate: PrecomittedPublicNonce,
+    ) -> Result<BTreeMap<XOnlyPublicKey, (String, PartialSignature)>, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_GET_PARTIAL_SIGNATURES)?;
+        let partials = stmt
+            .query(named_params! {":aggregate_nonce": aggregate})?
+            .map(|r| {
+                Ok((
+                    r.get::<_, PK>(0)?.0,
+                    (r.get(1)?, PartialSignature(r.get::<_, SK>(2)?.0)),
+                ))
+            })
+            .collect()?;
+        Ok(partials)
+    }
+}
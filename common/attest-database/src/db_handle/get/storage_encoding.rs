@@ -0,0 +1,111 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::db_handle::sql::get::storage_encoding::*;
+use crate::db_handle::{handle_type, MsgDBHandle};
+use attest_messages::{AttestEnvelopable, GenericEnvelope};
+use rusqlite::types::{FromSql, FromSqlError, ToSqlOutput};
+use rusqlite::{Connection, OptionalExtension, ToSql};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// How newly inserted envelopes are written to the messages table.
+///
+/// Existing rows are never rewritten, and rows in either encoding can always
+/// be read back, so the setting can be changed at any time.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageEncoding {
+    /// canonical json, queryable from SQL
+    Json,
+    /// see [`attest_messages::binary`]
+    Binary,
+}
+
+impl Default for StorageEncoding {
+    fn default() -> Self {
+        StorageEncoding::Json
+    }
+}
+
+impl ToSql for StorageEncoding {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            StorageEncoding::Json => "Json",
+            StorageEncoding::Binary => "Binary",
+        }
+        .into())
+    }
+}
+impl FromSql for StorageEncoding {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        match value.as_str()? {
+            "Json" => Ok(StorageEncoding::Json),
+            "Binary" => Ok(StorageEncoding::Binary),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// get the encoding used for newly inserted envelopes
+    pub fn get_storage_encoding(&self) -> Result<StorageEncoding, rusqlite::Error> {
+        storage_encoding(&self.0)
+    }
+}
+
+pub(crate) fn storage_encoding(conn: &Connection) -> Result<StorageEncoding, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(SQL_GET_STORAGE_ENCODING)?;
+    Ok(stmt
+        .query_row([], |r| r.get(0))
+        .optional()?
+        .unwrap_or_default())
+}
+
+/// Whether `body` decodes to exactly `data`.
+///
+/// The CHECKs on the messages table can't look inside a binary body, so
+/// before one is written it is checked here against the envelope the other
+/// columns are derived from.
+pub(crate) fn check_binary_body<M: AttestEnvelopable>(
+    data: &GenericEnvelope<M>,
+    body: &[u8],
+) -> Result<bool, rusqlite::Error> {
+    let decoded = match GenericEnvelope::<M>::from_binary(body) {
+        Ok(decoded) => decoded,
+        Err(_) => return Ok(false),
+    };
+    let canonical = |e: &GenericEnvelope<M>| {
+        ruma_serde::to_canonical_value(e)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+    };
+    Ok(
+        decoded.canonicalized_hash_ref() == data.canonicalized_hash_ref()
+            && canonical(&decoded)? == canonical(data)?,
+    )
+}
+
+/// The body to store for `data` in the given encoding.
+///
+/// Envelopes whose binary encoding doesn't check out are stored as json
+/// instead, where the table's CHECKs apply.
+pub(crate) fn encode_body<'a, M: AttestEnvelopable>(
+    data: &'a GenericEnvelope<M>,
+    encoding: StorageEncoding,
+) -> Result<Box<dyn ToSql + 'a>, rusqlite::Error> {
+    if encoding == StorageEncoding::Binary {
+        let body = data
+            .to_binary()
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        if check_binary_body(data, &body)? {
+            return Ok(Box::new(body));
+        }
+        warn!(hash=?data.canonicalized_hash_ref(), "Binary Encoding Does Not Round Trip, Storing as Json");
+    }
+    Ok(Box::new(data))
+}
@@ -30,6 +30,9 @@ where
     }
 
     /// build a keymap for all known keypairs.
+    ///
+    /// Keys that a chain has been rotated away from, or whose delegation has
+    /// ended, are left out as they can no longer sign for any chain.
     pub fn get_keymap(&self) -> Result<BTreeMap<XOnlyPublicKey, SecretKey>, rusqlite::Error> {
         let mut stmt = self.0.prepare_cached(SQL_GET_ALL_SECRET_KEYS)?;
         let rows = stmt.query([])?;
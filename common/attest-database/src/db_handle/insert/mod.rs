@@ -4,30 +4,45 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use super::get::ancestry::expected_ancestry_after;
+use super::get::archive::is_archived;
+use super::get::chain_keys::{check_chain_authority, record_chain_keys};
 use super::get::messages::message_exists_children;
+use super::get::storage_encoding::{encode_body, storage_encoding};
 use super::handle_type;
 use super::ChainCommitGroupID;
 use super::MsgDBHandle;
 use crate::db_handle::sql::insert::*;
+use crate::db_handle::sql::update::{
+    SQL_UPDATE_CLAIM_PREGENERATED_NONCE, SQL_UPDATE_RELEASE_NONCE, SQL_UPDATE_RETIRE_NONCE,
+};
 use crate::sql_error;
 use crate::sql_error::SqliteFail;
 use crate::sql_serializers::PK;
 use crate::sql_serializers::SK;
+use attest_messages::anchor::{find_anchor, merkle_root, AnchorError};
+use attest_messages::blobs::{BlobError, BlobRef};
+use attest_messages::musig::{GroupNonce, PartialSignature, SigningGroup};
 use attest_messages::nonce::PrecomittedNonce;
 use attest_messages::nonce::PrecomittedPublicNonce;
+use attest_messages::registry;
 use attest_messages::Ancestors;
 use attest_messages::AttestEnvelopable;
 use attest_messages::Authenticated;
 use attest_messages::CanonicalEnvelopeHash;
 
 use attest_messages::GenericEnvelope;
+use attest_messages::Header;
 use rusqlite::ffi;
 use rusqlite::ffi::{SQLITE_CONSTRAINT_CHECK, SQLITE_CONSTRAINT_NOTNULL, SQLITE_CONSTRAINT_UNIQUE};
 use rusqlite::params;
+use rusqlite::Connection;
 use rusqlite::ErrorCode;
+use rusqlite::OptionalExtension;
 use rusqlite::Transaction;
 use sapio_bitcoin::secp256k1::rand::thread_rng;
 use sapio_bitcoin::secp256k1::rand::Rng;
+use sapio_bitcoin::secp256k1::Message;
 
 use sapio_bitcoin::{
     hashes::hex::ToHex,
@@ -52,6 +67,48 @@ where
         let pk_nonce = self.save_nonce_for_user_by_key(nonce, secp, key)?;
         Ok(pk_nonce)
     }
+    /// Returns a nonce for the given user to commit to as a `next_nonce`,
+    /// taking the oldest pregenerated one if there are any left.
+    pub fn claim_nonce_for_user_by_key<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        key: XOnlyPublicKey,
+    ) -> Result<PrecomittedPublicNonce, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_UPDATE_CLAIM_PREGENERATED_NONCE)?;
+        match stmt
+            .query_row(rusqlite::named_params! {":key": PK(key)}, |r| r.get(0))
+            .optional()?
+        {
+            Some(nonce) => Ok(nonce),
+            None => {
+                debug!(key=%key, "No Pregenerated Nonces Left");
+                self.generate_fresh_nonce_for_user_by_key(secp, key)
+            }
+        }
+    }
+    /// Generates `n` nonces for the given user ahead of time, so that signing
+    /// doesn't have to wait on making one.
+    pub fn pregenerate_nonces_for_user_by_key<C: Signing>(
+        &mut self,
+        secp: &Secp256k1<C>,
+        key: XOnlyPublicKey,
+        n: usize,
+    ) -> Result<(), rusqlite::Error> {
+        let tx = self.0.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(SQL_INSERT_PREGENERATED_NONCE)?;
+            for _ in 0..n {
+                let nonce = PrecomittedNonce::new(secp);
+                stmt.insert(rusqlite::named_params! {
+                    ":key": PK(key),
+                    ":public_key": nonce.get_public(secp),
+                    ":private_key": nonce,
+                })?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
     /// Saves an arbitrary nonce for the given user.
     pub fn save_nonce_for_user_by_key<C: Signing>(
         &self,
@@ -146,6 +203,12 @@ where
     /// Will fail if the key is not registered.
     ///
     /// Will return false if the message already existed
+    ///
+    /// Stores of envelopes we signed erase the secret nonce they were signed
+    /// with, see [`super::get::nonces::NonceState`]. If the envelope is
+    /// refused the nonce is freed to sign another, so such an envelope must be
+    /// discarded rather than shared. If storing it fails outright nothing is
+    /// written and the nonce is left as it was.
     #[must_use = "Required to check if the insertion of an Envelope was successful"]
     pub fn try_insert_authenticated_envelope<M>(
         &mut self,
@@ -171,9 +234,27 @@ where
                 }
             }
         }
-        let res = try_insert_authenticated_envelope_with_txn(data, &tx);
+        let nonce = data.extract_used_nonce();
+        let res = match try_insert_authenticated_envelope_with_txn(data, &tx) {
+            Err(e) => {
+                // whatever was written is dropped with the txn, leaving the
+                // nonce as it was
+                if let Err(e) = tx.rollback() {
+                    debug!(error=?e, "Error Rolling Back Transaction");
+                }
+                return Err(e);
+            }
+            Ok(res) => res,
+        };
+        if let (Err(_), Some(nonce)) = (&res, nonce) {
+            // refused envelopes are never served, so if we signed it the
+            // nonce may sign a replacement. No-op once the nonce has been
+            // retired by an envelope that was stored.
+            tx.prepare_cached(SQL_UPDATE_RELEASE_NONCE)?
+                .execute(rusqlite::named_params! {":nonce": nonce})?;
+        }
         tx.commit()?;
-        res
+        Ok(res)
     }
 
     /// Create a new Chain Commit Group
@@ -221,6 +302,158 @@ where
         ))?;
         Ok(())
     }
+
+    /// Stores an attachment so that envelopes can reference it, see
+    /// [`attest_messages::blobs`]. Storing the same data twice has no effect.
+    pub fn save_blob(&self, data: &[u8]) -> Result<Result<BlobRef, BlobError>, rusqlite::Error> {
+        let blob = match BlobRef::of(data) {
+            Ok(blob) => blob,
+            Err(e) => return Ok(Err(e)),
+        };
+        insert_blob(&self.0, &blob, data)?;
+        Ok(Ok(blob))
+    }
+
+    /// Stores an attachment fetched from a peer, if it is the one `expected`
+    /// refers to.
+    pub fn save_fetched_blob(
+        &self,
+        expected: &BlobRef,
+        data: &[u8],
+    ) -> Result<Result<(), BlobError>, rusqlite::Error> {
+        if let Err(e) = expected.check(data) {
+            return Ok(Err(e));
+        }
+        insert_blob(&self.0, expected, data)?;
+        Ok(Ok(()))
+    }
+
+    /// Records a broadcast anchor of `leaves`, see [`attest_messages::anchor`].
+    ///
+    /// Fails if `tx` doesn't commit to the root of `leaves` in that order.
+    pub fn save_anchor(
+        &mut self,
+        leaves: &[CanonicalEnvelopeHash],
+        tx: &sapio_bitcoin::Transaction,
+    ) -> Result<Result<(), AnchorError>, rusqlite::Error> {
+        let root = match merkle_root(leaves) {
+            Some(root) if find_anchor(tx, &root).is_some() => root,
+            _ => return Ok(Err(AnchorError::NotCommitted)),
+        };
+        let tx_hex = sapio_bitcoin::consensus::encode::serialize_hex(tx);
+        let txn = self.0.transaction()?;
+        {
+            let mut stmt = txn.prepare_cached(SQL_INSERT_ANCHOR)?;
+            let anchor_id = stmt.insert(rusqlite::named_params!(
+                ":root": root.to_hex(),
+                ":txid": tx.txid().to_hex(),
+                ":tx": tx_hex,
+                ":created_time": attest_util::now(),
+            ))?;
+            let mut stmt = txn.prepare_cached(SQL_INSERT_ANCHOR_LEAF)?;
+            for (i, leaf) in leaves.iter().enumerate() {
+                stmt.execute(rusqlite::named_params!(
+                    ":anchor_id": anchor_id,
+                    ":leaf_index": i,
+                    ":message_hash": leaf,
+                ))?;
+            }
+        }
+        txn.commit()?;
+        Ok(Ok(()))
+    }
+
+    /// Saves a signing group and its members, see
+    /// [`attest_messages::musig`]. Saving a group twice has no effect.
+    pub fn save_signing_group(&self, group: &SigningGroup) -> Result<(), rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_INSERT_SIGNING_GROUP)?;
+        stmt.execute(rusqlite::named_params!(":group_key": PK(group.key())))?;
+        let mut stmt = self.0.prepare_cached(SQL_INSERT_SIGNING_GROUP_MEMBER)?;
+        for member in group.members() {
+            stmt.execute(rusqlite::named_params!(
+                ":group_key": PK(group.key()),
+                ":member_key": PK(*member),
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Saves the shares of a group nonce so that members can partially sign
+    /// with it.
+    ///
+    /// Fails if any share is already part of another group nonce.
+    pub fn save_group_nonce(
+        &mut self,
+        group: &SigningGroup,
+        nonce: &GroupNonce,
+    ) -> Result<(), rusqlite::Error> {
+        let tx = self.0.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(SQL_INSERT_GROUP_NONCE_SHARE)?;
+            for (member, share) in nonce.shares() {
+                stmt.execute(rusqlite::named_params!(
+                    ":group_key": PK(group.key()),
+                    ":aggregate_nonce": nonce.public(),
+                    ":member_key": PK(*member),
+                    ":nonce_share": share,
+                ))?;
+            }
+        }
+        tx.commit()
+    }
+}
+
+/// Stores `data`, which must already have been checked against `blob`.
+fn insert_blob(conn: &Connection, blob: &BlobRef, data: &[u8]) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(SQL_INSERT_BLOB)?;
+    stmt.execute(rusqlite::named_params!(
+        ":hash": blob.hash.to_hex(),
+        ":data": data,
+        ":received_time": attest_util::now(),
+    ))?;
+    Ok(())
+}
+
+/// Records which attachments the envelope `hash` references, so that the
+/// ones we lack can be fetched.
+fn record_attachments(
+    conn: &Connection,
+    header: &Header,
+    hash: CanonicalEnvelopeHash,
+) -> Result<(), rusqlite::Error> {
+    if header.attachments().is_empty() {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare_cached(SQL_INSERT_MESSAGE_ATTACHMENT)?;
+    for a in header.attachments() {
+        stmt.execute(rusqlite::named_params!(
+            ":message_hash": hash,
+            ":hash": a.hash.to_hex(),
+            ":size": a.size,
+        ))?;
+    }
+    Ok(())
+}
+
+/// Records `member`'s partial signature of `digest`.
+///
+/// Fails with a uniqueness violation if the member already partially signed
+/// with this group nonce.
+pub(crate) fn save_partial_signature(
+    conn: &Connection,
+    aggregate: PrecomittedPublicNonce,
+    member: XOnlyPublicKey,
+    digest: &Message,
+    partial: &PartialSignature,
+) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(SQL_INSERT_PARTIAL_SIGNATURE)?;
+    stmt.execute(rusqlite::named_params!(
+        ":aggregate_nonce": aggregate,
+        ":member_key": PK(member),
+        ":digest": digest.as_ref().to_hex(),
+        ":partial_signature": SK(partial.0),
+    ))?;
+    Ok(())
 }
 
 #[must_use = "Required to check if the insertion of an Envelope was successful"]
@@ -242,8 +475,60 @@ where
         .unwrap_or_else(CanonicalEnvelopeHash::genesis);
     trace!(?genesis, ?data, "attempt to insert envelope");
     let hash = data.clone().canonicalized_hash();
+    // we already had it, and chose not to keep it around
+    if data.header().height() > 0 && is_archived(tx, genesis, data.header().height())? {
+        debug!(?hash, "Insert skipped as the envelope is archived");
+        return Ok(Err((
+            SqliteFail::SqliteConstraintUnique,
+            Some("Envelope is archived".into()),
+        )));
+    }
+    let ancestry = data.header().ancestry();
+    if !ancestry.is_empty() {
+        let consistent = ancestry.well_formed_for(data.header().height())
+            && expected_ancestry_after::<M>(tx, prev_msg)?
+                .map_or(true, |expected| &expected == ancestry);
+        if !consistent {
+            debug!(
+                ?hash,
+                "Insert failed due to inconsistent ancestry commitment"
+            );
+            return Ok(Err((
+                SqliteFail::SqliteConstraintCheck,
+                Some("Ancestry commitment does not match parent".into()),
+            )));
+        }
+    }
+    if let Err(e) = check_chain_authority(tx, data.header(), genesis)? {
+        debug!(?hash, key=?data.header().key(), ?e, "Insert failed due to missing chain authority");
+        return Ok(Err((
+            SqliteFail::SqliteConstraintCheck,
+            Some(format!("Key not authorized for chain: {}", e)),
+        )));
+    }
+    let msg = data
+        .canonical_msg()
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    if let Err(e) = registry::global()
+        .read()
+        .expect("Registry Lock Not Poisoned")
+        .check(&msg)
+    {
+        debug!(?hash, ?e, "Insert failed due to invalid message");
+        return Ok(Err((
+            SqliteFail::SqliteConstraintCheck,
+            Some(format!("Invalid message: {}", e)),
+        )));
+    }
+    let body = encode_body(&data, storage_encoding(tx)?)?;
+    let nonce = data
+        .header()
+        .unsigned()
+        .signature()
+        .expect("Authenticated Envelope Must Have")[0..32]
+        .to_hex();
     match stmt.insert(rusqlite::named_params! {
-                ":body": data,
+                ":body": body,
                 ":hash": hash,
                 ":key": PK(data.header().key()),
                 ":genesis": genesis,
@@ -251,10 +536,18 @@ where
                 ":received_time": time,
                 ":sent_time": data.header().sent_time_ms(),
                 ":height": data.header().height(),
-                ":nonce": data.header().unsigned().signature().expect("Authenticated Envelope Must Have")[0..32].to_hex()
+                ":format_version": data.header().version().0,
+                ":expires_at": data.expires_at_ms(),
+                ":pruned": data.is_pruned(),
+                ":nonce": nonce
         }) {
             Ok(_rowid) => {
-
+                record_chain_keys(tx, data.header(), genesis, hash)?;
+                record_attachments(tx, data.header(), hash)?;
+                // the envelope is stored along with the secret's erasure, so
+                // there is never a window where one is durable without the other
+                tx.prepare_cached(SQL_UPDATE_RETIRE_NONCE)?
+                    .execute(rusqlite::named_params! {":nonce": nonce})?;
                 tracing::trace!(?hash, envelope=?data, "Successfully Inserted");
                 tracing::info!(?hash, "Successfully Inserted");
                 Ok(Ok(()))
@@ -7,10 +7,12 @@
 use std::marker::PhantomData;
 
 use super::sql_serializers::{self};
+use crate::connection::PooledConnection;
 use rusqlite::{types::FromSql, Connection, ToSql};
 use serde::{Deserialize, Serialize};
-use tokio::sync::OwnedMutexGuard;
 
+pub mod archive;
+pub mod backup;
 pub mod create;
 pub mod get;
 pub mod insert;
@@ -18,7 +20,7 @@ pub mod setup;
 pub mod sql;
 pub mod update;
 
-pub struct MsgDBHandle<T = handle_type::All>(pub OwnedMutexGuard<Connection>, pub PhantomData<T>);
+pub struct MsgDBHandle<T = handle_type::All>(pub PooledConnection, pub PhantomData<T>);
 
 pub enum ConsistentMessages {
     AllMessagesNotReady,
@@ -41,7 +43,7 @@ pub mod handle_type {
 macro_rules! row_type (
     {$RowType:ident} => {
 #[derive(PartialEq, PartialOrd, Ord, Eq, Clone, Copy, Serialize, Deserialize, Debug)]
-pub struct $RowType(i64);
+pub struct $RowType(pub(crate) i64);
 impl ToSql for $RowType {
     fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
         self.0.to_sql()
@@ -4,24 +4,90 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use tracing::trace;
+use rusqlite::OptionalExtension;
+use tracing::{info, trace};
 
 use super::{
     handle_type,
-    sql::{CACHED, SQL_CREATE_TABLES},
+    sql::{migrations::MIGRATIONS, CACHED, SQL_CREATE_TABLES},
     MsgDBHandle,
 };
 
+/// The schema version [`SQL_CREATE_TABLES`] creates, kept in
+/// `PRAGMA user_version`.
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
 impl<T> MsgDBHandle<T>
 where
     T: handle_type::Setup,
 {
-    /// Creates all the required tables for the application.
+    /// Creates all the required tables for the application, migrating a
+    /// database created by an earlier version first.
     /// Safe to call multiple times
     pub fn setup_tables(&mut self) {
+        self.migrate().expect("Migration Failed");
         self.0
             .execute_batch(SQL_CREATE_TABLES)
             .expect("Table Setup Failed");
+        self.0
+            .pragma_update(None, "user_version", SCHEMA_VERSION)
+            .expect("Setting the Schema Version Failed");
+        self.prepare_cached_statements();
+    }
+
+    /// Runs the [`MIGRATIONS`] from the database's `PRAGMA user_version` up to
+    /// [`SCHEMA_VERSION`], each in its own transaction. A new database has
+    /// nothing to migrate, [`SQL_CREATE_TABLES`] creates the latest schema.
+    ///
+    /// Panics on a database from a newer version, rather than corrupt it.
+    fn migrate(&mut self) -> Result<(), rusqlite::Error> {
+        let version: usize = self
+            .0
+            .pragma_query_value(None, "user_version", |r| r.get(0))?;
+        assert!(
+            version <= SCHEMA_VERSION,
+            "Database Schema Version {} is newer than {}",
+            version,
+            SCHEMA_VERSION
+        );
+        let existing = self
+            .0
+            .query_row(
+                "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'messages'",
+                [],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if !existing || version == SCHEMA_VERSION {
+            return Ok(());
+        }
+        // tables are rebuilt, which foreign keys would cascade through
+        self.0.pragma_update(None, "foreign_keys", false)?;
+        for (from, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            info!(from, to = from + 1, "Migrating Database");
+            let tx = self.0.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", from + 1)?;
+            let dangling: usize =
+                tx.query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", [], |r| {
+                    r.get(0)
+                })?;
+            assert_eq!(dangling, 0, "Migration left dangling foreign keys");
+            tx.commit()?;
+        }
+        // turned back on by SQL_CREATE_TABLES
+        Ok(())
+    }
+}
+
+impl<T> MsgDBHandle<T>
+where
+    T: handle_type::Get,
+{
+    /// Prepares every statement in [`CACHED`] ahead of time, also checking
+    /// that they are all valid.
+    pub fn prepare_cached_statements(&mut self) {
         // avoid accidental evictions with uncached statements
         self.0
             .set_prepared_statement_cache_capacity(CACHED.len() * 2);
@@ -0,0 +1,27 @@
+WITH RECURSIVE chain(message_id, prev_msg_id, height, hash) AS (
+    SELECT
+        M.message_id,
+        M.prev_msg_id,
+        M.height,
+        M.hash
+    FROM
+        messages M
+    WHERE
+        M.hash = :tip
+    UNION ALL
+    SELECT
+        M.message_id,
+        M.prev_msg_id,
+        M.height,
+        M.hash
+    FROM
+        messages M
+        INNER JOIN chain C ON M.message_id = C.prev_msg_id
+)
+SELECT
+    height,
+    hash
+FROM
+    chain
+ORDER BY
+    height
\ No newline at end of file
@@ -0,0 +1,14 @@
+SELECT
+    A.anchor_id,
+    A.tx,
+    A.tx_proof
+FROM
+    anchors A
+    INNER JOIN anchor_leaves L ON L.anchor_id = A.anchor_id
+WHERE
+    L.message_hash = :message_hash
+    AND A.tx_proof IS NOT NULL
+ORDER BY
+    A.height ASC
+LIMIT
+    1
\ No newline at end of file
@@ -0,0 +1,9 @@
+SELECT
+    EXISTS(
+        SELECT
+            1
+        FROM
+            anchor_leaves L
+        WHERE
+            L.message_hash = :message_hash
+    )
\ No newline at end of file
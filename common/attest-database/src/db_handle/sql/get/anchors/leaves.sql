@@ -0,0 +1,8 @@
+SELECT
+    L.message_hash
+FROM
+    anchor_leaves L
+WHERE
+    L.anchor_id = :anchor_id
+ORDER BY
+    L.leaf_index
\ No newline at end of file
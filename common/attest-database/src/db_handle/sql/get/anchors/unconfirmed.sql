@@ -0,0 +1,8 @@
+SELECT
+    A.txid
+FROM
+    anchors A
+WHERE
+    A.tx_proof IS NULL
+ORDER BY
+    A.anchor_id
\ No newline at end of file
@@ -0,0 +1,56 @@
+/* Chains with envelopes that can be archived, because either nothing was
+ received for them since :older_than, or (if :finished_groups) they are in a
+ finished chain commit group.
+ 
+ Only the connected part of a chain more than :keep envelopes below its tip
+ can be archived.
+ */
+SELECT
+    G.hash
+FROM
+    messages G
+WHERE
+    G.height = 0
+    AND (
+        (
+            SELECT
+                MAX(M.received_time)
+            FROM
+                messages M
+            WHERE
+                M.genesis = G.hash
+        ) < :older_than
+        OR (
+            :finished_groups
+            AND EXISTS(
+                SELECT
+                    1
+                FROM
+                    chain_commit_group_members CM
+                    INNER JOIN chain_commit_groups CG ON CG.group_id = CM.group_id
+                WHERE
+                    CM.member_id = G.message_id
+                    AND CG.finished
+            )
+        )
+    )
+    AND EXISTS(
+        SELECT
+            1
+        FROM
+            messages M
+        WHERE
+            M.genesis = G.hash
+            AND M.height > 0
+            AND M.connected
+            AND NOT M.prev_archived
+            AND M.height <= (
+                SELECT
+                    MAX(T.height)
+                FROM
+                    messages T
+                WHERE
+                    T.genesis = G.hash
+                    AND T.connected
+            ) - :keep
+    )
\ No newline at end of file
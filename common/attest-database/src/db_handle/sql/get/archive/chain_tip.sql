@@ -0,0 +1,11 @@
+SELECT
+    M.hash
+FROM
+    messages M
+WHERE
+    M.genesis = :genesis
+    AND M.connected
+ORDER BY
+    M.height DESC
+LIMIT
+    1
\ No newline at end of file
@@ -0,0 +1,15 @@
+SELECT
+    G.hash,
+    A.checkpoint,
+    A.first_height,
+    A.last_height,
+    A.path,
+    A.digest,
+    A.archived_time
+FROM
+    archived_chains A
+    INNER JOIN messages G ON G.message_id = A.genesis_id
+WHERE
+    G.hash = :genesis
+ORDER BY
+    A.first_height
\ No newline at end of file
@@ -0,0 +1,13 @@
+SELECT
+    EXISTS(
+        SELECT
+            1
+        FROM
+            archived_chains A
+            INNER JOIN messages G ON G.message_id = A.genesis_id
+        WHERE
+            G.hash = :genesis
+            AND NOT A.restoring
+            AND :height BETWEEN A.first_height
+            AND A.last_height
+    )
\ No newline at end of file
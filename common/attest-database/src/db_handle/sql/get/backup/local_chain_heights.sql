@@ -0,0 +1,9 @@
+SELECT
+    M.genesis,
+    MAX(M.height)
+FROM
+    messages M
+    JOIN users U ON U.user_id = M.user_id
+    JOIN private_keys K ON K.public_key = U.key
+GROUP BY
+    M.genesis
\ No newline at end of file
@@ -0,0 +1,9 @@
+SELECT
+    M.body,
+    M.hash,
+    M.genesis,
+    M.height,
+    M.connected,
+    M.prev_archived
+FROM
+    messages M
\ No newline at end of file
@@ -0,0 +1,7 @@
+SELECT
+    N.public_key,
+    N.private_key IS NULL
+FROM
+    message_nonces N
+WHERE
+    N.state = 'Used'
\ No newline at end of file
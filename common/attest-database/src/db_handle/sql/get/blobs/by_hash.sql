@@ -0,0 +1,6 @@
+SELECT
+    B.data
+FROM
+    blobs B
+WHERE
+    B.hash = :hash
\ No newline at end of file
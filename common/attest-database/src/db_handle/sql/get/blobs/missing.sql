@@ -0,0 +1,19 @@
+SELECT
+    DISTINCT MA.hash,
+    MA.size
+FROM
+    message_attachments MA
+WHERE
+    NOT EXISTS (
+        SELECT
+            1
+        FROM
+            blobs B
+        WHERE
+            B.hash = MA.hash
+    )
+    AND MA.size <= :max_size
+ORDER BY
+    RANDOM()
+LIMIT
+    :limit
\ No newline at end of file
@@ -0,0 +1,7 @@
+SELECT
+    DISTINCT M.genesis
+FROM
+    message_attachments MA
+    INNER JOIN messages M ON M.message_id = MA.message_id
+WHERE
+    MA.hash = :hash
\ No newline at end of file
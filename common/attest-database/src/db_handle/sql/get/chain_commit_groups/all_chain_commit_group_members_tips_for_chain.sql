@@ -1,24 +1,38 @@
 WITH groups_this_chain_subscribes_to AS (
     SELECT
-        Subscription.group_id
+        Subscription.group_id,
+        CommitGroup.tip_policy
     FROM
         users User
         INNER JOIN chain_commit_group_subscribers Subscription
         INNER JOIN messages Msg ON Msg.user_id = User.user_id
         AND Subscription.member_id = Msg.message_id
+        INNER JOIN chain_commit_groups CommitGroup ON CommitGroup.group_id = Subscription.group_id
     WHERE
         User.key = :key
         AND Msg.height = 0
+        AND CommitGroup.tip_policy != 'Paused'
+),
+-- a member of several groups follows the most inclusive policy
+member_policies AS (
+    SELECT
+        GroupMembers.member_id,
+        MIN(Subscription.tip_policy = 'Changed') AS changed_only
+    FROM
+        groups_this_chain_subscribes_to Subscription
+        INNER JOIN chain_commit_group_members GroupMembers ON GroupMembers.group_id = Subscription.group_id
+    GROUP BY
+        GroupMembers.member_id
 )
 SELECT
     Msg.body,
-    max(Msg.height)
+    max(Msg.height),
+    Policy.changed_only
 FROM
-    groups_this_chain_subscribes_to Subscription
-    INNER JOIN chain_commit_group_members GroupMembers ON GroupMembers.group_id = Subscription.group_id
+    member_policies Policy
     INNER JOIN messages Msg ON (
-        GroupMembers.member_id = Msg.message_id
-        OR GroupMembers.member_id = Msg.genesis_id
+        Policy.member_id = Msg.message_id
+        OR Policy.member_id = Msg.genesis_id
     )
 WHERE
     Msg.connected
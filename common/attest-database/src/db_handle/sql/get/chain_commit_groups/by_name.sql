@@ -0,0 +1,9 @@
+SELECT
+    CommitGroup.group_id,
+    CommitGroup.name,
+    CommitGroup.finished,
+    CommitGroup.tip_policy
+FROM
+    chain_commit_groups CommitGroup
+WHERE
+    CommitGroup.name = :name
\ No newline at end of file
@@ -0,0 +1,8 @@
+SELECT
+    DISTINCT Msg.genesis
+FROM
+    chain_commit_group_members GroupMember
+    INNER JOIN chain_commit_groups CommitGroup ON GroupMember.group_id = CommitGroup.group_id
+    INNER JOIN messages Msg ON GroupMember.member_id = Msg.message_id
+WHERE
+    CommitGroup.name = :name
\ No newline at end of file
@@ -0,0 +1,53 @@
+WITH chains_of_key AS (
+    SELECT
+        Msg.message_id,
+        Msg.hash
+    FROM
+        users User
+        INNER JOIN messages Msg ON Msg.user_id = User.user_id
+    WHERE
+        User.key = :key
+        AND Msg.height = 0
+),
+memberships AS (
+    SELECT
+        CommitGroup.group_id,
+        CommitGroup.name,
+        Chain.hash,
+        EXISTS (
+            SELECT
+                1
+            FROM
+                chain_commit_group_members GroupMember
+            WHERE
+                GroupMember.group_id = CommitGroup.group_id
+                AND GroupMember.member_id = Chain.message_id
+        ) AS is_member,
+        EXISTS (
+            SELECT
+                1
+            FROM
+                chain_commit_group_subscribers Subscription
+            WHERE
+                Subscription.group_id = CommitGroup.group_id
+                AND Subscription.member_id = Chain.message_id
+        ) AS is_subscriber,
+        CommitGroup.tip_policy
+    FROM
+        chains_of_key Chain
+        CROSS JOIN chain_commit_groups CommitGroup
+)
+SELECT
+    group_id,
+    name,
+    hash,
+    is_member,
+    is_subscriber,
+    tip_policy
+FROM
+    memberships
+WHERE
+    is_member
+    OR is_subscriber
+ORDER BY
+    group_id
\ No newline at end of file
@@ -0,0 +1,10 @@
+SELECT
+    U.key
+FROM
+    messages M
+    INNER JOIN users U ON U.user_id = M.user_id
+WHERE
+    M.hash = :genesis
+    AND M.height = 0
+LIMIT
+    1
\ No newline at end of file
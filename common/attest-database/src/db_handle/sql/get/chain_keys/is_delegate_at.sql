@@ -0,0 +1,16 @@
+SELECT
+    EXISTS(
+        SELECT
+            1
+        FROM
+            chain_keys CK
+        WHERE
+            CK.genesis = :genesis
+            AND CK.key = :key
+            AND CK.role = 'Delegate'
+            AND CK.from_height <= :height
+            AND (
+                CK.until_height IS NULL
+                OR :height < CK.until_height
+            )
+    )
\ No newline at end of file
@@ -0,0 +1,16 @@
+SELECT
+    CK.key
+FROM
+    chain_keys CK
+WHERE
+    CK.genesis = :genesis
+    AND CK.role = 'Root'
+    AND CK.from_height <= :height
+    AND (
+        CK.until_height IS NULL
+        OR :height < CK.until_height
+    )
+ORDER BY
+    CK.from_height DESC
+LIMIT
+    1
\ No newline at end of file
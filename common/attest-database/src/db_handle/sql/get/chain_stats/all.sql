@@ -0,0 +1,22 @@
+SELECT
+    S.genesis,
+    U.key,
+    U.nickname,
+    S.messages,
+    S.first_seen,
+    S.last_seen,
+    S.first_sent,
+    S.last_sent,
+    S.total_delay,
+    S.max_delay,
+    S.delay_negative,
+    S.delay_lt_100ms,
+    S.delay_lt_1s,
+    S.delay_lt_10s,
+    S.delay_lt_1m,
+    S.delay_ge_1m
+FROM
+    chain_stats S
+    INNER JOIN users U ON U.user_id = S.user_id
+ORDER BY
+    S.last_seen DESC
\ No newline at end of file
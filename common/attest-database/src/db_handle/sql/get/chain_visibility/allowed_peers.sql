@@ -0,0 +1,11 @@
+-- Returns no rows if the chain is public, and a single row of NULLs if it is
+-- private but not shared with anyone.
+SELECT
+    S.service_url,
+    S.port
+FROM
+    private_chains P
+    LEFT JOIN private_chain_peers A ON A.genesis = P.genesis
+    LEFT JOIN hidden_services S ON S.service_id = A.service_id
+WHERE
+    P.genesis = :genesis
\ No newline at end of file
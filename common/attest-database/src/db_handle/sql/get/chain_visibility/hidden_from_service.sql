@@ -0,0 +1,16 @@
+SELECT
+    P.genesis
+FROM
+    private_chains P
+WHERE
+    NOT EXISTS (
+        SELECT
+            1
+        FROM
+            private_chain_peers A
+            INNER JOIN hidden_services S ON S.service_id = A.service_id
+        WHERE
+            A.genesis = P.genesis
+            AND S.service_url = :service_url
+            AND S.port = :port
+    )
\ No newline at end of file
@@ -0,0 +1,45 @@
+/* Envelopes that can't be connected because their parent isn't stored, with
+ the highest height of the chain stored (or archived) below them. The heights
+ in between are missing, and prev_msg is the hash of the highest one.
+ */
+SELECT
+    M.genesis,
+    M.height,
+    M.prev_msg,
+    MAX(
+        (
+            SELECT
+                IFNULL(MAX(B.height), 0)
+            FROM
+                messages B
+            WHERE
+                B.genesis = M.genesis
+                AND B.height < M.height
+        ),
+        (
+            SELECT
+                IFNULL(MAX(A.last_height), 0)
+            FROM
+                archived_chains A
+            WHERE
+                A.genesis_id = M.genesis_id
+                AND A.last_height < M.height
+        )
+    )
+FROM
+    messages M
+WHERE
+    M.height > 0
+    AND M.connected = 0
+    AND NOT M.prev_archived
+    AND NOT EXISTS (
+        SELECT
+            1
+        FROM
+            messages P
+        WHERE
+            P.hash = M.prev_msg
+    )
+ORDER BY
+    M.genesis,
+    M.height
\ No newline at end of file
@@ -0,0 +1,11 @@
+SELECT
+    M.body
+FROM
+    messages M
+WHERE
+    M.genesis = :genesis
+    AND M.height BETWEEN :first_height AND :last_height
+ORDER BY
+    M.height
+LIMIT
+    :limit
\ No newline at end of file
@@ -4,13 +4,27 @@ FROM
     messages m
     INNER JOIN users u ON m.user_id = u.user_id
 WHERE
-    m.user_id = (
-        SELECT
-            user_id
-        FROM
-            users
-        where
-            key = ?
+    m.user_id = COALESCE(
+        -- a key the chain was rotated or delegated to
+        (
+            SELECT
+                CK.user_id
+            FROM
+                chain_keys CK
+            WHERE
+                CK.key = ?1
+            ORDER BY
+                CK.from_height DESC
+            LIMIT
+                1
+        ), (
+            SELECT
+                user_id
+            FROM
+                users
+            where
+                key = ?1
+        )
     )
     AND m.connected
 ORDER BY
@@ -0,0 +1,9 @@
+SELECT
+    M.message_id,
+    M.body,
+    M.binary_body
+FROM
+    messages M
+WHERE
+    M.expires_at <= :now
+    AND NOT M.pruned
\ No newline at end of file
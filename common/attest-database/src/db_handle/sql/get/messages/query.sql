@@ -0,0 +1,35 @@
+/* Envelopes whose message matches every filter in :filters, a json array of
+ {"op": "exists" | "eq" | "gt" | "lt", "path": <sqlite path suffix>, "value": <json>}
+ 
+ Further conditions are appended by db_handle::get::query.
+ */
+SELECT
+    M.body
+FROM
+    messages M
+    INNER JOIN users U ON U.user_id = M.user_id
+WHERE
+    CASE
+        -- checked after decoding instead
+        WHEN M.binary_body THEN 1
+        ELSE NOT EXISTS(
+            SELECT
+                1
+            FROM
+                json_each(:filters) F
+            WHERE
+                NOT IFNULL(
+                    CASE
+                        F.value ->> '$.op'
+                        WHEN 'exists' THEN json_type(M.body, '$.msg' || (F.value ->> '$.path')) IS NOT NULL
+                        WHEN 'eq' THEN (M.body -> ('$.msg' || (F.value ->> '$.path'))) = (F.value -> '$.value')
+                        WHEN 'gt' THEN json_type(M.body, '$.msg' || (F.value ->> '$.path')) = 'integer'
+                        AND (M.body ->> ('$.msg' || (F.value ->> '$.path'))) > (F.value ->> '$.value')
+                        WHEN 'lt' THEN json_type(M.body, '$.msg' || (F.value ->> '$.path')) = 'integer'
+                        AND (M.body ->> ('$.msg' || (F.value ->> '$.path'))) < (F.value ->> '$.value')
+                        ELSE 0
+                    END,
+                    0
+                )
+        )
+    END
\ No newline at end of file
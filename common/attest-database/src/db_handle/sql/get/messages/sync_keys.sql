@@ -0,0 +1,10 @@
+SELECT
+    genesis,
+    height,
+    hash
+FROM
+    messages
+ORDER BY
+    genesis,
+    height,
+    hash
\ No newline at end of file
@@ -0,0 +1,8 @@
+SELECT
+    COUNT(*)
+FROM
+    message_nonces N
+    JOIN private_keys K ON N.key_id = K.key_id
+WHERE
+    K.public_key = :key
+    AND N.state = 'Generated'
\ No newline at end of file
@@ -0,0 +1,6 @@
+SELECT
+    state
+FROM
+    message_nonces
+WHERE
+    public_key = :nonce
\ No newline at end of file
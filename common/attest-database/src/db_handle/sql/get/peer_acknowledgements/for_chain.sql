@@ -0,0 +1,31 @@
+SELECT
+    S.service_url,
+    S.port,
+    A.height,
+    A.hash,
+    A.updated_time
+FROM
+    hidden_services S
+    LEFT JOIN peer_acknowledgements A ON A.service_id = S.service_id
+    AND A.genesis = :genesis
+WHERE
+    S.push_to
+    AND (
+        NOT EXISTS (
+            SELECT
+                1
+            FROM
+                private_chains P
+            WHERE
+                P.genesis = :genesis
+        )
+        OR EXISTS (
+            SELECT
+                1
+            FROM
+                private_chain_peers V
+            WHERE
+                V.genesis = :genesis
+                AND V.service_id = S.service_id
+        )
+    )
\ No newline at end of file
@@ -0,0 +1,11 @@
+SELECT
+    A.genesis,
+    A.height,
+    A.hash,
+    A.updated_time
+FROM
+    peer_acknowledgements A
+    INNER JOIN hidden_services S ON S.service_id = A.service_id
+WHERE
+    S.service_url = :service_url
+    AND S.port = :port
\ No newline at end of file
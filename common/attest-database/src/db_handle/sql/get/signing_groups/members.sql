@@ -0,0 +1,7 @@
+SELECT
+    M.member_key
+FROM
+    signing_group_members M
+    INNER JOIN signing_groups G ON G.signing_group_id = M.signing_group_id
+WHERE
+    G.group_key = :group_key
\ No newline at end of file
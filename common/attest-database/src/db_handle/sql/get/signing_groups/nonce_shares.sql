@@ -0,0 +1,9 @@
+SELECT
+    S.member_key,
+    S.nonce_share
+FROM
+    group_nonce_shares S
+    INNER JOIN signing_groups G ON G.signing_group_id = S.signing_group_id
+WHERE
+    G.group_key = :group_key
+    AND S.aggregate_nonce = :aggregate_nonce
\ No newline at end of file
@@ -0,0 +1,8 @@
+SELECT
+    member_key,
+    digest,
+    partial_signature
+FROM
+    partial_signatures
+WHERE
+    aggregate_nonce = :aggregate_nonce
\ No newline at end of file
@@ -0,0 +1,6 @@
+SELECT
+    encoding
+FROM
+    storage_encoding
+WHERE
+    singleton = 0
\ No newline at end of file
@@ -1,5 +1,35 @@
 SELECT
-    public_key,
-    private_key
+    P.public_key,
+    P.private_key
 FROM
-    private_keys
\ No newline at end of file
+    private_keys P
+WHERE
+    -- skip keys that were rotated away from or whose delegations ended,
+    -- they can't sign the next envelope of any chain
+    NOT EXISTS(
+        SELECT
+            1
+        FROM
+            chain_keys CK
+        WHERE
+            CK.key = P.public_key
+    )
+    OR EXISTS(
+        SELECT
+            1
+        FROM
+            chain_keys CK
+        WHERE
+            CK.key = P.public_key
+            AND (
+                CK.until_height IS NULL
+                OR CK.until_height > (
+                    SELECT
+                        IFNULL(MAX(M.height), -1) + 1
+                    FROM
+                        messages M
+                    WHERE
+                        M.genesis = CK.genesis
+                )
+            )
+    )
\ No newline at end of file
@@ -0,0 +1,8 @@
+SELECT
+    private_key
+FROM
+    private_keys
+WHERE
+    public_key = ?
+LIMIT
+    1
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- see db_handle::update::prune_expired_messages
+CREATE INDEX IF NOT EXISTS messages_by_expires_at ON messages(expires_at)
+WHERE
+    expires_at IS NOT NULL
+    AND NOT pruned;
\ No newline at end of file
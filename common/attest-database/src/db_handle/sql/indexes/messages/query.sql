@@ -0,0 +1,6 @@
+-- see db_handle::get::query
+CREATE INDEX IF NOT EXISTS messages_by_user_height ON messages(user_id, height);
+
+CREATE INDEX IF NOT EXISTS messages_by_msg_type ON messages(msg_type, height);
+
+CREATE INDEX IF NOT EXISTS messages_by_binary_body ON messages(binary_body, height);
\ No newline at end of file
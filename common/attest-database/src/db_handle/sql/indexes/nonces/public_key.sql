@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS message_nonces_public_key ON message_nonces(public_key);
\ No newline at end of file
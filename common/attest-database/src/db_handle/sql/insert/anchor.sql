@@ -0,0 +1,4 @@
+INSERT INTO
+    anchors (root, txid, tx, created_time)
+VALUES
+    (:root, :txid, :tx, :created_time)
\ No newline at end of file
@@ -0,0 +1,4 @@
+INSERT INTO
+    anchor_leaves (anchor_id, leaf_index, message_hash)
+VALUES
+    (:anchor_id, :leaf_index, :message_hash)
\ No newline at end of file
@@ -0,0 +1,24 @@
+INSERT INTO
+    archived_chains (
+        genesis_id,
+        checkpoint,
+        first_height,
+        last_height,
+        path,
+        digest,
+        archived_time
+    )
+VALUES
+    (
+        (
+            SELECT
+                M.message_id
+            FROM
+                messages M
+            WHERE
+                M.hash = :genesis
+                AND M.height = 0
+            LIMIT
+                1
+        ), :checkpoint, :first_height, :last_height, :path, :digest, :archived_time
+    )
\ No newline at end of file
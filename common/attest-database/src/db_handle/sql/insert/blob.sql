@@ -0,0 +1,4 @@
+INSERT
+    OR IGNORE INTO blobs (hash, data, received_time)
+VALUES
+    (:hash, :data, :received_time)
\ No newline at end of file
@@ -0,0 +1,27 @@
+INSERT
+    OR IGNORE INTO chain_keys (
+        genesis,
+        key,
+        user_id,
+        role,
+        from_height,
+        until_height
+    )
+VALUES
+    (
+        :genesis,
+        :key,
+        (
+            SELECT
+                M.user_id
+            FROM
+                messages M
+            WHERE
+                M.hash = :granted_in
+            LIMIT
+                1
+        ),
+        :role,
+        :from_height,
+        :until_height
+    )
\ No newline at end of file
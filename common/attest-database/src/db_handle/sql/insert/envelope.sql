@@ -11,7 +11,10 @@ INSERT INTO
         user_id,
         prev_msg_id,
         genesis_id,
-        connected
+        connected,
+        format_version,
+        expires_at,
+        pruned
     )
 VALUES
     (
@@ -23,15 +26,29 @@ VALUES
         :prev_msg,
         :height,
         :nonce,
-        (
-            SELECT
-                U.user_id
-            FROM
-                users U
-            WHERE
-                U.key = :key
-            LIMIT
-                1
+        -- keys the chain has granted authority to belong to the chain's
+        -- user, see attest_messages::authority
+        COALESCE(
+            (
+                SELECT
+                    CK.user_id
+                FROM
+                    chain_keys CK
+                WHERE
+                    CK.genesis = :genesis
+                    AND CK.key = :key
+                LIMIT
+                    1
+            ), (
+                SELECT
+                    U.user_id
+                FROM
+                    users U
+                WHERE
+                    U.key = :key
+                LIMIT
+                    1
+            )
         ), (
             SELECT
                 M.message_id
@@ -64,5 +81,8 @@ VALUES
                     LIMIT
                         1
                 )
-        )
+        ),
+        :format_version,
+        :expires_at,
+        :pruned
     )
\ No newline at end of file
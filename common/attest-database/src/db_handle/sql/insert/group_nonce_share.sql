@@ -0,0 +1,21 @@
+INSERT INTO
+    group_nonce_shares (
+        signing_group_id,
+        aggregate_nonce,
+        member_key,
+        nonce_share
+    )
+VALUES
+    (
+        (
+            SELECT
+                signing_group_id
+            FROM
+                signing_groups
+            WHERE
+                group_key = :group_key
+        ),
+        :aggregate_nonce,
+        :member_key,
+        :nonce_share
+    )
\ No newline at end of file
@@ -0,0 +1,15 @@
+INSERT
+    OR IGNORE INTO message_attachments (message_id, hash, size)
+VALUES
+    (
+        (
+            SELECT
+                M.message_id
+            FROM
+                messages M
+            WHERE
+                M.hash = :message_hash
+        ),
+        :hash,
+        :size
+    )
\ No newline at end of file
@@ -0,0 +1,14 @@
+INSERT INTO
+    partial_signatures (
+        aggregate_nonce,
+        member_key,
+        digest,
+        partial_signature
+    )
+VALUES
+    (
+        :aggregate_nonce,
+        :member_key,
+        :digest,
+        :partial_signature
+    )
\ No newline at end of file
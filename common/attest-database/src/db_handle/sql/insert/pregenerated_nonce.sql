@@ -0,0 +1,16 @@
+INSERT INTO
+    message_nonces (key_id, public_key, private_key, state)
+VALUES
+    (
+        (
+            SELECT
+                key_id
+            FROM
+                private_keys
+            WHERE
+                public_key = :key
+        ),
+        :public_key,
+        :private_key,
+        'Generated'
+    )
\ No newline at end of file
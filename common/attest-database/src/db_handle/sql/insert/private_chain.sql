@@ -0,0 +1,4 @@
+INSERT INTO
+    private_chains (genesis)
+VALUES
+    (:genesis)
\ No newline at end of file
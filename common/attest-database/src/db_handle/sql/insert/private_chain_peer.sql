@@ -0,0 +1,17 @@
+INSERT INTO
+    private_chain_peers (genesis, service_id)
+VALUES
+    (
+        :genesis,
+        (
+            SELECT
+                S.service_id
+            FROM
+                hidden_services S
+            WHERE
+                S.service_url = :service_url
+                AND S.port = :port
+            LIMIT
+                1
+        )
+    )
\ No newline at end of file
@@ -0,0 +1,4 @@
+INSERT
+    OR IGNORE INTO signing_groups (group_key)
+VALUES
+    (:group_key)
\ No newline at end of file
@@ -0,0 +1,14 @@
+INSERT
+    OR IGNORE INTO signing_group_members (signing_group_id, member_key)
+VALUES
+    (
+        (
+            SELECT
+                signing_group_id
+            FROM
+                signing_groups
+            WHERE
+                group_key = :group_key
+        ),
+        :member_key
+    )
\ No newline at end of file
@@ -0,0 +1,324 @@
+/* Migrates a database from user_version 0 to 1.
+ 
+ Tables whose constraints changed are rebuilt, see
+ https://www.sqlite.org/lang_altertable.html#otheralter, tables new in version
+ 1 are then created by SQL_CREATE_TABLES, except chain_stats which is filled
+ in from the envelopes already stored. Frozen once released, later schema
+ changes get a migration of their own.
+ */
+CREATE TABLE messages_v1 (
+    message_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    body TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    received_time INTEGER NOT NULL,
+    prev_msg_id INTEGER,
+    genesis_id INTEGER,
+    height INTEGER NOT NULL,
+    sent_time INTEGER NOT NULL,
+    prev_msg TEXT NOT NULL,
+    genesis TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    connected BOOLEAN NOT NULL,
+    -- see attest_messages::FormatVersion
+    format_version INTEGER NOT NULL DEFAULT 0,
+    -- set once the envelopes below this one are archived, see
+    -- archived_chains
+    prev_archived BOOLEAN NOT NULL DEFAULT 0,
+    -- when the message of an ephemeral envelope may be pruned, see
+    -- attest_messages::ephemeral
+    expires_at INTEGER,
+    -- set once the message has been pruned, the body keeps its hash valid
+    pruned BOOLEAN NOT NULL DEFAULT 0,
+    -- binary bodies (see attest_messages::binary) can't be inspected from SQL
+    binary_body BOOLEAN GENERATED ALWAYS AS (typeof(body) = 'blob') VIRTUAL,
+    -- the tag of registered message types, see attest_messages::registry
+    msg_type TEXT GENERATED ALWAYS AS (
+        CASE
+            WHEN typeof(body) = 'blob' THEN NULL
+            ELSE body ->> '$.msg."@type"'
+        END
+    ) VIRTUAL,
+    FOREIGN KEY(genesis_id) references messages(message_id) ON DELETE CASCADE,
+    FOREIGN KEY(user_id) references users(user_id),
+    FOREIGN KEY(prev_msg_id) references messages(message_id) ON DELETE
+    SET
+        NULL,
+        UNIQUE(hash),
+        CHECK(
+            height = 0
+            OR (genesis_id IS NOT NULL)
+        ),
+        CHECK(
+            (
+                connected
+                AND (
+                    prev_msg_id IS NOT NULL
+                    OR prev_archived
+                )
+                AND genesis_id IS NOT NULL
+            )
+            OR NOT connected
+            OR (
+                height = 0
+                AND connected
+            )
+        ),
+        -- only paid attention to for the genesis column
+        -- binary bodies (see attest_messages::binary) can't be inspected
+        -- from SQL (CASE keeps json() from ever seeing them), instead they
+        -- are checked to decode to the envelope the columns are derived from
+        -- before being written, see storage_encoding::check_binary_body.
+        CHECK(
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE json_valid(body)
+            END
+        ),
+        CHECK(
+            height > 0
+            OR (
+                height = 0
+                AND prev_msg = "0000000000000000000000000000000000000000000000000000000000000000"
+            )
+        ),
+        CHECK(
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE IFNULL(
+                    json(body) ->> '$.header.ancestors.genesis',
+                    hash
+                ) = genesis
+            END
+        ),
+        CHECK(
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE IFNULL(
+                    json(body) ->> '$.header.ancestors.prev_msg',
+                    prev_msg
+                ) = prev_msg
+            END
+        ),
+        CHECK(
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE (json(body) ->> '$.header.height') = height
+            END
+        ),
+        CHECK(
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE (json(body) ->> '$.header.sent_time_ms') = sent_time
+            END
+        ),
+        CHECK(
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE substr(
+                    json(body) ->> '$.header.nonce',
+                    0,
+                    64
+                ) = nonce
+            END
+        ),
+        CHECK(
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE IFNULL(json(body) ->> '$.header.version', 0) = format_version
+            END
+        ),
+        CHECK(
+            NOT pruned
+            OR expires_at IS NOT NULL
+        )
+);
+
+INSERT INTO
+    messages_v1 (
+        message_id,
+        body,
+        hash,
+        user_id,
+        received_time,
+        prev_msg_id,
+        genesis_id,
+        height,
+        sent_time,
+        prev_msg,
+        genesis,
+        nonce,
+        connected,
+        format_version
+    )
+SELECT
+    message_id,
+    body,
+    hash,
+    user_id,
+    received_time,
+    prev_msg_id,
+    genesis_id,
+    height,
+    sent_time,
+    prev_msg,
+    genesis,
+    nonce,
+    connected,
+    IFNULL(json(body) ->> '$.header.version', 0)
+FROM
+    messages;
+
+DROP TABLE messages;
+
+ALTER TABLE
+    messages_v1 RENAME TO messages;
+
+CREATE TABLE message_nonces_v1 (
+    nonce_id INTEGER PRIMARY KEY,
+    key_id INTEGER,
+    -- erased once the envelope signed with it is stored, see
+    -- db_handle::get::nonces::NonceState
+    private_key TEXT,
+    public_key TEXT,
+    state TEXT NOT NULL DEFAULT 'Committed',
+    FOREIGN KEY(key_id) REFERENCES private_keys(key_id),
+    UNIQUE(key_id, private_key, public_key),
+    CHECK(state IN ('Generated', 'Committed', 'Used')),
+    CHECK(
+        private_key IS NOT NULL
+        OR state = 'Used'
+    )
+);
+
+INSERT INTO
+    message_nonces_v1 (nonce_id, key_id, private_key, public_key)
+SELECT
+    nonce_id,
+    key_id,
+    private_key,
+    public_key
+FROM
+    message_nonces;
+
+-- nonces used to be kept after signing, the ones that signed a stored
+-- envelope are erased so they can't sign again
+UPDATE
+    message_nonces_v1
+SET
+    state = 'Used',
+    private_key = NULL
+WHERE
+    public_key IN (
+        SELECT
+            M.nonce
+        FROM
+            messages M
+    );
+
+DROP TABLE message_nonces;
+
+ALTER TABLE
+    message_nonces_v1 RENAME TO message_nonces;
+
+CREATE TABLE chain_commit_groups_v1 (
+    group_id INTEGER PRIMARY KEY,
+    name TEXT UNIQUE,
+    -- members of finished groups may be archived
+    finished BOOLEAN NOT NULL DEFAULT 0,
+    -- see GroupTipPolicy
+    tip_policy TEXT NOT NULL DEFAULT 'All',
+    CHECK(tip_policy IN ('All', 'Changed', 'Paused'))
+);
+
+INSERT INTO
+    chain_commit_groups_v1 (group_id, name)
+SELECT
+    group_id,
+    name
+FROM
+    chain_commit_groups;
+
+DROP TABLE chain_commit_groups;
+
+ALTER TABLE
+    chain_commit_groups_v1 RENAME TO chain_commit_groups;
+
+-- per chain statistics, kept up to date by the message_chain_stats trigger on every
+-- stored envelope, see db_handle::get::chain_stats
+CREATE TABLE chain_stats (
+    genesis TEXT PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL,
+    messages INTEGER NOT NULL,
+    -- local time the first and last envelopes were stored
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    -- the earliest and latest sent_time_ms of stored envelopes
+    first_sent INTEGER NOT NULL,
+    last_sent INTEGER NOT NULL,
+    -- propagation delay, received_time - sent_time, over all envelopes
+    total_delay INTEGER NOT NULL,
+    max_delay INTEGER NOT NULL,
+    -- histogram of the propagation delay, negative delays are envelopes
+    -- sent from a clock ahead of ours
+    delay_negative INTEGER NOT NULL,
+    delay_lt_100ms INTEGER NOT NULL,
+    delay_lt_1s INTEGER NOT NULL,
+    delay_lt_10s INTEGER NOT NULL,
+    delay_lt_1m INTEGER NOT NULL,
+    delay_ge_1m INTEGER NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES users(user_id)
+);
+
+-- envelopes stored so far are counted, but not towards the propagation delay,
+-- see db_handle::get::chain_stats
+INSERT INTO
+    chain_stats (
+        genesis,
+        user_id,
+        messages,
+        first_seen,
+        last_seen,
+        first_sent,
+        last_sent,
+        total_delay,
+        max_delay,
+        delay_negative,
+        delay_lt_100ms,
+        delay_lt_1s,
+        delay_lt_10s,
+        delay_lt_1m,
+        delay_ge_1m
+    )
+SELECT
+    S.genesis,
+    G.user_id,
+    S.messages,
+    S.first_seen,
+    S.last_seen,
+    S.first_sent,
+    S.last_sent,
+    0,
+    0,
+    0,
+    0,
+    0,
+    0,
+    0,
+    0
+FROM
+    (
+        SELECT
+            M.genesis,
+            COUNT(*) AS messages,
+            MIN(M.received_time) AS first_seen,
+            MAX(M.received_time) AS last_seen,
+            MIN(M.sent_time) AS first_sent,
+            MAX(M.sent_time) AS last_sent
+        FROM
+            messages M
+        GROUP BY
+            M.genesis
+    ) S
+    INNER JOIN messages G ON G.hash = S.genesis
+    AND G.height = 0
\ No newline at end of file
@@ -10,6 +10,8 @@ pub use setup::*;
 pub use update::*;
 pub mod insert {
     pub const SQL_INSERT_NONCE_BY_KEY: &str = include_str!("../sql/insert/nonce.sql");
+    pub const SQL_INSERT_PREGENERATED_NONCE: &str =
+        include_str!("../sql/insert/pregenerated_nonce.sql");
     pub const SQL_INSERT_HIDDEN_SERVICE: &str = include_str!("../sql/insert/hidden_service.sql");
     pub const SQL_INSERT_KEYPAIR: &str = include_str!("../sql/insert/keypair.sql");
     pub const SQL_INSERT_USER: &str = include_str!("../sql/insert/user.sql");
@@ -20,20 +22,127 @@ pub mod insert {
     pub const SQL_INSERT_CHAIN_COMMIT_GROUP_SUBSCRIBER: &str =
         include_str!("../sql/insert/add_chain_commit_group_subscriber.sql");
     pub const SQL_INSERT_ENVELOPE: &str = include_str!("../sql/insert/envelope.sql");
+    pub const SQL_INSERT_PRIVATE_CHAIN: &str = include_str!("../sql/insert/private_chain.sql");
+    pub const SQL_INSERT_PRIVATE_CHAIN_PEER: &str =
+        include_str!("../sql/insert/private_chain_peer.sql");
+    pub const SQL_INSERT_CHAIN_KEY: &str = include_str!("../sql/insert/chain_key.sql");
+    pub const SQL_INSERT_SIGNING_GROUP: &str = include_str!("../sql/insert/signing_group.sql");
+    pub const SQL_INSERT_SIGNING_GROUP_MEMBER: &str =
+        include_str!("../sql/insert/signing_group_member.sql");
+    pub const SQL_INSERT_GROUP_NONCE_SHARE: &str =
+        include_str!("../sql/insert/group_nonce_share.sql");
+    pub const SQL_INSERT_PARTIAL_SIGNATURE: &str =
+        include_str!("../sql/insert/partial_signature.sql");
+    pub const SQL_INSERT_BLOB: &str = include_str!("../sql/insert/blob.sql");
+    pub const SQL_INSERT_MESSAGE_ATTACHMENT: &str =
+        include_str!("../sql/insert/message_attachment.sql");
+    pub const SQL_INSERT_ANCHOR: &str = include_str!("../sql/insert/anchor.sql");
+    pub const SQL_INSERT_ANCHOR_LEAF: &str = include_str!("../sql/insert/anchor_leaf.sql");
+    pub const SQL_INSERT_ARCHIVED_CHAIN: &str = include_str!("../sql/insert/archived_chain.sql");
 }
 
 pub mod update {
     pub const SQL_UPDATE_CONNECT_RECURSIVE: &str = include_str!("../sql/update/do_connect.sql");
     pub const SQL_UPDATE_HIDDEN_SERVICE: &str = include_str!("../sql/update/hidden_service.sql");
     pub const SQL_UPDATE_CONNECT_PARENTS: &str = include_str!("../sql/update/resolve_prev_ids.sql");
+    pub const SQL_UPDATE_PEER_ACKNOWLEDGEMENT: &str =
+        include_str!("../sql/update/peer_acknowledgement.sql");
+    pub const SQL_UPDATE_MAKE_CHAIN_PUBLIC: &str =
+        include_str!("../sql/update/make_chain_public.sql");
+    pub const SQL_UPDATE_STORAGE_ENCODING: &str =
+        include_str!("../sql/update/storage_encoding.sql");
+    pub const SQL_UPDATE_END_CHAIN_KEY: &str = include_str!("../sql/update/end_chain_key.sql");
+    pub const SQL_UPDATE_CONFIRM_ANCHOR: &str = include_str!("../sql/update/confirm_anchor.sql");
+    pub const SQL_UPDATE_ABANDON_ANCHOR: &str = include_str!("../sql/update/abandon_anchor.sql");
+    pub const SQL_UPDATE_PRUNE_MESSAGE: &str = include_str!("../sql/update/prune_message.sql");
+    pub const SQL_UPDATE_MARK_PREV_ARCHIVED: &str =
+        include_str!("../sql/update/mark_prev_archived.sql");
+    pub const SQL_UPDATE_DELETE_ARCHIVED_CHAINS: &str =
+        include_str!("../sql/update/delete_archived_chains.sql");
+    pub const SQL_UPDATE_MARK_ARCHIVED_CHAINS_RESTORING: &str =
+        include_str!("../sql/update/mark_archived_chains_restoring.sql");
+    pub const SQL_UPDATE_FINISH_CHAIN_COMMIT_GROUP: &str =
+        include_str!("../sql/update/finish_chain_commit_group.sql");
+    pub const SQL_UPDATE_REMOVE_CHAIN_COMMIT_GROUP_MEMBER: &str =
+        include_str!("../sql/update/remove_chain_commit_group_member.sql");
+    pub const SQL_UPDATE_REMOVE_CHAIN_COMMIT_GROUP_SUBSCRIBER: &str =
+        include_str!("../sql/update/remove_chain_commit_group_subscriber.sql");
+    pub const SQL_UPDATE_RENAME_CHAIN_COMMIT_GROUP: &str =
+        include_str!("../sql/update/rename_chain_commit_group.sql");
+    pub const SQL_UPDATE_CHAIN_COMMIT_GROUP_TIP_POLICY: &str =
+        include_str!("../sql/update/chain_commit_group_tip_policy.sql");
+    pub const SQL_UPDATE_COMPACT: &str = include_str!("../sql/update/compact.sql");
+    pub const SQL_UPDATE_CLAIM_PREGENERATED_NONCE: &str =
+        include_str!("../sql/update/claim_pregenerated_nonce.sql");
+    pub const SQL_UPDATE_USE_NONCE: &str = include_str!("../sql/update/use_nonce.sql");
+    pub const SQL_UPDATE_RETIRE_NONCE: &str = include_str!("../sql/update/retire_nonce.sql");
+    pub const SQL_UPDATE_RELEASE_NONCE: &str = include_str!("../sql/update/release_nonce.sql");
+    pub const SQL_UPDATE_COLLECT_NONCES: &str = include_str!("../sql/update/collect_nonces.sql");
+    pub const SQL_UPDATE_RELEASE_UNSTORED_NONCES: &str =
+        include_str!("../sql/update/release_unstored_nonces.sql");
+    pub const SQL_UPDATE_DROP_EXPIRED_MESSAGE: &str =
+        include_str!("../sql/update/drop_expired_message.sql");
 }
 
 pub mod get {
+    pub use ancestry::*;
+    pub use anchors::*;
+    pub use archive::*;
+    pub use backup::*;
+    pub use blobs::*;
     pub use chain_commit_groups::*;
+    pub use chain_keys::*;
+    pub use chain_stats::*;
+    pub use chain_visibility::*;
+    pub use gaps::*;
     pub use hidden_services::*;
     pub use messages::*;
     pub use nonces::*;
+    pub use peer_acknowledgements::*;
+    pub use signing_groups::*;
+    pub use storage_encoding::*;
     pub use users::*;
+    pub mod anchors {
+        pub const SQL_GET_UNCONFIRMED_ANCHORS: &str =
+            include_str!("../sql/get/anchors/unconfirmed.sql");
+        pub const SQL_GET_MESSAGE_IS_ANCHORED: &str =
+            include_str!("../sql/get/anchors/is_anchored.sql");
+        pub const SQL_GET_EARLIEST_ANCHOR_FOR_MESSAGE: &str =
+            include_str!("../sql/get/anchors/earliest_for_message.sql");
+        pub const SQL_GET_ANCHOR_LEAVES: &str = include_str!("../sql/get/anchors/leaves.sql");
+    }
+    pub mod backup {
+        pub const SQL_GET_SNAPSHOT_MESSAGES: &str =
+            include_str!("../sql/get/backup/snapshot_messages.sql");
+        pub const SQL_GET_LOCAL_CHAIN_HEIGHTS: &str =
+            include_str!("../sql/get/backup/local_chain_heights.sql");
+        pub const SQL_GET_SPENT_NONCES: &str = include_str!("../sql/get/backup/spent_nonces.sql");
+    }
+    pub mod archive {
+        pub const SQL_GET_ALL_ARCHIVED_CHAINS: &str = include_str!("../sql/get/archive/all.sql");
+        pub const SQL_GET_ARCHIVED_CHAINS_FOR_CHAIN: &str =
+            include_str!("../sql/get/archive/for_chain.sql");
+        pub const SQL_GET_IS_ARCHIVED: &str = include_str!("../sql/get/archive/is_archived.sql");
+        pub const SQL_GET_ARCHIVE_CHAIN_TIP: &str =
+            include_str!("../sql/get/archive/chain_tip.sql");
+        pub const SQL_GET_ARCHIVE_CANDIDATES: &str =
+            include_str!("../sql/get/archive/candidates.sql");
+    }
+    pub mod ancestry {
+        pub const SQL_GET_CHAIN_ENDING_AT: &str =
+            include_str!("../sql/get/ancestry/chain_ending_at.sql");
+    }
+    pub mod blobs {
+        pub const SQL_GET_BLOB_BY_HASH: &str = include_str!("../sql/get/blobs/by_hash.sql");
+        pub const SQL_GET_BLOB_SIZE_BY_HASH: &str =
+            include_str!("../sql/get/blobs/size_by_hash.sql");
+        pub const SQL_GET_MISSING_BLOBS: &str = include_str!("../sql/get/blobs/missing.sql");
+        pub const SQL_GET_BLOB_REFERENCING_CHAINS: &str =
+            include_str!("../sql/get/blobs/referencing_chains.sql");
+    }
+    pub mod chain_stats {
+        pub const SQL_GET_ALL_CHAIN_STATS: &str = include_str!("../sql/get/chain_stats/all.sql");
+    }
     pub mod chain_commit_groups {
         pub const SQL_GET_ALL_CHAIN_COMMIT_GROUPS: &str =
             include_str!("../sql/get/chain_commit_groups/all_chain_commit_groups.sql");
@@ -49,6 +158,31 @@ pub mod get {
         pub const SQL_GET_ALL_CHAIN_COMMIT_GROUP_MEMBERS_NEW_ENVELOPES_FOR_CHAIN: &str = include_str!(
             "../sql/get/chain_commit_groups/all_chain_commit_group_members_new_envelopes_for_chain.sql"
         );
+        pub const SQL_GET_CHAIN_COMMIT_GROUP_MEMBER_CHAINS_BY_GROUP_NAME: &str =
+            include_str!("../sql/get/chain_commit_groups/member_chains_by_group_name.sql");
+        pub const SQL_GET_CHAIN_COMMIT_GROUP_BY_NAME: &str =
+            include_str!("../sql/get/chain_commit_groups/by_name.sql");
+        pub const SQL_GET_CHAIN_COMMIT_GROUP_MEMBERSHIPS_FOR_KEY: &str =
+            include_str!("../sql/get/chain_commit_groups/memberships_for_key.sql");
+    }
+    pub mod chain_keys {
+        pub const SQL_GET_CHAIN_ROOT_KEY_AT: &str =
+            include_str!("../sql/get/chain_keys/root_at.sql");
+        pub const SQL_GET_CHAIN_KEY_IS_DELEGATE_AT: &str =
+            include_str!("../sql/get/chain_keys/is_delegate_at.sql");
+        pub const SQL_GET_CHAIN_GENESIS_KEY: &str =
+            include_str!("../sql/get/chain_keys/genesis_key.sql");
+    }
+    pub mod chain_visibility {
+        pub const SQL_GET_CHAIN_VISIBILITY_ALLOWED_PEERS: &str =
+            include_str!("../sql/get/chain_visibility/allowed_peers.sql");
+        pub const SQL_GET_CHAINS_HIDDEN_FROM_SERVICE: &str =
+            include_str!("../sql/get/chain_visibility/hidden_from_service.sql");
+    }
+    pub mod gaps {
+        pub const SQL_GET_GAPS_MISSING_PARENTS: &str =
+            include_str!("../sql/get/gaps/missing_parents.sql");
+        pub const SQL_GET_GAPS_RANGE: &str = include_str!("../sql/get/gaps/range.sql");
     }
     pub mod hidden_services {
 
@@ -86,12 +220,39 @@ pub mod get {
             include_str!("../sql/get/messages/exists_children.sql");
         pub const SQL_GET_MESSAGE_BY_HASH: &str = include_str!("../sql/get/messages/by_hash.sql");
         pub const SQL_GET_MESSAGE_BY_ID: &str = include_str!("../sql/get/messages/by_id.sql");
+        pub const SQL_GET_MESSAGE_SYNC_KEYS: &str =
+            include_str!("../sql/get/messages/sync_keys.sql");
+        pub const SQL_GET_MESSAGES_QUERY: &str = include_str!("../sql/get/messages/query.sql");
+        pub const SQL_GET_MESSAGES_EXPIRED: &str = include_str!("../sql/get/messages/expired.sql");
     }
     pub mod nonces {
 
         pub const SQL_GET_SECRET_FOR_NONCE: &str =
             include_str!("../sql/get/nonces/secret_for_nonce.sql");
         pub const SQL_GET_REUSED_NONCE: &str = include_str!("../sql/get/nonces/reused_nonces.sql");
+        pub const SQL_GET_NONCE_STATE: &str = include_str!("../sql/get/nonces/state.sql");
+        pub const SQL_GET_PREGENERATED_NONCE_COUNT: &str =
+            include_str!("../sql/get/nonces/pregenerated_count.sql");
+    }
+    pub mod peer_acknowledgements {
+
+        pub const SQL_GET_PEER_ACKNOWLEDGEMENTS_FOR_SERVICE: &str =
+            include_str!("../sql/get/peer_acknowledgements/for_service.sql");
+        pub const SQL_GET_PEER_ACKNOWLEDGEMENTS_FOR_CHAIN: &str =
+            include_str!("../sql/get/peer_acknowledgements/for_chain.sql");
+    }
+    pub mod signing_groups {
+
+        pub const SQL_GET_SIGNING_GROUP_MEMBERS: &str =
+            include_str!("../sql/get/signing_groups/members.sql");
+        pub const SQL_GET_GROUP_NONCE_SHARES: &str =
+            include_str!("../sql/get/signing_groups/nonce_shares.sql");
+        pub const SQL_GET_PARTIAL_SIGNATURES: &str =
+            include_str!("../sql/get/signing_groups/partial_signatures.sql");
+    }
+    pub mod storage_encoding {
+
+        pub const SQL_GET_STORAGE_ENCODING: &str = include_str!("../sql/get/storage_encoding.sql");
     }
     pub mod users {
 
@@ -99,11 +260,23 @@ pub mod get {
         pub const SQL_GET_USER_BY_KEY: &str = include_str!("../sql/get/users/user_by_key.sql");
         pub const SQL_GET_ALL_SECRET_KEYS: &str =
             include_str!("../sql/get/users/all_secret_keys.sql");
+        pub const SQL_GET_SECRET_KEY_BY_KEY: &str =
+            include_str!("../sql/get/users/secret_key_by_key.sql");
     }
 }
+pub mod migrations {
+    /// `MIGRATIONS[v]` migrates a database at `PRAGMA user_version` `v` to
+    /// `v + 1`, see [`crate::db_handle::setup`]
+    pub const MIGRATIONS: &[&str] = &[include_str!("../sql/migrations/v0_to_v1.sql")];
+    /// the schema of databases from before migrations, to test them with
+    #[cfg(test)]
+    pub const SQL_SCHEMA_V0: &str = include_str!("../sql/migrations/v0.sql");
+}
 pub mod setup {
     pub const SQL_CREATE_TABLES: &str = concat!(
         "PRAGMA foreign_keys = ON;",
+        // overwrite erased nonces rather than leave them in free pages
+        "PRAGMA secure_delete = ON;",
         include_str!("../sql/tables/users.sql"),
         include_str!("../sql/tables/messages.sql"),
         include_str!("../sql/tables/nonces.sql"),
@@ -112,13 +285,33 @@ pub mod setup {
         include_str!("../sql/tables/chain_commit_group_members.sql"),
         include_str!("../sql/tables/chain_commit_group_subscribers.sql"),
         include_str!("../sql/tables/hidden_services.sql"),
+        include_str!("../sql/tables/peer_acknowledgements.sql"),
+        include_str!("../sql/tables/private_chains.sql"),
+        include_str!("../sql/tables/private_chain_peers.sql"),
+        include_str!("../sql/tables/storage_encoding.sql"),
+        include_str!("../sql/tables/chain_keys.sql"),
+        include_str!("../sql/tables/signing_groups.sql"),
+        include_str!("../sql/tables/signing_group_members.sql"),
+        include_str!("../sql/tables/group_nonce_shares.sql"),
+        include_str!("../sql/tables/partial_signatures.sql"),
+        include_str!("../sql/tables/blobs.sql"),
+        include_str!("../sql/tables/message_attachments.sql"),
+        include_str!("../sql/tables/anchors.sql"),
+        include_str!("../sql/tables/anchor_leaves.sql"),
+        include_str!("../sql/tables/archived_chains.sql"),
+        include_str!("../sql/tables/chain_stats.sql"),
         include_str!("../sql/triggers/messages/connect_gap_parent.sql"),
+        include_str!("../sql/triggers/messages/chain_stats.sql"),
+        include_str!("../sql/indexes/messages/query.sql"),
+        include_str!("../sql/indexes/messages/expires_at.sql"),
+        include_str!("../sql/indexes/nonces/public_key.sql"),
         "PRAGMA journal_mode = WAL;"
     );
 }
 
 pub const CACHED: &[&str] = &[
     SQL_INSERT_NONCE_BY_KEY,
+    SQL_INSERT_PREGENERATED_NONCE,
     SQL_INSERT_HIDDEN_SERVICE,
     SQL_INSERT_KEYPAIR,
     SQL_INSERT_USER,
@@ -126,14 +319,76 @@ pub const CACHED: &[&str] = &[
     SQL_INSERT_CHAIN_COMMIT_GROUP_MEMBER,
     SQL_INSERT_CHAIN_COMMIT_GROUP_SUBSCRIBER,
     SQL_INSERT_ENVELOPE,
+    SQL_INSERT_PRIVATE_CHAIN,
+    SQL_INSERT_PRIVATE_CHAIN_PEER,
+    SQL_INSERT_CHAIN_KEY,
+    SQL_INSERT_SIGNING_GROUP,
+    SQL_INSERT_SIGNING_GROUP_MEMBER,
+    SQL_INSERT_GROUP_NONCE_SHARE,
+    SQL_INSERT_PARTIAL_SIGNATURE,
+    SQL_INSERT_BLOB,
+    SQL_INSERT_MESSAGE_ATTACHMENT,
+    SQL_INSERT_ANCHOR,
+    SQL_INSERT_ANCHOR_LEAF,
+    SQL_INSERT_ARCHIVED_CHAIN,
     SQL_UPDATE_CONNECT_RECURSIVE,
     SQL_UPDATE_HIDDEN_SERVICE,
     SQL_UPDATE_CONNECT_PARENTS,
+    SQL_UPDATE_PEER_ACKNOWLEDGEMENT,
+    SQL_UPDATE_MAKE_CHAIN_PUBLIC,
+    SQL_UPDATE_STORAGE_ENCODING,
+    SQL_UPDATE_END_CHAIN_KEY,
+    SQL_UPDATE_CONFIRM_ANCHOR,
+    SQL_UPDATE_ABANDON_ANCHOR,
+    SQL_UPDATE_PRUNE_MESSAGE,
+    SQL_UPDATE_MARK_PREV_ARCHIVED,
+    SQL_UPDATE_DELETE_ARCHIVED_CHAINS,
+    SQL_UPDATE_MARK_ARCHIVED_CHAINS_RESTORING,
+    SQL_UPDATE_FINISH_CHAIN_COMMIT_GROUP,
+    SQL_UPDATE_REMOVE_CHAIN_COMMIT_GROUP_MEMBER,
+    SQL_UPDATE_REMOVE_CHAIN_COMMIT_GROUP_SUBSCRIBER,
+    SQL_UPDATE_RENAME_CHAIN_COMMIT_GROUP,
+    SQL_UPDATE_CHAIN_COMMIT_GROUP_TIP_POLICY,
+    SQL_UPDATE_COMPACT,
+    SQL_UPDATE_CLAIM_PREGENERATED_NONCE,
+    SQL_UPDATE_USE_NONCE,
+    SQL_UPDATE_RETIRE_NONCE,
+    SQL_UPDATE_RELEASE_NONCE,
+    SQL_UPDATE_COLLECT_NONCES,
+    SQL_UPDATE_RELEASE_UNSTORED_NONCES,
+    SQL_UPDATE_DROP_EXPIRED_MESSAGE,
+    SQL_GET_UNCONFIRMED_ANCHORS,
+    SQL_GET_MESSAGE_IS_ANCHORED,
+    SQL_GET_EARLIEST_ANCHOR_FOR_MESSAGE,
+    SQL_GET_ANCHOR_LEAVES,
+    SQL_GET_ALL_ARCHIVED_CHAINS,
+    SQL_GET_ARCHIVED_CHAINS_FOR_CHAIN,
+    SQL_GET_IS_ARCHIVED,
+    SQL_GET_ARCHIVE_CHAIN_TIP,
+    SQL_GET_ARCHIVE_CANDIDATES,
+    SQL_GET_SNAPSHOT_MESSAGES,
+    SQL_GET_LOCAL_CHAIN_HEIGHTS,
+    SQL_GET_SPENT_NONCES,
+    SQL_GET_CHAIN_ENDING_AT,
+    SQL_GET_GAPS_MISSING_PARENTS,
+    SQL_GET_GAPS_RANGE,
+    SQL_GET_BLOB_BY_HASH,
+    SQL_GET_BLOB_SIZE_BY_HASH,
+    SQL_GET_MISSING_BLOBS,
+    SQL_GET_BLOB_REFERENCING_CHAINS,
     SQL_GET_ALL_CHAIN_COMMIT_GROUPS,
     SQL_GET_ALL_CHAIN_COMMIT_GROUPS_FOR_CHAIN,
     SQL_GET_ALL_CHAIN_COMMIT_GROUP_MEMBERS_FOR_CHAIN,
     SQL_GET_ALL_CHAIN_COMMIT_GROUP_MEMBERS_TIPS_FOR_CHAIN,
     SQL_GET_ALL_CHAIN_COMMIT_GROUP_MEMBERS_NEW_ENVELOPES_FOR_CHAIN,
+    SQL_GET_CHAIN_COMMIT_GROUP_MEMBER_CHAINS_BY_GROUP_NAME,
+    SQL_GET_CHAIN_COMMIT_GROUP_BY_NAME,
+    SQL_GET_CHAIN_COMMIT_GROUP_MEMBERSHIPS_FOR_KEY,
+    SQL_GET_CHAIN_ROOT_KEY_AT,
+    SQL_GET_CHAIN_KEY_IS_DELEGATE_AT,
+    SQL_GET_CHAIN_GENESIS_KEY,
+    SQL_GET_CHAIN_VISIBILITY_ALLOWED_PEERS,
+    SQL_GET_CHAINS_HIDDEN_FROM_SERVICE,
     SQL_GET_ALL_HIDDEN_SERVICES,
     SQL_GET_MESSAGES_NEWER_THAN_FOR_GENESIS,
     SQL_GET_MESSAGES_BY_HEIGHT_AND_USER,
@@ -151,9 +406,22 @@ pub const CACHED: &[&str] = &[
     SQL_GET_MESSAGE_EXISTS_CHILDREN,
     SQL_GET_MESSAGE_BY_HASH,
     SQL_GET_MESSAGE_BY_ID,
+    SQL_GET_MESSAGE_SYNC_KEYS,
+    SQL_GET_MESSAGES_QUERY,
+    SQL_GET_MESSAGES_EXPIRED,
+    SQL_GET_ALL_CHAIN_STATS,
     SQL_GET_SECRET_FOR_NONCE,
     SQL_GET_REUSED_NONCE,
+    SQL_GET_NONCE_STATE,
+    SQL_GET_PREGENERATED_NONCE_COUNT,
+    SQL_GET_PEER_ACKNOWLEDGEMENTS_FOR_SERVICE,
+    SQL_GET_PEER_ACKNOWLEDGEMENTS_FOR_CHAIN,
+    SQL_GET_SIGNING_GROUP_MEMBERS,
+    SQL_GET_GROUP_NONCE_SHARES,
+    SQL_GET_PARTIAL_SIGNATURES,
+    SQL_GET_STORAGE_ENCODING,
     SQL_GET_ALL_USERS,
     SQL_GET_USER_BY_KEY,
     SQL_GET_ALL_SECRET_KEYS,
+    SQL_GET_SECRET_KEY_BY_KEY,
 ];
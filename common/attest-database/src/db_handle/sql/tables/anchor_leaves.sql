@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS anchor_leaves (
+    anchor_id INTEGER NOT NULL,
+    leaf_index INTEGER NOT NULL,
+    message_hash TEXT NOT NULL,
+    FOREIGN KEY(anchor_id) REFERENCES anchors(anchor_id) ON DELETE CASCADE,
+    UNIQUE(anchor_id, leaf_index)
+);
\ No newline at end of file
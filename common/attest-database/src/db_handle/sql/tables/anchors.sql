@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS anchors (
+    anchor_id INTEGER PRIMARY KEY,
+    root TEXT NOT NULL,
+    txid TEXT NOT NULL,
+    tx TEXT NOT NULL,
+    tx_proof TEXT,
+    block_hash TEXT,
+    height INTEGER,
+    created_time INTEGER NOT NULL,
+    UNIQUE(root),
+    UNIQUE(txid),
+    CHECK((tx_proof IS NULL) = (block_hash IS NULL)),
+    CHECK((tx_proof IS NULL) = (height IS NULL))
+);
\ No newline at end of file
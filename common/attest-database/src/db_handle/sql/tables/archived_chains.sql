@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS archived_chains (
+    archive_id INTEGER PRIMARY KEY,
+    genesis_id INTEGER NOT NULL,
+    -- the lowest envelope still stored above the archived range, its
+    -- prev_msg is the last envelope in the archive
+    checkpoint TEXT NOT NULL,
+    first_height INTEGER NOT NULL,
+    last_height INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    -- sha256 of the archive file
+    digest TEXT NOT NULL,
+    archived_time INTEGER NOT NULL,
+    -- set while the archive is put back, its heights are no longer refused
+    -- but aren't counted again in chain_stats
+    restoring BOOLEAN NOT NULL DEFAULT 0,
+    FOREIGN KEY(genesis_id) REFERENCES messages(message_id) ON DELETE CASCADE,
+    UNIQUE(genesis_id, first_height),
+    CHECK(
+        0 < first_height
+        AND first_height <= last_height
+    )
+);
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS blobs (
+    blob_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    hash TEXT NOT NULL,
+    data BLOB NOT NULL,
+    received_time INTEGER NOT NULL,
+    UNIQUE(hash)
+);
\ No newline at end of file
@@ -1,4 +1,9 @@
 CREATE TABLE IF NOT EXISTS chain_commit_groups (
     group_id INTEGER PRIMARY KEY,
-    name TEXT UNIQUE
+    name TEXT UNIQUE,
+    -- members of finished groups may be archived
+    finished BOOLEAN NOT NULL DEFAULT 0,
+    -- see GroupTipPolicy
+    tip_policy TEXT NOT NULL DEFAULT 'All',
+    CHECK(tip_policy IN ('All', 'Changed', 'Paused'))
 );
\ No newline at end of file
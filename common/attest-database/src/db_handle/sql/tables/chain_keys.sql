@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS chain_keys (
+    chain_key_id INTEGER PRIMARY KEY,
+    genesis TEXT NOT NULL,
+    key TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    -- the key may sign envelopes in [from_height, until_height)
+    from_height INTEGER NOT NULL,
+    until_height INTEGER,
+    FOREIGN KEY(user_id) references users(user_id),
+    UNIQUE(genesis, key, role, from_height),
+    CHECK(role IN ('Root', 'Delegate')),
+    CHECK(
+        until_height IS NULL
+        OR until_height > from_height
+    )
+);
\ No newline at end of file
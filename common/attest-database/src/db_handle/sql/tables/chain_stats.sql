@@ -0,0 +1,25 @@
+-- per chain statistics, kept up to date by the message_chain_stats trigger on every
+-- stored envelope, see db_handle::get::chain_stats
+CREATE TABLE IF NOT EXISTS chain_stats (
+    genesis TEXT PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL,
+    messages INTEGER NOT NULL,
+    -- local time the first and last envelopes were stored
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    -- the earliest and latest sent_time_ms of stored envelopes
+    first_sent INTEGER NOT NULL,
+    last_sent INTEGER NOT NULL,
+    -- propagation delay, received_time - sent_time, over all envelopes
+    total_delay INTEGER NOT NULL,
+    max_delay INTEGER NOT NULL,
+    -- histogram of the propagation delay, negative delays are envelopes
+    -- sent from a clock ahead of ours
+    delay_negative INTEGER NOT NULL,
+    delay_lt_100ms INTEGER NOT NULL,
+    delay_lt_1s INTEGER NOT NULL,
+    delay_lt_10s INTEGER NOT NULL,
+    delay_lt_1m INTEGER NOT NULL,
+    delay_ge_1m INTEGER NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES users(user_id)
+);
\ No newline at end of file
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS group_nonce_shares (
+    group_nonce_share_id INTEGER PRIMARY KEY,
+    signing_group_id INTEGER NOT NULL,
+    aggregate_nonce TEXT NOT NULL,
+    member_key TEXT NOT NULL,
+    nonce_share TEXT NOT NULL,
+    FOREIGN KEY(signing_group_id) REFERENCES signing_groups(signing_group_id),
+    UNIQUE(aggregate_nonce, member_key),
+    -- a share may only ever be part of one group nonce
+    UNIQUE(nonce_share)
+);
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS message_attachments (
+    message_id INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    FOREIGN KEY(message_id) REFERENCES messages(message_id) ON DELETE CASCADE,
+    UNIQUE(message_id, hash)
+);
\ No newline at end of file
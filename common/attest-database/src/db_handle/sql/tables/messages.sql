@@ -12,6 +12,25 @@ CREATE TABLE IF NOT EXISTS messages (
     genesis TEXT NOT NULL,
     nonce TEXT NOT NULL,
     connected BOOLEAN NOT NULL,
+    -- see attest_messages::FormatVersion
+    format_version INTEGER NOT NULL DEFAULT 0,
+    -- set once the envelopes below this one are archived, see
+    -- archived_chains
+    prev_archived BOOLEAN NOT NULL DEFAULT 0,
+    -- when the message of an ephemeral envelope may be pruned, see
+    -- attest_messages::ephemeral
+    expires_at INTEGER,
+    -- set once the message has been pruned, the body keeps its hash valid
+    pruned BOOLEAN NOT NULL DEFAULT 0,
+    -- binary bodies (see attest_messages::binary) can't be inspected from SQL
+    binary_body BOOLEAN GENERATED ALWAYS AS (typeof(body) = 'blob') VIRTUAL,
+    -- the tag of registered message types, see attest_messages::registry
+    msg_type TEXT GENERATED ALWAYS AS (
+        CASE
+            WHEN typeof(body) = 'blob' THEN NULL
+            ELSE body ->> '$.msg."@type"'
+        END
+    ) VIRTUAL,
     FOREIGN KEY(genesis_id) references messages(message_id) ON DELETE CASCADE,
     FOREIGN KEY(user_id) references users(user_id),
     FOREIGN KEY(prev_msg_id) references messages(message_id) ON DELETE
@@ -25,7 +44,10 @@ CREATE TABLE IF NOT EXISTS messages (
         CHECK(
             (
                 connected
-                AND prev_msg_id IS NOT NULL
+                AND (
+                    prev_msg_id IS NOT NULL
+                    OR prev_archived
+                )
                 AND genesis_id IS NOT NULL
             )
             OR NOT connected
@@ -35,7 +57,16 @@ CREATE TABLE IF NOT EXISTS messages (
             )
         ),
         -- only paid attention to for the genesis column
-        CHECK(json_valid(body)),
+        -- binary bodies (see attest_messages::binary) can't be inspected
+        -- from SQL (CASE keeps json() from ever seeing them), instead they
+        -- are checked to decode to the envelope the columns are derived from
+        -- before being written, see storage_encoding::check_binary_body.
+        CHECK(
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE json_valid(body)
+            END
+        ),
         CHECK(
             height > 0
             OR (
@@ -44,28 +75,53 @@ CREATE TABLE IF NOT EXISTS messages (
             )
         ),
         CHECK(
-            IFNULL(
-                json(body) ->> '$.header.ancestors.genesis',
-                hash
-            ) = genesis
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE IFNULL(
+                    json(body) ->> '$.header.ancestors.genesis',
+                    hash
+                ) = genesis
+            END
+        ),
+        CHECK(
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE IFNULL(
+                    json(body) ->> '$.header.ancestors.prev_msg',
+                    prev_msg
+                ) = prev_msg
+            END
+        ),
+        CHECK(
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE (json(body) ->> '$.header.height') = height
+            END
         ),
         CHECK(
-            IFNULL(
-                json(body) ->> '$.header.ancestors.prev_msg',
-                prev_msg
-            ) = prev_msg
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE (json(body) ->> '$.header.sent_time_ms') = sent_time
+            END
         ),
         CHECK(
-            (json(body) ->> '$.header.height') = height
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE substr(
+                    json(body) ->> '$.header.nonce',
+                    0,
+                    64
+                ) = nonce
+            END
         ),
         CHECK(
-            (json(body) ->> '$.header.sent_time_ms') = sent_time
+            CASE
+                WHEN typeof(body) = 'blob' THEN 1
+                ELSE IFNULL(json(body) ->> '$.header.version', 0) = format_version
+            END
         ),
         CHECK(
-            substr(
-                json(body) ->> '$.header.nonce',
-                0,
-                64
-            ) = nonce
+            NOT pruned
+            OR expires_at IS NOT NULL
         )
 );
\ No newline at end of file
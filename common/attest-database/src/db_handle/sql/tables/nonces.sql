@@ -1,8 +1,16 @@
 CREATE TABLE IF NOT EXISTS message_nonces (
     nonce_id INTEGER PRIMARY KEY,
     key_id INTEGER,
+    -- erased once the envelope signed with it is stored, see
+    -- db_handle::get::nonces::NonceState
     private_key TEXT,
     public_key TEXT,
+    state TEXT NOT NULL DEFAULT 'Committed',
     FOREIGN KEY(key_id) REFERENCES private_keys(key_id),
-    UNIQUE(key_id, private_key, public_key)
+    UNIQUE(key_id, private_key, public_key),
+    CHECK(state IN ('Generated', 'Committed', 'Used')),
+    CHECK(
+        private_key IS NOT NULL
+        OR state = 'Used'
+    )
 );
\ No newline at end of file
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS partial_signatures (
+    partial_signature_id INTEGER PRIMARY KEY,
+    aggregate_nonce TEXT NOT NULL,
+    member_key TEXT NOT NULL,
+    digest TEXT NOT NULL,
+    partial_signature TEXT NOT NULL,
+    -- a second partial signature with the same nonce share would leak the
+    -- member's key
+    UNIQUE(aggregate_nonce, member_key)
+);
\ No newline at end of file
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS peer_acknowledgements (
+    service_id INTEGER NOT NULL,
+    genesis TEXT NOT NULL,
+    height INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    updated_time INTEGER NOT NULL,
+    FOREIGN KEY(service_id) REFERENCES hidden_services(service_id) ON DELETE CASCADE,
+    UNIQUE(service_id, genesis)
+);
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS private_chain_peers (
+    genesis TEXT NOT NULL,
+    service_id INTEGER NOT NULL,
+    FOREIGN KEY(genesis) REFERENCES private_chains(genesis) ON DELETE CASCADE,
+    FOREIGN KEY(service_id) REFERENCES hidden_services(service_id) ON DELETE CASCADE,
+    UNIQUE(genesis, service_id)
+);
\ No newline at end of file
@@ -0,0 +1,3 @@
+CREATE TABLE IF NOT EXISTS private_chains (
+    genesis TEXT PRIMARY KEY
+);
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS signing_group_members (
+    signing_group_member_id INTEGER PRIMARY KEY,
+    signing_group_id INTEGER NOT NULL,
+    member_key TEXT NOT NULL,
+    FOREIGN KEY(signing_group_id) REFERENCES signing_groups(signing_group_id),
+    UNIQUE(signing_group_id, member_key)
+);
\ No newline at end of file
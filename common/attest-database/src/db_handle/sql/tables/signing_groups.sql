@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS signing_groups (
+    signing_group_id INTEGER PRIMARY KEY,
+    group_key TEXT UNIQUE NOT NULL
+);
\ No newline at end of file
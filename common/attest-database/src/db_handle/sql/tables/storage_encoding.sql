@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS storage_encoding (
+    singleton INTEGER PRIMARY KEY CHECK(singleton = 0),
+    encoding TEXT NOT NULL CHECK(encoding IN ('Json', 'Binary'))
+);
\ No newline at end of file
@@ -0,0 +1,77 @@
+/* Counts every stored envelope towards the statistics of its chain, see
+ db_handle::get::chain_stats. Refused envelopes are never inserted, so they
+ aren't counted, and envelopes restored from an archive were counted when
+ first stored.
+ */
+CREATE TRIGGER IF NOT EXISTS message_chain_stats
+AFTER
+INSERT
+    ON messages
+    WHEN NOT EXISTS (
+        SELECT
+            1
+        FROM
+            archived_chains A
+        WHERE
+            A.restoring
+            AND A.genesis_id = NEW.genesis_id
+            AND NEW.height BETWEEN A.first_height
+            AND A.last_height
+    ) BEGIN
+INSERT INTO
+    chain_stats (
+        genesis,
+        user_id,
+        messages,
+        first_seen,
+        last_seen,
+        first_sent,
+        last_sent,
+        total_delay,
+        max_delay,
+        delay_negative,
+        delay_lt_100ms,
+        delay_lt_1s,
+        delay_lt_10s,
+        delay_lt_1m,
+        delay_ge_1m
+    )
+VALUES
+    (
+        NEW.genesis,
+        NEW.user_id,
+        1,
+        NEW.received_time,
+        NEW.received_time,
+        NEW.sent_time,
+        NEW.sent_time,
+        NEW.received_time - NEW.sent_time,
+        NEW.received_time - NEW.sent_time,
+        NEW.received_time - NEW.sent_time < 0,
+        NEW.received_time - NEW.sent_time BETWEEN 0
+        AND 99,
+        NEW.received_time - NEW.sent_time BETWEEN 100
+        AND 999,
+        NEW.received_time - NEW.sent_time BETWEEN 1000
+        AND 9999,
+        NEW.received_time - NEW.sent_time BETWEEN 10000
+        AND 59999,
+        NEW.received_time - NEW.sent_time >= 60000
+    ) ON CONFLICT(genesis) DO
+UPDATE
+SET
+    messages = messages + 1,
+    first_seen = MIN(first_seen, excluded.first_seen),
+    last_seen = MAX(last_seen, excluded.last_seen),
+    first_sent = MIN(first_sent, excluded.first_sent),
+    last_sent = MAX(last_sent, excluded.last_sent),
+    total_delay = total_delay + excluded.total_delay,
+    max_delay = MAX(max_delay, excluded.max_delay),
+    delay_negative = delay_negative + excluded.delay_negative,
+    delay_lt_100ms = delay_lt_100ms + excluded.delay_lt_100ms,
+    delay_lt_1s = delay_lt_1s + excluded.delay_lt_1s,
+    delay_lt_10s = delay_lt_10s + excluded.delay_lt_10s,
+    delay_lt_1m = delay_lt_1m + excluded.delay_lt_1m,
+    delay_ge_1m = delay_ge_1m + excluded.delay_ge_1m;
+
+END;
\ No newline at end of file
@@ -0,0 +1,5 @@
+DELETE FROM
+    anchors
+WHERE
+    txid = :txid
+    AND tx_proof IS NULL
\ No newline at end of file
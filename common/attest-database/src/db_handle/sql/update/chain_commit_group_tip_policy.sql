@@ -0,0 +1,6 @@
+UPDATE
+    chain_commit_groups
+SET
+    tip_policy = :tip_policy
+WHERE
+    group_id = :group_id
\ No newline at end of file
@@ -0,0 +1,19 @@
+UPDATE
+    message_nonces
+SET
+    state = 'Committed'
+WHERE
+    nonce_id = (
+        SELECT
+            N.nonce_id
+        FROM
+            message_nonces N
+            JOIN private_keys K ON N.key_id = K.key_id
+        WHERE
+            K.public_key = :key
+            AND N.state = 'Generated'
+        ORDER BY
+            N.nonce_id
+        LIMIT
+            1
+    ) RETURNING public_key
\ No newline at end of file
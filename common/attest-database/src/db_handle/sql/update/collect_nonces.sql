@@ -0,0 +1,22 @@
+UPDATE
+    message_nonces
+SET
+    state = 'Used',
+    private_key = NULL
+WHERE
+    private_key IS NOT NULL
+    AND (
+        public_key IN (
+            SELECT
+                M.nonce
+            FROM
+                messages M
+        )
+        OR public_key IN (
+            SELECT
+                S.nonce_share
+            FROM
+                group_nonce_shares S
+                JOIN messages M ON M.nonce = S.aggregate_nonce
+        )
+    )
\ No newline at end of file
@@ -0,0 +1,8 @@
+UPDATE
+    anchors
+SET
+    tx_proof = :tx_proof,
+    block_hash = :block_hash,
+    height = :height
+WHERE
+    txid = :txid
\ No newline at end of file
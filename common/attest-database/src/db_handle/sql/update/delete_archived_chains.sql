@@ -0,0 +1,12 @@
+DELETE FROM
+    archived_chains
+WHERE
+    genesis_id = (
+        SELECT
+            M.message_id
+        FROM
+            messages M
+        WHERE
+            M.hash = :genesis
+            AND M.height = 0
+    )
\ No newline at end of file
@@ -0,0 +1,7 @@
+UPDATE
+    messages
+SET
+    body = :body,
+    pruned = 1
+WHERE
+    message_id = :message_id
\ No newline at end of file
@@ -0,0 +1,13 @@
+UPDATE
+    chain_keys
+SET
+    until_height = :height
+WHERE
+    genesis = :genesis
+    AND key = :key
+    AND role = :role
+    AND from_height < :height
+    AND (
+        until_height IS NULL
+        OR until_height > :height
+    )
\ No newline at end of file
@@ -0,0 +1,6 @@
+UPDATE
+    chain_commit_groups
+SET
+    finished = 1
+WHERE
+    name = :name
\ No newline at end of file
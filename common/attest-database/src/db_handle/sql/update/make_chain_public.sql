@@ -0,0 +1,4 @@
+DELETE FROM
+    private_chains
+WHERE
+    genesis = :genesis
\ No newline at end of file
@@ -0,0 +1,14 @@
+UPDATE
+    archived_chains
+SET
+    restoring = 1
+WHERE
+    genesis_id = (
+        SELECT
+            M.message_id
+        FROM
+            messages M
+        WHERE
+            M.hash = :genesis
+            AND M.height = 0
+    )
\ No newline at end of file
@@ -0,0 +1,6 @@
+UPDATE
+    messages
+SET
+    prev_archived = :prev_archived
+WHERE
+    hash = :hash
\ No newline at end of file
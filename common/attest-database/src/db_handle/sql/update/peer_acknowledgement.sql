@@ -0,0 +1,23 @@
+INSERT INTO
+    peer_acknowledgements (service_id, genesis, height, hash, updated_time)
+VALUES
+    (
+        (
+            SELECT
+                S.service_id
+            FROM
+                hidden_services S
+            WHERE
+                S.service_url = :service_url
+                AND S.port = :port
+            LIMIT
+                1
+        ), :genesis, :height, :hash, :updated_time
+    ) ON CONFLICT(service_id, genesis) DO
+UPDATE
+SET
+    height = excluded.height,
+    hash = excluded.hash,
+    updated_time = excluded.updated_time
+WHERE
+    excluded.height > peer_acknowledgements.height
\ No newline at end of file
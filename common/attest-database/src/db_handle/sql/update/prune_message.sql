@@ -0,0 +1,5 @@
+DELETE FROM
+    messages
+WHERE
+    hash = :hash
+    AND height > 0
\ No newline at end of file
@@ -0,0 +1,8 @@
+UPDATE
+    message_nonces
+SET
+    state = 'Committed'
+WHERE
+    public_key = :nonce
+    AND state = 'Used'
+    AND private_key IS NOT NULL
\ No newline at end of file
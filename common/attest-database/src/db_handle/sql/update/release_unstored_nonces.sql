@@ -0,0 +1,19 @@
+UPDATE
+    message_nonces
+SET
+    state = 'Committed'
+WHERE
+    state = 'Used'
+    AND private_key IS NOT NULL
+    AND public_key NOT IN (
+        SELECT
+            M.nonce
+        FROM
+            messages M
+    )
+    AND public_key NOT IN (
+        SELECT
+            S.nonce_share
+        FROM
+            group_nonce_shares S
+    )
\ No newline at end of file
@@ -0,0 +1,13 @@
+DELETE FROM
+    chain_commit_group_members
+WHERE
+    group_id = :group_id
+    AND member_id IN (
+        SELECT
+            M.message_id
+        FROM
+            messages M
+        WHERE
+            M.hash = :genesis_hash
+            AND M.height = 0
+    )
\ No newline at end of file
@@ -0,0 +1,6 @@
+UPDATE
+    chain_commit_groups
+SET
+    name = :name
+WHERE
+    group_id = :group_id
\ No newline at end of file
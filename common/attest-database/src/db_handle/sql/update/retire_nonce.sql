@@ -0,0 +1,18 @@
+UPDATE
+    message_nonces
+SET
+    state = 'Used',
+    private_key = NULL
+WHERE
+    private_key IS NOT NULL
+    AND (
+        public_key = :nonce
+        OR public_key IN (
+            SELECT
+                nonce_share
+            FROM
+                group_nonce_shares
+            WHERE
+                aggregate_nonce = :nonce
+        )
+    )
\ No newline at end of file
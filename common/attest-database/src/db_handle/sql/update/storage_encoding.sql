@@ -0,0 +1,7 @@
+INSERT INTO
+    storage_encoding (singleton, encoding)
+VALUES
+    (0, :encoding) ON CONFLICT(singleton) DO
+UPDATE
+SET
+    encoding = excluded.encoding
\ No newline at end of file
@@ -0,0 +1,7 @@
+UPDATE
+    message_nonces
+SET
+    state = 'Used'
+WHERE
+    public_key = :nonce
+    AND state != 'Used'
\ No newline at end of file
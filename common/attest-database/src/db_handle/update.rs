@@ -4,9 +4,24 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use super::get::chain_commit_groups::GroupTipPolicy;
+use super::get::chain_visibility::ChainVisibility;
+use super::get::storage_encoding::{encode_body, StorageEncoding};
 use super::handle_type;
+use super::ChainCommitGroupID;
 use super::MsgDBHandle;
+use crate::db_handle::sql::get::messages::SQL_GET_MESSAGES_EXPIRED;
+use crate::db_handle::sql::insert::{SQL_INSERT_PRIVATE_CHAIN, SQL_INSERT_PRIVATE_CHAIN_PEER};
 use crate::db_handle::sql::update::*;
+use attest_messages::anchor::AnchorError;
+use attest_messages::nonce::PrecomittedPublicNonce;
+use attest_messages::CanonicalEnvelopeHash;
+use attest_messages::Envelope;
+use fallible_iterator::FallibleIterator;
+use sapio_bitcoin::consensus::encode::serialize_hex;
+use sapio_bitcoin::hashes::hex::ToHex;
+use sapio_bitcoin::util::merkleblock::MerkleBlock;
+use sapio_bitcoin::Txid;
 impl<T> MsgDBHandle<T>
 where
     T: handle_type::Get + handle_type::Insert,
@@ -44,4 +59,250 @@ where
         ))?;
         Ok(())
     }
+
+    /// records that a peer has persisted a chain up to (at least) the given
+    /// envelope.
+    ///
+    /// Only ever moves the high-water mark forward, so it is safe to call with
+    /// stale information. Fails if the peer is not a known hidden service.
+    pub fn upsert_peer_acknowledgement(
+        &self,
+        service_url: &str,
+        port: u16,
+        genesis: CanonicalEnvelopeHash,
+        height: i64,
+        hash: CanonicalEnvelopeHash,
+    ) -> Result<(), rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_UPDATE_PEER_ACKNOWLEDGEMENT)?;
+        stmt.execute(rusqlite::named_params!(
+            ":service_url": service_url,
+            ":port": port,
+            ":genesis": genesis,
+            ":height": height,
+            ":hash": hash,
+            ":updated_time": attest_util::now()
+        ))?;
+        Ok(())
+    }
+
+    /// replaces the visibility policy of a chain.
+    ///
+    /// Every allowed peer must already be a known hidden service, otherwise
+    /// the policy is left unchanged and an error is returned.
+    pub fn set_chain_visibility(
+        &mut self,
+        genesis: CanonicalEnvelopeHash,
+        visibility: &ChainVisibility,
+    ) -> Result<(), rusqlite::Error> {
+        let tx = self.0.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(SQL_UPDATE_MAKE_CHAIN_PUBLIC)?;
+            stmt.execute(rusqlite::named_params!(":genesis": genesis))?;
+            if let ChainVisibility::Private { allowed } = visibility {
+                let mut stmt = tx.prepare_cached(SQL_INSERT_PRIVATE_CHAIN)?;
+                stmt.execute(rusqlite::named_params!(":genesis": genesis))?;
+                let mut stmt = tx.prepare_cached(SQL_INSERT_PRIVATE_CHAIN_PEER)?;
+                for (service_url, port) in allowed {
+                    stmt.execute(rusqlite::named_params!(
+                        ":genesis": genesis,
+                        ":service_url": service_url,
+                        ":port": port
+                    ))?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// records that the anchor broadcast in `txid` confirmed at `height` in the
+    /// block of `tx_proof`.
+    ///
+    /// Fails if `tx_proof` doesn't show `txid` in the block.
+    pub fn confirm_anchor(
+        &self,
+        txid: Txid,
+        tx_proof: &MerkleBlock,
+        height: u32,
+    ) -> Result<Result<(), AnchorError>, rusqlite::Error> {
+        let mut matches = vec![];
+        let mut indexes = vec![];
+        if tx_proof
+            .extract_matches(&mut matches, &mut indexes)
+            .is_err()
+        {
+            return Ok(Err(AnchorError::InvalidTxProof));
+        }
+        if !matches.contains(&txid) {
+            return Ok(Err(AnchorError::TxNotInBlock));
+        }
+        let mut stmt = self.0.prepare_cached(SQL_UPDATE_CONFIRM_ANCHOR)?;
+        stmt.execute(rusqlite::named_params!(
+            ":txid": txid.to_hex(),
+            ":tx_proof": serialize_hex(tx_proof),
+            ":block_hash": tx_proof.header.block_hash().to_hex(),
+            ":height": height,
+        ))?;
+        Ok(Ok(()))
+    }
+
+    /// forgets an unconfirmed anchor, e.g. if it was dropped from the mempool,
+    /// so that its envelopes get anchored again. Confirmed anchors are kept.
+    pub fn abandon_anchor(&self, txid: Txid) -> Result<(), rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_UPDATE_ABANDON_ANCHOR)?;
+        stmt.execute(rusqlite::named_params!(":txid": txid.to_hex()))?;
+        Ok(())
+    }
+
+    /// marks a chain commit group as finished, making its members eligible
+    /// for archiving, see [`crate::db_handle::archive`].
+    pub fn finish_chain_commit_group(&self, name: &str) -> Result<(), rusqlite::Error> {
+        let mut stmt = self
+            .0
+            .prepare_cached(SQL_UPDATE_FINISH_CHAIN_COMMIT_GROUP)?;
+        stmt.execute(rusqlite::named_params!(":name": name))?;
+        Ok(())
+    }
+
+    /// removes a chain from a group's members, so subscribers stop committing
+    /// to its tips. Returns false if it wasn't a member.
+    pub fn remove_member_from_chain_commit_group(
+        &self,
+        group_id: ChainCommitGroupID,
+        genesis_hash: CanonicalEnvelopeHash,
+    ) -> Result<bool, rusqlite::Error> {
+        let mut stmt = self
+            .0
+            .prepare_cached(SQL_UPDATE_REMOVE_CHAIN_COMMIT_GROUP_MEMBER)?;
+        let n = stmt.execute(rusqlite::named_params!(
+            ":genesis_hash": genesis_hash,
+            ":group_id": group_id
+        ))?;
+        Ok(n > 0)
+    }
+
+    /// stops a chain from subscribing to a group. Returns false if it wasn't
+    /// subscribed.
+    pub fn remove_subscriber_from_chain_commit_group(
+        &self,
+        group_id: ChainCommitGroupID,
+        genesis_hash: CanonicalEnvelopeHash,
+    ) -> Result<bool, rusqlite::Error> {
+        let mut stmt = self
+            .0
+            .prepare_cached(SQL_UPDATE_REMOVE_CHAIN_COMMIT_GROUP_SUBSCRIBER)?;
+        let n = stmt.execute(rusqlite::named_params!(
+            ":genesis_hash": genesis_hash,
+            ":group_id": group_id
+        ))?;
+        Ok(n > 0)
+    }
+
+    /// Fails if the name is taken. Returns false if there is no such group.
+    pub fn rename_chain_commit_group(
+        &self,
+        group_id: ChainCommitGroupID,
+        name: &str,
+    ) -> Result<bool, rusqlite::Error> {
+        let mut stmt = self
+            .0
+            .prepare_cached(SQL_UPDATE_RENAME_CHAIN_COMMIT_GROUP)?;
+        let n = stmt.execute(rusqlite::named_params!(":name": name, ":group_id": group_id))?;
+        Ok(n > 0)
+    }
+
+    /// Returns false if there is no such group.
+    pub fn set_chain_commit_group_tip_policy(
+        &self,
+        group_id: ChainCommitGroupID,
+        tip_policy: GroupTipPolicy,
+    ) -> Result<bool, rusqlite::Error> {
+        let mut stmt = self
+            .0
+            .prepare_cached(SQL_UPDATE_CHAIN_COMMIT_GROUP_TIP_POLICY)?;
+        let n = stmt.execute(rusqlite::named_params!(
+            ":tip_policy": tip_policy,
+            ":group_id": group_id
+        ))?;
+        Ok(n > 0)
+    }
+
+    /// set the encoding used for envelopes inserted from now on
+    pub fn set_storage_encoding(&self, encoding: StorageEncoding) -> Result<(), rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_UPDATE_STORAGE_ENCODING)?;
+        stmt.execute(rusqlite::named_params!(":encoding": encoding))?;
+        Ok(())
+    }
+
+    /// erases the secret of every nonce that has signed a stored envelope,
+    /// returning how many were erased.
+    ///
+    /// Storing an envelope already erases its nonce, this catches the rest,
+    /// e.g. envelopes that arrived from a peer or a snapshot rather than being
+    /// signed here.
+    pub fn collect_used_nonces(&self) -> Result<usize, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_UPDATE_COLLECT_NONCES)?;
+        stmt.execute([])
+    }
+
+    /// frees `nonce` to sign again, after the envelope it signed failed to
+    /// be stored, returning whether it was freed. The envelope must be
+    /// discarded rather than shared.
+    ///
+    /// Does nothing once the nonce's secret has been erased.
+    pub fn release_unstored_nonce(
+        &self,
+        nonce: PrecomittedPublicNonce,
+    ) -> Result<bool, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_UPDATE_RELEASE_NONCE)?;
+        let n = stmt.execute(rusqlite::named_params!(":nonce": nonce))?;
+        Ok(n > 0)
+    }
+
+    /// frees every nonce marked used by signing whose envelope was never
+    /// stored, e.g. as the process died or the store failed in between,
+    /// returning how many were freed. Otherwise the chain could never be
+    /// extended, as its next nonce is spent.
+    ///
+    /// Nonces shared with a signing group are left alone, their partial
+    /// signatures may have been handed out. Only safe while nothing is
+    /// signing, and only if none of the envelopes left the process before
+    /// being lost, so it is never run on its own: it is up to the operator.
+    pub fn release_unstored_nonces(&self) -> Result<usize, rusqlite::Error> {
+        let mut stmt = self.0.prepare_cached(SQL_UPDATE_RELEASE_UNSTORED_NONCES)?;
+        stmt.execute([])
+    }
+
+    /// drops the message of every ephemeral envelope expired by `now_ms`,
+    /// returning how many were pruned, see [`attest_messages::ephemeral`].
+    ///
+    /// Bodies are re-encoded the way they were stored, hashes are unaffected.
+    pub fn prune_expired_messages(&mut self, now_ms: i64) -> Result<usize, rusqlite::Error> {
+        let tx = self.0.transaction()?;
+        let pruned = {
+            let mut stmt = tx.prepare_cached(SQL_GET_MESSAGES_EXPIRED)?;
+            let expired: Vec<(i64, Envelope, bool)> = stmt
+                .query(rusqlite::named_params!(":now": now_ms))?
+                .map(|r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+                .collect()?;
+            let mut update = tx.prepare_cached(SQL_UPDATE_DROP_EXPIRED_MESSAGE)?;
+            let n = expired.len();
+            for (message_id, mut envelope, binary) in expired {
+                envelope.prune();
+                let encoding = if binary {
+                    StorageEncoding::Binary
+                } else {
+                    StorageEncoding::Json
+                };
+                let body = encode_body(&envelope, encoding)?;
+                update.execute(rusqlite::named_params!(
+                    ":body": body,
+                    ":message_id": message_id
+                ))?;
+            }
+            n
+        };
+        tx.commit()?;
+        Ok(pruned)
+    }
 }
@@ -8,8 +8,8 @@ use attest_messages::{
     nonce::PrecomittedNonce, AttestEnvelopable, GenericEnvelope, Header, Unsigned,
 };
 use attest_util::{ensure_dir, CrossPlatformPermissions};
-use connection::MsgDB;
-use rusqlite::Connection;
+use connection::{MsgDB, PoolConfig};
+use rusqlite::{Connection, OpenFlags};
 use sapio_bitcoin::{
     secp256k1::{rand, Secp256k1, Signing},
     KeyPair,
@@ -22,11 +22,19 @@ pub mod connection;
 pub mod db_handle;
 pub mod sql_error;
 pub mod sql_serializers;
+pub mod storage;
 
 #[cfg(test)]
 mod tests;
 
 pub async fn setup_db_at(dir: PathBuf, name: &str) -> Result<MsgDB, Box<dyn Error>> {
+    setup_db_at_with(dir, name, PoolConfig::default()).await
+}
+pub async fn setup_db_at_with(
+    dir: PathBuf,
+    name: &str,
+    config: PoolConfig,
+) -> Result<MsgDB, Box<dyn Error>> {
     tracing::debug!(
         "Request to Open Message DB at {} name {}",
         dir.display(),
@@ -38,17 +46,32 @@ pub async fn setup_db_at(dir: PathBuf, name: &str) -> Result<MsgDB, Box<dyn Erro
     db_file.push(name);
     db_file.set_extension("sqlite3");
     tracing::debug!("Opening Message DB at: {}", db_file.display());
-    let conn = || Connection::open(&db_file).unwrap();
-    let mdb = MsgDB::new(
-        (0..10)
-            .map(|_| Arc::new(tokio::sync::Mutex::new(conn())))
-            .collect(),
-    );
-    mdb.map_all_sequential(|mut h| Box::pin(async move { h.setup_tables() }))
-        .await;
+    let writer = Connection::open(&db_file)?;
+    // read only connections can't switch the DB to WAL mode themselves
+    writer.execute_batch("PRAGMA journal_mode = WAL;")?;
+    let read_only = OpenFlags::SQLITE_OPEN_READ_ONLY
+        | OpenFlags::SQLITE_OPEN_URI
+        | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let readers = (0..config.readers.max(1))
+        .map(|_| {
+            Ok(Arc::new(Mutex::new(Connection::open_with_flags(
+                &db_file, read_only,
+            )?)))
+        })
+        .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+    let mdb = MsgDB::new(Arc::new(Mutex::new(writer)), readers, config);
+    mdb.setup().await;
+    mdb.start_checkpointing();
     Ok(mdb)
 }
 pub async fn setup_db(application: &str, prefix: Option<PathBuf>) -> Result<MsgDB, Box<dyn Error>> {
+    setup_db_with(application, prefix, PoolConfig::default()).await
+}
+pub async fn setup_db_with(
+    application: &str,
+    prefix: Option<PathBuf>,
+    config: PoolConfig,
+) -> Result<MsgDB, Box<dyn Error>> {
     let dirs = directories::ProjectDirs::from("org", "judica", application).unwrap();
     let data_dir: PathBuf = dirs.data_dir().into();
     let data_dir = if let Some(prefix) = prefix {
@@ -57,20 +80,19 @@ pub async fn setup_db(application: &str, prefix: Option<PathBuf>) -> Result<MsgD
     } else {
         data_dir
     };
-    setup_db_at(data_dir, "attestations").await
+    setup_db_at_with(data_dir, "attestations", config).await
 }
 
 pub async fn setup_test_db() -> MsgDB {
     trace!("Setting up Test DB");
+    // an in memory DB only exists for its one connection, so it is shared
     let first = Arc::new(Mutex::new(Connection::open(":memory:").unwrap()));
-    let conn = MsgDB::new(vec![
-        first.clone(),
+    let conn = MsgDB::new(
         first.clone(),
-        first.clone(),
-        first.clone(),
-    ]);
-    conn.map_all_sequential(|mut h| Box::pin(async move { h.setup_tables() }))
-        .await;
+        vec![first.clone(), first.clone(), first],
+        PoolConfig::default(),
+    );
+    conn.setup().await;
     conn
 }
 pub fn generate_new_user<C: Signing, M: AttestEnvelopable, Im: Into<M>>(
@@ -100,6 +122,7 @@ pub fn generate_new_user_keypair<C: Signing, M: AttestEnvelopable, Im: Into<M>>(
             sent_time_ms,
             Unsigned::new(Default::default()),
             Default::default(),
+            Default::default(),
         ),
         init,
     );
@@ -0,0 +1,472 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks every [`MsgStore`] is expected to pass, run against each backend.
+
+use super::memory::MemoryStore;
+use super::MsgStore;
+use crate::db_handle::ChainCommitGroupID;
+use crate::generate_new_user;
+use crate::setup_test_db;
+use crate::sql_error::SqliteFail;
+use attest_messages::ancestry::AncestryPeaks;
+use attest_messages::authority::KeyGrant;
+use attest_messages::nonce::PrecomittedNonce;
+use attest_messages::{
+    Ancestors, Authenticated, CanonicalEnvelopeHash, GenericEnvelope, Header, Unsigned, WrappedJson,
+};
+use ruma_serde::CanonicalJsonValue;
+use sapio_bitcoin::secp256k1::{All, Secp256k1};
+use sapio_bitcoin::KeyPair;
+use std::collections::BTreeSet;
+use test_log::test;
+
+type Env = Authenticated<GenericEnvelope<WrappedJson>>;
+
+fn new_user<S: MsgStore>(store: &mut S, secp: &Secp256k1<All>, name: &str) -> (KeyPair, Env) {
+    let (kp, nonce, genesis) =
+        generate_new_user::<_, WrappedJson, _>(secp, CanonicalJsonValue::Null).unwrap();
+    store.save_keypair(kp).unwrap();
+    let genesis = genesis.self_authenticate(secp).unwrap();
+    store
+        .insert_user_by_genesis_envelope(name.into(), genesis.clone())
+        .unwrap()
+        .unwrap();
+    store
+        .save_nonce_for_user_by_key(nonce, secp, kp.x_only_public_key().0)
+        .unwrap();
+    (kp, genesis)
+}
+
+/// signs the envelope after `tip` with `kp` the way a node would, without
+/// storing it
+fn child_of<S: MsgStore>(store: &mut S, secp: &Secp256k1<All>, kp: &KeyPair, tip: &Env) -> Env {
+    let ancestry = AncestryPeaks::for_child_of(tip).unwrap_or_default();
+    signed_child(store, secp, kp, tip, None, ancestry)
+}
+
+/// like [`child_of`], carrying `grant` and committing to `ancestry`
+fn signed_child<S: MsgStore>(
+    store: &mut S,
+    secp: &Secp256k1<All>,
+    kp: &KeyPair,
+    tip: &Env,
+    grant: Option<KeyGrant>,
+    ancestry: AncestryPeaks,
+) -> Env {
+    let key = kp.x_only_public_key().0;
+    let secret = store
+        .get_secret_for_public_nonce(tip.header().next_nonce())
+        .unwrap()
+        .unwrap();
+    let next_nonce = store
+        .save_nonce_for_user_by_key(PrecomittedNonce::new(secp), secp, key)
+        .unwrap();
+    let header = Header::new(
+        key,
+        next_nonce,
+        Some(Ancestors::new(
+            tip.canonicalized_hash_ref(),
+            tip.get_genesis_hash(),
+        )),
+        vec![],
+        tip.header().height() + 1,
+        attest_util::now(),
+        Unsigned::new(Default::default()),
+        Default::default(),
+        ancestry,
+    );
+    let header = match grant {
+        Some(grant) => header.with_grant(grant),
+        None => header,
+    };
+    let mut e = GenericEnvelope::<WrappedJson>::new(header, CanonicalJsonValue::Null);
+    e.sign_with(kp, secp, secret).unwrap();
+    e.self_authenticate(secp).unwrap()
+}
+
+fn hash_of(e: Option<Env>) -> Option<CanonicalEnvelopeHash> {
+    e.map(|e| e.canonicalized_hash_ref())
+}
+
+fn check_users_and_tips<S: MsgStore>(store: &mut S, secp: &Secp256k1<All>) {
+    let (alice, alice_genesis) = new_user(store, secp, "alice");
+    let (bob, bob_genesis) = new_user(store, secp, "bob");
+    let (alice_key, bob_key) = (alice.x_only_public_key().0, bob.x_only_public_key().0);
+    let users = store.get_all_users().unwrap();
+    assert!(users.contains(&(alice_key, "alice".into())));
+    assert!(users.contains(&(bob_key, "bob".into())));
+    assert_eq!(
+        hash_of(store.get_tip_for_user_by_key(alice_key).unwrap()),
+        Some(alice_genesis.canonicalized_hash_ref())
+    );
+
+    let mut chain = vec![alice_genesis];
+    for _ in 0..3 {
+        let e = child_of(store, secp, &alice, chain.last().unwrap());
+        store
+            .try_insert_authenticated_envelope(e.clone(), true)
+            .unwrap()
+            .unwrap();
+        chain.push(e);
+    }
+    let tip = store
+        .get_tip_for_user_by_key::<WrappedJson>(alice_key)
+        .unwrap()
+        .unwrap();
+    assert_eq!(tip.header().height(), 3);
+    assert_eq!(
+        tip.canonicalized_hash_ref(),
+        chain[3].canonicalized_hash_ref()
+    );
+    assert_eq!(
+        hash_of(store.get_message_at_height_for_user(alice_key, 1).unwrap()),
+        Some(chain[1].canonicalized_hash_ref())
+    );
+    assert!(store
+        .get_message_at_height_for_user::<WrappedJson>(bob_key, 1)
+        .unwrap()
+        .is_none());
+    let stored = store
+        .message_by_hash::<WrappedJson>(chain[2].canonicalized_hash_ref())
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.inner_ref(), chain[2].inner_ref());
+
+    let tips: BTreeSet<_> = store
+        .get_tips_for_all_users::<WrappedJson>()
+        .unwrap()
+        .iter()
+        .map(|e| e.canonicalized_hash_ref())
+        .collect();
+    assert!(tips.contains(&chain[3].canonicalized_hash_ref()));
+    assert!(tips.contains(&bob_genesis.canonicalized_hash_ref()));
+    assert!(!tips.contains(&chain[2].canonicalized_hash_ref()));
+}
+
+fn check_refused_envelopes<S: MsgStore>(store: &mut S, secp: &Secp256k1<All>) {
+    let (carol, genesis) = new_user(store, secp, "carol");
+    let (mallory, _) = new_user(store, secp, "mallory");
+    let e1 = child_of(store, secp, &carol, &genesis);
+    store
+        .try_insert_authenticated_envelope(e1.clone(), true)
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        store.try_insert_authenticated_envelope(e1, false).unwrap(),
+        Err((SqliteFail::SqliteConstraintUnique, _))
+    ));
+    // a second child of the same parent reuses its nonce
+    let e1b = child_of(store, secp, &carol, &genesis);
+    assert!(matches!(
+        store.try_insert_authenticated_envelope(e1b, true).unwrap(),
+        Err((SqliteFail::SqliteConstraintUnique, _))
+    ));
+    // only the chain's key may extend it
+    let forged = child_of(store, secp, &mallory, &genesis);
+    assert!(matches!(
+        store
+            .try_insert_authenticated_envelope(forged, false)
+            .unwrap(),
+        Err((SqliteFail::SqliteConstraintCheck, _))
+    ));
+    // envelopes from unknown users are refused
+    let (stranger, _, stranger_genesis) =
+        generate_new_user::<_, WrappedJson, _>(secp, CanonicalJsonValue::Null).unwrap();
+    let stranger_genesis = stranger_genesis.self_authenticate(secp).unwrap();
+    assert!(matches!(
+        store
+            .try_insert_authenticated_envelope(stranger_genesis, false)
+            .unwrap(),
+        Err((SqliteFail::SqliteConstraintNotNull, _))
+    ));
+    assert!(store
+        .get_tip_for_user_by_key::<WrappedJson>(stranger.x_only_public_key().0)
+        .unwrap()
+        .is_none());
+}
+
+fn check_out_of_order<S: MsgStore>(store: &mut S, secp: &Secp256k1<All>) {
+    let (dave, genesis) = new_user(store, secp, "dave");
+    let key = dave.x_only_public_key().0;
+    let e1 = child_of(store, secp, &dave, &genesis);
+    let e2 = child_of(store, secp, &dave, &e1);
+    store
+        .try_insert_authenticated_envelope(e2.clone(), false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        hash_of(store.get_tip_for_user_by_key(key).unwrap()),
+        Some(genesis.canonicalized_hash_ref())
+    );
+    store
+        .try_insert_authenticated_envelope(e1.clone(), false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        hash_of(store.get_tip_for_user_by_key(key).unwrap()),
+        Some(e1.canonicalized_hash_ref())
+    );
+    assert!(store.attach_tips().unwrap() > 0);
+    assert_eq!(
+        hash_of(store.get_tip_for_user_by_key(key).unwrap()),
+        Some(e2.canonicalized_hash_ref())
+    );
+}
+
+fn check_ancestry<S: MsgStore>(store: &mut S, secp: &Secp256k1<All>) {
+    let (erin, genesis) = new_user(store, secp, "erin");
+    let e1 = child_of(store, secp, &erin, &genesis);
+    store
+        .try_insert_authenticated_envelope(e1.clone(), false)
+        .unwrap()
+        .unwrap();
+    // well formed for its height, but committing to some other chain
+    let other = [
+        genesis.canonicalized_hash_ref(),
+        genesis.canonicalized_hash_ref(),
+    ];
+    let bad = signed_child(
+        store,
+        secp,
+        &erin,
+        &e1,
+        None,
+        AncestryPeaks::from_chain(&other),
+    );
+    assert!(bad.header().ancestry().well_formed_for(2));
+    assert_ne!(
+        bad.header().ancestry(),
+        &AncestryPeaks::for_child_of(&e1).unwrap_or_default()
+    );
+    assert!(matches!(
+        store.try_insert_authenticated_envelope(bad, false).unwrap(),
+        Err((SqliteFail::SqliteConstraintCheck, _))
+    ));
+    let e2 = child_of(store, secp, &erin, &e1);
+    store
+        .try_insert_authenticated_envelope(e2.clone(), false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        hash_of(
+            store
+                .get_tip_for_user_by_key(erin.x_only_public_key().0)
+                .unwrap()
+        ),
+        Some(e2.canonicalized_hash_ref())
+    );
+}
+
+fn check_chain_authority<S: MsgStore>(store: &mut S, secp: &Secp256k1<All>) {
+    let (root, genesis) = new_user(store, secp, "root");
+    let [rotated, sub] = [(); 2].map(|_| {
+        let k = KeyPair::new(secp, &mut sapio_bitcoin::secp256k1::rand::thread_rng());
+        store.save_keypair(k).unwrap();
+        k
+    });
+    let pk = |k: &KeyPair| k.x_only_public_key().0;
+    let mut tip = genesis;
+    let post = |store: &mut S, tip: &mut Env, k: &KeyPair, grant: Option<KeyGrant>| {
+        let ancestry = AncestryPeaks::for_child_of(tip).unwrap_or_default();
+        let e = signed_child(store, secp, k, tip, grant, ancestry);
+        let res = store
+            .try_insert_authenticated_envelope(e.clone(), false)
+            .unwrap();
+        if res.is_ok() {
+            *tip = e;
+        }
+        res
+    };
+
+    // delegate to a key that isn't a user, which may post but not grant
+    post(
+        store,
+        &mut tip,
+        &root,
+        Some(KeyGrant::Delegate {
+            to: pk(&sub),
+            until_height: None,
+        }),
+    )
+    .unwrap();
+    post(store, &mut tip, &sub, None).unwrap();
+    assert!(matches!(
+        post(
+            store,
+            &mut tip,
+            &sub,
+            Some(KeyGrant::Rotate { to: pk(&rotated) })
+        ),
+        Err((SqliteFail::SqliteConstraintCheck, _))
+    ));
+    // its envelopes belong to the chain's user
+    assert_eq!(
+        hash_of(store.get_tip_for_user_by_key(pk(&root)).unwrap()),
+        Some(tip.canonicalized_hash_ref())
+    );
+
+    // and revoke it again
+    post(
+        store,
+        &mut tip,
+        &root,
+        Some(KeyGrant::Revoke { key: pk(&sub) }),
+    )
+    .unwrap();
+    assert!(matches!(
+        post(store, &mut tip, &sub, None),
+        Err((SqliteFail::SqliteConstraintCheck, _))
+    ));
+
+    // after a rotation only the new key may post
+    post(
+        store,
+        &mut tip,
+        &root,
+        Some(KeyGrant::Rotate { to: pk(&rotated) }),
+    )
+    .unwrap();
+    assert!(matches!(
+        post(store, &mut tip, &root, None),
+        Err((SqliteFail::SqliteConstraintCheck, _))
+    ));
+    post(store, &mut tip, &rotated, None).unwrap();
+    assert_eq!(tip.header().height(), 5);
+    assert_eq!(
+        hash_of(store.get_tip_for_user_by_key(pk(&rotated)).unwrap()),
+        Some(tip.canonicalized_hash_ref())
+    );
+}
+
+fn check_keys_and_nonces<S: MsgStore>(store: &mut S, secp: &Secp256k1<All>) {
+    let kp = KeyPair::new(secp, &mut sapio_bitcoin::secp256k1::rand::thread_rng());
+    let key = kp.x_only_public_key().0;
+    store.save_keypair(kp).unwrap();
+    assert_eq!(
+        store.get_keymap().unwrap().get(&key),
+        Some(&kp.secret_key())
+    );
+    let nonce = PrecomittedNonce::new(secp);
+    let public = store.save_nonce_for_user_by_key(nonce, secp, key).unwrap();
+    assert_eq!(public, nonce.get_public(secp));
+    assert_eq!(
+        store
+            .get_secret_for_public_nonce(public)
+            .unwrap()
+            .map(|n| n.get_public(secp)),
+        Some(public)
+    );
+    let unknown = PrecomittedNonce::new(secp).get_public(secp);
+    assert!(store
+        .get_secret_for_public_nonce(unknown)
+        .unwrap()
+        .is_none());
+}
+
+fn check_groups<S: MsgStore>(store: &mut S, secp: &Secp256k1<All>) {
+    let (_, a) = new_user(store, secp, "member-a");
+    let (_, b) = new_user(store, secp, "member-b");
+    let (name, id) = store.new_chain_commit_group(Some("game".into())).unwrap();
+    assert_eq!(name, "game");
+    let (random, random_id) = store.new_chain_commit_group(None).unwrap();
+    assert_eq!(random.len(), 64);
+    assert!(store.new_chain_commit_group(Some("game".into())).is_err());
+    let groups = store.get_all_chain_commit_groups().unwrap();
+    assert!(groups.contains(&(id, name.clone())));
+    assert!(groups.contains(&(random_id, random.clone())));
+
+    store
+        .add_member_to_chain_commit_group(id, a.canonicalized_hash_ref())
+        .unwrap();
+    store
+        .add_member_to_chain_commit_group(id, b.canonicalized_hash_ref())
+        .unwrap();
+    assert!(store
+        .add_member_to_chain_commit_group(id, a.canonicalized_hash_ref())
+        .is_err());
+    let members: BTreeSet<_> = store
+        .get_chain_commit_group_member_chains_by_name(&name)
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(
+        members,
+        [a.canonicalized_hash_ref(), b.canonicalized_hash_ref()]
+            .into_iter()
+            .collect()
+    );
+    assert!(store
+        .get_chain_commit_group_member_chains_by_name("no-such-group")
+        .unwrap()
+        .is_empty());
+
+    assert!(store
+        .remove_member_from_chain_commit_group(id, a.canonicalized_hash_ref())
+        .unwrap());
+    assert!(!store
+        .remove_member_from_chain_commit_group(id, a.canonicalized_hash_ref())
+        .unwrap());
+    assert!(store.rename_chain_commit_group(id, "game-2").unwrap());
+    assert!(store.rename_chain_commit_group(id, &random).is_err());
+    assert!(!store
+        .rename_chain_commit_group(ChainCommitGroupID(1000), "nothing")
+        .unwrap());
+    assert!(store
+        .get_chain_commit_group_member_chains_by_name(&name)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        store
+            .get_chain_commit_group_member_chains_by_name("game-2")
+            .unwrap(),
+        vec![b.canonicalized_hash_ref()]
+    );
+}
+
+fn check_hidden_services<S: MsgStore>(store: &mut S) {
+    let flags = |store: &S| {
+        store
+            .get_all_hidden_services()
+            .unwrap()
+            .into_iter()
+            .find(|s| s.service_url == "peer.onion" && s.port == 46789)
+            .map(|s| (s.fetch_from, s.push_to, s.allow_unsolicited_tips))
+    };
+    assert_eq!(flags(store), None);
+    store
+        .upsert_hidden_service("peer.onion".into(), 46789, Some(true), None, None)
+        .unwrap();
+    assert_eq!(flags(store), Some((true, false, false)));
+    store
+        .upsert_hidden_service("peer.onion".into(), 46789, None, Some(true), None)
+        .unwrap();
+    assert_eq!(flags(store), Some((true, true, false)));
+}
+
+fn check_all<S: MsgStore>(store: &mut S) {
+    let secp = Secp256k1::new();
+    check_users_and_tips(store, &secp);
+    check_refused_envelopes(store, &secp);
+    check_out_of_order(store, &secp);
+    check_ancestry(store, &secp);
+    check_chain_authority(store, &secp);
+    check_keys_and_nonces(store, &secp);
+    check_groups(store, &secp);
+    check_hidden_services(store);
+}
+
+#[test(tokio::test)]
+async fn test_sqlite_store() {
+    let db = setup_test_db().await;
+    let mut handle = db.get_handle_all().await;
+    check_all(&mut handle);
+}
+
+#[test]
+fn test_memory_store() {
+    check_all(&mut MemoryStore::new());
+}
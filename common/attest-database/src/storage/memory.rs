@@ -0,0 +1,688 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A [`MsgStore`] that keeps everything in memory.
+//!
+//! Envelopes are checked the way the SQLite schema checks them, including
+//! the key grants of each chain and the ancestry commitments envelopes make
+//! to their parents.
+
+use super::{InsertFail, MsgStore};
+use crate::db_handle::get::PeerInfo;
+use crate::db_handle::ChainCommitGroupID;
+use crate::sql_error::SqliteFail;
+use attest_messages::ancestry::AncestryPeaks;
+use attest_messages::authority::{AuthorityError, KeyGrant, Role};
+use attest_messages::nonce::{PrecomittedNonce, PrecomittedPublicNonce};
+use attest_messages::registry;
+use attest_messages::{
+    AttestEnvelopable, Authenticated, CanonicalEnvelopeHash, GenericEnvelope, Header,
+};
+use rusqlite::types::{FromSql, ValueRef};
+use sapio_bitcoin::hashes::hex::ToHex;
+use sapio_bitcoin::secp256k1::rand::{thread_rng, Rng};
+use sapio_bitcoin::secp256k1::{Secp256k1, SecretKey, Signing};
+use sapio_bitcoin::{KeyPair, XOnlyPublicKey};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt::Display;
+use tracing::debug;
+
+#[derive(Debug)]
+pub enum MemoryStoreError {
+    /// the same constraint the SQLite schema enforces was violated
+    Constraint(&'static str),
+    Encoding(Box<dyn Error + Send + Sync>),
+}
+
+impl Display for MemoryStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for MemoryStoreError {}
+
+struct StoredEnvelope {
+    /// the binary encoding, see [`attest_messages::binary`]
+    body: Vec<u8>,
+    /// the key of the user the envelope belongs to, which for keys the chain
+    /// has granted authority to is the chain's user
+    user: XOnlyPublicKey,
+    prev_msg: Option<CanonicalEnvelopeHash>,
+    height: i64,
+    connected: bool,
+    /// insertion order, standing in for the rowid
+    seq: usize,
+}
+
+impl StoredEnvelope {
+    fn decode<M: AttestEnvelopable>(
+        &self,
+    ) -> Result<Authenticated<GenericEnvelope<M>>, MemoryStoreError> {
+        // only authenticated envelopes are stored, same as in the DB
+        Authenticated::<GenericEnvelope<M>>::column_result(ValueRef::Blob(&self.body))
+            .map_err(|e| MemoryStoreError::Encoding(Box::new(e)))
+    }
+}
+
+/// A row of the chain_keys table: `key` may sign envelopes of the chain from
+/// `from_height` up to, but not including, `until_height`.
+struct ChainKey {
+    key: XOnlyPublicKey,
+    /// the user of the envelope granting it
+    user: XOnlyPublicKey,
+    role: Role,
+    from_height: i64,
+    until_height: Option<i64>,
+}
+
+impl ChainKey {
+    fn covers(&self, height: i64) -> bool {
+        self.from_height <= height && self.until_height.map_or(true, |until| height < until)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    users: Vec<(XOnlyPublicKey, String)>,
+    messages: HashMap<CanonicalEnvelopeHash, StoredEnvelope>,
+    /// by genesis
+    chain_keys: HashMap<CanonicalEnvelopeHash, Vec<ChainKey>>,
+    keys: BTreeMap<XOnlyPublicKey, SecretKey>,
+    nonces: HashMap<PrecomittedPublicNonce, PrecomittedNonce>,
+    /// indexed by group id - 1
+    groups: Vec<(String, BTreeSet<CanonicalEnvelopeHash>)>,
+    services: Vec<PeerInfo>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn has_user(&self, key: XOnlyPublicKey) -> bool {
+        self.users.iter().any(|(k, _)| *k == key)
+    }
+
+    /// the user `key` signs for, either as a key granted authority over a
+    /// chain or as the user's own key
+    fn user_of(&self, key: XOnlyPublicKey) -> Option<XOnlyPublicKey> {
+        self.chain_keys
+            .values()
+            .flatten()
+            .filter(|k| k.key == key)
+            .max_by_key(|k| k.from_height)
+            .map(|k| k.user)
+            .or_else(|| self.has_user(key).then(|| key))
+    }
+
+    /// the highest connected envelope of each user, by key
+    fn tips(&self) -> BTreeMap<XOnlyPublicKey, &StoredEnvelope> {
+        let mut tips: BTreeMap<XOnlyPublicKey, &StoredEnvelope> = BTreeMap::new();
+        for m in self.messages.values().filter(|m| m.connected) {
+            let tip = tips.entry(m.user).or_insert(m);
+            if (m.height, m.seq) > (tip.height, tip.seq) {
+                *tip = m;
+            }
+        }
+        tips
+    }
+
+    fn insert<M>(
+        &mut self,
+        data: Authenticated<GenericEnvelope<M>>,
+    ) -> Result<Result<(), InsertFail>, MemoryStoreError>
+    where
+        M: AttestEnvelopable,
+    {
+        let hash = data.canonicalized_hash_ref();
+        let genesis = data.get_genesis_hash();
+        let header = data.header();
+        let height = header.height();
+        let ancestry = header.ancestry();
+        if !ancestry.is_empty() {
+            let consistent = ancestry.well_formed_for(height)
+                && match header.ancestors().map(|a| a.prev_msg()) {
+                    Some(prev) => self
+                        .expected_ancestry_after::<M>(prev)?
+                        .map_or(true, |expected| &expected == ancestry),
+                    None => true,
+                };
+            if !consistent {
+                debug!(
+                    ?hash,
+                    "Insert failed due to inconsistent ancestry commitment"
+                );
+                return Ok(Err((
+                    SqliteFail::SqliteConstraintCheck,
+                    Some("Ancestry commitment does not match parent".into()),
+                )));
+            }
+        }
+        if let Err(e) = self.check_chain_authority(header, genesis) {
+            debug!(?hash, key=?header.key(), ?e, "Insert failed due to missing chain authority");
+            return Ok(Err((
+                SqliteFail::SqliteConstraintCheck,
+                Some(format!("Key not authorized for chain: {}", e)),
+            )));
+        }
+        let msg = data
+            .canonical_msg()
+            .map_err(|e| MemoryStoreError::Encoding(Box::new(e)))?;
+        if let Err(e) = registry::global()
+            .read()
+            .expect("Registry Lock Not Poisoned")
+            .check(&msg)
+        {
+            return Ok(Err((
+                SqliteFail::SqliteConstraintCheck,
+                Some(format!("Invalid message: {}", e)),
+            )));
+        }
+        // in the order SQLite checks its constraints
+        let granted_to = self
+            .chain_keys
+            .get(&genesis)
+            .and_then(|keys| keys.iter().find(|k| k.key == header.key()))
+            .map(|k| k.user);
+        let user = match granted_to.or_else(|| self.has_user(header.key()).then(|| header.key())) {
+            Some(user) => user,
+            None => {
+                return Ok(Err((
+                    SqliteFail::SqliteConstraintNotNull,
+                    Some("NOT NULL constraint failed: messages.user_id".into()),
+                )))
+            }
+        };
+        if height > 0 && !self.messages.contains_key(&genesis) {
+            return Ok(Err((
+                SqliteFail::SqliteConstraintCheck,
+                Some("CHECK constraint failed: messages".into()),
+            )));
+        }
+        if self.messages.contains_key(&hash) {
+            return Ok(Err((
+                SqliteFail::SqliteConstraintUnique,
+                Some("UNIQUE constraint failed: messages.hash".into()),
+            )));
+        }
+        let prev_msg = header.ancestors().map(|a| a.prev_msg());
+        let connected = match prev_msg {
+            None => true,
+            Some(prev) => self.messages.get(&prev).map_or(false, |p| p.connected),
+        };
+        let stored = StoredEnvelope {
+            body: data
+                .to_binary()
+                .map_err(|e| MemoryStoreError::Encoding(Box::new(e)))?,
+            user,
+            prev_msg,
+            height,
+            connected,
+            seq: self.messages.len(),
+        };
+        self.messages.insert(hash, stored);
+        self.record_chain_keys(header, genesis, user);
+        Ok(Ok(()))
+    }
+
+    /// every stored envelope from `tip` down, following `prev_msg` links for
+    /// as long as they resolve, lowest first.
+    fn stored_chain_ending_at(
+        &self,
+        tip: CanonicalEnvelopeHash,
+    ) -> Vec<(i64, CanonicalEnvelopeHash)> {
+        let mut chain = vec![];
+        let mut next = Some(tip);
+        while let Some(m) = next.and_then(|h| self.messages.get(&h).map(|m| (h, m))) {
+            chain.push((m.1.height, m.0));
+            next = m.1.prev_msg;
+        }
+        chain.reverse();
+        chain
+    }
+
+    /// see [`crate::db_handle::get::ancestry`], `None` unless the chain is
+    /// connected all the way to its genesis.
+    fn chain_ending_at(&self, tip: CanonicalEnvelopeHash) -> Option<Vec<CanonicalEnvelopeHash>> {
+        let rows = self.stored_chain_ending_at(tip);
+        let connected = !rows.is_empty()
+            && rows
+                .iter()
+                .enumerate()
+                .all(|(i, (height, _))| *height == i as i64);
+        connected.then(|| rows.into_iter().map(|(_, h)| h).collect())
+    }
+
+    /// The commitment a child of the envelope `prev` must make, if it can be
+    /// determined from what is stored.
+    fn expected_ancestry_after<M: AttestEnvelopable>(
+        &self,
+        prev: CanonicalEnvelopeHash,
+    ) -> Result<Option<AncestryPeaks>, MemoryStoreError> {
+        let parent = match self.messages.get(&prev) {
+            Some(parent) => parent.decode::<M>()?.inner(),
+            None => return Ok(None),
+        };
+        if let Some(peaks) = AncestryPeaks::for_child_of(&parent) {
+            return Ok(Some(peaks));
+        }
+        Ok(self
+            .chain_ending_at(prev)
+            .map(|chain| AncestryPeaks::from_chain(&chain)))
+    }
+
+    /// The root recorded for the chain, chains from before rotations were
+    /// tracked have none.
+    fn tracked_root_key_at(
+        &self,
+        genesis: CanonicalEnvelopeHash,
+        height: i64,
+    ) -> Option<XOnlyPublicKey> {
+        self.chain_keys
+            .get(&genesis)?
+            .iter()
+            .filter(|k| k.role == Role::Root && k.covers(height))
+            .max_by_key(|k| k.from_height)
+            .map(|k| k.key)
+    }
+
+    fn root_key_at(&self, genesis: CanonicalEnvelopeHash, height: i64) -> Option<XOnlyPublicKey> {
+        self.tracked_root_key_at(genesis, height).or_else(|| {
+            self.messages
+                .get(&genesis)
+                .filter(|g| g.height == 0)
+                .map(|g| g.user)
+        })
+    }
+
+    fn is_delegate_at(
+        &self,
+        genesis: CanonicalEnvelopeHash,
+        key: XOnlyPublicKey,
+        height: i64,
+    ) -> bool {
+        self.chain_keys.get(&genesis).map_or(false, |keys| {
+            keys.iter()
+                .any(|k| k.key == key && k.role == Role::Delegate && k.covers(height))
+        })
+    }
+
+    /// Checks that the signer of `header` may post to the chain at its
+    /// height, an unknown genesis leaves the signer treated as the root.
+    fn check_chain_authority(
+        &self,
+        header: &Header,
+        genesis: CanonicalEnvelopeHash,
+    ) -> Result<Role, AuthorityError> {
+        let height = header.height();
+        if height == 0 {
+            return Ok(Role::Root);
+        }
+        let role = match self.root_key_at(genesis, height) {
+            None => Role::Root,
+            Some(root) if root == header.key() => Role::Root,
+            Some(_) if self.is_delegate_at(genesis, header.key(), height) => Role::Delegate,
+            Some(_) => return Err(AuthorityError::UnauthorizedKey),
+        };
+        if header.grant().is_some() && role != Role::Root {
+            return Err(AuthorityError::GrantFromDelegate);
+        }
+        Ok(role)
+    }
+
+    /// Records the keys a newly inserted envelope hands authority to, the
+    /// same way the chain_keys table is kept.
+    fn record_chain_keys(
+        &mut self,
+        header: &Header,
+        genesis: CanonicalEnvelopeHash,
+        user: XOnlyPublicKey,
+    ) {
+        let height = header.height();
+        let untracked = self.tracked_root_key_at(genesis, height).is_none();
+        let keys = self.chain_keys.entry(genesis).or_default();
+        // INSERT OR IGNORE on (key, role, from_height)
+        let insert = |keys: &mut Vec<ChainKey>, key, role, from_height, until_height| {
+            if !keys
+                .iter()
+                .any(|k| k.key == key && k.role == role && k.from_height == from_height)
+            {
+                keys.push(ChainKey {
+                    key,
+                    user,
+                    role,
+                    from_height,
+                    until_height,
+                });
+            }
+        };
+        let end = |keys: &mut Vec<ChainKey>, key, role, height: i64| {
+            for k in keys.iter_mut().filter(|k| {
+                k.key == key
+                    && k.role == role
+                    && k.from_height < height
+                    && k.until_height.map_or(true, |until| until > height)
+            }) {
+                k.until_height = Some(height);
+            }
+        };
+        if height == 0 {
+            insert(keys, header.key(), Role::Root, 0, None);
+        }
+        // grants apply from the next envelope on
+        let effective = height + 1;
+        match header.grant() {
+            Some(KeyGrant::Rotate { to }) => {
+                if untracked {
+                    // a chain from before rotations were tracked
+                    insert(keys, header.key(), Role::Root, 0, None);
+                }
+                end(keys, header.key(), Role::Root, effective);
+                end(keys, *to, Role::Delegate, effective);
+                insert(keys, *to, Role::Root, effective, None);
+            }
+            Some(KeyGrant::Delegate { to, until_height }) => {
+                insert(keys, *to, Role::Delegate, effective, *until_height);
+            }
+            Some(KeyGrant::Revoke { key }) => {
+                end(keys, *key, Role::Delegate, effective);
+            }
+            None => {}
+        }
+    }
+}
+
+impl MsgStore for MemoryStore {
+    type Error = MemoryStoreError;
+
+    fn insert_user_by_genesis_envelope<M>(
+        &mut self,
+        nickname: String,
+        envelope: Authenticated<GenericEnvelope<M>>,
+    ) -> Result<Result<String, InsertFail>, Self::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        let key = envelope.header().key();
+        // an existing user keeps their nickname
+        if !self.has_user(key) {
+            self.users.push((key, nickname));
+        }
+        Ok(self.insert(envelope)?.map(|()| key.to_hex()))
+    }
+
+    fn try_insert_authenticated_envelope<M>(
+        &mut self,
+        data: Authenticated<GenericEnvelope<M>>,
+        reuse_safe: bool,
+    ) -> Result<Result<(), InsertFail>, Self::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        if reuse_safe {
+            if let Some(prev) = data.header().ancestors().map(|a| a.prev_msg()) {
+                if self.messages.values().any(|m| m.prev_msg == Some(prev)) {
+                    return Ok(Err((
+                        SqliteFail::SqliteConstraintUnique,
+                        Some("Uniqueness Check: Nonce Already Used".into()),
+                    )));
+                }
+            }
+        }
+        self.insert(data)
+    }
+
+    fn attach_tips(&mut self) -> Result<usize, Self::Error> {
+        let mut attached = 0;
+        loop {
+            let connectable: Vec<CanonicalEnvelopeHash> = self
+                .messages
+                .iter()
+                .filter(|(_, m)| !m.connected)
+                .filter(|(_, m)| {
+                    m.prev_msg
+                        .and_then(|p| self.messages.get(&p))
+                        .map_or(false, |p| p.connected)
+                })
+                .map(|(h, _)| *h)
+                .collect();
+            if connectable.is_empty() {
+                return Ok(attached);
+            }
+            attached += connectable.len();
+            for h in connectable {
+                if let Some(m) = self.messages.get_mut(&h) {
+                    m.connected = true;
+                }
+            }
+        }
+    }
+
+    fn get_all_users(&self) -> Result<Vec<(XOnlyPublicKey, String)>, Self::Error> {
+        Ok(self.users.clone())
+    }
+
+    fn message_by_hash<M>(
+        &self,
+        hash: CanonicalEnvelopeHash,
+    ) -> Result<Option<Authenticated<GenericEnvelope<M>>>, Self::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        self.messages.get(&hash).map(|m| m.decode()).transpose()
+    }
+
+    fn get_tip_for_user_by_key<M>(
+        &self,
+        key: XOnlyPublicKey,
+    ) -> Result<Option<Authenticated<GenericEnvelope<M>>>, Self::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        match self.user_of(key) {
+            Some(user) => self.tips().get(&user).map(|m| m.decode()).transpose(),
+            None => Ok(None),
+        }
+    }
+
+    fn get_tips_for_all_users<M>(
+        &self,
+    ) -> Result<Vec<Authenticated<GenericEnvelope<M>>>, Self::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        self.tips().values().map(|m| m.decode()).collect()
+    }
+
+    fn get_message_at_height_for_user<M>(
+        &self,
+        key: XOnlyPublicKey,
+        height: u64,
+    ) -> Result<Option<Authenticated<GenericEnvelope<M>>>, Self::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        self.messages
+            .values()
+            .filter(|m| m.user == key && m.height as u64 == height)
+            .min_by_key(|m| m.seq)
+            .map(|m| m.decode())
+            .transpose()
+    }
+
+    fn save_keypair(&mut self, kp: KeyPair) -> Result<(), Self::Error> {
+        let key = kp.x_only_public_key().0;
+        if self.keys.contains_key(&key) {
+            return Err(MemoryStoreError::Constraint(
+                "UNIQUE constraint failed: private_keys.public_key",
+            ));
+        }
+        self.keys.insert(key, kp.secret_key());
+        Ok(())
+    }
+
+    fn get_keymap(&self) -> Result<BTreeMap<XOnlyPublicKey, SecretKey>, Self::Error> {
+        Ok(self.keys.clone())
+    }
+
+    fn save_nonce_for_user_by_key<C: Signing>(
+        &mut self,
+        nonce: PrecomittedNonce,
+        secp: &Secp256k1<C>,
+        _key: XOnlyPublicKey,
+    ) -> Result<PrecomittedPublicNonce, Self::Error> {
+        let pk_nonce = nonce.get_public(secp);
+        self.nonces.insert(pk_nonce, nonce);
+        Ok(pk_nonce)
+    }
+
+    fn get_secret_for_public_nonce(
+        &self,
+        nonce: PrecomittedPublicNonce,
+    ) -> Result<Option<PrecomittedNonce>, Self::Error> {
+        Ok(self.nonces.get(&nonce).cloned())
+    }
+
+    fn new_chain_commit_group(
+        &mut self,
+        name: Option<String>,
+    ) -> Result<(String, ChainCommitGroupID), Self::Error> {
+        let name = name.unwrap_or_else(|| thread_rng().gen::<[u8; 32]>().to_hex());
+        if self.groups.iter().any(|(n, _)| *n == name) {
+            return Err(MemoryStoreError::Constraint(
+                "UNIQUE constraint failed: chain_commit_groups.name",
+            ));
+        }
+        self.groups.push((name.clone(), BTreeSet::new()));
+        Ok((name, ChainCommitGroupID(self.groups.len() as i64)))
+    }
+
+    fn add_member_to_chain_commit_group(
+        &mut self,
+        group_id: ChainCommitGroupID,
+        genesis: CanonicalEnvelopeHash,
+    ) -> Result<(), Self::Error> {
+        if !self.messages.contains_key(&genesis) {
+            return Err(MemoryStoreError::Constraint(
+                "NOT NULL constraint failed: chain_commit_group_members.member_id",
+            ));
+        }
+        let (_, members) = usize::try_from(group_id.0 - 1)
+            .ok()
+            .and_then(|i| self.groups.get_mut(i))
+            .ok_or(MemoryStoreError::Constraint(
+                "FOREIGN KEY constraint failed",
+            ))?;
+        if !members.insert(genesis) {
+            return Err(MemoryStoreError::Constraint(
+                "UNIQUE constraint failed: chain_commit_group_members.group_id, chain_commit_group_members.member_id",
+            ));
+        }
+        Ok(())
+    }
+
+    fn remove_member_from_chain_commit_group(
+        &mut self,
+        group_id: ChainCommitGroupID,
+        genesis: CanonicalEnvelopeHash,
+    ) -> Result<bool, Self::Error> {
+        Ok(usize::try_from(group_id.0 - 1)
+            .ok()
+            .and_then(|i| self.groups.get_mut(i))
+            .map_or(false, |(_, members)| members.remove(&genesis)))
+    }
+
+    fn rename_chain_commit_group(
+        &mut self,
+        group_id: ChainCommitGroupID,
+        name: &str,
+    ) -> Result<bool, Self::Error> {
+        let index = match usize::try_from(group_id.0 - 1) {
+            Ok(i) if i < self.groups.len() => i,
+            _ => return Ok(false),
+        };
+        if self
+            .groups
+            .iter()
+            .enumerate()
+            .any(|(i, (n, _))| i != index && n == name)
+        {
+            return Err(MemoryStoreError::Constraint(
+                "UNIQUE constraint failed: chain_commit_groups.name",
+            ));
+        }
+        self.groups[index].0 = name.into();
+        Ok(true)
+    }
+
+    fn get_all_chain_commit_groups(
+        &self,
+    ) -> Result<Vec<(ChainCommitGroupID, String)>, Self::Error> {
+        Ok(self
+            .groups
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| (ChainCommitGroupID(i as i64 + 1), name.clone()))
+            .collect())
+    }
+
+    fn get_chain_commit_group_member_chains_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Vec<CanonicalEnvelopeHash>, Self::Error> {
+        Ok(self
+            .groups
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, members)| members.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn upsert_hidden_service(
+        &mut self,
+        service_url: String,
+        port: u16,
+        fetch_from: Option<bool>,
+        push_to: Option<bool>,
+        allow_unsolicited_tips: Option<bool>,
+    ) -> Result<(), Self::Error> {
+        match self
+            .services
+            .iter_mut()
+            .find(|s| s.service_url == service_url && s.port == port)
+        {
+            Some(s) => {
+                s.fetch_from = fetch_from.unwrap_or(s.fetch_from);
+                s.push_to = push_to.unwrap_or(s.push_to);
+                s.allow_unsolicited_tips =
+                    allow_unsolicited_tips.unwrap_or(s.allow_unsolicited_tips);
+            }
+            None => self.services.push(PeerInfo {
+                service_url,
+                port,
+                fetch_from: fetch_from.unwrap_or_default(),
+                push_to: push_to.unwrap_or_default(),
+                allow_unsolicited_tips: allow_unsolicited_tips.unwrap_or_default(),
+            }),
+        }
+        Ok(())
+    }
+
+    fn get_all_hidden_services(&self) -> Result<Vec<PeerInfo>, Self::Error> {
+        Ok(self
+            .services
+            .iter()
+            .map(|s| PeerInfo {
+                service_url: s.service_url.clone(),
+                port: s.port,
+                fetch_from: s.fetch_from,
+                push_to: s.push_to,
+                allow_unsolicited_tips: s.allow_unsolicited_tips,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A storage interface over the core of [`crate::db_handle`].
+//!
+//! [`MsgStore`] covers what a node needs to run a chain: users and their
+//! envelopes, tips, nonces, keys, chain commit groups and hidden services. It
+//! is implemented by the SQLite backed [`MsgDBHandle`] and by
+//! [`memory::MemoryStore`], which keeps everything in memory for tests and
+//! short lived tools.
+//!
+//! Both implementations are checked against the same conformance suite, so
+//! code written against [`MsgStore`] behaves the same on either.
+
+use crate::db_handle::get::PeerInfo;
+use crate::db_handle::ChainCommitGroupID;
+use crate::sql_error::SqliteFail;
+use attest_messages::nonce::{PrecomittedNonce, PrecomittedPublicNonce};
+use attest_messages::{AttestEnvelopable, Authenticated, CanonicalEnvelopeHash, GenericEnvelope};
+use sapio_bitcoin::secp256k1::{Secp256k1, SecretKey, Signing};
+use sapio_bitcoin::{KeyPair, XOnlyPublicKey};
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+mod conformance;
+pub mod memory;
+mod sqlite;
+
+/// Why an envelope was refused, in the terms of the SQLite schema so that
+/// callers can handle both backends the same way.
+pub type InsertFail = (SqliteFail, Option<String>);
+
+pub trait MsgStore {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// creates a new user from a genesis envelope, returning the hex key
+    fn insert_user_by_genesis_envelope<M>(
+        &mut self,
+        nickname: String,
+        envelope: Authenticated<GenericEnvelope<M>>,
+    ) -> Result<Result<String, InsertFail>, Self::Error>
+    where
+        M: AttestEnvelopable;
+
+    /// stores an envelope for a known user
+    ///
+    /// If `reuse_safe`, refuses envelopes whose parent already has a child.
+    fn try_insert_authenticated_envelope<M>(
+        &mut self,
+        data: Authenticated<GenericEnvelope<M>>,
+        reuse_safe: bool,
+    ) -> Result<Result<(), InsertFail>, Self::Error>
+    where
+        M: AttestEnvelopable;
+
+    /// connects envelopes received before their parents, returning how many
+    /// were updated
+    fn attach_tips(&mut self) -> Result<usize, Self::Error>;
+
+    fn get_all_users(&self) -> Result<Vec<(XOnlyPublicKey, String)>, Self::Error>;
+
+    fn message_by_hash<M>(
+        &self,
+        hash: CanonicalEnvelopeHash,
+    ) -> Result<Option<Authenticated<GenericEnvelope<M>>>, Self::Error>
+    where
+        M: AttestEnvelopable;
+
+    /// the highest connected envelope of a user's chain
+    fn get_tip_for_user_by_key<M>(
+        &self,
+        key: XOnlyPublicKey,
+    ) -> Result<Option<Authenticated<GenericEnvelope<M>>>, Self::Error>
+    where
+        M: AttestEnvelopable;
+
+    /// the highest connected envelope of every user's chain, in no particular
+    /// order
+    fn get_tips_for_all_users<M>(
+        &self,
+    ) -> Result<Vec<Authenticated<GenericEnvelope<M>>>, Self::Error>
+    where
+        M: AttestEnvelopable;
+
+    fn get_message_at_height_for_user<M>(
+        &self,
+        key: XOnlyPublicKey,
+        height: u64,
+    ) -> Result<Option<Authenticated<GenericEnvelope<M>>>, Self::Error>
+    where
+        M: AttestEnvelopable;
+
+    fn save_keypair(&mut self, kp: KeyPair) -> Result<(), Self::Error>;
+
+    fn get_keymap(&self) -> Result<BTreeMap<XOnlyPublicKey, SecretKey>, Self::Error>;
+
+    fn save_nonce_for_user_by_key<C: Signing>(
+        &mut self,
+        nonce: PrecomittedNonce,
+        secp: &Secp256k1<C>,
+        key: XOnlyPublicKey,
+    ) -> Result<PrecomittedPublicNonce, Self::Error>;
+
+    fn get_secret_for_public_nonce(
+        &self,
+        nonce: PrecomittedPublicNonce,
+    ) -> Result<Option<PrecomittedNonce>, Self::Error>;
+
+    /// creates a group, with a random name if none is given
+    fn new_chain_commit_group(
+        &mut self,
+        name: Option<String>,
+    ) -> Result<(String, ChainCommitGroupID), Self::Error>;
+
+    fn add_member_to_chain_commit_group(
+        &mut self,
+        group_id: ChainCommitGroupID,
+        genesis: CanonicalEnvelopeHash,
+    ) -> Result<(), Self::Error>;
+
+    /// returns false if the chain wasn't a member
+    fn remove_member_from_chain_commit_group(
+        &mut self,
+        group_id: ChainCommitGroupID,
+        genesis: CanonicalEnvelopeHash,
+    ) -> Result<bool, Self::Error>;
+
+    /// fails if the name is taken, returns false if there is no such group
+    fn rename_chain_commit_group(
+        &mut self,
+        group_id: ChainCommitGroupID,
+        name: &str,
+    ) -> Result<bool, Self::Error>;
+
+    fn get_all_chain_commit_groups(&self)
+        -> Result<Vec<(ChainCommitGroupID, String)>, Self::Error>;
+
+    fn get_chain_commit_group_member_chains_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Vec<CanonicalEnvelopeHash>, Self::Error>;
+
+    /// adds a hidden service, or updates the flags that are `Some` if it is
+    /// already known
+    fn upsert_hidden_service(
+        &mut self,
+        service_url: String,
+        port: u16,
+        fetch_from: Option<bool>,
+        push_to: Option<bool>,
+        allow_unsolicited_tips: Option<bool>,
+    ) -> Result<(), Self::Error>;
+
+    fn get_all_hidden_services(&self) -> Result<Vec<PeerInfo>, Self::Error>;
+}
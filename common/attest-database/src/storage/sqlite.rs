@@ -0,0 +1,187 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! [`MsgStore`] for the SQLite backed [`MsgDBHandle`], by way of its inherent
+//! methods.
+
+use super::{InsertFail, MsgStore};
+use crate::db_handle::get::PeerInfo;
+use crate::db_handle::handle_type::All;
+use crate::db_handle::sql::get::messages::SQL_GET_MESSAGE_BY_HASH;
+use crate::db_handle::{ChainCommitGroupID, MsgDBHandle};
+use attest_messages::nonce::{PrecomittedNonce, PrecomittedPublicNonce};
+use attest_messages::{AttestEnvelopable, Authenticated, CanonicalEnvelopeHash, GenericEnvelope};
+use rusqlite::OptionalExtension;
+use sapio_bitcoin::secp256k1::{Secp256k1, SecretKey, Signing};
+use sapio_bitcoin::{KeyPair, XOnlyPublicKey};
+use std::collections::BTreeMap;
+
+type H = MsgDBHandle<All>;
+
+impl MsgStore for H {
+    type Error = rusqlite::Error;
+
+    fn insert_user_by_genesis_envelope<M>(
+        &mut self,
+        nickname: String,
+        envelope: Authenticated<GenericEnvelope<M>>,
+    ) -> Result<Result<String, InsertFail>, Self::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        H::insert_user_by_genesis_envelope(self, nickname, envelope)
+    }
+
+    fn try_insert_authenticated_envelope<M>(
+        &mut self,
+        data: Authenticated<GenericEnvelope<M>>,
+        reuse_safe: bool,
+    ) -> Result<Result<(), InsertFail>, Self::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        H::try_insert_authenticated_envelope(self, data, reuse_safe)
+    }
+
+    fn attach_tips(&mut self) -> Result<usize, Self::Error> {
+        H::attach_tips(self)
+    }
+
+    fn get_all_users(&self) -> Result<Vec<(XOnlyPublicKey, String)>, Self::Error> {
+        H::get_all_users(self)
+    }
+
+    fn message_by_hash<M>(
+        &self,
+        hash: CanonicalEnvelopeHash,
+    ) -> Result<Option<Authenticated<GenericEnvelope<M>>>, Self::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        let mut stmt = self.0.prepare_cached(SQL_GET_MESSAGE_BY_HASH)?;
+        stmt.query_row([hash], |r| r.get(0)).optional()
+    }
+
+    fn get_tip_for_user_by_key<M>(
+        &self,
+        key: XOnlyPublicKey,
+    ) -> Result<Option<Authenticated<GenericEnvelope<M>>>, Self::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        H::get_tip_for_user_by_key(self, key).optional()
+    }
+
+    fn get_tips_for_all_users<M>(
+        &self,
+    ) -> Result<Vec<Authenticated<GenericEnvelope<M>>>, Self::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        H::get_tips_for_all_users::<Authenticated<GenericEnvelope<M>>, M>(self)
+    }
+
+    fn get_message_at_height_for_user<M>(
+        &self,
+        key: XOnlyPublicKey,
+        height: u64,
+    ) -> Result<Option<Authenticated<GenericEnvelope<M>>>, Self::Error>
+    where
+        M: AttestEnvelopable,
+    {
+        H::get_message_at_height_for_user(self, key, height)
+    }
+
+    fn save_keypair(&mut self, kp: KeyPair) -> Result<(), Self::Error> {
+        H::save_keypair(self, kp)
+    }
+
+    fn get_keymap(&self) -> Result<BTreeMap<XOnlyPublicKey, SecretKey>, Self::Error> {
+        H::get_keymap(self)
+    }
+
+    fn save_nonce_for_user_by_key<C: Signing>(
+        &mut self,
+        nonce: PrecomittedNonce,
+        secp: &Secp256k1<C>,
+        key: XOnlyPublicKey,
+    ) -> Result<PrecomittedPublicNonce, Self::Error> {
+        H::save_nonce_for_user_by_key(self, nonce, secp, key)
+    }
+
+    fn get_secret_for_public_nonce(
+        &self,
+        nonce: PrecomittedPublicNonce,
+    ) -> Result<Option<PrecomittedNonce>, Self::Error> {
+        H::get_secret_for_public_nonce(self, nonce).optional()
+    }
+
+    fn new_chain_commit_group(
+        &mut self,
+        name: Option<String>,
+    ) -> Result<(String, ChainCommitGroupID), Self::Error> {
+        H::new_chain_commit_group(self, name)
+    }
+
+    fn add_member_to_chain_commit_group(
+        &mut self,
+        group_id: ChainCommitGroupID,
+        genesis: CanonicalEnvelopeHash,
+    ) -> Result<(), Self::Error> {
+        H::add_member_to_chain_commit_group(self, group_id, genesis)
+    }
+
+    fn remove_member_from_chain_commit_group(
+        &mut self,
+        group_id: ChainCommitGroupID,
+        genesis: CanonicalEnvelopeHash,
+    ) -> Result<bool, Self::Error> {
+        H::remove_member_from_chain_commit_group(self, group_id, genesis)
+    }
+
+    fn rename_chain_commit_group(
+        &mut self,
+        group_id: ChainCommitGroupID,
+        name: &str,
+    ) -> Result<bool, Self::Error> {
+        H::rename_chain_commit_group(self, group_id, name)
+    }
+
+    fn get_all_chain_commit_groups(
+        &self,
+    ) -> Result<Vec<(ChainCommitGroupID, String)>, Self::Error> {
+        H::get_all_chain_commit_groups(self)
+    }
+
+    fn get_chain_commit_group_member_chains_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Vec<CanonicalEnvelopeHash>, Self::Error> {
+        H::get_chain_commit_group_member_chains_by_name(self, name)
+    }
+
+    fn upsert_hidden_service(
+        &mut self,
+        service_url: String,
+        port: u16,
+        fetch_from: Option<bool>,
+        push_to: Option<bool>,
+        allow_unsolicited_tips: Option<bool>,
+    ) -> Result<(), Self::Error> {
+        H::upsert_hidden_service(
+            self,
+            service_url,
+            port,
+            fetch_from,
+            push_to,
+            allow_unsolicited_tips,
+        )
+    }
+
+    fn get_all_hidden_services(&self) -> Result<Vec<PeerInfo>, Self::Error> {
+        H::get_all_hidden_services(self)
+    }
+}
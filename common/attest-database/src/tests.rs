@@ -4,27 +4,55 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::connection::{MutexList, PoolConfig};
+use crate::db_handle::archive::ArchiveError;
+use crate::db_handle::backup::{rotate_snapshots, verify_snapshot, BackupError};
 use crate::db_handle::create::TipControl;
-use crate::db_handle::get::nonces::extract_sk_from_envelopes;
+use crate::db_handle::get::chain_commit_groups::{ChainCommitGroupMembership, GroupTipPolicy};
+use crate::db_handle::get::chain_visibility::ChainVisibility;
+use crate::db_handle::get::gaps::Gap;
+use crate::db_handle::get::nonces::{extract_sk_from_envelopes, NonceState};
+use crate::db_handle::get::query::EnvelopeQuery;
+use crate::db_handle::get::storage_encoding::{check_binary_body, StorageEncoding};
+use crate::db_handle::handle_type::ReadOnly;
 use crate::db_handle::MsgDBHandle;
+use crate::sql_error::SqliteFail;
 
 use super::connection::MsgDB;
 use super::*;
 
-use attest_messages::{Authenticated, CanonicalEnvelopeHash, Envelope, WrappedJson};
+use attest_messages::anchor::{anchor_script, merkle_root, AnchorError};
+use attest_messages::authority::{KeyGrant, Role};
+use attest_messages::blobs::{BlobError, BlobRef, MAX_BLOB_SIZE};
+use attest_messages::encrypted::{EncryptedPayload, EncryptionError};
+use attest_messages::musig::{GroupError, SigningGroup};
+use attest_messages::nonce::PrecomittedPublicNonce;
+use attest_messages::registry::{self, MessageType, Versioned};
+use attest_messages::{
+    Ancestors, Authenticated, AuthenticationError, CanonicalEnvelopeHash, Envelope, FormatVersion,
+    GenericEnvelope, Header, SigningError, Unsigned, WrappedJson,
+};
 use fallible_iterator::FallibleIterator;
 use ruma_serde::CanonicalJsonValue;
 use rusqlite::params;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
+use sapio_bitcoin::hashes::hex::ToHex;
 use sapio_bitcoin::secp256k1::rand::{thread_rng, Rng};
-use sapio_bitcoin::secp256k1::{All, Secp256k1};
-use sapio_bitcoin::KeyPair;
+use sapio_bitcoin::secp256k1::{All, Message, Secp256k1};
+use sapio_bitcoin::util::merkleblock::MerkleBlock;
+use sapio_bitcoin::{Block, BlockHeader, KeyPair, Transaction, TxOut};
 
 use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::task::spawn_blocking;
 
 use test_log::test;
 
-use tracing::debug;
+use tracing::{debug, info};
 
 #[test(tokio::test)]
 async fn test_setup_db() {
@@ -33,6 +61,163 @@ async fn test_setup_db() {
     conn.get_handle_all().await.setup_tables();
 }
 
+#[test(tokio::test)]
+async fn test_migrate_from_v0() {
+    // a chain signed the current way, keeping the nonce secrets that version
+    // 0 never erased
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let kp = make_test_user(&secp, &mut handle, "alice".into());
+    let key = kp.x_only_public_key().0;
+    let mut chain = vec![handle.get_tip_for_user_by_key::<WrappedJson>(key).unwrap()];
+    let mut secrets = vec![];
+    for _ in 0..3 {
+        let next = chain.last().unwrap().header().next_nonce();
+        secrets.push((next, handle.get_secret_for_public_nonce(next).unwrap()));
+        let e = handle
+            .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                CanonicalJsonValue::Null,
+                &kp,
+                &secp,
+                None,
+                None,
+                TipControl::NoTips,
+            )
+            .unwrap()
+            .unwrap()
+            .self_authenticate(&secp)
+            .unwrap();
+        handle
+            .try_insert_authenticated_envelope(e.clone(), false)
+            .unwrap()
+            .unwrap();
+        chain.push(e);
+    }
+    let next = chain.last().unwrap().header().next_nonce();
+    secrets.push((next, handle.get_secret_for_public_nonce(next).unwrap()));
+    drop(handle);
+
+    // stored the way version 0 stored them
+    let dir = std::env::temp_dir().join(format!("attest-v0-{}", thread_rng().gen::<u64>()));
+    std::fs::create_dir_all(&dir).unwrap();
+    {
+        let old = rusqlite::Connection::open(dir.join("v0.sqlite3")).unwrap();
+        old.execute_batch(db_handle::sql::migrations::SQL_SCHEMA_V0)
+            .unwrap();
+        old.execute(
+            "INSERT INTO users (nickname, key) VALUES ('alice', ?)",
+            [sql_serializers::PK(key)],
+        )
+        .unwrap();
+        old.execute(
+            "INSERT INTO private_keys (public_key, private_key) VALUES (?, ?)",
+            params![
+                sql_serializers::PK(key),
+                sql_serializers::SK(kp.secret_key())
+            ],
+        )
+        .unwrap();
+        for (public, secret) in &secrets {
+            old.execute(
+                "INSERT INTO message_nonces (key_id, public_key, private_key) VALUES (1, ?, ?)",
+                params![public, secret],
+            )
+            .unwrap();
+        }
+        for e in &chain {
+            old.execute(
+                "INSERT INTO messages (body, hash, user_id, received_time, sent_time, genesis, \
+                prev_msg, height, nonce, prev_msg_id, genesis_id, connected) VALUES (?, ?, 1, ?, \
+                ?, ?, ?, ?, ?, (SELECT message_id FROM messages WHERE hash = ?6), (SELECT \
+                message_id FROM messages WHERE hash = ?5), 1)",
+                params![
+                    e.inner_ref(),
+                    e.canonicalized_hash_ref(),
+                    attest_util::now(),
+                    e.header().sent_time_ms(),
+                    e.get_genesis_hash(),
+                    e.header()
+                        .ancestors()
+                        .map(|a| a.prev_msg())
+                        .unwrap_or_else(CanonicalEnvelopeHash::genesis),
+                    e.header().height(),
+                    e.header().unsigned().signature().unwrap()[0..32].to_hex(),
+                ],
+            )
+            .unwrap();
+        }
+        old.execute(
+            "INSERT INTO chain_commit_groups (name) VALUES ('group')",
+            [],
+        )
+        .unwrap();
+    }
+
+    let conn = setup_db_at(dir.clone(), "v0").await.unwrap();
+    let mut handle = conn.get_handle_all().await;
+    let version: usize = handle
+        .0
+        .pragma_query_value(None, "user_version", |r| r.get(0))
+        .unwrap();
+    assert_eq!(version, db_handle::setup::SCHEMA_VERSION);
+    // envelopes read back as they were
+    let tip = handle.get_tip_for_user_by_key::<WrappedJson>(key).unwrap();
+    assert_eq!(
+        tip.canonicalized_hash_ref(),
+        chain.last().unwrap().canonicalized_hash_ref()
+    );
+    // the nonces that signed them are spent, the committed one is not
+    let (spent, committed) = secrets.split_at(secrets.len() - 1);
+    for (public, _) in spent {
+        assert_eq!(
+            handle.get_nonce_state(*public).unwrap(),
+            Some(NonceState::Used)
+        );
+        assert!(handle.get_secret_for_public_nonce(*public).is_err());
+    }
+    assert_eq!(
+        handle.get_nonce_state(committed[0].0).unwrap(),
+        Some(NonceState::Committed)
+    );
+    let group = handle.get_chain_commit_group_by_name("group").unwrap();
+    assert_eq!(
+        group.map(|g| (g.finished, g.tip_policy)),
+        Some((false, GroupTipPolicy::All))
+    );
+    // stored envelopes are counted, but not towards the delay
+    let stats = handle.get_chain_stats().unwrap();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].messages, chain.len() as u64);
+    assert_eq!(stats[0].delay_histogram, [0; 6]);
+    assert_eq!(stats[0].mean_delay_ms, 0);
+    // and the chain carries on
+    let e = handle
+        .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+            CanonicalJsonValue::Null,
+            &kp,
+            &secp,
+            None,
+            None,
+            TipControl::NoTips,
+        )
+        .unwrap()
+        .unwrap()
+        .self_authenticate(&secp)
+        .unwrap();
+    handle
+        .try_insert_authenticated_envelope(e, false)
+        .unwrap()
+        .unwrap();
+    let stats = handle.get_chain_stats().unwrap();
+    assert_eq!(stats[0].messages, chain.len() as u64 + 1);
+    assert_eq!(stats[0].delay_histogram.iter().sum::<u64>(), 1);
+    // opening again has nothing left to migrate
+    handle.setup_tables();
+    drop(handle);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
 #[test(tokio::test)]
 async fn test_add_user() {
     let conn = setup_db().await;
@@ -60,16 +245,34 @@ async fn test_reused_nonce() {
         .unwrap()
         .unwrap();
     let envelope_1 = envelope_1.self_authenticate(&secp).unwrap();
-    let envelope_2 = handle
-        .wrap_message_in_envelope_for_user_by_key(
-            CanonicalJsonValue::String("distinct".into()),
+    // signing again with the same nonce is refused...
+    let nonce = handle
+        .get_tip_for_user_by_key::<WrappedJson>(kp.x_only_public_key().0)
+        .unwrap()
+        .header()
+        .next_nonce();
+    match handle.wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+        CanonicalJsonValue::String("distinct".into()),
+        &kp,
+        &secp,
+        None,
+        None,
+        TipControl::AllTips,
+    ) {
+        Ok(Err(SigningError::NonceAlreadyUsed(n))) => assert_eq!(n, nonce),
+        e => panic!("expected the nonce to be refused, got {:?}", e),
+    }
+    // ...so reuse it by hand, while its secret is still around
+    let mut envelope_2 = Envelope::new(
+        envelope_1.header().clone(),
+        WrappedJson::from(CanonicalJsonValue::String("distinct".into())),
+    );
+    envelope_2
+        .sign_with(
             &kp,
             &secp,
-            None,
-            None,
-            TipControl::AllTips,
+            handle.get_secret_for_public_nonce(nonce).unwrap(),
         )
-        .unwrap()
         .unwrap();
     let envelope_2 = envelope_2.self_authenticate(&secp).unwrap();
     handle
@@ -441,6 +644,24 @@ async fn test_chain_commit_groups() {
         }
     }
 
+    for (i, (_kp, friend_groups, _genesis_hash)) in users.iter().enumerate() {
+        for friend_group in friend_groups {
+            let chains: BTreeSet<_> = handle
+                .get_chain_commit_group_member_chains_by_name(&format!(
+                    "g-{}-{:?}",
+                    i, friend_group
+                ))
+                .unwrap()
+                .into_iter()
+                .collect();
+            assert_eq!(chains, friend_group.iter().map(|f| users[*f].2).collect());
+        }
+    }
+    assert!(handle
+        .get_chain_commit_group_member_chains_by_name("no-such-group")
+        .unwrap()
+        .is_empty());
+
     for (i, (_kp, friend_groups, genesis_hash)) in users.iter().enumerate() {
         let ids = handle
             .get_all_chain_commit_group_members_for_chain(*genesis_hash)
@@ -520,6 +741,2281 @@ async fn test_chain_commit_groups() {
         }
     }
 }
+#[test(tokio::test)]
+async fn test_chain_commit_group_management() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let new_user = |handle: &mut MsgDBHandle, name: &str| {
+        let kp = make_test_user(&secp, handle, name.into());
+        let genesis = handle
+            .get_tip_for_user_by_key::<WrappedJson>(kp.x_only_public_key().0)
+            .unwrap()
+            .get_genesis_hash();
+        (kp, genesis)
+    };
+    let (host, host_genesis) = new_user(&mut handle, "host");
+    let (bob, bob_genesis) = new_user(&mut handle, "bob");
+    let (carol, carol_genesis) = new_user(&mut handle, "carol");
+    let (_, id) = handle.new_chain_commit_group(Some("game".into())).unwrap();
+    handle
+        .add_subscriber_to_chain_commit_group(id, host_genesis)
+        .unwrap();
+    for genesis in [host_genesis, bob_genesis, carol_genesis] {
+        handle
+            .add_member_to_chain_commit_group(id, genesis)
+            .unwrap();
+    }
+
+    let memberships = handle
+        .get_chain_commit_group_memberships_for_key(host.x_only_public_key().0)
+        .unwrap();
+    assert_eq!(
+        memberships,
+        vec![ChainCommitGroupMembership {
+            group_id: id,
+            name: "game".into(),
+            genesis: host_genesis,
+            member: true,
+            subscriber: true,
+            tip_policy: GroupTipPolicy::All,
+        }]
+    );
+    let memberships = handle
+        .get_chain_commit_group_memberships_for_key(bob.x_only_public_key().0)
+        .unwrap();
+    assert_eq!(memberships.len(), 1);
+    assert!(memberships[0].member && !memberships[0].subscriber);
+
+    // the tips the host would commit to next, storing the envelope if `post`
+    let tips_of_next = |handle: &mut MsgDBHandle, kp: &KeyPair, post: bool| {
+        let e = handle
+            .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                CanonicalJsonValue::Null,
+                kp,
+                &secp,
+                None,
+                None,
+                TipControl::GroupsOnly,
+            )
+            .unwrap()
+            .unwrap()
+            .self_authenticate(&secp)
+            .unwrap();
+        let tips: BTreeSet<_> = e.header().tips().iter().map(|t| t.2).collect();
+        if post {
+            handle
+                .try_insert_authenticated_envelope(e, false)
+                .unwrap()
+                .unwrap();
+        }
+        tips
+    };
+    // nobody but the host has posted yet
+    let (bob_tip, carol_tip) = (bob_genesis, carol_genesis);
+    assert_eq!(
+        tips_of_next(&mut handle, &host, true),
+        [bob_tip, carol_tip].into_iter().collect()
+    );
+
+    // only tips that moved since the host's last envelope
+    assert!(handle
+        .set_chain_commit_group_tip_policy(id, GroupTipPolicy::Changed)
+        .unwrap());
+    assert!(tips_of_next(&mut handle, &host, false).is_empty());
+    let bob_next = handle
+        .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+            CanonicalJsonValue::Null,
+            &bob,
+            &secp,
+            None,
+            None,
+            TipControl::NoTips,
+        )
+        .unwrap()
+        .unwrap()
+        .self_authenticate(&secp)
+        .unwrap();
+    let bob_tip = bob_next.canonicalized_hash_ref();
+    handle
+        .try_insert_authenticated_envelope(bob_next, false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        tips_of_next(&mut handle, &host, false),
+        [bob_tip].into_iter().collect()
+    );
+
+    assert!(handle
+        .set_chain_commit_group_tip_policy(id, GroupTipPolicy::Paused)
+        .unwrap());
+    assert!(tips_of_next(&mut handle, &host, false).is_empty());
+
+    // kick carol
+    assert!(handle
+        .set_chain_commit_group_tip_policy(id, GroupTipPolicy::All)
+        .unwrap());
+    assert!(handle
+        .remove_member_from_chain_commit_group(id, carol_genesis)
+        .unwrap());
+    assert!(!handle
+        .remove_member_from_chain_commit_group(id, carol_genesis)
+        .unwrap());
+    assert_eq!(
+        tips_of_next(&mut handle, &host, false),
+        [bob_tip].into_iter().collect()
+    );
+    assert!(handle
+        .get_chain_commit_group_memberships_for_key(carol.x_only_public_key().0)
+        .unwrap()
+        .is_empty());
+
+    assert!(handle.rename_chain_commit_group(id, "game-over").unwrap());
+    assert!(handle
+        .get_chain_commit_group_by_name("game")
+        .unwrap()
+        .is_none());
+    let group = handle
+        .get_chain_commit_group_by_name("game-over")
+        .unwrap()
+        .unwrap();
+    assert_eq!(group.id, id);
+    assert_eq!(group.tip_policy, GroupTipPolicy::All);
+    handle.new_chain_commit_group(Some("taken".into())).unwrap();
+    assert!(handle.rename_chain_commit_group(id, "taken").is_err());
+
+    assert!(handle
+        .remove_subscriber_from_chain_commit_group(id, host_genesis)
+        .unwrap());
+    assert!(tips_of_next(&mut handle, &host, false).is_empty());
+}
+
+#[test(tokio::test)]
+async fn test_peer_acknowledgements() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let kp = make_test_user(&secp, &mut handle, "TestUser".into());
+    let mut envelopes = vec![handle
+        .get_tip_for_user_by_key::<WrappedJson>(kp.x_only_public_key().0)
+        .unwrap()];
+    for _ in 0..3 {
+        let e = handle
+            .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                CanonicalJsonValue::Null,
+                &kp,
+                &secp,
+                None,
+                None,
+                TipControl::NoTips,
+            )
+            .unwrap()
+            .unwrap()
+            .self_authenticate(&secp)
+            .unwrap();
+        handle
+            .try_insert_authenticated_envelope(e.clone(), false)
+            .unwrap()
+            .unwrap();
+        envelopes.push(e);
+    }
+    let genesis = envelopes[0].get_genesis_hash();
+    handle
+        .insert_hidden_service("peer-a".into(), 1, false, true, false)
+        .unwrap();
+    handle
+        .insert_hidden_service("peer-b".into(), 2, false, true, false)
+        .unwrap();
+    // unknown services can't acknowledge anything
+    assert!(handle
+        .upsert_peer_acknowledgement("peer-c", 3, genesis, 0, genesis)
+        .is_err());
+
+    let ack = |handle: &MsgDBHandle, url: &str, port: u16, i: usize| {
+        handle
+            .upsert_peer_acknowledgement(
+                url,
+                port,
+                genesis,
+                envelopes[i].header().height(),
+                envelopes[i].canonicalized_hash_ref(),
+            )
+            .unwrap()
+    };
+    ack(&handle, "peer-a", 1, 2);
+    // stale acknowledgements do not move the high-water mark backwards
+    ack(&handle, "peer-a", 1, 1);
+    let acks = handle.get_peer_acknowledgements("peer-a", 1).unwrap();
+    assert_eq!(acks.len(), 1);
+    assert_eq!(acks[0].genesis, genesis);
+    assert_eq!(acks[0].height, 2);
+    assert_eq!(acks[0].hash, envelopes[2].canonicalized_hash_ref());
+    assert!(handle
+        .get_peer_acknowledgements("peer-b", 2)
+        .unwrap()
+        .is_empty());
+
+    let status = handle.get_replication_status_for_chain(genesis).unwrap();
+    assert_eq!(status.len(), 2);
+    for peer in &status {
+        match peer.service_url.as_str() {
+            "peer-a" => assert_eq!(
+                peer.acknowledged,
+                Some((2, envelopes[2].canonicalized_hash_ref()))
+            ),
+            "peer-b" => assert_eq!(peer.acknowledged, None),
+            _ => panic!("Unexpected Peer"),
+        }
+    }
+    ack(&handle, "peer-b", 2, 3);
+    let status = handle.get_replication_status_for_chain(genesis).unwrap();
+    assert!(status.iter().all(|p| p.acknowledged.is_some()));
+}
+
+#[test(tokio::test)]
+async fn test_chain_visibility() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let public = make_test_user(&secp, &mut handle, "Public".into());
+    let team = make_test_user(&secp, &mut handle, "Team".into());
+    let genesis_of = |handle: &MsgDBHandle, kp: &KeyPair| {
+        handle
+            .get_tip_for_user_by_key::<WrappedJson>(kp.x_only_public_key().0)
+            .unwrap()
+            .get_genesis_hash()
+    };
+    let public = genesis_of(&handle, &public);
+    let team = genesis_of(&handle, &team);
+    handle
+        .insert_hidden_service("teammate".into(), 1, false, true, false)
+        .unwrap();
+    handle
+        .insert_hidden_service("opponent".into(), 2, false, true, false)
+        .unwrap();
+
+    assert_eq!(
+        handle.get_chain_visibility(team).unwrap(),
+        ChainVisibility::Public
+    );
+    assert!(handle
+        .get_chains_hidden_from("opponent", 2)
+        .unwrap()
+        .is_empty());
+
+    let policy = ChainVisibility::Private {
+        allowed: vec![("teammate".into(), 1)],
+    };
+    handle.set_chain_visibility(team, &policy).unwrap();
+    assert_eq!(handle.get_chain_visibility(team).unwrap(), policy);
+    assert_eq!(
+        handle.get_chain_visibility(public).unwrap(),
+        ChainVisibility::Public
+    );
+    assert!(handle
+        .get_chains_hidden_from("teammate", 1)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        handle.get_chains_hidden_from("opponent", 2).unwrap(),
+        [team].into_iter().collect()
+    );
+    // only allowed push peers count towards replication
+    let status = handle.get_replication_status_for_chain(team).unwrap();
+    assert_eq!(status.len(), 1);
+    assert_eq!(status[0].service_url, "teammate");
+
+    // unknown peers can't be allowed, and the old policy is kept
+    assert!(handle
+        .set_chain_visibility(
+            team,
+            &ChainVisibility::Private {
+                allowed: vec![("stranger".into(), 3)],
+            },
+        )
+        .is_err());
+    assert_eq!(handle.get_chain_visibility(team).unwrap(), policy);
+
+    // private with nobody allowed hides from everyone
+    let nobody = ChainVisibility::Private { allowed: vec![] };
+    handle.set_chain_visibility(team, &nobody).unwrap();
+    assert_eq!(handle.get_chain_visibility(team).unwrap(), nobody);
+    assert!(handle
+        .get_chains_hidden_from("teammate", 1)
+        .unwrap()
+        .contains(&team));
+
+    handle
+        .set_chain_visibility(team, &ChainVisibility::Public)
+        .unwrap();
+    assert_eq!(
+        handle.get_chain_visibility(team).unwrap(),
+        ChainVisibility::Public
+    );
+    assert!(handle
+        .get_chains_hidden_from("opponent", 2)
+        .unwrap()
+        .is_empty());
+}
+
+#[test(tokio::test)]
+async fn test_sync_keys() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let mut expected = vec![];
+    for u in 0..3 {
+        let kp = make_test_user(&secp, &mut handle, format!("u-{}", u));
+        let genesis = handle
+            .get_tip_for_user_by_key::<WrappedJson>(kp.x_only_public_key().0)
+            .unwrap();
+        expected.push((
+            genesis.get_genesis_hash(),
+            0,
+            genesis.canonicalized_hash_ref(),
+        ));
+        for _ in 0..u {
+            let e = handle
+                .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                    CanonicalJsonValue::Null,
+                    &kp,
+                    &secp,
+                    None,
+                    None,
+                    TipControl::NoTips,
+                )
+                .unwrap()
+                .unwrap()
+                .self_authenticate(&secp)
+                .unwrap();
+            handle
+                .try_insert_authenticated_envelope(e.clone(), false)
+                .unwrap()
+                .unwrap();
+            expected.push((
+                e.get_genesis_hash(),
+                e.header().height(),
+                e.canonicalized_hash_ref(),
+            ));
+        }
+    }
+    expected.sort();
+    assert_eq!(handle.get_all_sync_keys().unwrap(), expected);
+}
+
+#[test(tokio::test)]
+async fn test_ancestry_proofs() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let kp = make_test_user(&secp, &mut handle, "prover".into());
+    let genesis = handle
+        .get_tip_for_user_by_key::<WrappedJson>(kp.x_only_public_key().0)
+        .unwrap();
+    let mut chain = vec![genesis.canonicalized_hash_ref()];
+    for _ in 0..20 {
+        let e = handle
+            .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                CanonicalJsonValue::Null,
+                &kp,
+                &secp,
+                None,
+                None,
+                TipControl::NoTips,
+            )
+            .unwrap()
+            .unwrap()
+            .self_authenticate(&secp)
+            .unwrap();
+        assert!(e.header().ancestry().well_formed_for(e.header().height()));
+        handle
+            .try_insert_authenticated_envelope(e.clone(), false)
+            .unwrap()
+            .unwrap();
+        chain.push(e.canonicalized_hash_ref());
+    }
+    let tip = *chain.last().unwrap();
+    assert_eq!(
+        handle.get_chain_ending_at(tip).unwrap(),
+        Some(chain.clone())
+    );
+    for h in &chain {
+        assert_eq!(
+            handle.check_ancestry_commitment::<WrappedJson>(*h).unwrap(),
+            Some(true)
+        );
+    }
+    for (height, ancestor) in chain[..chain.len() - 1].iter().enumerate() {
+        let proof = handle
+            .get_ancestry_proof::<WrappedJson>(*ancestor, tip)
+            .unwrap()
+            .unwrap();
+        assert_eq!(proof.ancestor_height, height as i64);
+        proof.verify(&secp).unwrap();
+    }
+    // not an ancestor of itself, and not of an earlier envelope
+    assert!(handle
+        .get_ancestry_proof::<WrappedJson>(tip, tip)
+        .unwrap()
+        .is_none());
+    assert!(handle
+        .get_ancestry_proof::<WrappedJson>(chain[10], chain[5])
+        .unwrap()
+        .is_none());
+}
+
+#[test(tokio::test)]
+async fn test_key_rotation() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let kp = make_test_user(&secp, &mut handle, "rotator".into());
+    let [rotated, sub] = [(); 2].map(|_| {
+        let k = KeyPair::new(&secp, &mut thread_rng());
+        handle.save_keypair(k).unwrap();
+        k
+    });
+    let pk = |k: &KeyPair| k.x_only_public_key().0;
+    let genesis = handle
+        .get_tip_for_user_by_key::<WrappedJson>(pk(&kp))
+        .unwrap()
+        .canonicalized_hash_ref();
+    let post = |handle: &mut MsgDBHandle, k: &KeyPair, grant: Option<KeyGrant>| {
+        let e = match grant {
+            Some(g) => handle.wrap_grant_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                CanonicalJsonValue::Null,
+                k,
+                &secp,
+                None,
+                g,
+            ),
+            None => handle.wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                CanonicalJsonValue::Null,
+                k,
+                &secp,
+                None,
+                None,
+                TipControl::NoTips,
+            ),
+        }
+        .unwrap()
+        .unwrap()
+        .self_authenticate(&secp)
+        .unwrap();
+        handle.try_insert_authenticated_envelope(e, false).unwrap()
+    };
+
+    post(&mut handle, &kp, None).unwrap();
+    assert_eq!(handle.get_root_key_at(genesis, 2).unwrap(), Some(pk(&kp)));
+    post(
+        &mut handle,
+        &kp,
+        Some(KeyGrant::Rotate { to: pk(&rotated) }),
+    )
+    .unwrap();
+    assert_eq!(handle.get_root_key_at(genesis, 2).unwrap(), Some(pk(&kp)));
+    assert_eq!(
+        handle.get_root_key_at(genesis, 3).unwrap(),
+        Some(pk(&rotated))
+    );
+
+    // the old key is done, and drops out of the keymap
+    assert!(matches!(
+        post(&mut handle, &kp, None),
+        Err((SqliteFail::SqliteConstraintCheck, _))
+    ));
+    let keymap = handle.get_keymap().unwrap();
+    assert!(!keymap.contains_key(&pk(&kp)));
+    assert!(keymap.contains_key(&pk(&rotated)));
+
+    // the new key continues the same chain
+    post(&mut handle, &rotated, None).unwrap();
+    let tip = handle
+        .get_tip_for_user_by_key::<WrappedJson>(pk(&rotated))
+        .unwrap();
+    assert_eq!(tip.get_genesis_hash(), genesis);
+    assert_eq!(tip.header().key(), pk(&rotated));
+
+    // delegate to a sub-key, which may post but not grant
+    post(
+        &mut handle,
+        &rotated,
+        Some(KeyGrant::Delegate {
+            to: pk(&sub),
+            until_height: None,
+        }),
+    )
+    .unwrap();
+    post(&mut handle, &sub, None).unwrap();
+    assert_eq!(
+        handle.get_chain_role(genesis, pk(&sub), 6).unwrap(),
+        Some(Role::Delegate)
+    );
+    assert!(matches!(
+        post(&mut handle, &sub, Some(KeyGrant::Rotate { to: pk(&kp) })),
+        Err((SqliteFail::SqliteConstraintCheck, _))
+    ));
+
+    // and revoke it again
+    post(
+        &mut handle,
+        &rotated,
+        Some(KeyGrant::Revoke { key: pk(&sub) }),
+    )
+    .unwrap();
+    assert!(matches!(
+        post(&mut handle, &sub, None),
+        Err((SqliteFail::SqliteConstraintCheck, _))
+    ));
+    assert_eq!(handle.get_chain_role(genesis, pk(&sub), 8).unwrap(), None);
+    assert!(!handle.get_keymap().unwrap().contains_key(&pk(&sub)));
+    post(&mut handle, &rotated, None).unwrap();
+}
+
+#[test(tokio::test)]
+async fn test_encrypted_payloads() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let alice = make_test_user(&secp, &mut handle, "alice".into());
+    let bob = make_test_user(&secp, &mut handle, "bob".into());
+    let stranger = KeyPair::new(&secp, &mut thread_rng()).x_only_public_key().0;
+    let offer = CanonicalJsonValue::String("I'll trade 10 ASIC for 1 Bitcoin".into());
+
+    let mut post = |to: &[sapio_bitcoin::XOnlyPublicKey]| {
+        let payload =
+            EncryptedPayload::seal(&secp, alice.x_only_public_key().0, to, &offer).unwrap();
+        let e = handle
+            .wrap_message_in_envelope_for_user_by_key::<_, EncryptedPayload, _>(
+                payload,
+                &alice,
+                &secp,
+                None,
+                None,
+                TipControl::NoTips,
+            )
+            .unwrap()
+            .unwrap()
+            .self_authenticate(&secp)
+            .unwrap();
+        handle
+            .try_insert_authenticated_envelope(e.clone(), false)
+            .unwrap()
+            .unwrap();
+        e.canonicalized_hash_ref()
+    };
+    let to_bob = post(&[bob.x_only_public_key().0]);
+    let to_stranger = post(&[stranger]);
+    drop(post);
+
+    assert_eq!(
+        handle
+            .get_decrypted_message_by_hash(&secp, to_bob)
+            .unwrap()
+            .unwrap(),
+        offer
+    );
+    assert!(matches!(
+        handle
+            .get_decrypted_message_by_hash(&secp, to_stranger)
+            .unwrap(),
+        Err(EncryptionError::NotARecipient)
+    ));
+    // the plaintext never reaches the database
+    let body = handle
+        .messages_by_hash::<_, Authenticated<Envelope>, WrappedJson>([to_bob].iter())
+        .unwrap();
+    assert!(!body[0].msg().as_ref().to_string().contains("ASIC"));
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Eq, PartialEq)]
+struct Note {
+    text: String,
+}
+impl MessageType for Note {
+    const TAG: &'static str = "test-note";
+    const VERSION: u32 = 1;
+}
+
+#[test(tokio::test)]
+async fn test_registered_messages_validated_on_insert() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let kp = make_test_user(&secp, &mut handle, "alice".into());
+    registry::register::<Note>();
+
+    let mut post = |msg: CanonicalJsonValue| {
+        let e = handle
+            .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                msg,
+                &kp,
+                &secp,
+                None,
+                None,
+                TipControl::NoTips,
+            )
+            .unwrap()
+            .unwrap()
+            .self_authenticate(&secp)
+            .unwrap();
+        handle
+            .try_insert_authenticated_envelope(e.clone(), false)
+            .unwrap()
+            .map(|()| e.canonicalized_hash_ref())
+    };
+    let tagged = |version: u32, body: serde_json::Value| {
+        CanonicalJsonValue::try_from(serde_json::json!({
+            "@type": "test-note", "@version": version, "@body": body
+        }))
+        .unwrap()
+    };
+
+    // a wrong field type is reported with its path
+    match post(tagged(1, serde_json::json!({"text": 5}))) {
+        Err((SqliteFail::SqliteConstraintCheck, Some(msg))) => assert!(msg.contains("/text")),
+        e => panic!("expected a check failure, got {:?}", e),
+    }
+    // versions older than this node knows are refused, newer ones may be
+    // valid for their sender and are stored like unregistered types
+    assert!(matches!(
+        post(tagged(0, serde_json::json!({"text": "hi"}))),
+        Err((SqliteFail::SqliteConstraintCheck, _))
+    ));
+    post(tagged(2, serde_json::json!({"text": 5, "mood": "happy"}))).unwrap();
+    // unregistered types and untagged messages are stored as before
+    post(
+        CanonicalJsonValue::try_from(serde_json::json!({
+            "@type": "unknown-note", "@version": 7, "@body": {}
+        }))
+        .unwrap(),
+    )
+    .unwrap();
+    post(CanonicalJsonValue::String("untagged".into())).unwrap();
+
+    let hash = post(tagged(1, serde_json::json!({"text": "hi"}))).unwrap();
+    drop(post);
+    let notes = handle
+        .messages_by_hash::<_, Authenticated<GenericEnvelope<Versioned<Note>>>, Versioned<Note>>(
+            [hash].iter(),
+        )
+        .unwrap();
+    assert_eq!(notes[0].msg().text, "hi");
+}
+
+#[test(tokio::test)]
+async fn test_group_chain() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let members: Vec<KeyPair> = (0..3)
+        .map(|_| {
+            let k = KeyPair::new(&secp, &mut thread_rng());
+            handle.save_keypair(k).unwrap();
+            k
+        })
+        .collect();
+    let group = SigningGroup::new(
+        &secp,
+        members.iter().map(|k| k.x_only_public_key().0).collect(),
+    )
+    .unwrap();
+    handle.save_signing_group(&group).unwrap();
+    assert_eq!(
+        handle.get_signing_group(&secp, group.key()).unwrap(),
+        Some(group.clone())
+    );
+    let fresh_nonce = |handle: &mut MsgDBHandle| {
+        let shares = members
+            .iter()
+            .map(|k| {
+                let m = k.x_only_public_key().0;
+                (
+                    m,
+                    handle
+                        .generate_fresh_nonce_for_user_by_key(&secp, m)
+                        .unwrap(),
+                )
+            })
+            .collect();
+        let nonce = group.aggregate_nonce(shares).unwrap();
+        handle.save_group_nonce(&group, &nonce).unwrap();
+        nonce
+    };
+    let sign =
+        |handle: &MsgDBHandle, e: &Envelope, genesis_nonce: Option<PrecomittedPublicNonce>| {
+            for k in &members {
+                let partial = handle
+                    .partially_sign_group_envelope(&secp, &group, e, k, genesis_nonce)
+                    .unwrap()
+                    .unwrap();
+                handle
+                    .add_group_partial_signature(
+                        &secp,
+                        &group,
+                        e,
+                        k.x_only_public_key().0,
+                        partial,
+                        genesis_nonce,
+                    )
+                    .unwrap()
+                    .unwrap();
+            }
+            handle
+                .finish_group_envelope(&secp, &group, e.clone(), genesis_nonce)
+                .unwrap()
+        };
+
+    let genesis_nonce = fresh_nonce(&mut handle);
+    let next = fresh_nonce(&mut handle);
+    let genesis: Envelope = handle
+        .new_group_envelope(&group, CanonicalJsonValue::Null, &next, None)
+        .unwrap();
+    // nothing is committed for a genesis unless we say so
+    assert!(matches!(
+        handle.finish_group_envelope(&secp, &group, genesis.clone(), None),
+        Ok(Err(GroupError::UncommittedNonce))
+    ));
+    let genesis = sign(&handle, &genesis, Some(genesis_nonce.public()))
+        .unwrap()
+        .self_authenticate(&secp)
+        .unwrap();
+    handle
+        .insert_user_by_genesis_envelope("group".into(), genesis.clone())
+        .unwrap()
+        .unwrap();
+
+    let after = fresh_nonce(&mut handle);
+    let e1: Envelope = handle
+        .new_group_envelope(&group, CanonicalJsonValue::String("a".into()), &after, None)
+        .unwrap();
+    let e1b: Envelope = handle
+        .new_group_envelope(&group, CanonicalJsonValue::String("b".into()), &after, None)
+        .unwrap();
+    // a partial signature alone is not enough
+    let first = handle
+        .partially_sign_group_envelope(&secp, &group, &e1, &members[0], None)
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        handle.finish_group_envelope(&secp, &group, e1.clone(), None),
+        Ok(Err(GroupError::MissingPartialSignature(_)))
+    ));
+    let e1 = sign(&handle, &e1, None).unwrap();
+    assert_eq!(
+        handle
+            .partially_sign_group_envelope(&secp, &group, &e1, &members[0], None)
+            .unwrap(),
+        Ok(first)
+    );
+    let e1 = e1.self_authenticate(&secp).unwrap();
+    handle
+        .try_insert_authenticated_envelope(e1.clone(), false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        handle
+            .get_tip_for_user_by_key::<WrappedJson>(group.key())
+            .unwrap()
+            .canonicalized_hash_ref(),
+        e1.canonicalized_hash_ref()
+    );
+
+    // members refuse to sign a conflicting envelope with the same nonce...
+    for k in &members {
+        assert_eq!(
+            handle
+                .partially_sign_group_envelope(&secp, &group, &e1b, k, None)
+                .unwrap(),
+            Err(GroupError::NonceAlreadyUsed)
+        );
+    }
+    // ...because doing so would leak the group's key
+    let mut e1b = e1b;
+    let digest = Message::from(e1b.signature_digest_mut());
+    let nonce = handle
+        .get_group_nonce(&group, genesis.header().next_nonce())
+        .unwrap()
+        .unwrap();
+    let partials = members
+        .iter()
+        .map(|k| {
+            let member = k.x_only_public_key().0;
+            let secret = handle
+                .get_secret_for_public_nonce(nonce.shares()[&member])
+                .unwrap();
+            let partial = group
+                .sign_partial(&secp, &nonce, &digest, k, secret)
+                .unwrap();
+            (member, partial)
+        })
+        .collect();
+    group
+        .sign_envelope(&secp, &mut e1b, &nonce, &partials)
+        .unwrap();
+    let e1b = e1b.self_authenticate(&secp).unwrap();
+    let k = extract_sk_from_envelopes(e1, e1b).expect("Extract successful");
+    assert_eq!(k.keypair(&secp).x_only_public_key().0, group.key());
+}
+
+#[test(tokio::test)]
+async fn test_binary_storage() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    assert_eq!(
+        handle.get_storage_encoding().unwrap(),
+        StorageEncoding::Json
+    );
+    let kp = make_test_user(&secp, &mut handle, "mixed".into());
+    let mut inserted = vec![];
+    for i in 0..6 {
+        let encoding = if i % 2 == 0 {
+            StorageEncoding::Binary
+        } else {
+            StorageEncoding::Json
+        };
+        handle.set_storage_encoding(encoding).unwrap();
+        assert_eq!(handle.get_storage_encoding().unwrap(), encoding);
+        let e = handle
+            .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                CanonicalJsonValue::String(format!("message {}", i)),
+                &kp,
+                &secp,
+                None,
+                None,
+                TipControl::NoTips,
+            )
+            .unwrap()
+            .unwrap()
+            .self_authenticate(&secp)
+            .unwrap();
+        handle
+            .try_insert_authenticated_envelope(e.clone(), false)
+            .unwrap()
+            .unwrap();
+        let stored_as: String = handle
+            .0
+            .query_row(
+                "SELECT typeof(body) FROM messages WHERE hash = ?",
+                [e.canonicalized_hash_ref()],
+                |r| r.get(0),
+            )
+            .unwrap();
+        match encoding {
+            StorageEncoding::Binary => assert_eq!(stored_as, "blob"),
+            StorageEncoding::Json => assert_eq!(stored_as, "text"),
+        }
+        inserted.push(e.inner());
+    }
+    // both encodings read back to the same envelope, with the same hash
+    let hashes: Vec<_> = inserted
+        .iter()
+        .map(|e| e.canonicalized_hash_ref())
+        .collect();
+    let read: Vec<Authenticated<Envelope>> = handle.messages_by_hash(hashes.iter()).unwrap();
+    for (r, e) in read.iter().zip(inserted.iter()) {
+        assert_eq!(r.inner_ref(), e);
+        assert_eq!(r.canonicalized_hash_ref(), e.canonicalized_hash_ref());
+    }
+    // the chain stays connected across encodings
+    let all: Vec<Authenticated<Envelope>> = handle
+        .load_all_messages_for_user_by_key_connected(&kp.x_only_public_key().0)
+        .unwrap();
+    assert_eq!(all.len(), inserted.len() + 1);
+}
+
+#[test(tokio::test)]
+async fn test_binary_body_checked() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let kp = make_test_user(&secp, &mut handle, "checked".into());
+    let mut envelopes = vec![];
+    for i in 0..2 {
+        let e = handle
+            .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                CanonicalJsonValue::String(format!("message {}", i)),
+                &kp,
+                &secp,
+                None,
+                None,
+                TipControl::NoTips,
+            )
+            .unwrap()
+            .unwrap();
+        handle
+            .try_insert_authenticated_envelope(e.clone().self_authenticate(&secp).unwrap(), false)
+            .unwrap()
+            .unwrap();
+        envelopes.push(e);
+    }
+    let body = envelopes[0].to_binary().unwrap();
+    assert!(check_binary_body(&envelopes[0], &body).unwrap());
+    // another envelope's body, or a damaged one, never passes for this one
+    let other = envelopes[1].to_binary().unwrap();
+    assert!(!check_binary_body(&envelopes[0], &other).unwrap());
+    assert!(!check_binary_body(&envelopes[0], &body[..body.len() - 1]).unwrap());
+    let mut flipped = body.clone();
+    *flipped.last_mut().unwrap() ^= 1;
+    assert!(!check_binary_body(&envelopes[0], &flipped).unwrap());
+}
+
+#[test(tokio::test)]
+async fn test_format_versions() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let kp = make_test_user(&secp, &mut handle, "versions".into());
+    let key = kp.x_only_public_key().0;
+    let stored_version = |handle: &MsgDBHandle, e: &Authenticated<Envelope>| -> u32 {
+        handle
+            .0
+            .query_row(
+                "SELECT format_version FROM messages WHERE hash = ?",
+                [e.canonicalized_hash_ref()],
+                |r| r.get(0),
+            )
+            .unwrap()
+    };
+
+    // an envelope as made before versions were introduced
+    let genesis = handle.get_tip_for_user_by_key::<WrappedJson>(key).unwrap();
+    let mut legacy = Envelope::new(
+        Header::new(
+            key,
+            handle
+                .generate_fresh_nonce_for_user_by_key(&secp, key)
+                .unwrap(),
+            Some(Ancestors::new(
+                genesis.canonicalized_hash_ref(),
+                genesis.get_genesis_hash(),
+            )),
+            vec![],
+            1,
+            attest_util::now(),
+            Unsigned::new(None),
+            Default::default(),
+            Default::default(),
+        )
+        .with_version(FormatVersion::LEGACY),
+        WrappedJson::from(CanonicalJsonValue::Null),
+    );
+    let secret = handle
+        .get_secret_for_public_nonce(genesis.header().next_nonce())
+        .unwrap();
+    legacy.sign_with(&kp, &secp, secret).unwrap();
+    assert!(serde_json::to_value(&legacy).unwrap()["header"]
+        .get("version")
+        .is_none());
+    let legacy = legacy.self_authenticate(&secp).unwrap();
+    handle
+        .try_insert_authenticated_envelope(legacy.clone(), false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored_version(&handle, &legacy), FormatVersion::LEGACY.0);
+
+    // new envelopes are made at the lowest version covering what they use,
+    // and still chain on
+    let current = handle
+        .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+            CanonicalJsonValue::Null,
+            &kp,
+            &secp,
+            None,
+            None,
+            TipControl::NoTips,
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(current.header().version(), FormatVersion::V1);
+    assert_eq!(
+        serde_json::to_value(&current).unwrap()["header"]["version"],
+        FormatVersion::V1.0
+    );
+    let current = current.self_authenticate(&secp).unwrap();
+    handle
+        .try_insert_authenticated_envelope(current.clone(), false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored_version(&handle, &current), FormatVersion::V1.0);
+
+    // both read back and verify as they were
+    let read: Vec<Envelope> = handle
+        .messages_by_hash(
+            [
+                legacy.canonicalized_hash_ref(),
+                current.canonicalized_hash_ref(),
+            ]
+            .iter(),
+        )
+        .unwrap();
+    assert_eq!(read[0].header().version(), FormatVersion::LEGACY);
+    assert_eq!(read[1].header().version(), FormatVersion::V1);
+    for e in read {
+        e.self_authenticate(&secp).unwrap();
+    }
+
+    // the version is signed for...
+    let downgraded = Envelope::new(
+        current.header().clone().with_version(FormatVersion::LEGACY),
+        current.clone().inner().into_msg(),
+    );
+    assert!(matches!(
+        downgraded.self_authenticate(&secp),
+        Err(AuthenticationError::ValidationError(_))
+    ));
+    // ...and versions from the future are refused outright
+    let future = FormatVersion(FormatVersion::CURRENT.0 + 1);
+    let upgraded = Envelope::new(
+        current.header().clone().with_version(future),
+        current.clone().inner().into_msg(),
+    );
+    assert!(matches!(
+        upgraded.self_authenticate(&secp),
+        Err(AuthenticationError::UnsupportedVersion(v)) if v == future
+    ));
+}
+
+#[test(tokio::test)]
+async fn test_blob_attachments() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let kp = make_test_user(&secp, &mut handle, "attacher".into());
+    let contract = b"compiled contract bytes".to_vec();
+    let psbt = b"a psbt we only have a reference to".to_vec();
+    let contract_ref = handle.save_blob(&contract).unwrap().unwrap();
+    let psbt_ref = BlobRef::of(&psbt).unwrap();
+
+    let e = handle
+        .wrap_attachments_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+            CanonicalJsonValue::Null,
+            &kp,
+            &secp,
+            None,
+            vec![contract_ref, psbt_ref],
+        )
+        .unwrap()
+        .unwrap()
+        .self_authenticate(&secp)
+        .unwrap();
+    assert_eq!(e.header().attachments(), &[contract_ref, psbt_ref][..]);
+    // attachments are the only reason for V2
+    assert_eq!(e.header().version(), FormatVersion::V2);
+    handle
+        .try_insert_authenticated_envelope(e.clone(), false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        handle.get_missing_blobs(MAX_BLOB_SIZE, 10).unwrap(),
+        vec![psbt_ref]
+    );
+    // too large to bother fetching
+    assert!(handle
+        .get_missing_blobs(psbt_ref.size - 1, 10)
+        .unwrap()
+        .is_empty());
+
+    // fetched attachments are checked against the reference
+    assert!(matches!(
+        handle.save_fetched_blob(&psbt_ref, &contract).unwrap(),
+        Err(BlobError::SizeMismatch { .. })
+    ));
+    let mut tampered = psbt.clone();
+    tampered[0] ^= 1;
+    assert!(matches!(
+        handle.save_fetched_blob(&psbt_ref, &tampered).unwrap(),
+        Err(BlobError::HashMismatch { .. })
+    ));
+    // rejected attachments are still missing
+    assert_eq!(
+        handle.get_missing_blobs(MAX_BLOB_SIZE, 10).unwrap(),
+        vec![psbt_ref]
+    );
+    handle.save_fetched_blob(&psbt_ref, &psbt).unwrap().unwrap();
+    assert!(handle
+        .get_missing_blobs(MAX_BLOB_SIZE, 10)
+        .unwrap()
+        .is_empty());
+    assert_eq!(handle.get_blob(psbt_ref.hash).unwrap(), Some(psbt));
+    assert_eq!(handle.get_blob(contract_ref.hash).unwrap(), Some(contract));
+    assert_eq!(
+        handle.get_blob_size(psbt_ref.hash).unwrap(),
+        Some(psbt_ref.size as u64)
+    );
+
+    // attachments of private chains are only served to those who may see them
+    handle
+        .insert_hidden_service("opponent".into(), 2, false, true, false)
+        .unwrap();
+    assert!(handle
+        .get_blob_visible_to(contract_ref.hash, "opponent", 2)
+        .unwrap()
+        .is_some());
+    handle
+        .set_chain_visibility(
+            e.get_genesis_hash(),
+            &ChainVisibility::Private { allowed: vec![] },
+        )
+        .unwrap();
+    assert!(handle
+        .get_blob_visible_to(contract_ref.hash, "opponent", 2)
+        .unwrap()
+        .is_none());
+    // and never for blobs no envelope references
+    let loose = handle.save_blob(b"unreferenced").unwrap().unwrap();
+    assert!(handle.get_blob(loose.hash).unwrap().is_some());
+    assert!(handle
+        .get_blob_visible_to(loose.hash, "opponent", 2)
+        .unwrap()
+        .is_none());
+}
+
+#[test(tokio::test)]
+async fn test_anchors() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    for i in 0..3 {
+        make_test_user(&secp, &mut handle, format!("anchored-{}", i));
+    }
+    let mut leaves: Vec<CanonicalEnvelopeHash> = handle
+        .get_tips_for_all_users::<Envelope, WrappedJson>()
+        .unwrap()
+        .iter()
+        .map(|e| e.canonicalized_hash_ref())
+        .collect();
+    leaves.sort_unstable();
+    let root = merkle_root(&leaves).unwrap();
+    let tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![],
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: anchor_script(&root),
+        }],
+    };
+    let txid = tx.txid();
+
+    // the transaction has to commit to exactly these leaves
+    assert_eq!(
+        handle.save_anchor(&leaves[1..], &tx).unwrap(),
+        Err(AnchorError::NotCommitted)
+    );
+    assert!(!handle.is_anchored(leaves[0]).unwrap());
+    handle.save_anchor(&leaves, &tx).unwrap().unwrap();
+    assert!(leaves.iter().all(|l| handle.is_anchored(*l).unwrap()));
+    assert_eq!(handle.get_unconfirmed_anchors().unwrap(), vec![txid]);
+    assert_eq!(handle.get_anchor_proof(leaves[0]).unwrap(), None);
+
+    let mut block = Block {
+        header: BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: Default::default(),
+            time: 0,
+            bits: 0,
+            nonce: 0,
+        },
+        txdata: vec![tx],
+    };
+    block.header.merkle_root = block.compute_merkle_root().unwrap();
+    let tx_proof = MerkleBlock::from_block_with_predicate(&block, |t| *t == txid);
+    let other = Transaction {
+        version: 1,
+        ..block.txdata[0].clone()
+    };
+    assert_eq!(
+        handle.confirm_anchor(other.txid(), &tx_proof, 100).unwrap(),
+        Err(AnchorError::TxNotInBlock)
+    );
+    handle
+        .confirm_anchor(txid, &tx_proof, 100)
+        .unwrap()
+        .unwrap();
+    assert!(handle.get_unconfirmed_anchors().unwrap().is_empty());
+    for l in &leaves {
+        let proof = handle.get_anchor_proof(*l).unwrap().unwrap();
+        assert_eq!(proof.envelope, *l);
+        assert_eq!(proof.block_hash(), block.block_hash());
+        proof.verify().unwrap();
+    }
+
+    // confirmed anchors can't be abandoned...
+    handle.abandon_anchor(txid).unwrap();
+    assert!(handle.get_anchor_proof(leaves[0]).unwrap().is_some());
+    // ...but unconfirmed ones free their envelopes up to be anchored again
+    let single = &leaves[..1];
+    let root = merkle_root(single).unwrap();
+    let retry = Transaction {
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: anchor_script(&root),
+        }],
+        ..other
+    };
+    handle.save_anchor(single, &retry).unwrap().unwrap();
+    assert_eq!(
+        handle.get_unconfirmed_anchors().unwrap(),
+        vec![retry.txid()]
+    );
+    handle.abandon_anchor(retry.txid()).unwrap();
+    assert!(handle.get_unconfirmed_anchors().unwrap().is_empty());
+    assert!(handle.is_anchored(leaves[0]).unwrap());
+}
+
+#[test(tokio::test)]
+async fn test_archive() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let dir = std::env::temp_dir().join(format!("attest-archive-{}", thread_rng().gen::<u64>()));
+    let kp = make_test_user(&secp, &mut handle, "archived".into());
+    let genesis = handle
+        .get_tip_for_user_by_key::<WrappedJson>(kp.x_only_public_key().0)
+        .unwrap()
+        .canonicalized_hash_ref();
+    let extend = |handle: &mut MsgDBHandle, n: usize| -> Vec<Authenticated<Envelope>> {
+        (0..n)
+            .map(|_| {
+                let e = handle
+                    .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                        CanonicalJsonValue::Null,
+                        &kp,
+                        &secp,
+                        None,
+                        None,
+                        TipControl::NoTips,
+                    )
+                    .unwrap()
+                    .unwrap()
+                    .self_authenticate(&secp)
+                    .unwrap();
+                handle
+                    .try_insert_authenticated_envelope(e.clone(), false)
+                    .unwrap()
+                    .unwrap();
+                e
+            })
+            .collect()
+    };
+    let mut chain = extend(&mut handle, 10);
+
+    // nothing is due yet
+    assert!(handle
+        .get_archive_candidates(0, true, 3)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        handle.get_archive_candidates(i64::MAX, false, 3).unwrap(),
+        vec![genesis]
+    );
+    let group = handle.new_chain_commit_group(None).unwrap();
+    handle
+        .add_member_to_chain_commit_group(group.1, genesis)
+        .unwrap();
+    handle.finish_chain_commit_group(&group.0).unwrap();
+    assert_eq!(
+        handle.get_archive_candidates(0, true, 3).unwrap(),
+        vec![genesis]
+    );
+
+    // heights 1 through 7 go, 8 is the checkpoint
+    let archive = handle
+        .archive_chain::<WrappedJson>(genesis, 3, &dir)
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!((archive.first_height, archive.last_height), (1, 7));
+    assert_eq!(archive.checkpoint, chain[7].canonicalized_hash_ref());
+    assert_eq!(handle.get_archived_chains().unwrap(), vec![archive.clone()]);
+    for e in &chain[..7] {
+        assert!(matches!(
+            handle.messages_by_hash::<_, Envelope, WrappedJson>(std::iter::once(
+                &e.canonicalized_hash_ref()
+            )),
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        ));
+    }
+    assert!(handle
+        .get_archive_candidates(i64::MAX, true, 3)
+        .unwrap()
+        .is_empty());
+    assert!(handle
+        .archive_chain::<WrappedJson>(genesis, 3, &dir)
+        .unwrap()
+        .unwrap()
+        .is_none());
+
+    // archived envelopes don't come back in through sync...
+    assert!(matches!(
+        handle
+            .try_insert_authenticated_envelope(chain[3].clone(), false)
+            .unwrap(),
+        Err((SqliteFail::SqliteConstraintUnique, _))
+    ));
+    // ...but the chain can still be extended, and archived again
+    chain.extend(extend(&mut handle, 2));
+    let second = handle
+        .archive_chain::<WrappedJson>(genesis, 3, &dir)
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!((second.first_height, second.last_height), (8, 9));
+    assert_eq!(
+        handle.get_archived_chains_for(genesis).unwrap(),
+        vec![archive.clone(), second.clone()]
+    );
+    handle.compact().unwrap();
+
+    // a tampered archive is refused, and nothing changes
+    let original = std::fs::read(&archive.path).unwrap();
+    let mut tampered = original.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    std::fs::write(&archive.path, &tampered).unwrap();
+    assert!(matches!(
+        handle
+            .restore_chain::<WrappedJson, _>(&secp, genesis)
+            .unwrap(),
+        Err(ArchiveError::DigestMismatch { .. })
+    ));
+    assert_eq!(handle.get_archived_chains_for(genesis).unwrap().len(), 2);
+    std::fs::write(&archive.path, &original).unwrap();
+
+    let stats = handle.get_chain_stats().unwrap();
+    assert_eq!(
+        handle
+            .restore_chain::<WrappedJson, _>(&secp, genesis)
+            .unwrap()
+            .unwrap(),
+        9
+    );
+    // restored envelopes were counted when first stored
+    assert_eq!(handle.get_chain_stats().unwrap(), stats);
+    assert!(handle.get_archived_chains().unwrap().is_empty());
+    assert!(!archive.path.exists() && !second.path.exists());
+    let tip = chain.last().unwrap().canonicalized_hash_ref();
+    let mut hashes = vec![genesis];
+    hashes.extend(chain.iter().map(|e| e.canonicalized_hash_ref()));
+    assert_eq!(handle.get_chain_ending_at(tip).unwrap(), Some(hashes));
+    assert_eq!(
+        handle
+            .check_ancestry_commitment::<WrappedJson>(tip)
+            .unwrap(),
+        Some(true)
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test(tokio::test)]
+async fn test_backup_and_restore() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let dir = std::env::temp_dir().join(format!("attest-backup-{}", thread_rng().gen::<u64>()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let kp = make_test_user(&secp, &mut handle, "backed_up".into());
+    let extend = |handle: &mut MsgDBHandle, n: usize| {
+        for _ in 0..n {
+            let e = handle
+                .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                    CanonicalJsonValue::Null,
+                    &kp,
+                    &secp,
+                    None,
+                    None,
+                    TipControl::NoTips,
+                )
+                .unwrap()
+                .unwrap()
+                .self_authenticate(&secp)
+                .unwrap();
+            handle
+                .try_insert_authenticated_envelope(e, false)
+                .unwrap()
+                .unwrap();
+        }
+    };
+    extend(&mut handle, 4);
+    let snapshot = dir.join("snapshot-1.sqlite3");
+    handle.backup_to(&snapshot).unwrap().unwrap();
+    let verified = verify_snapshot::<WrappedJson, _>(&secp, &snapshot)
+        .unwrap()
+        .unwrap();
+    assert_eq!((verified.envelopes, verified.chains), (5, 1));
+
+    // a snapshot behind a chain we sign for is refused, as restoring it would
+    // sign at heights already signed at
+    extend(&mut handle, 3);
+    let height = |handle: &MsgDBHandle| {
+        handle
+            .get_tip_for_user_by_key::<WrappedJson>(kp.x_only_public_key().0)
+            .unwrap()
+            .header()
+            .height()
+    };
+    assert_eq!(height(&handle), 7);
+    assert!(matches!(
+        handle
+            .restore_snapshot::<WrappedJson, _>(&secp, &snapshot)
+            .unwrap(),
+        Err(BackupError::Behind {
+            snapshot: Some(4),
+            live: 7,
+            ..
+        })
+    ));
+    assert_eq!(height(&handle), 7);
+
+    // one that is up to date is restored, without forgetting nonces used
+    // since it was taken
+    let current = dir.join("snapshot-2.sqlite3");
+    handle.backup_to(&current).unwrap().unwrap();
+    let verified = verify_snapshot::<WrappedJson, _>(&secp, &current)
+        .unwrap()
+        .unwrap();
+    let wrap = |handle: &MsgDBHandle| {
+        handle.wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+            CanonicalJsonValue::Null,
+            &kp,
+            &secp,
+            None,
+            None,
+            TipControl::NoTips,
+        )
+    };
+    wrap(&handle).unwrap().unwrap();
+    let restored = handle
+        .restore_snapshot::<WrappedJson, _>(&secp, &current)
+        .unwrap()
+        .unwrap();
+    assert_eq!(restored, verified);
+    assert_eq!(height(&handle), 7);
+    assert!(matches!(
+        wrap(&handle).unwrap(),
+        Err(SigningError::NonceAlreadyUsed(_))
+    ));
+    let _ = std::fs::remove_file(&current);
+
+    // a snapshot missing a connected envelope's parent is refused
+    let broken = dir.join("snapshot-3.sqlite3");
+    handle.backup_to(&broken).unwrap().unwrap();
+    rusqlite::Connection::open(&broken)
+        .unwrap()
+        .execute_batch(
+            "PRAGMA ignore_check_constraints = 1; DELETE FROM messages WHERE height = 2;",
+        )
+        .unwrap();
+    assert!(matches!(
+        handle
+            .restore_snapshot::<WrappedJson, _>(&secp, &broken)
+            .unwrap(),
+        Err(BackupError::Invalid { .. })
+    ));
+    assert_eq!(height(&handle), 7);
+
+    assert_eq!(
+        rotate_snapshots(&dir, "snapshot-", 1).unwrap(),
+        vec![snapshot.clone()]
+    );
+    assert!(!snapshot.exists() && broken.exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test(tokio::test)]
+async fn test_connection_pool() {
+    let dir = std::env::temp_dir().join(format!("attest-pool-{}", thread_rng().gen::<u64>()));
+    let config = PoolConfig {
+        readers: 2,
+        checkout_timeout: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let conn = setup_db_at_with(dir.clone(), "pool", config).await.unwrap();
+    let secp = Secp256k1::new();
+    {
+        let mut handle = conn.get_handle_all().await;
+        make_test_user(&secp, &mut handle, "pooled".into());
+    }
+
+    // readers see what the writer wrote, but can't write themselves
+    let first = conn.get_handle_read().await;
+    assert_eq!(first.get_all_users().unwrap().len(), 1);
+    assert!(first.0.execute_batch("DELETE FROM users").is_err());
+
+    // with every reader checked out, the next has to wait for one back
+    let second = conn.try_get_handle_read().await.unwrap();
+    assert!(conn.try_get_handle_read().await.is_err());
+    assert_eq!(conn.stats().idle_readers, 0);
+    drop(first);
+    let third = conn.try_get_handle_read().await.unwrap();
+    drop((second, third));
+
+    let stats = conn.stats();
+    assert_eq!(
+        (
+            stats.readers,
+            stats.idle_readers,
+            stats.read_checkouts,
+            stats.timeouts
+        ),
+        (2, 2, 3, 1)
+    );
+    assert_eq!(stats.write_checkouts, 2);
+
+    let checkpoint = conn.checkpoint().await.unwrap();
+    assert!(!checkpoint.busy);
+    assert_eq!(conn.stats().checkpoints, 1);
+    drop(conn);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// A read checkout that gives up while waiting for its reader's lock puts
+/// the reader back rather than losing it.
+#[test(tokio::test)]
+async fn test_abandoned_checkout_returns_reader() {
+    // every reader shares the writer's connection, as in memory
+    let shared = Arc::new(Mutex::new(Connection::open(":memory:").unwrap()));
+    let config = PoolConfig {
+        checkout_timeout: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let conn = MsgDB::new(shared.clone(), vec![shared.clone(), shared], config);
+    conn.setup().await;
+    let writer = conn.get_handle_all().await;
+    // a reader is free, but its lock isn't
+    assert!(conn.try_get_handle_read().await.is_err());
+    assert!(conn.try_get_handle_read().await.is_err());
+    assert_eq!(conn.stats().timeouts, 2);
+    drop(writer);
+    // both readers were handed back, or the next checkouts would find none
+    for _ in 0..3 {
+        let read = conn.try_get_handle_read().await.unwrap();
+        assert!(read.get_all_users().unwrap().is_empty());
+    }
+    assert_eq!(conn.stats().idle_readers, 2);
+}
+
+/// Either connection pool, so [`load_test_connection_pool`] can put the same
+/// load on both.
+#[derive(Clone)]
+enum LoadTestDB {
+    MutexList(Arc<MutexList>),
+    Pool(MsgDB),
+}
+
+impl LoadTestDB {
+    async fn get_handle_all(&self) -> MsgDBHandle {
+        match self {
+            LoadTestDB::MutexList(db) => db.get_handle_all().await,
+            LoadTestDB::Pool(db) => db.get_handle_all().await,
+        }
+    }
+    async fn get_handle_read(&self) -> MsgDBHandle<ReadOnly> {
+        match self {
+            LoadTestDB::MutexList(db) => db.get_handle_read().await,
+            LoadTestDB::Pool(db) => db.get_handle_read().await,
+        }
+    }
+}
+
+/// Reads tips from many tasks at once while envelopes are being written, with
+/// the mutex list the pool replaced and with the pool at one reader and at its
+/// default size. The mutex list gets as many connections as the default pool.
+///
+/// Timing dependent, so run by hand with `cargo test -- --ignored`.
+#[test(tokio::test(flavor = "multi_thread", worker_threads = 8))]
+#[ignore]
+async fn load_test_connection_pool() {
+    const TASKS: usize = 32;
+    const READS: usize = 200;
+    let default_readers = PoolConfig::default().readers;
+    let mut throughput = vec![];
+    for (kind, readers) in [
+        ("mutex list", default_readers),
+        ("pool", 1),
+        ("pool", default_readers),
+    ] {
+        let dir = std::env::temp_dir().join(format!("attest-load-{}", thread_rng().gen::<u64>()));
+        let conn = if kind == "mutex list" {
+            std::fs::create_dir_all(&dir).unwrap();
+            let list = MutexList::open(&dir.join("load.sqlite3"), readers + 1).await;
+            LoadTestDB::MutexList(Arc::new(list))
+        } else {
+            let config = PoolConfig {
+                readers,
+                ..Default::default()
+            };
+            let db = setup_db_at_with(dir.clone(), "load", config).await.unwrap();
+            LoadTestDB::Pool(db)
+        };
+        let secp = Secp256k1::new();
+        let kps: Vec<KeyPair> = {
+            let mut handle = conn.get_handle_all().await;
+            (0..20)
+                .map(|i| make_test_user(&secp, &mut handle, format!("user-{}", i)))
+                .collect()
+        };
+        let writer = {
+            let conn = conn.clone();
+            tokio::spawn(async move {
+                for kp in kps.iter().cycle().take(200) {
+                    let mut handle = conn.get_handle_all().await;
+                    let secp = secp.clone();
+                    let kp = *kp;
+                    spawn_blocking(move || {
+                        let e = handle
+                            .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                                CanonicalJsonValue::Null,
+                                &kp,
+                                &secp,
+                                None,
+                                None,
+                                TipControl::NoTips,
+                            )
+                            .unwrap()
+                            .unwrap()
+                            .self_authenticate(&secp)
+                            .unwrap();
+                        handle
+                            .try_insert_authenticated_envelope(e, false)
+                            .unwrap()
+                            .unwrap();
+                    })
+                    .await
+                    .unwrap();
+                }
+            })
+        };
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|_| {
+                let conn = conn.clone();
+                tokio::spawn(async move {
+                    for _ in 0..READS {
+                        let handle = conn.get_handle_read().await;
+                        spawn_blocking(move || {
+                            handle
+                                .get_tips_for_all_users::<Authenticated<Envelope>, WrappedJson>()
+                                .unwrap()
+                        })
+                        .await
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let elapsed = start.elapsed();
+        writer.await.unwrap();
+        let reads_per_sec = (TASKS * READS) as f64 / elapsed.as_secs_f64();
+        match &conn {
+            LoadTestDB::MutexList(_) => info!(kind, readers, reads_per_sec, "Pool Load Test"),
+            LoadTestDB::Pool(db) => {
+                info!(kind, readers, reads_per_sec, stats=?db.stats(), "Pool Load Test")
+            }
+        }
+        throughput.push(reads_per_sec);
+        drop(conn);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    // the pool beats what it replaced, and more readers help it
+    assert!(throughput[2] > throughput[0]);
+    assert!(throughput[2] > throughput[1]);
+}
+
+#[test(tokio::test)]
+async fn test_query_envelopes() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let alice = make_test_user(&secp, &mut handle, "alice".into());
+    let bob = make_test_user(&secp, &mut handle, "bob".into());
+    let post = |handle: &mut MsgDBHandle,
+                kp: &KeyPair,
+                encoding: StorageEncoding,
+                msg: serde_json::Value| {
+        handle.set_storage_encoding(encoding).unwrap();
+        let e = handle
+            .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                CanonicalJsonValue::try_from(msg).unwrap(),
+                kp,
+                &secp,
+                None,
+                None,
+                TipControl::NoTips,
+            )
+            .unwrap()
+            .unwrap()
+            .self_authenticate(&secp)
+            .unwrap();
+        handle
+            .try_insert_authenticated_envelope(e.clone(), false)
+            .unwrap()
+            .unwrap();
+        e.canonicalized_hash_ref()
+    };
+    use serde_json::json;
+    use StorageEncoding::{Binary, Json};
+    let a1 = post(
+        &mut handle,
+        &alice,
+        Json,
+        json!({"data": {"trade": {"amount": 5}}}),
+    );
+    let a2 = post(
+        &mut handle,
+        &alice,
+        Binary,
+        json!({"data": {"trade": {"amount": 12}}}),
+    );
+    let a3 = post(&mut handle, &alice, Json, json!({"data": {"chat": "hi"}}));
+    let a4 = post(
+        &mut handle,
+        &alice,
+        Json,
+        json!({"@type": "unknown-note", "@version": 7, "@body": {}}),
+    );
+    let a5 = post(
+        &mut handle,
+        &alice,
+        Binary,
+        json!({"@type": "unknown-note", "@version": 7, "@body": {"n": [1, 2]}}),
+    );
+    let b1 = post(
+        &mut handle,
+        &bob,
+        Json,
+        json!({"data": {"trade": {"amount": 20}}}),
+    );
+    let b2 = post(&mut handle, &bob, Binary, json!({"data": {"chat": "hi"}}));
+
+    let query = |q: serde_json::Value| -> Vec<CanonicalEnvelopeHash> {
+        let q: EnvelopeQuery = serde_json::from_value(q).unwrap();
+        handle
+            .query_envelopes::<Authenticated<Envelope>, WrappedJson>(&q)
+            .unwrap()
+            .iter()
+            .map(|e| e.canonicalized_hash_ref())
+            .collect()
+    };
+    let alice_key = alice.x_only_public_key().0;
+    assert_eq!(
+        query(json!({"key": alice_key, "filters": [{"path": "data.trade", "op": "exists"}]})),
+        vec![a1, a2]
+    );
+    // binary bodies are filtered just the same
+    assert_eq!(
+        query(json!({"filters": [{"path": "$.data.trade.amount", "op": "gt", "value": 10}]})),
+        vec![a2, b1]
+    );
+    assert_eq!(
+        query(json!({"filters": [{"path": "data.trade.amount", "op": "lt", "value": 10}]})),
+        vec![a1]
+    );
+    assert_eq!(
+        query(json!({"filters": [{"path": "data.chat", "op": "eq", "value": "hi"}]})),
+        vec![a3, b2]
+    );
+    assert_eq!(
+        query(json!({"filters": [{"path": "data", "op": "eq", "value": {"chat": "hi"}}]})),
+        vec![a3, b2]
+    );
+    assert_eq!(query(json!({"msg_type": "unknown-note"})), vec![a4, a5]);
+    assert_eq!(
+        query(json!({"filters": [{"path": "@body.n[1]", "op": "eq", "value": 2}]})),
+        vec![a5]
+    );
+    assert_eq!(
+        query(json!({"key": alice_key, "after_height": 2, "limit": 2})),
+        vec![a3, a4]
+    );
+    // no filters, everything including both genesis envelopes
+    assert_eq!(query(json!({})).len(), 9);
+    assert!(query(json!({"filters": [{"path": "nothing", "op": "exists"}]})).is_empty());
+}
+
+#[test(tokio::test)]
+async fn test_chain_gaps() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let kp = make_test_user(&secp, &mut handle, "alice".into());
+    // heights 1 through 7, none of them stored yet
+    let mut envs: Vec<Authenticated<Envelope>> = vec![];
+    for _ in 0..7 {
+        let e = handle
+            .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                CanonicalJsonValue::Null,
+                &kp,
+                &secp,
+                None,
+                envs.last().map(|e| e.inner_ref().clone()),
+                TipControl::NoTips,
+            )
+            .unwrap()
+            .unwrap()
+            .self_authenticate(&secp)
+            .unwrap();
+        envs.push(e);
+    }
+    let genesis = envs[0].get_genesis_hash();
+    let insert = |handle: &mut MsgDBHandle, heights: &[usize]| {
+        for h in heights {
+            handle
+                .try_insert_authenticated_envelope(envs[h - 1].clone(), false)
+                .unwrap()
+                .unwrap();
+        }
+        handle.attach_tips().unwrap();
+    };
+    assert!(handle.get_chain_gaps().unwrap().is_empty());
+
+    insert(&mut handle, &[1, 2, 5, 7]);
+    let gaps = handle.get_chain_gaps().unwrap();
+    assert_eq!(gaps.len(), 1);
+    assert_eq!(gaps[0].genesis, genesis);
+    assert_eq!(
+        gaps[0].gaps,
+        vec![
+            Gap {
+                first_height: 3,
+                last_height: 4,
+                needed: envs[3].canonicalized_hash_ref(),
+            },
+            Gap {
+                first_height: 6,
+                last_height: 6,
+                needed: envs[5].canonicalized_hash_ref(),
+            },
+        ]
+    );
+
+    let heights = |v: Vec<Envelope>| v.iter().map(|e| e.header().height()).collect::<Vec<_>>();
+    let range = handle
+        .get_envelopes_in_range::<Envelope, WrappedJson>(genesis, 1, 7, 10)
+        .unwrap();
+    assert_eq!(heights(range), vec![1, 2, 5, 7]);
+    let range = handle
+        .get_envelopes_in_range::<Envelope, WrappedJson>(genesis, 2, 7, 2)
+        .unwrap();
+    assert_eq!(heights(range), vec![2, 5]);
+
+    insert(&mut handle, &[3, 4]);
+    let gaps = handle.get_chain_gaps().unwrap();
+    assert_eq!(gaps.len(), 1);
+    assert_eq!(
+        gaps[0].gaps,
+        vec![Gap {
+            first_height: 6,
+            last_height: 6,
+            needed: envs[5].canonicalized_hash_ref(),
+        }]
+    );
+
+    insert(&mut handle, &[6]);
+    assert!(handle.get_chain_gaps().unwrap().is_empty());
+    assert_eq!(
+        handle
+            .get_tip_for_user_by_key::<WrappedJson>(kp.x_only_public_key().0)
+            .unwrap()
+            .canonicalized_hash_ref(),
+        envs[6].canonicalized_hash_ref()
+    );
+}
+
+#[test(tokio::test)]
+async fn test_nonce_lifecycle() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let kp = make_test_user(&secp, &mut handle, "alice".into());
+    let key = kp.x_only_public_key().0;
+    let secret_of = |handle: &MsgDBHandle, n: PrecomittedPublicNonce| -> Option<String> {
+        handle
+            .0
+            .query_row(
+                "SELECT private_key FROM message_nonces WHERE public_key = ?",
+                [n],
+                |r| r.get(0),
+            )
+            .unwrap()
+    };
+    let wrap = |handle: &MsgDBHandle| {
+        handle.wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+            CanonicalJsonValue::Null,
+            &kp,
+            &secp,
+            None,
+            None,
+            TipControl::NoTips,
+        )
+    };
+
+    // pregenerated nonces are committed to oldest first
+    handle
+        .pregenerate_nonces_for_user_by_key(&secp, key, 3)
+        .unwrap();
+    assert_eq!(handle.get_pregenerated_nonce_count(key).unwrap(), 3);
+    let first: PrecomittedPublicNonce = handle
+        .0
+        .query_row(
+            "SELECT public_key FROM message_nonces WHERE state = 'Generated' ORDER BY nonce_id LIMIT 1",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(
+        handle.get_nonce_state(first).unwrap(),
+        Some(NonceState::Generated)
+    );
+
+    let genesis = handle.get_tip_for_user_by_key::<WrappedJson>(key).unwrap();
+    let used = genesis.header().next_nonce();
+    assert_eq!(
+        handle.get_nonce_state(used).unwrap(),
+        Some(NonceState::Committed)
+    );
+    let envelope = wrap(&handle).unwrap().unwrap();
+    assert_eq!(envelope.header().next_nonce(), first);
+    assert_eq!(handle.get_pregenerated_nonce_count(key).unwrap(), 2);
+    assert_eq!(
+        handle.get_nonce_state(first).unwrap(),
+        Some(NonceState::Committed)
+    );
+
+    // signing marks the nonce used, and it won't sign again
+    assert_eq!(
+        handle.get_nonce_state(used).unwrap(),
+        Some(NonceState::Used)
+    );
+    assert!(secret_of(&handle, used).is_some());
+    assert!(matches!(
+        wrap(&handle).unwrap(),
+        Err(SigningError::NonceAlreadyUsed(n)) if n == used
+    ));
+
+    // storing the envelope erases the secret
+    handle
+        .try_insert_authenticated_envelope(envelope.self_authenticate(&secp).unwrap(), false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        handle.get_nonce_state(used).unwrap(),
+        Some(NonceState::Used)
+    );
+    assert_eq!(secret_of(&handle, used), None);
+    assert!(handle.get_secret_for_public_nonce(used).is_err());
+
+    // a refused envelope frees its nonce to sign a replacement
+    registry::register::<Note>();
+    let refused = handle
+        .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+            CanonicalJsonValue::try_from(serde_json::json!({
+                "@type": "test-note", "@version": 1, "@body": {"text": 5}
+            }))
+            .unwrap(),
+            &kp,
+            &secp,
+            None,
+            None,
+            TipControl::NoTips,
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        handle.get_nonce_state(first).unwrap(),
+        Some(NonceState::Used)
+    );
+    assert!(handle
+        .try_insert_authenticated_envelope(refused.self_authenticate(&secp).unwrap(), false)
+        .unwrap()
+        .is_err());
+    assert_eq!(
+        handle.get_nonce_state(first).unwrap(),
+        Some(NonceState::Committed)
+    );
+    let envelope = wrap(&handle).unwrap().unwrap();
+    handle
+        .try_insert_authenticated_envelope(envelope.self_authenticate(&secp).unwrap(), false)
+        .unwrap()
+        .unwrap();
+    assert_eq!(secret_of(&handle, first), None);
+
+    // secrets left behind by envelopes stored without erasing them, e.g. in a
+    // DB from before nonces were tracked, are collected
+    let next = envelope.header().next_nonce();
+    let secret = handle.get_secret_for_public_nonce(next).unwrap();
+    let envelope = wrap(&handle).unwrap().unwrap();
+    handle
+        .try_insert_authenticated_envelope(envelope.self_authenticate(&secp).unwrap(), false)
+        .unwrap()
+        .unwrap();
+    handle
+        .0
+        .execute(
+            "UPDATE message_nonces SET private_key = ?, state = 'Committed' WHERE public_key = ?",
+            params![secret, next],
+        )
+        .unwrap();
+    assert!(secret_of(&handle, next).is_some());
+    assert_eq!(handle.collect_used_nonces().unwrap(), 1);
+    assert_eq!(secret_of(&handle, next), None);
+    assert_eq!(
+        handle.get_nonce_state(next).unwrap(),
+        Some(NonceState::Used)
+    );
+    assert_eq!(handle.collect_used_nonces().unwrap(), 0);
+}
+
+/// A nonce spent signing an envelope that was never stored, e.g. as the
+/// process died in between, stays spent when the DB is next opened until the
+/// operator frees it, after which the chain can still be extended.
+#[test(tokio::test)]
+async fn test_unstored_nonces_released_on_request() {
+    let dir = std::env::temp_dir().join(format!("attest-nonces-{}", thread_rng().gen::<u64>()));
+    let secp = Secp256k1::new();
+    let conn = setup_db_at(dir.clone(), "nonces").await.unwrap();
+    let mut handle = conn.get_handle_all().await;
+    let kp = make_test_user(&secp, &mut handle, "alice".into());
+    let wrap = |handle: &MsgDBHandle| {
+        handle.wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+            CanonicalJsonValue::Null,
+            &kp,
+            &secp,
+            None,
+            None,
+            TipControl::NoTips,
+        )
+    };
+    let lost = wrap(&handle).unwrap().unwrap();
+    let nonce = lost.extract_used_nonce().unwrap();
+    assert!(matches!(
+        wrap(&handle).unwrap(),
+        Err(SigningError::NonceAlreadyUsed(_))
+    ));
+    drop(handle);
+    drop(conn);
+
+    let conn = setup_db_at(dir.clone(), "nonces").await.unwrap();
+    let mut handle = conn.get_handle_all().await;
+    assert_eq!(
+        handle.get_nonce_state(nonce).unwrap(),
+        Some(NonceState::Used)
+    );
+    assert_eq!(handle.release_unstored_nonces().unwrap(), 1);
+    assert_eq!(
+        handle.get_nonce_state(nonce).unwrap(),
+        Some(NonceState::Committed)
+    );
+    let envelope = wrap(&handle).unwrap().unwrap();
+    assert_eq!(envelope.extract_used_nonce(), Some(nonce));
+    handle
+        .try_insert_authenticated_envelope(envelope.self_authenticate(&secp).unwrap(), false)
+        .unwrap()
+        .unwrap();
+    // stored, so its nonce stays spent
+    assert_eq!(handle.release_unstored_nonces().unwrap(), 0);
+    assert!(!handle.release_unstored_nonce(nonce).unwrap());
+    assert_eq!(
+        handle.get_nonce_state(nonce).unwrap(),
+        Some(NonceState::Used)
+    );
+
+    // one that fails in process can be freed straight away
+    let failed = wrap(&handle).unwrap().unwrap();
+    let nonce = failed.extract_used_nonce().unwrap();
+    assert!(handle.release_unstored_nonce(nonce).unwrap());
+    assert_eq!(
+        handle.get_nonce_state(nonce).unwrap(),
+        Some(NonceState::Committed)
+    );
+    drop(handle);
+    drop(conn);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test(tokio::test)]
+async fn test_ephemeral_messages() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    let kp = make_test_user(&secp, &mut handle, "alice".into());
+    let chat = CanonicalJsonValue::try_from(serde_json::json!({"chat": "gg"})).unwrap();
+    let mut hashes = vec![];
+    for (encoding, ttl) in [
+        (StorageEncoding::Json, Some(0)),
+        (StorageEncoding::Binary, Some(0)),
+        (StorageEncoding::Json, None),
+        (StorageEncoding::Json, Some(u64::MAX)),
+    ] {
+        handle.set_storage_encoding(encoding).unwrap();
+        let envelope = match ttl {
+            Some(ttl) => handle.wrap_ephemeral_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                chat.clone(),
+                &kp,
+                &secp,
+                None,
+                ttl,
+            ),
+            None => handle.wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                chat.clone(),
+                &kp,
+                &secp,
+                None,
+                None,
+                TipControl::NoTips,
+            ),
+        }
+        .unwrap()
+        .unwrap();
+        hashes.push(envelope.canonicalized_hash_ref());
+        handle
+            .try_insert_authenticated_envelope(envelope.self_authenticate(&secp).unwrap(), false)
+            .unwrap()
+            .unwrap();
+    }
+
+    // only expired messages are pruned, and only once
+    assert_eq!(
+        handle.prune_expired_messages(attest_util::now()).unwrap(),
+        2
+    );
+    assert_eq!(
+        handle.prune_expired_messages(attest_util::now()).unwrap(),
+        0
+    );
+    let read: Vec<Authenticated<Envelope>> = handle.messages_by_hash(hashes.iter()).unwrap();
+    for (e, h) in read.iter().zip(&hashes) {
+        assert_eq!(e.canonicalized_hash_ref(), *h);
+        e.self_authenticate(&secp).unwrap();
+    }
+    assert!(read[0].is_pruned());
+    assert!(read[1].is_pruned());
+    assert_eq!(read[2].msg(), &chat);
+    assert_eq!(read[3].msg(), &chat);
+    // bodies keep the encoding they were stored with
+    let binary: bool = handle
+        .0
+        .query_row(
+            "SELECT binary_body FROM messages WHERE hash = ?",
+            [hashes[1]],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert!(binary);
+
+    // the chain carries on past pruned envelopes
+    let envelope = handle
+        .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+            chat.clone(),
+            &kp,
+            &secp,
+            None,
+            None,
+            TipControl::NoTips,
+        )
+        .unwrap()
+        .unwrap();
+    handle
+        .try_insert_authenticated_envelope(envelope.self_authenticate(&secp).unwrap(), false)
+        .unwrap()
+        .unwrap();
+    let tip = handle
+        .get_tip_for_user_by_key::<WrappedJson>(kp.x_only_public_key().0)
+        .unwrap();
+    assert_eq!(
+        tip.canonicalized_hash_ref(),
+        envelope.canonicalized_hash_ref()
+    );
+
+    // envelopes which arrive already pruned are stored as such
+    let conn2 = setup_db().await;
+    let mut other = conn2.get_handle_all().await;
+    let mut chain = handle
+        .load_all_messages_for_user_by_key_connected::<WrappedJson, Authenticated<Envelope>>(
+            &kp.x_only_public_key().0,
+        )
+        .unwrap();
+    chain.sort_by_key(|e| e.header().height());
+    other
+        .insert_user_by_genesis_envelope("alice".into(), chain[0].clone())
+        .unwrap()
+        .unwrap();
+    for e in &chain[1..] {
+        other
+            .try_insert_authenticated_envelope(e.clone(), false)
+            .unwrap()
+            .unwrap();
+    }
+    assert_eq!(other.prune_expired_messages(attest_util::now()).unwrap(), 0);
+}
+
+#[test(tokio::test)]
+async fn test_chain_stats() {
+    let conn = setup_db().await;
+    let mut handle = conn.get_handle_all().await;
+    let secp = Secp256k1::new();
+    assert!(handle.get_chain_stats().unwrap().is_empty());
+    let alice = make_test_user(&secp, &mut handle, "alice".into());
+    let bob = make_test_user(&secp, &mut handle, "bob".into());
+    let mut last = None;
+    for i in 0..3 {
+        let envelope = handle
+            .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                CanonicalJsonValue::try_from(serde_json::json!(i)).unwrap(),
+                &alice,
+                &secp,
+                None,
+                None,
+                TipControl::NoTips,
+            )
+            .unwrap()
+            .unwrap()
+            .self_authenticate(&secp)
+            .unwrap();
+        handle
+            .try_insert_authenticated_envelope(envelope.clone(), false)
+            .unwrap()
+            .unwrap();
+        last = Some(envelope);
+    }
+    // refused envelopes don't count
+    assert!(handle
+        .try_insert_authenticated_envelope(last.unwrap(), false)
+        .unwrap()
+        .is_err());
+
+    let stats = handle.get_chain_stats().unwrap();
+    assert_eq!(stats.len(), 2);
+    let of = |kp: &KeyPair| {
+        stats
+            .iter()
+            .find(|s| s.key == kp.x_only_public_key().0)
+            .unwrap()
+    };
+    let (a, b) = (of(&alice), of(&bob));
+    assert_eq!(a.nickname.as_deref(), Some("alice"));
+    assert_eq!(b.nickname.as_deref(), Some("bob"));
+    assert_eq!(a.messages, 4);
+    assert_eq!(b.messages, 1);
+    for s in &stats {
+        assert!(s.first_seen <= s.last_seen);
+        assert!(s.first_sent <= s.last_sent);
+        assert!(s.mean_delay_ms <= s.max_delay_ms);
+        assert_eq!(s.delay_histogram.iter().sum::<u64>(), s.messages);
+    }
+    assert_eq!(b.messages_per_minute, 0.0);
+    let genesis = handle
+        .get_tip_for_user_by_key::<WrappedJson>(alice.x_only_public_key().0)
+        .unwrap()
+        .get_genesis_hash();
+    assert_eq!(a.genesis, genesis);
+}
+
 #[test(tokio::test)]
 async fn test_tables() {
     let conn = setup_db().await;
@@ -542,13 +3038,28 @@ async fn test_tables() {
         .unwrap();
     assert_eq!(
         vec![
+            "anchor_leaves",
+            "anchors",
+            "archived_chains",
+            "blobs",
             "chain_commit_group_members",
             "chain_commit_group_subscribers",
             "chain_commit_groups",
+            "chain_keys",
+            "chain_stats",
+            "group_nonce_shares",
             "hidden_services",
+            "message_attachments",
             "message_nonces",
             "messages",
+            "partial_signatures",
+            "peer_acknowledgements",
+            "private_chain_peers",
+            "private_chains",
             "private_keys",
+            "signing_group_members",
+            "signing_groups",
+            "storage_encoding",
             "users"
         ],
         vit
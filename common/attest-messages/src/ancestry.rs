@@ -0,0 +1,339 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compact proofs that an envelope is an ancestor of another.
+//!
+//! Every envelope at height `n` may commit to a Merkle Mountain Range (MMR)
+//! over the hashes of the `n` envelopes before it in its chain by listing the
+//! range's peaks in its [`Header`]. The peaks of a child follow from its
+//! parent alone (append the parent's hash to the parent's peaks), so creating
+//! an envelope never requires walking the chain.
+//!
+//! An [`AncestryProof`] is the signed tip plus a Merkle path from the
+//! ancestor's hash up to one of the tip's peaks, `O(log n)` hashes, which a
+//! third party can check without any other part of the chain.
+//!
+//! Envelopes from before this commitment existed carry no peaks, they (and
+//! their descendants until a chain is re-committed) can't be used as the tip
+//! of a proof.
+
+use crate::{
+    AttestEnvelopable, AuthenticationError, CanonicalEnvelopeHash, GenericEnvelope, Header,
+};
+use sapio_bitcoin::hashes::{sha256, Hash, HashEngine};
+use sapio_bitcoin::secp256k1::{Secp256k1, Verification};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Display;
+
+const LEAF_TAG: u8 = 0;
+const NODE_TAG: u8 = 1;
+
+fn leaf(h: &CanonicalEnvelopeHash) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[LEAF_TAG]);
+    engine.input(h.as_ref());
+    sha256::Hash::from_engine(engine)
+}
+
+fn node(left: &sha256::Hash, right: &sha256::Hash) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[NODE_TAG]);
+    engine.input(&left[..]);
+    engine.input(&right[..]);
+    sha256::Hash::from_engine(engine)
+}
+
+/// The peaks of the MMR over all of an envelope's ancestors, largest subtree
+/// first. An envelope at height `n` has one peak per set bit of `n`.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash, JsonSchema, Default)]
+#[serde(transparent)]
+pub struct AncestryPeaks(pub Vec<sha256::Hash>);
+
+impl AncestryPeaks {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// If these peaks could belong to an envelope at `height`.
+    pub fn well_formed_for(&self, height: i64) -> bool {
+        height >= 0 && self.0.len() == (height as u64).count_ones() as usize
+    }
+
+    /// Peaks for an envelope at height `hashes.len()` whose ancestors have
+    /// the given hashes, in order of height.
+    pub fn from_chain(hashes: &[CanonicalEnvelopeHash]) -> Self {
+        let mut peaks = AncestryPeaks::default();
+        for (n, h) in hashes.iter().enumerate() {
+            peaks.append(n as u64, h);
+        }
+        peaks
+    }
+
+    /// The peaks for the child of `parent`, or `None` if `parent` has no
+    /// commitment to extend.
+    pub fn for_child_of<T: AttestEnvelopable>(parent: &GenericEnvelope<T>) -> Option<Self> {
+        let height = parent.header().height();
+        let mut peaks = parent.header().ancestry().clone();
+        if !peaks.well_formed_for(height) {
+            return None;
+        }
+        peaks.append(height as u64, &parent.canonicalized_hash_ref());
+        Some(peaks)
+    }
+
+    /// adds the `n`th leaf
+    fn append(&mut self, n: u64, h: &CanonicalEnvelopeHash) {
+        let mut acc = leaf(h);
+        let mut k = n;
+        while k & 1 == 1 {
+            let left = self.0.pop().expect("A peak per set bit");
+            acc = node(&left, &acc);
+            k >>= 1;
+        }
+        self.0.push(acc);
+    }
+}
+
+/// Locates the leaf `index` among the peaks of a range of `size` leaves,
+/// returning which peak and the leaf's offset within it and the peak's
+/// height.
+fn locate(size: u64, index: u64) -> Option<(usize, u64, u32)> {
+    if index >= size {
+        return None;
+    }
+    let mut start = 0u64;
+    let mut peak = 0;
+    for bit in (0..64).rev() {
+        let width = 1u64 << bit;
+        if size & width == 0 {
+            continue;
+        }
+        if index < start + width {
+            return Some((peak, index - start, bit));
+        }
+        start += width;
+        peak += 1;
+    }
+    None
+}
+
+/// The Merkle path for leaf `index` given every leaf hash in order.
+pub fn path_for(hashes: &[CanonicalEnvelopeHash], index: u64) -> Option<Vec<sha256::Hash>> {
+    let (_, offset, bit) = locate(hashes.len() as u64, index)?;
+    let start = (index - offset) as usize;
+    let mut level: Vec<sha256::Hash> = hashes[start..start + (1usize << bit)]
+        .iter()
+        .map(leaf)
+        .collect();
+    let mut i = offset as usize;
+    let mut path = Vec::with_capacity(bit as usize);
+    while level.len() > 1 {
+        path.push(level[i ^ 1]);
+        level = level.chunks(2).map(|p| node(&p[0], &p[1])).collect();
+        i >>= 1;
+    }
+    Some(path)
+}
+
+#[derive(Debug)]
+pub enum ProofError {
+    /// the tip does not commit to its ancestry
+    NoCommitment,
+    /// the ancestor is not below the tip
+    NotAnAncestor,
+    /// the path does not lead to the tip's peak
+    Mismatch,
+    Authentication(AuthenticationError),
+}
+
+impl Display for ProofError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for ProofError {}
+
+/// Proves that `ancestor` is the envelope at `ancestor_height` in the chain
+/// ending at `tip`.
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+#[serde(bound = "T: AttestEnvelopable")]
+pub struct AncestryProof<T: AttestEnvelopable> {
+    pub tip: GenericEnvelope<T>,
+    pub ancestor: CanonicalEnvelopeHash,
+    pub ancestor_height: i64,
+    pub path: Vec<sha256::Hash>,
+}
+
+impl<T: AttestEnvelopable> AncestryProof<T> {
+    /// Checks the tip's signature, and that the path leads from the ancestor
+    /// to the tip's committed peaks.
+    pub fn verify<C: Verification>(&self, secp: &Secp256k1<C>) -> Result<(), ProofError> {
+        self.tip
+            .self_authenticate(secp)
+            .map_err(ProofError::Authentication)?;
+        verify_path(
+            self.tip.header(),
+            &self.ancestor,
+            self.ancestor_height,
+            &self.path,
+        )
+    }
+}
+
+/// Checks a path against a header without checking the header's signature.
+pub fn verify_path(
+    tip: &Header,
+    ancestor: &CanonicalEnvelopeHash,
+    ancestor_height: i64,
+    path: &[sha256::Hash],
+) -> Result<(), ProofError> {
+    let height = tip.height();
+    if !tip.ancestry().well_formed_for(height) {
+        return Err(ProofError::NoCommitment);
+    }
+    if ancestor_height < 0 {
+        return Err(ProofError::NotAnAncestor);
+    }
+    let (peak, offset, bit) =
+        locate(height as u64, ancestor_height as u64).ok_or(ProofError::NotAnAncestor)?;
+    if path.len() != bit as usize {
+        return Err(ProofError::Mismatch);
+    }
+    let mut acc = leaf(ancestor);
+    for (level, sibling) in path.iter().enumerate() {
+        acc = if (offset >> level) & 1 == 0 {
+            node(&acc, sibling)
+        } else {
+            node(sibling, &acc)
+        };
+    }
+    if tip.ancestry().0[peak] == acc {
+        Ok(())
+    } else {
+        Err(ProofError::Mismatch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::checkpoints::BitcoinCheckPoints;
+    use crate::nonce::PrecomittedNonce;
+    use crate::{Ancestors, Envelope, Unsigned, WrappedJson};
+    use ruma_serde::CanonicalJsonValue;
+    use sapio_bitcoin::secp256k1::{rand, All};
+    use sapio_bitcoin::KeyPair;
+
+    fn chain(secp: &Secp256k1<All>, n: usize) -> Vec<Envelope> {
+        let kp = KeyPair::new(secp, &mut rand::thread_rng());
+        let mut nonce = PrecomittedNonce::new(secp);
+        let mut chain: Vec<Envelope> = vec![];
+        for i in 0..n {
+            let next = PrecomittedNonce::new(secp);
+            let header = Header::new(
+                kp.x_only_public_key().0,
+                next.get_public(secp),
+                chain
+                    .last()
+                    .map(|p| Ancestors::new(p.canonicalized_hash_ref(), p.get_genesis_hash())),
+                vec![],
+                i as i64,
+                i as i64,
+                Unsigned::new(None),
+                BitcoinCheckPoints::default(),
+                chain
+                    .last()
+                    .map(|p| AncestryPeaks::for_child_of(p).unwrap())
+                    .unwrap_or_default(),
+            );
+            let mut e = Envelope::new(
+                header,
+                WrappedJson::from(CanonicalJsonValue::String(format!("move {}", i))),
+            );
+            e.sign_with(&kp, secp, nonce).unwrap();
+            nonce = next;
+            chain.push(e);
+        }
+        chain
+    }
+
+    #[test]
+    fn test_incremental_matches_from_chain() {
+        let secp = Secp256k1::new();
+        let c = chain(&secp, 40);
+        let hashes: Vec<_> = c.iter().map(|e| e.canonicalized_hash_ref()).collect();
+        for (i, e) in c.iter().enumerate() {
+            assert!(e.header().ancestry().well_formed_for(i as i64));
+            assert_eq!(
+                e.header().ancestry(),
+                &AncestryPeaks::from_chain(&hashes[..i])
+            );
+        }
+    }
+
+    #[test]
+    fn test_every_ancestor_provable() {
+        let secp = Secp256k1::new();
+        let c = chain(&secp, 40);
+        let hashes: Vec<_> = c.iter().map(|e| e.canonicalized_hash_ref()).collect();
+        for tip in 0..c.len() {
+            for a in 0..tip {
+                let proof = AncestryProof {
+                    tip: c[tip].clone(),
+                    ancestor: hashes[a],
+                    ancestor_height: a as i64,
+                    path: path_for(&hashes[..tip], a as u64).unwrap(),
+                };
+                proof.verify(&secp).unwrap();
+                // paths are logarithmic in the length of the chain
+                assert!(proof.path.len() <= 6);
+
+                // the wrong hash or height fails
+                let mut bad = proof.clone();
+                bad.ancestor = hashes[(a + 1) % tip.max(1)];
+                if bad.ancestor != proof.ancestor {
+                    assert!(bad.verify(&secp).is_err());
+                }
+                let mut bad = proof.clone();
+                bad.ancestor_height = tip as i64;
+                assert!(matches!(bad.verify(&secp), Err(ProofError::NotAnAncestor)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_forged_tip_rejected() {
+        let secp = Secp256k1::new();
+        let c = chain(&secp, 8);
+        let hashes: Vec<_> = c.iter().map(|e| e.canonicalized_hash_ref()).collect();
+        let mut tip = c[7].clone();
+        // swap in a commitment to a different history, the signature no
+        // longer matches
+        let forged = {
+            let mut h = hashes[..7].to_vec();
+            h[2] = hashes[6];
+            AncestryPeaks::from_chain(&h)
+        };
+        tip.header.ancestry = forged;
+        tip.cache = None;
+        let proof = AncestryProof {
+            tip,
+            ancestor: hashes[6],
+            ancestor_height: 2,
+            path: {
+                let mut h = hashes[..7].to_vec();
+                h[2] = hashes[6];
+                path_for(&h, 2).unwrap()
+            },
+        };
+        assert!(matches!(
+            proof.verify(&secp),
+            Err(ProofError::Authentication(_))
+        ));
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Proofs that an envelope existed before a Bitcoin block.
+//!
+//! [`crate::checkpoints::BitcoinCheckPoints`] only show an envelope was made
+//! *after* some blocks. To bound it from the other side, a batch of envelope
+//! hashes is put in a merkle tree and the root committed to in an `OP_RETURN`
+//! output (see [`anchor_script`]). Once that transaction confirms, an
+//! [`AnchorProof`] links an envelope to the block.
+//!
+//! Since every envelope commits to its ancestors, anchoring a tip also anchors
+//! the rest of its chain, so only tips need to be anchored.
+
+use crate::CanonicalEnvelopeHash;
+use sapio_bitcoin::blockdata::opcodes;
+use sapio_bitcoin::blockdata::script::Builder;
+use sapio_bitcoin::hashes::{sha256, Hash, HashEngine};
+use sapio_bitcoin::util::merkleblock::MerkleBlock;
+use sapio_bitcoin::{BlockHash, Script, Transaction};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Display;
+
+/// Prefixes the root in the anchor output so anchors are easy to spot
+pub const ANCHOR_MAGIC: [u8; 4] = *b"ATST";
+
+fn tagged_hash(tag: &[u8]) -> sha256::HashEngine {
+    let tag = sha256::Hash::hash(tag);
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    engine
+}
+
+fn leaf(h: &CanonicalEnvelopeHash) -> sha256::Hash {
+    let mut engine = tagged_hash(b"attest/anchor/leaf");
+    engine.input(h.as_ref());
+    sha256::Hash::from_engine(engine)
+}
+
+fn node(left: &sha256::Hash, right: &sha256::Hash) -> sha256::Hash {
+    let mut engine = tagged_hash(b"attest/anchor/node");
+    engine.input(&left[..]);
+    engine.input(&right[..]);
+    sha256::Hash::from_engine(engine)
+}
+
+/// Which side of the path a sibling is on
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// The siblings on the path from a leaf to the root, lowest first
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+#[serde(transparent)]
+pub struct MerkleBranch(pub Vec<(Side, sha256::Hash)>);
+
+impl MerkleBranch {
+    /// The root `envelope` hashes up to along this branch
+    pub fn root(&self, envelope: &CanonicalEnvelopeHash) -> sha256::Hash {
+        self.0
+            .iter()
+            .fold(leaf(envelope), |acc, (side, sibling)| match side {
+                Side::Left => node(sibling, &acc),
+                Side::Right => node(&acc, sibling),
+            })
+    }
+}
+
+fn levels(leaves: &[CanonicalEnvelopeHash]) -> Vec<Vec<sha256::Hash>> {
+    let mut levels = vec![leaves.iter().map(leaf).collect::<Vec<_>>()];
+    while levels.last().map_or(false, |l| l.len() > 1) {
+        let next = levels
+            .last()
+            .expect("Checked Above")
+            .chunks(2)
+            .map(|c| match c {
+                [l, r] => node(l, r),
+                // an odd node is carried up as is rather than paired with
+                // itself, so no two leaf lists share a root
+                [l] => *l,
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(next);
+    }
+    levels
+}
+
+/// The root of the tree over `leaves`, `None` if there are none.
+pub fn merkle_root(leaves: &[CanonicalEnvelopeHash]) -> Option<sha256::Hash> {
+    levels(leaves).last()?.first().cloned()
+}
+
+/// The branch from `leaves[index]` to [`merkle_root`].
+pub fn merkle_branch(leaves: &[CanonicalEnvelopeHash], mut index: usize) -> Option<MerkleBranch> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut branch = vec![];
+    for level in levels(leaves) {
+        let sibling = index ^ 1;
+        if let Some(h) = level.get(sibling) {
+            branch.push((
+                if sibling < index {
+                    Side::Left
+                } else {
+                    Side::Right
+                },
+                *h,
+            ));
+        }
+        index /= 2;
+    }
+    Some(MerkleBranch(branch))
+}
+
+/// The output script committing to `root`
+pub fn anchor_script(root: &sha256::Hash) -> Script {
+    let mut data = ANCHOR_MAGIC.to_vec();
+    data.extend_from_slice(&root[..]);
+    Builder::new()
+        .push_opcode(opcodes::all::OP_RETURN)
+        .push_slice(&data)
+        .into_script()
+}
+
+/// The output of `tx` committing to `root`, if any
+pub fn find_anchor(tx: &Transaction, root: &sha256::Hash) -> Option<usize> {
+    let script = anchor_script(root);
+    tx.output.iter().position(|o| o.script_pubkey == script)
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AnchorError {
+    /// the transaction doesn't commit to the branch's root
+    NotCommitted,
+    /// the proof doesn't match its block header
+    InvalidTxProof,
+    /// the proof is valid, but for some other transaction
+    TxNotInBlock,
+}
+
+impl Display for AnchorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for AnchorError {}
+
+/// Shows `envelope` was committed to by `tx`, and `tx` was in a block.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct AnchorProof {
+    pub envelope: CanonicalEnvelopeHash,
+    pub branch: MerkleBranch,
+    pub tx: Transaction,
+    #[serde(with = "consensus_hex")]
+    pub tx_proof: MerkleBlock,
+}
+
+impl AnchorProof {
+    /// The block the envelope was anchored in
+    pub fn block_hash(&self) -> BlockHash {
+        self.tx_proof.header.block_hash()
+    }
+
+    /// Checks everything that can be checked without a view of the chain.
+    ///
+    /// The caller still has to check that [`Self::block_hash`] is in the best
+    /// chain, and buried deep enough for their liking.
+    pub fn verify(&self) -> Result<(), AnchorError> {
+        let root = self.branch.root(&self.envelope);
+        find_anchor(&self.tx, &root).ok_or(AnchorError::NotCommitted)?;
+        let mut matches = vec![];
+        let mut indexes = vec![];
+        self.tx_proof
+            .extract_matches(&mut matches, &mut indexes)
+            .map_err(|_| AnchorError::InvalidTxProof)?;
+        if !matches.contains(&self.tx.txid()) {
+            return Err(AnchorError::TxNotInBlock);
+        }
+        Ok(())
+    }
+}
+
+mod consensus_hex {
+    use sapio_bitcoin::consensus::encode::{deserialize, serialize_hex, Decodable, Encodable};
+    use sapio_bitcoin::hashes::hex::FromHex;
+    use serde::{Deserialize, Deserializer, Serializer};
+    pub fn serialize<T: Encodable, S: Serializer>(t: &T, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&serialize_hex(t))
+    }
+    pub fn deserialize<'de, T: Decodable, D: Deserializer<'de>>(d: D) -> Result<T, D::Error> {
+        let s = String::deserialize(d)?;
+        let bytes = Vec::<u8>::from_hex(&s).map_err(serde::de::Error::custom)?;
+        deserialize(&bytes).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sapio_bitcoin::{Block, BlockHeader, TxOut};
+
+    fn leaves(n: u8) -> Vec<CanonicalEnvelopeHash> {
+        (0..n)
+            .map(|i| CanonicalEnvelopeHash(sha256::Hash::hash(&[i])))
+            .collect()
+    }
+
+    fn anchored_block(root: &sha256::Hash) -> (Transaction, MerkleBlock) {
+        let anchor = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: anchor_script(root),
+            }],
+        };
+        let other = Transaction {
+            version: 1,
+            ..anchor.clone()
+        };
+        let txid = anchor.txid();
+        let mut block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: Default::default(),
+                merkle_root: Default::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata: vec![other, anchor.clone()],
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        (
+            anchor,
+            MerkleBlock::from_block_with_predicate(&block, |t| *t == txid),
+        )
+    }
+
+    #[test]
+    fn test_merkle_branches() {
+        assert_eq!(merkle_root(&[]), None);
+        for n in 1..=9 {
+            let leaves = leaves(n);
+            let root = merkle_root(&leaves).unwrap();
+            for (i, l) in leaves.iter().enumerate() {
+                let branch = merkle_branch(&leaves, i).unwrap();
+                assert_eq!(branch.root(l), root);
+                // a branch is only good for its own leaf
+                assert!(n == 1 || branch.root(&leaves[(i + 1) % leaves.len()]) != root);
+            }
+            assert_eq!(merkle_branch(&leaves, leaves.len()), None);
+        }
+        // unlike bitcoin's tree, duplicating the odd leaf changes the root
+        let mut three = leaves(3);
+        let r = merkle_root(&three);
+        three.push(three[2]);
+        assert_ne!(r, merkle_root(&three));
+    }
+
+    #[test]
+    fn test_anchor_proof() {
+        let leaves = leaves(5);
+        let root = merkle_root(&leaves).unwrap();
+        let (tx, tx_proof) = anchored_block(&root);
+        let proof = AnchorProof {
+            envelope: leaves[3],
+            branch: merkle_branch(&leaves, 3).unwrap(),
+            tx,
+            tx_proof,
+        };
+        proof.verify().unwrap();
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(serde_json::from_str::<AnchorProof>(&json).unwrap(), proof);
+
+        let mut wrong_envelope = proof.clone();
+        wrong_envelope.envelope = leaves[2];
+        assert_eq!(wrong_envelope.verify(), Err(AnchorError::NotCommitted));
+
+        let mut wrong_tx = proof.clone();
+        wrong_tx.tx.lock_time = 1;
+        assert_eq!(wrong_tx.verify(), Err(AnchorError::TxNotInBlock));
+
+        let mut wrong_header = proof;
+        wrong_header.tx_proof.header.merkle_root = Default::default();
+        assert_eq!(wrong_header.verify(), Err(AnchorError::InvalidTxProof));
+    }
+}
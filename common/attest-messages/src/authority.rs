@@ -0,0 +1,301 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Handing a chain's authority to keys other than the genesis key.
+//!
+//! A chain starts out with its genesis key as the *root*. The root may put a
+//! [`KeyGrant`] in the header of any envelope it signs:
+//!
+//! - [`KeyGrant::Rotate`] makes another key the root, the old root may not
+//!   sign anything after the rotating envelope.
+//! - [`KeyGrant::Delegate`] lets a sub-key post to the chain, optionally only
+//!   below some height. Delegates can post but can never grant.
+//! - [`KeyGrant::Revoke`] ends a delegation.
+//!
+//! Grants take effect from the envelope after the one carrying them. Because
+//! the grant is part of the signed header, the rotation is signed by the key
+//! giving up authority.
+//!
+//! A rotation can't protect against a leaked key racing the honest owner to
+//! rotate first; it lets the owner carry on after a key leaks, the signed
+//! history makes any competing rotation an equivocation that peers can see.
+
+use crate::Header;
+use sapio_bitcoin::XOnlyPublicKey;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Display;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash, JsonSchema)]
+#[serde(tag = "type")]
+pub enum KeyGrant {
+    /// `to` becomes the chain's root key, replacing the signer
+    Rotate {
+        #[schemars(with = "String")]
+        to: XOnlyPublicKey,
+    },
+    /// `to` may post below `until_height`, or forever if `None`
+    Delegate {
+        #[schemars(with = "String")]
+        to: XOnlyPublicKey,
+        #[serde(default)]
+        until_height: Option<i64>,
+    },
+    /// ends a delegation to `key`
+    Revoke {
+        #[schemars(with = "String")]
+        key: XOnlyPublicKey,
+    },
+}
+
+impl KeyGrant {
+    /// Checks the parts of a grant that don't depend on the chain, for a
+    /// grant found in `header`.
+    pub fn check_well_formed(&self, header: &Header) -> Result<(), AuthorityError> {
+        match self {
+            KeyGrant::Rotate { to } | KeyGrant::Delegate { to, .. } if *to == header.key() => {
+                Err(AuthorityError::GrantToSelf)
+            }
+            KeyGrant::Delegate {
+                until_height: Some(h),
+                ..
+            } if *h <= header.height() + 1 => Err(AuthorityError::EmptyDelegation),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Role {
+    Root,
+    Delegate,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AuthorityError {
+    /// the signing key may not post at this height
+    UnauthorizedKey,
+    /// only the root key may carry a grant
+    GrantFromDelegate,
+    /// a key can't be granted authority it already has
+    GrantToSelf,
+    /// the delegation would expire before it could be used
+    EmptyDelegation,
+}
+
+impl Display for AuthorityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for AuthorityError {}
+
+/// Who may sign the next envelope of a chain, built by replaying the grants
+/// in the chain's headers in order of height.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChainAuthority {
+    root: XOnlyPublicKey,
+    delegates: BTreeMap<XOnlyPublicKey, Option<i64>>,
+}
+
+impl ChainAuthority {
+    /// The authority over a chain before any grants, held by the genesis key.
+    pub fn new(genesis_key: XOnlyPublicKey) -> Self {
+        ChainAuthority {
+            root: genesis_key,
+            delegates: Default::default(),
+        }
+    }
+
+    pub fn root(&self) -> XOnlyPublicKey {
+        self.root
+    }
+
+    /// The role `key` has at `height`, if any.
+    pub fn role_of(&self, key: XOnlyPublicKey, height: i64) -> Option<Role> {
+        if key == self.root {
+            return Some(Role::Root);
+        }
+        match self.delegates.get(&key) {
+            Some(None) => Some(Role::Delegate),
+            Some(Some(until)) if height < *until => Some(Role::Delegate),
+            _ => None,
+        }
+    }
+
+    /// Checks that `header` was signed by a key allowed to, and that only the
+    /// root grants.
+    pub fn check(&self, header: &Header) -> Result<Role, AuthorityError> {
+        let role = self
+            .role_of(header.key(), header.height())
+            .ok_or(AuthorityError::UnauthorizedKey)?;
+        if let Some(grant) = header.grant() {
+            if role != Role::Root {
+                return Err(AuthorityError::GrantFromDelegate);
+            }
+            grant.check_well_formed(header)?;
+        }
+        Ok(role)
+    }
+
+    /// Applies the grant in an accepted `header`, if any.
+    pub fn apply(&mut self, header: &Header) {
+        match header.grant() {
+            Some(KeyGrant::Rotate { to }) => {
+                self.delegates.remove(to);
+                self.root = *to;
+            }
+            Some(KeyGrant::Delegate { to, until_height }) => {
+                self.delegates.insert(*to, *until_height);
+            }
+            Some(KeyGrant::Revoke { key }) => {
+                self.delegates.remove(key);
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::checkpoints::BitcoinCheckPoints;
+    use crate::nonce::PrecomittedNonce;
+    use crate::{Ancestors, AuthenticationError, Envelope, Unsigned, WrappedJson};
+    use ruma_serde::CanonicalJsonValue;
+    use sapio_bitcoin::secp256k1::{rand, All, Secp256k1};
+    use sapio_bitcoin::KeyPair;
+
+    fn next(
+        secp: &Secp256k1<All>,
+        prev: Option<&Envelope>,
+        kp: &KeyPair,
+        grant: Option<KeyGrant>,
+    ) -> Envelope {
+        let height = prev.map_or(0, |p| p.header().height() + 1);
+        let mut header = Header::new(
+            kp.x_only_public_key().0,
+            PrecomittedNonce::new(secp).get_public(secp),
+            prev.map(|p| Ancestors::new(p.canonicalized_hash_ref(), p.get_genesis_hash())),
+            vec![],
+            height,
+            height,
+            Unsigned::new(None),
+            BitcoinCheckPoints::default(),
+            Default::default(),
+        );
+        if let Some(g) = grant {
+            header = header.with_grant(g);
+        }
+        let mut e = Envelope::new(
+            header,
+            WrappedJson::from(CanonicalJsonValue::String(format!("{}", height))),
+        );
+        e.sign_with(kp, secp, PrecomittedNonce::new(secp)).unwrap();
+        e
+    }
+
+    #[test]
+    fn test_rotate_and_delegate() {
+        let secp = Secp256k1::new();
+        let [genesis_key, rotated, sub] =
+            [(); 3].map(|_| KeyPair::new(&secp, &mut rand::thread_rng()));
+        let pk = |k: &KeyPair| k.x_only_public_key().0;
+
+        let g = next(&secp, None, &genesis_key, None);
+        let mut authority = ChainAuthority::new(pk(&genesis_key));
+        g.self_authenticate_in_chain(&secp, &authority).unwrap();
+        authority.apply(g.header());
+
+        // the genesis key hands over the chain
+        let e1 = next(
+            &secp,
+            Some(&g),
+            &genesis_key,
+            Some(KeyGrant::Rotate { to: pk(&rotated) }),
+        );
+        e1.self_authenticate_in_chain(&secp, &authority).unwrap();
+        authority.apply(e1.header());
+        assert_eq!(authority.root(), pk(&rotated));
+
+        // the old key is now locked out
+        let stale = next(&secp, Some(&e1), &genesis_key, None);
+        stale.self_authenticate(&secp).unwrap();
+        assert!(matches!(
+            stale.self_authenticate_in_chain(&secp, &authority),
+            Err(AuthenticationError::Unauthorized(
+                AuthorityError::UnauthorizedKey
+            ))
+        ));
+
+        // the new root delegates for two envelopes
+        let e2 = next(
+            &secp,
+            Some(&e1),
+            &rotated,
+            Some(KeyGrant::Delegate {
+                to: pk(&sub),
+                until_height: Some(5),
+            }),
+        );
+        e2.self_authenticate_in_chain(&secp, &authority).unwrap();
+        authority.apply(e2.header());
+        let e3 = next(&secp, Some(&e2), &sub, None);
+        e3.self_authenticate_in_chain(&secp, &authority).unwrap();
+        authority.apply(e3.header());
+
+        // delegates can't grant
+        let bad = next(
+            &secp,
+            Some(&e3),
+            &sub,
+            Some(KeyGrant::Rotate {
+                to: pk(&genesis_key),
+            }),
+        );
+        assert!(matches!(
+            bad.self_authenticate_in_chain(&secp, &authority),
+            Err(AuthenticationError::Unauthorized(
+                AuthorityError::GrantFromDelegate
+            ))
+        ));
+        let e4 = next(&secp, Some(&e3), &sub, None);
+        e4.self_authenticate_in_chain(&secp, &authority).unwrap();
+        authority.apply(e4.header());
+
+        // and the delegation runs out
+        let e5 = next(&secp, Some(&e4), &sub, None);
+        assert!(e5.self_authenticate_in_chain(&secp, &authority).is_err());
+        let e5 = next(&secp, Some(&e4), &rotated, None);
+        e5.self_authenticate_in_chain(&secp, &authority).unwrap();
+    }
+
+    #[test]
+    fn test_malformed_grants() {
+        let secp = Secp256k1::new();
+        let kp = KeyPair::new(&secp, &mut rand::thread_rng());
+        let g = next(&secp, None, &kp, None);
+        for grant in [
+            KeyGrant::Rotate {
+                to: kp.x_only_public_key().0,
+            },
+            KeyGrant::Delegate {
+                to: KeyPair::new(&secp, &mut rand::thread_rng())
+                    .x_only_public_key()
+                    .0,
+                until_height: Some(2),
+            },
+        ] {
+            let e = next(&secp, Some(&g), &kp, Some(grant));
+            assert!(matches!(
+                e.self_authenticate(&secp),
+                Err(AuthenticationError::InvalidGrant(_))
+            ));
+        }
+    }
+}
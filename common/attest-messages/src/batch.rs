@@ -0,0 +1,142 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Verifying many envelopes at once.
+//!
+//! Almost all of the cost of authenticating an envelope is canonicalizing and
+//! hashing it before the signature can be checked, and each envelope is
+//! independent, so batches are spread over rayon's thread pool. The bundled
+//! secp256k1 has no batch Schnorr verification, so each signature is still
+//! checked on its own.
+//!
+//! These functions block until the whole batch is done, async callers should
+//! run them with `spawn_blocking`.
+
+use crate::{AttestEnvelopable, Authenticated, AuthenticationError, GenericEnvelope};
+use rayon::prelude::*;
+use sapio_bitcoin::secp256k1::{Secp256k1, Verification};
+
+/// Authenticates every envelope, returning a result for each in input order.
+pub fn authenticate_all<T, C>(
+    envelopes: Vec<GenericEnvelope<T>>,
+    secp: &Secp256k1<C>,
+) -> Vec<Result<Authenticated<GenericEnvelope<T>>, AuthenticationError>>
+where
+    T: AttestEnvelopable,
+    C: Verification + Sync,
+{
+    envelopes
+        .into_par_iter()
+        .map(|e| e.into_authenticated(secp))
+        .collect()
+}
+
+/// Like [`authenticate_all`] but borrows the envelopes, copying the authentic
+/// ones.
+pub fn authenticate_all_ref<'a, T, C, I>(
+    envelopes: I,
+    secp: &Secp256k1<C>,
+) -> Vec<Result<Authenticated<GenericEnvelope<T>>, AuthenticationError>>
+where
+    T: AttestEnvelopable + 'a,
+    C: Verification + Sync,
+    I: IntoParallelIterator<Item = &'a GenericEnvelope<T>>,
+{
+    envelopes
+        .into_par_iter()
+        .map(|e| e.self_authenticate(secp))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::checkpoints::BitcoinCheckPoints;
+    use crate::nonce::PrecomittedNonce;
+    use crate::{Ancestors, Envelope, Header, Unsigned, WrappedJson};
+    use ruma_serde::CanonicalJsonValue;
+    use sapio_bitcoin::secp256k1::rand;
+    use sapio_bitcoin::KeyPair;
+
+    fn signed_chain(secp: &Secp256k1<sapio_bitcoin::secp256k1::All>, n: usize) -> Vec<Envelope> {
+        let kp = KeyPair::new(secp, &mut rand::thread_rng());
+        let mut nonce = PrecomittedNonce::new(secp);
+        let mut chain: Vec<Envelope> = vec![];
+        for i in 0..n {
+            let next = PrecomittedNonce::new(secp);
+            let header = Header::new(
+                kp.x_only_public_key().0,
+                next.get_public(secp),
+                chain
+                    .last()
+                    .map(|p| Ancestors::new(p.canonicalized_hash_ref(), p.get_genesis_hash())),
+                vec![],
+                i as i64,
+                i as i64,
+                Unsigned::new(None),
+                BitcoinCheckPoints::default(),
+                Default::default(),
+            );
+            let mut e = Envelope::new(
+                header,
+                WrappedJson::from(CanonicalJsonValue::String(format!("{}", i))),
+            );
+            e.sign_with(&kp, secp, nonce).unwrap();
+            nonce = next;
+            chain.push(e);
+        }
+        chain
+    }
+
+    #[test]
+    fn test_matches_serial() {
+        let secp = Secp256k1::new();
+        let mut envelopes = signed_chain(&secp, 200);
+        // break a few in different ways
+        envelopes[3] = {
+            let e = &envelopes[3];
+            let mut header = e.header().clone();
+            header.height += 1;
+            Envelope::new(header, WrappedJson::from(e.msg().clone()))
+        };
+        envelopes[50].header.unsigned = Unsigned::new(None);
+        envelopes[50].cache = None;
+        envelopes[120].header.ancestors = None;
+        envelopes[120].cache = None;
+
+        let serial: Vec<_> = envelopes
+            .iter()
+            .map(|e| e.self_authenticate(&secp).map(|a| a.inner()))
+            .collect();
+        let by_ref: Vec<_> = authenticate_all_ref(&envelopes, &secp)
+            .into_iter()
+            .map(|r| r.map(|a| a.inner()))
+            .collect();
+        let owned: Vec<_> = authenticate_all(envelopes.clone(), &secp)
+            .into_iter()
+            .map(|r| r.map(|a| a.inner()))
+            .collect();
+        for (i, ((s, r), o)) in serial
+            .iter()
+            .zip(by_ref.iter())
+            .zip(owned.iter())
+            .enumerate()
+        {
+            match (s, r, o) {
+                (Ok(s), Ok(r), Ok(o)) => {
+                    assert_eq!(s, &envelopes[i]);
+                    assert_eq!(r, &envelopes[i]);
+                    assert_eq!(o, &envelopes[i]);
+                }
+                (Err(_), Err(_), Err(_)) => {
+                    assert!([3, 50, 120].contains(&i));
+                }
+                _ => panic!("batch and serial verification disagree at {}", i),
+            }
+        }
+        assert_eq!(serial.iter().filter(|r| r.is_err()).count(), 3);
+    }
+}
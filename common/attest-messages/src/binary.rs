@@ -0,0 +1,724 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compact, deterministic binary encoding for [`GenericEnvelope`].
+//!
+//! Layout (version 2):
+//!
+//! ```text
+//! version: u8 = 2
+//! header:
+//!     format_version: varint, never 0
+//!     key: [u8; 32]
+//!     next_nonce: [u8; 32]
+//!     ancestors: 0u8 | 1u8 prev_msg: [u8; 32] genesis: [u8; 32]
+//!     tips: varint count, then count * (key: [u8; 32], height: zigzag, hash: [u8; 32])
+//!     height: zigzag
+//!     sent_time_ms: zigzag
+//!     signature: 0u8 | 1u8 [u8; 64]
+//!     checkpoints: 5 * (block: [u8; 32], height: zigzag)
+//!     ancestry: varint count, then count * [u8; 32]
+//!     grant: 0u8
+//!          | 1u8 (rotate) to: [u8; 32]
+//!          | 2u8 (delegate) to: [u8; 32] until_height: 0u8 | 1u8 zigzag
+//!          | 3u8 (revoke) key: [u8; 32]
+//!     attachments (format_version 2 onwards):
+//!         varint count, then count * (hash: [u8; 32], size: varint)
+//!     ephemeral (format_version 3 onwards):
+//!         0u8 | 1u8 ttl_ms: varint msg_hash: [u8; 32]
+//! msg: varint length, then the canonical JSON of the message
+//! ```
+//!
+//! Version 1 is the same without `format_version`, and is used for exactly
+//! the envelopes of [`FormatVersion::LEGACY`].
+//!
+//! Varints are unsigned LEB128 and must be minimally encoded, signed values
+//! are zigzag encoded first. Decoding rejects anything that would not
+//! re-encode to the exact same bytes, so every envelope has exactly one
+//! binary form.
+//!
+//! The binary form is only a representation: [`CanonicalEnvelopeHash`] is
+//! still defined over the canonical JSON of the envelope, so signatures and
+//! hashes are identical no matter which encoding carried the envelope. A
+//! decoded envelope has its hash recomputed from the decoded contents, a hash
+//! is never read from the encoding. A future encoding that changes how hashes
+//! are derived must use a new version byte.
+
+use crate::ancestry::AncestryPeaks;
+use crate::authority::KeyGrant;
+use crate::blobs::BlobRef;
+use crate::checkpoints::BitcoinCheckPoints;
+use crate::ephemeral::Ephemeral;
+use crate::nonce::PrecomittedPublicNonce;
+use crate::registry::RegistryError;
+use crate::{
+    Ancestors, AttestEnvelopable, CanonicalEnvelopeHash, FormatVersion, GenericEnvelope, Header,
+    Unsigned,
+};
+use ruma_serde::CanonicalJsonValue;
+use sapio_bitcoin::hashes::{sha256, Hash};
+use sapio_bitcoin::secp256k1::schnorr::Signature;
+use sapio_bitcoin::{BlockHash, XOnlyPublicKey};
+use std::error::Error;
+use std::fmt::Display;
+
+/// The version byte written at the start of binary envelopes
+pub const BINARY_VERSION: u8 = 2;
+/// The version byte written at the start of binary envelopes of
+/// [`FormatVersion::LEGACY`]
+pub const LEGACY_BINARY_VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum BinaryError {
+    UnknownVersion(u8),
+    UnexpectedEnd,
+    TrailingBytes,
+    InvalidTag(u8),
+    NonMinimalVarint,
+    VarintOverflow,
+    /// more ancestry peaks than a height could have
+    TooManyPeaks(u64),
+    Secp256k1Error(sapio_bitcoin::secp256k1::Error),
+    NonCanonicalMsg,
+    NonCanonicalVersion,
+    SerializerError(serde_json::Error),
+    CanonicalizationError(ruma_serde::CanonicalJsonError),
+    MessageError(RegistryError),
+}
+
+impl Display for BinaryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for BinaryError {}
+
+impl From<serde_json::Error> for BinaryError {
+    fn from(e: serde_json::Error) -> Self {
+        BinaryError::SerializerError(e)
+    }
+}
+impl From<ruma_serde::CanonicalJsonError> for BinaryError {
+    fn from(e: ruma_serde::CanonicalJsonError) -> Self {
+        BinaryError::CanonicalizationError(e)
+    }
+}
+impl From<RegistryError> for BinaryError {
+    fn from(e: RegistryError) -> Self {
+        BinaryError::MessageError(e)
+    }
+}
+impl From<sapio_bitcoin::secp256k1::Error> for BinaryError {
+    fn from(e: sapio_bitcoin::secp256k1::Error) -> Self {
+        BinaryError::Secp256k1Error(e)
+    }
+}
+
+pub fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+pub fn get_varint(input: &mut &[u8]) -> Result<u64, BinaryError> {
+    let mut v: u64 = 0;
+    for i in 0..10 {
+        let b = get_u8(input)?;
+        if i == 9 && b > 1 {
+            return Err(BinaryError::VarintOverflow);
+        }
+        v |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            // a trailing zero byte could have been left off
+            if b == 0 && i > 0 {
+                return Err(BinaryError::NonMinimalVarint);
+            }
+            return Ok(v);
+        }
+    }
+    Err(BinaryError::VarintOverflow)
+}
+
+fn put_i64(out: &mut Vec<u8>, v: i64) {
+    put_varint(out, ((v << 1) ^ (v >> 63)) as u64)
+}
+
+fn get_i64(input: &mut &[u8]) -> Result<i64, BinaryError> {
+    let v = get_varint(input)?;
+    Ok(((v >> 1) as i64) ^ -((v & 1) as i64))
+}
+
+fn get_u8(input: &mut &[u8]) -> Result<u8, BinaryError> {
+    let (b, rest) = input.split_first().ok_or(BinaryError::UnexpectedEnd)?;
+    *input = rest;
+    Ok(*b)
+}
+
+fn get_bytes<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], BinaryError> {
+    if input.len() < n {
+        return Err(BinaryError::UnexpectedEnd);
+    }
+    let (b, rest) = input.split_at(n);
+    *input = rest;
+    Ok(b)
+}
+
+fn get_flag(input: &mut &[u8]) -> Result<bool, BinaryError> {
+    match get_u8(input)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        t => Err(BinaryError::InvalidTag(t)),
+    }
+}
+
+fn put_hash(out: &mut Vec<u8>, h: &CanonicalEnvelopeHash) {
+    out.extend_from_slice(h.as_ref())
+}
+
+fn get_hash(input: &mut &[u8]) -> Result<CanonicalEnvelopeHash, BinaryError> {
+    let b = get_bytes(input, 32)?;
+    Ok(CanonicalEnvelopeHash(
+        sha256::Hash::from_slice(b).expect("Length Checked"),
+    ))
+}
+
+fn get_key(input: &mut &[u8]) -> Result<XOnlyPublicKey, BinaryError> {
+    Ok(XOnlyPublicKey::from_slice(get_bytes(input, 32)?)?)
+}
+
+impl Header {
+    /// The version byte an envelope with this header is encoded under.
+    pub fn binary_version(&self) -> u8 {
+        if self.version.is_legacy() {
+            LEGACY_BINARY_VERSION
+        } else {
+            BINARY_VERSION
+        }
+    }
+
+    pub fn encode_binary(&self, out: &mut Vec<u8>) {
+        if !self.version.is_legacy() {
+            put_varint(out, self.version.0 as u64);
+        }
+        out.extend_from_slice(&self.key.serialize());
+        out.extend_from_slice(&self.next_nonce.0.serialize());
+        match &self.ancestors {
+            None => out.push(0),
+            Some(a) => {
+                out.push(1);
+                put_hash(out, &a.prev_msg);
+                put_hash(out, &a.genesis);
+            }
+        }
+        put_varint(out, self.tips.len() as u64);
+        for (key, height, hash) in &self.tips {
+            out.extend_from_slice(&key.serialize());
+            put_i64(out, *height);
+            put_hash(out, hash);
+        }
+        put_i64(out, self.height);
+        put_i64(out, self.sent_time_ms);
+        match &self.unsigned.signature {
+            None => out.push(0),
+            Some(sig) => {
+                out.push(1);
+                out.extend_from_slice(sig.as_ref());
+            }
+        }
+        for (block, height) in &self.checkpoints.checkpoints {
+            out.extend_from_slice(&block.into_inner());
+            put_i64(out, *height);
+        }
+        put_varint(out, self.ancestry.0.len() as u64);
+        for peak in &self.ancestry.0 {
+            out.extend_from_slice(&peak[..]);
+        }
+        match &self.grant {
+            None => out.push(0),
+            Some(KeyGrant::Rotate { to }) => {
+                out.push(1);
+                out.extend_from_slice(&to.serialize());
+            }
+            Some(KeyGrant::Delegate { to, until_height }) => {
+                out.push(2);
+                out.extend_from_slice(&to.serialize());
+                match until_height {
+                    None => out.push(0),
+                    Some(h) => {
+                        out.push(1);
+                        put_i64(out, *h);
+                    }
+                }
+            }
+            Some(KeyGrant::Revoke { key }) => {
+                out.push(3);
+                out.extend_from_slice(&key.serialize());
+            }
+        }
+        if self.version >= FormatVersion::V2 {
+            put_varint(out, self.attachments.len() as u64);
+            for a in &self.attachments {
+                out.extend_from_slice(&a.hash[..]);
+                put_varint(out, a.size as u64);
+            }
+        }
+        if self.version >= FormatVersion::V3 {
+            match &self.ephemeral {
+                None => out.push(0),
+                Some(e) => {
+                    out.push(1);
+                    put_varint(out, e.ttl_ms);
+                    out.extend_from_slice(&e.msg_hash[..]);
+                }
+            }
+        }
+    }
+
+    /// Decodes a header written under `binary_version`.
+    pub fn decode_binary(input: &mut &[u8], binary_version: u8) -> Result<Header, BinaryError> {
+        let version = match binary_version {
+            LEGACY_BINARY_VERSION => FormatVersion::LEGACY,
+            BINARY_VERSION => {
+                let v =
+                    u32::try_from(get_varint(input)?).map_err(|_| BinaryError::VarintOverflow)?;
+                // legacy envelopes only have the legacy encoding
+                if v == FormatVersion::LEGACY.0 {
+                    return Err(BinaryError::NonCanonicalVersion);
+                }
+                FormatVersion(v)
+            }
+            v => return Err(BinaryError::UnknownVersion(v)),
+        };
+        let key = get_key(input)?;
+        let next_nonce = PrecomittedPublicNonce(get_key(input)?);
+        let ancestors = if get_flag(input)? {
+            let prev_msg = get_hash(input)?;
+            let genesis = get_hash(input)?;
+            Some(Ancestors::new(prev_msg, genesis))
+        } else {
+            None
+        };
+        let n_tips = get_varint(input)?;
+        // every tip takes at least 65 bytes, don't let the count alone
+        // allocate
+        if n_tips > (input.len() / 65) as u64 {
+            return Err(BinaryError::UnexpectedEnd);
+        }
+        let mut tips = Vec::with_capacity(n_tips as usize);
+        for _ in 0..n_tips {
+            let key = get_key(input)?;
+            let height = get_i64(input)?;
+            let hash = get_hash(input)?;
+            tips.push((key, height, hash));
+        }
+        let height = get_i64(input)?;
+        let sent_time_ms = get_i64(input)?;
+        let signature = if get_flag(input)? {
+            Some(Signature::from_slice(get_bytes(input, 64)?)?)
+        } else {
+            None
+        };
+        let mut checkpoints = BitcoinCheckPoints::default();
+        for c in checkpoints.checkpoints.iter_mut() {
+            let block = BlockHash::from_slice(get_bytes(input, 32)?).expect("Length Checked");
+            *c = (block, get_i64(input)?);
+        }
+        let n_peaks = get_varint(input)?;
+        // at most one peak per bit of the height
+        if n_peaks > 64 {
+            return Err(BinaryError::TooManyPeaks(n_peaks));
+        }
+        let mut peaks = Vec::with_capacity(n_peaks as usize);
+        for _ in 0..n_peaks {
+            peaks.push(sha256::Hash::from_slice(get_bytes(input, 32)?).expect("Length Checked"));
+        }
+        let grant = match get_u8(input)? {
+            0 => None,
+            1 => Some(KeyGrant::Rotate {
+                to: get_key(input)?,
+            }),
+            2 => Some(KeyGrant::Delegate {
+                to: get_key(input)?,
+                until_height: if get_flag(input)? {
+                    Some(get_i64(input)?)
+                } else {
+                    None
+                },
+            }),
+            3 => Some(KeyGrant::Revoke {
+                key: get_key(input)?,
+            }),
+            t => return Err(BinaryError::InvalidTag(t)),
+        };
+        let mut attachments = vec![];
+        if version >= FormatVersion::V2 {
+            let n = get_varint(input)?;
+            // every attachment takes at least 33 bytes
+            if n > (input.len() / 33) as u64 {
+                return Err(BinaryError::UnexpectedEnd);
+            }
+            attachments.reserve(n as usize);
+            for _ in 0..n {
+                let hash = sha256::Hash::from_slice(get_bytes(input, 32)?).expect("Length Checked");
+                let size =
+                    u32::try_from(get_varint(input)?).map_err(|_| BinaryError::VarintOverflow)?;
+                attachments.push(BlobRef { hash, size });
+            }
+        }
+        let ephemeral = if version >= FormatVersion::V3 && get_flag(input)? {
+            let ttl_ms = get_varint(input)?;
+            let msg_hash = sha256::Hash::from_slice(get_bytes(input, 32)?).expect("Length Checked");
+            Some(Ephemeral { ttl_ms, msg_hash })
+        } else {
+            None
+        };
+        let header = Header::new(
+            key,
+            next_nonce,
+            ancestors,
+            tips,
+            height,
+            sent_time_ms,
+            Unsigned::new(signature),
+            checkpoints,
+            AncestryPeaks(peaks),
+        )
+        .with_attachments(attachments);
+        let header = match ephemeral {
+            Some(e) => header.with_ephemeral(e),
+            None => header,
+        };
+        // the version as encoded, even if it doesn't cover the features used,
+        // so that such envelopes fail to authenticate rather than change hash
+        let header = header.with_version(version);
+        Ok(match grant {
+            Some(g) => header.with_grant(g),
+            None => header,
+        })
+    }
+}
+
+impl<T> GenericEnvelope<T>
+where
+    T: AttestEnvelopable,
+{
+    /// Encodes this envelope in the binary format described in
+    /// [`crate::binary`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the message can't be
+    /// canonicalized.
+    pub fn to_binary(&self) -> Result<Vec<u8>, BinaryError> {
+        let mut out = Vec::with_capacity(512);
+        self.encode_binary(&mut out)?;
+        Ok(out)
+    }
+
+    pub fn encode_binary(&self, out: &mut Vec<u8>) -> Result<(), BinaryError> {
+        out.push(self.header.binary_version());
+        self.header.encode_binary(out);
+        let msg = self.canonical_msg()?.to_string();
+        put_varint(out, msg.len() as u64);
+        out.extend_from_slice(msg.as_bytes());
+        Ok(())
+    }
+
+    /// Decodes a single envelope, which must span all of `b`.
+    pub fn from_binary(b: &[u8]) -> Result<Self, BinaryError> {
+        let mut input = b;
+        let e = Self::decode_binary(&mut input)?;
+        if !input.is_empty() {
+            return Err(BinaryError::TrailingBytes);
+        }
+        Ok(e)
+    }
+
+    /// Decodes an envelope from the front of `input`, advancing it.
+    ///
+    /// The hash of the returned envelope is computed from its contents.
+    pub fn decode_binary(input: &mut &[u8]) -> Result<Self, BinaryError> {
+        let binary_version = get_u8(input)?;
+        let header = Header::decode_binary(input, binary_version)?;
+        let len = get_varint(input)?;
+        if len > input.len() as u64 {
+            return Err(BinaryError::UnexpectedEnd);
+        }
+        let raw = get_bytes(input, len as usize)?;
+        let value: serde_json::Value = serde_json::from_slice(raw)?;
+        let canonical = CanonicalJsonValue::try_from(value)?;
+        // enforce a single encoding per envelope
+        if canonical.to_string().as_bytes() != raw {
+            return Err(BinaryError::NonCanonicalMsg);
+        }
+        let msg = T::from_canonical(canonical.clone())?;
+        Ok(GenericEnvelope::new_as_read(header, msg, canonical))
+    }
+}
+
+/// A list of envelopes as a count followed by each envelope prefixed with its
+/// length.
+pub fn encode_envelopes<T: AttestEnvelopable>(
+    envelopes: &[GenericEnvelope<T>],
+    out: &mut Vec<u8>,
+) -> Result<(), BinaryError> {
+    put_varint(out, envelopes.len() as u64);
+    let mut buf = Vec::with_capacity(512);
+    for e in envelopes {
+        buf.clear();
+        e.encode_binary(&mut buf)?;
+        put_varint(out, buf.len() as u64);
+        out.extend_from_slice(&buf);
+    }
+    Ok(())
+}
+
+pub fn decode_envelopes<T: AttestEnvelopable>(
+    input: &mut &[u8],
+) -> Result<Vec<GenericEnvelope<T>>, BinaryError> {
+    let n = get_varint(input)?;
+    // an envelope is well over 100 bytes
+    if n > (input.len() / 100) as u64 {
+        return Err(BinaryError::UnexpectedEnd);
+    }
+    let mut envelopes = Vec::with_capacity(n as usize);
+    for _ in 0..n {
+        let len = get_varint(input)?;
+        if len > input.len() as u64 {
+            return Err(BinaryError::UnexpectedEnd);
+        }
+        envelopes.push(GenericEnvelope::from_binary(get_bytes(
+            input,
+            len as usize,
+        )?)?);
+    }
+    Ok(envelopes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nonce::PrecomittedNonce;
+    use crate::{Envelope, WrappedJson};
+    use sapio_bitcoin::secp256k1::rand;
+    use sapio_bitcoin::secp256k1::Secp256k1;
+    use sapio_bitcoin::KeyPair;
+    use serde_json::json;
+
+    fn make_chain(n: usize) -> Vec<Envelope> {
+        let secp = Secp256k1::new();
+        let kp = KeyPair::new(&secp, &mut rand::thread_rng());
+        let tip_kp = KeyPair::new(&secp, &mut rand::thread_rng());
+        let mut nonce = PrecomittedNonce::new(&secp);
+        let mut chain: Vec<Envelope> = vec![];
+        for i in 0..n {
+            let next = PrecomittedNonce::new(&secp);
+            let ancestors = chain
+                .last()
+                .map(|p| Ancestors::new(p.canonicalized_hash_ref(), p.get_genesis_hash()));
+            let tips = chain
+                .last()
+                .map(|p| {
+                    vec![(
+                        tip_kp.x_only_public_key().0,
+                        -(i as i64),
+                        p.canonicalized_hash_ref(),
+                    )]
+                })
+                .unwrap_or_default();
+            let mut checkpoints = BitcoinCheckPoints::default();
+            checkpoints.checkpoints[0] = (BlockHash::hash(&[i as u8]), 750_000 + i as i64);
+            let header = Header::new(
+                kp.x_only_public_key().0,
+                next.get_public(&secp),
+                ancestors,
+                tips,
+                i as i64,
+                1_660_000_000_000 + i as i64,
+                Unsigned::new(None),
+                checkpoints,
+                chain
+                    .last()
+                    .and_then(AncestryPeaks::for_child_of)
+                    .unwrap_or_default(),
+            );
+            // exercise every kind of grant
+            let delegate = tip_kp.x_only_public_key().0;
+            let header = match i % 4 {
+                1 => header.with_grant(KeyGrant::Delegate {
+                    to: delegate,
+                    until_height: Some(i as i64 + 10),
+                }),
+                2 => header.with_grant(KeyGrant::Revoke { key: delegate }),
+                3 => header.with_grant(KeyGrant::Delegate {
+                    to: delegate,
+                    until_height: None,
+                }),
+                _ => header,
+            };
+            let msg = CanonicalJsonValue::try_from(
+                json!({"sequence": i, "d": {"Trade": {"pair": ["Bitcoin", "ASIC"], "amount": -100 * i as i64}}}),
+            )
+            .unwrap();
+            // and envelopes from before format versions, with attachments or
+            // ephemeral
+            let header = match i % 3 {
+                2 => header.with_version(FormatVersion::LEGACY),
+                1 => header.with_attachments(vec![
+                    BlobRef::of(b"contract").unwrap(),
+                    BlobRef::of(&[i as u8; 300]).unwrap(),
+                ]),
+                _ if i % 2 == 1 => header.with_ephemeral(Ephemeral::of(60_000, &msg)),
+                _ => header,
+            };
+            let mut e = Envelope::new(header, WrappedJson::from(msg));
+            e.sign_with(&kp, &secp, nonce).unwrap();
+            nonce = next;
+            chain.push(e);
+        }
+        chain
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let secp = Secp256k1::new();
+        for e in make_chain(10) {
+            let b = e.to_binary().unwrap();
+            let d = Envelope::from_binary(&b).unwrap();
+            assert_eq!(d, e);
+            assert_eq!(d.to_binary().unwrap(), b);
+            d.self_authenticate(&secp).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_cross_encoding_hash() {
+        for e in make_chain(10) {
+            let via_json: Envelope =
+                serde_json::from_str(&serde_json::to_string(&e).unwrap()).unwrap();
+            let via_binary = Envelope::from_binary(&e.to_binary().unwrap()).unwrap();
+            assert_eq!(
+                via_json.canonicalized_hash_ref(),
+                via_binary.canonicalized_hash_ref()
+            );
+            assert_eq!(
+                e.canonicalized_hash_ref(),
+                via_binary.canonicalized_hash_ref()
+            );
+            // json -> binary and binary -> json agree byte for byte
+            assert_eq!(via_json.to_binary().unwrap(), e.to_binary().unwrap());
+            assert_eq!(
+                serde_json::to_string(&via_binary).unwrap(),
+                serde_json::to_string(&e).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_smaller_than_json() {
+        let chain = make_chain(10);
+        let mut b = vec![];
+        encode_envelopes(&chain, &mut b).unwrap();
+        let json = serde_json::to_string(&chain).unwrap();
+        assert!(b.len() * 2 < json.len());
+        let mut input = &b[..];
+        let decoded: Vec<Envelope> = decode_envelopes(&mut input).unwrap();
+        assert!(input.is_empty());
+        assert_eq!(decoded, chain);
+    }
+
+    #[test]
+    fn test_rejects_non_canonical() {
+        let e = &make_chain(1)[0];
+        let b = e.to_binary().unwrap();
+
+        let mut v = b.clone();
+        v[0] = 3;
+        assert!(matches!(
+            Envelope::from_binary(&v),
+            Err(BinaryError::UnknownVersion(3))
+        ));
+
+        // a current envelope can't be passed off as a legacy one
+        let mut v = b.clone();
+        v[0] = LEGACY_BINARY_VERSION;
+        assert!(Envelope::from_binary(&v).is_err());
+
+        let mut v = b.clone();
+        v.push(0);
+        assert!(matches!(
+            Envelope::from_binary(&v),
+            Err(BinaryError::TrailingBytes)
+        ));
+
+        for i in 0..b.len() {
+            assert!(Envelope::from_binary(&b[..i]).is_err());
+        }
+
+        // same message, but with whitespace
+        let mut v = vec![];
+        v.push(e.header().binary_version());
+        e.header().encode_binary(&mut v);
+        let msg = serde_json::to_string_pretty(e.msg()).unwrap();
+        put_varint(&mut v, msg.len() as u64);
+        v.extend_from_slice(msg.as_bytes());
+        assert!(matches!(
+            Envelope::from_binary(&v),
+            Err(BinaryError::NonCanonicalMsg)
+        ));
+
+        let mut input: &[u8] = &[0x80, 0x00];
+        assert!(matches!(
+            get_varint(&mut input),
+            Err(BinaryError::NonMinimalVarint)
+        ));
+    }
+
+    #[test]
+    fn test_rejects_too_many_peaks() {
+        let e = &make_chain(2)[1];
+        let h = e.header();
+        let header = Header::new(
+            h.key(),
+            h.next_nonce(),
+            h.ancestors().cloned(),
+            h.tips().to_vec(),
+            h.height(),
+            h.sent_time_ms(),
+            h.unsigned().clone(),
+            h.checkpoints().clone(),
+            AncestryPeaks(vec![e.canonicalized_hash_ref().0; 65]),
+        );
+        let mut v = vec![header.binary_version()];
+        header.encode_binary(&mut v);
+        let msg = e.canonical_msg().unwrap().to_string();
+        put_varint(&mut v, msg.len() as u64);
+        v.extend_from_slice(msg.as_bytes());
+        assert!(matches!(
+            Envelope::from_binary(&v),
+            Err(BinaryError::TooManyPeaks(65))
+        ));
+    }
+
+    #[test]
+    fn test_varint() {
+        for v in [0, 1, 127, 128, 300, u64::MAX / 2, u64::MAX] {
+            let mut b = vec![];
+            put_varint(&mut b, v);
+            let mut input = &b[..];
+            assert_eq!(get_varint(&mut input).unwrap(), v);
+            assert!(input.is_empty());
+        }
+        for v in [0, -1, 1, i64::MIN, i64::MAX] {
+            let mut b = vec![];
+            put_i64(&mut b, v);
+            let mut input = &b[..];
+            assert_eq!(get_i64(&mut input).unwrap(), v);
+        }
+    }
+}
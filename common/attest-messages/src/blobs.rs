@@ -0,0 +1,126 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Large attachments referenced from envelopes by their hash.
+//!
+//! Things like compiled contracts or PSBTs don't need to be inlined in every
+//! message and every sync. Instead an envelope lists the [`BlobRef`]s of its
+//! attachments in its header, and the attachments themselves are stored and
+//! fetched separately, on demand.
+//!
+//! Since a [`BlobRef`] commits to the sha256 of the attachment, an attachment
+//! can be checked against the envelope no matter who it was fetched from. The
+//! size is committed to as well so that a fetcher knows how much to expect
+//! before asking.
+//!
+//! Attachments need at least [`crate::FormatVersion::V2`], peers from before
+//! it would drop them and so fail to verify the envelope.
+
+use sapio_bitcoin::hashes::{sha256, Hash};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Display;
+
+/// Largest attachment an envelope may reference
+pub const MAX_BLOB_SIZE: u32 = 16 * 1024 * 1024;
+/// Most attachments a single envelope may reference
+pub const MAX_ATTACHMENTS: usize = 16;
+
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Hash, JsonSchema,
+)]
+pub struct BlobRef {
+    #[schemars(with = "String")]
+    pub hash: sha256::Hash,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BlobError {
+    TooLarge {
+        size: u64,
+        max: u32,
+    },
+    SizeMismatch {
+        expected: u32,
+        found: u64,
+    },
+    HashMismatch {
+        expected: sha256::Hash,
+        found: sha256::Hash,
+    },
+}
+
+impl Display for BlobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for BlobError {}
+
+impl BlobRef {
+    /// The reference to `data`, if it is small enough to attach.
+    pub fn of(data: &[u8]) -> Result<BlobRef, BlobError> {
+        let size = u32::try_from(data.len())
+            .ok()
+            .filter(|s| *s <= MAX_BLOB_SIZE)
+            .ok_or(BlobError::TooLarge {
+                size: data.len() as u64,
+                max: MAX_BLOB_SIZE,
+            })?;
+        Ok(BlobRef {
+            hash: sha256::Hash::hash(data),
+            size,
+        })
+    }
+
+    /// Checks that `data` is the attachment this refers to.
+    pub fn check(&self, data: &[u8]) -> Result<(), BlobError> {
+        if data.len() as u64 != self.size as u64 {
+            return Err(BlobError::SizeMismatch {
+                expected: self.size,
+                found: data.len() as u64,
+            });
+        }
+        let found = sha256::Hash::hash(data);
+        if found != self.hash {
+            return Err(BlobError::HashMismatch {
+                expected: self.hash,
+                found,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_blob_ref() {
+        let data = b"a compiled contract".to_vec();
+        let r = BlobRef::of(&data).unwrap();
+        assert_eq!(r.size as usize, data.len());
+        r.check(&data).unwrap();
+
+        let mut tampered = data.clone();
+        tampered[0] ^= 1;
+        assert!(matches!(
+            r.check(&tampered),
+            Err(BlobError::HashMismatch { .. })
+        ));
+        assert!(matches!(
+            r.check(&data[1..]),
+            Err(BlobError::SizeMismatch { .. })
+        ));
+        assert!(matches!(
+            BlobRef::of(&vec![0; MAX_BLOB_SIZE as usize + 1]),
+            Err(BlobError::TooLarge { .. })
+        ));
+    }
+}
@@ -0,0 +1,327 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Message bodies only designated recipients can read.
+//!
+//! An [`EncryptedPayload`] is an ordinary message body, so its ciphertext is
+//! committed to by the envelope's hash and signature and it replicates like
+//! any other message; only holders of a recipient key can open it.
+//!
+//! Sealing picks a random content key and encrypts the canonical JSON of the
+//! plaintext under it with ChaCha20-Poly1305. The content key is then wrapped
+//! for each recipient under a key derived from an ECDH between a fresh
+//! ephemeral key and the recipient's key. The sender, ephemeral key and
+//! recipient list are authenticated as associated data, so none of them can
+//! be swapped without the payload failing to open.
+//!
+//! Recipients are x-only keys (the keys chains are signed with), and are
+//! taken to have an even y coordinate for the ECDH.
+
+use crate::AttestEnvelopable;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ruma_serde::CanonicalJsonValue;
+use sapio_bitcoin::hashes::hex::{FromHex, ToHex};
+use sapio_bitcoin::hashes::{sha256, Hash, HashEngine};
+use sapio_bitcoin::secp256k1::ecdh::SharedSecret;
+use sapio_bitcoin::secp256k1::rand::{thread_rng, Rng};
+use sapio_bitcoin::secp256k1::{Parity, PublicKey, Secp256k1, SecretKey, Signing};
+use sapio_bitcoin::{KeyPair, XOnlyPublicKey};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::error::Error;
+use std::fmt::Display;
+
+/// A payload may not be addressed to more keys than this.
+pub const MAX_RECIPIENTS: usize = 64;
+
+const KEY_WRAP_TAG: &[u8] = b"attest/encrypted/key-wrap";
+
+/// Bytes that serialize as hex.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, JsonSchema)]
+pub struct HexBytes(#[schemars(with = "String")] pub Vec<u8>);
+
+impl Serialize for HexBytes {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.0.to_hex())
+    }
+}
+impl<'de> Deserialize<'de> for HexBytes {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        Vec::<u8>::from_hex(&s)
+            .map(HexBytes)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The content key, encrypted for one recipient.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash, JsonSchema)]
+pub struct SealedKey {
+    #[schemars(with = "String")]
+    pub to: XOnlyPublicKey,
+    pub key: HexBytes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash, JsonSchema)]
+pub struct EncryptedPayload {
+    /// who sealed the payload, expected to be the key signing the envelope
+    #[schemars(with = "String")]
+    sender: XOnlyPublicKey,
+    #[schemars(with = "String")]
+    ephemeral: PublicKey,
+    nonce: HexBytes,
+    recipients: Vec<SealedKey>,
+    ciphertext: HexBytes,
+}
+
+#[derive(Debug)]
+pub enum EncryptionError {
+    /// none of the given keys can open the payload
+    NotARecipient,
+    TooManyRecipients,
+    NoRecipients,
+    /// the payload was sealed by a different key than signed its envelope
+    SenderMismatch,
+    /// the ciphertext or a sealed key failed to authenticate
+    DecryptionFailed,
+    Secp256k1Error(sapio_bitcoin::secp256k1::Error),
+    SerializerError(serde_json::Error),
+    CanonicalizationError(ruma_serde::CanonicalJsonError),
+}
+
+impl Display for EncryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for EncryptionError {}
+
+impl From<sapio_bitcoin::secp256k1::Error> for EncryptionError {
+    fn from(e: sapio_bitcoin::secp256k1::Error) -> Self {
+        EncryptionError::Secp256k1Error(e)
+    }
+}
+impl From<serde_json::Error> for EncryptionError {
+    fn from(e: serde_json::Error) -> Self {
+        EncryptionError::SerializerError(e)
+    }
+}
+impl From<ruma_serde::CanonicalJsonError> for EncryptionError {
+    fn from(e: ruma_serde::CanonicalJsonError) -> Self {
+        EncryptionError::CanonicalizationError(e)
+    }
+}
+
+fn even_point(key: &XOnlyPublicKey) -> Result<PublicKey, EncryptionError> {
+    let mut compressed = [2u8; 33];
+    compressed[1..].copy_from_slice(&key.serialize());
+    Ok(PublicKey::from_slice(&compressed)?)
+}
+
+fn key_wrap_cipher(
+    shared: &SharedSecret,
+    ephemeral: &PublicKey,
+    to: &XOnlyPublicKey,
+) -> ChaCha20Poly1305 {
+    let mut engine = sha256::Hash::engine();
+    engine.input(KEY_WRAP_TAG);
+    engine.input(shared.as_ref());
+    engine.input(&ephemeral.serialize());
+    engine.input(&to.serialize());
+    let kek = sha256::Hash::from_engine(engine);
+    ChaCha20Poly1305::new(Key::from_slice(&kek[..]))
+}
+
+impl EncryptedPayload {
+    /// Encrypts `plaintext` so that only `recipients` can read it.
+    ///
+    /// Include the sender's own key in `recipients` to be able to read it
+    /// back later.
+    pub fn seal<C: Signing>(
+        secp: &Secp256k1<C>,
+        sender: XOnlyPublicKey,
+        recipients: &[XOnlyPublicKey],
+        plaintext: &CanonicalJsonValue,
+    ) -> Result<Self, EncryptionError> {
+        if recipients.is_empty() {
+            return Err(EncryptionError::NoRecipients);
+        }
+        if recipients.len() > MAX_RECIPIENTS {
+            return Err(EncryptionError::TooManyRecipients);
+        }
+        let mut rng = thread_rng();
+        let content_key: [u8; 32] = rng.gen();
+        let nonce: [u8; 12] = rng.gen();
+        let ephemeral_sk = SecretKey::new(&mut rng);
+        let ephemeral = PublicKey::from_secret_key(secp, &ephemeral_sk);
+        let mut sealed = Vec::with_capacity(recipients.len());
+        for to in recipients {
+            let shared = SharedSecret::new(&even_point(to)?, &ephemeral_sk);
+            // every key wrapping key is used exactly once, so a fixed nonce
+            // is safe
+            let key = key_wrap_cipher(&shared, &ephemeral, to)
+                .encrypt(Nonce::from_slice(&[0u8; 12]), &content_key[..])
+                .map_err(|_| EncryptionError::DecryptionFailed)?;
+            sealed.push(SealedKey {
+                to: *to,
+                key: HexBytes(key),
+            });
+        }
+        let mut payload = EncryptedPayload {
+            sender,
+            ephemeral,
+            nonce: HexBytes(nonce.to_vec()),
+            recipients: sealed,
+            ciphertext: HexBytes(vec![]),
+        };
+        let aad = payload.associated_data();
+        payload.ciphertext = HexBytes(
+            ChaCha20Poly1305::new(Key::from_slice(&content_key))
+                .encrypt(
+                    Nonce::from_slice(&nonce),
+                    Payload {
+                        msg: plaintext.to_string().as_bytes(),
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| EncryptionError::DecryptionFailed)?,
+        );
+        Ok(payload)
+    }
+
+    /// Decrypts the payload with one of the recipients' keys.
+    pub fn open<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        keypair: &KeyPair,
+    ) -> Result<CanonicalJsonValue, EncryptionError> {
+        let (me, parity) = keypair.x_only_public_key();
+        let sealed = self
+            .recipients
+            .iter()
+            .find(|r| r.to == me)
+            .ok_or(EncryptionError::NotARecipient)?;
+        // the sender used the even point for our x-only key
+        let mut sk = keypair.secret_key();
+        if parity == Parity::Odd {
+            sk.negate_assign();
+        }
+        debug_assert_eq!(PublicKey::from_secret_key(secp, &sk), even_point(&me)?);
+        let shared = SharedSecret::new(&self.ephemeral, &sk);
+        let content_key = key_wrap_cipher(&shared, &self.ephemeral, &me)
+            .decrypt(Nonce::from_slice(&[0u8; 12]), &sealed.key.0[..])
+            .map_err(|_| EncryptionError::DecryptionFailed)?;
+        if content_key.len() != 32 || self.nonce.0.len() != 12 {
+            return Err(EncryptionError::DecryptionFailed);
+        }
+        let plaintext = ChaCha20Poly1305::new(Key::from_slice(&content_key))
+            .decrypt(
+                Nonce::from_slice(&self.nonce.0),
+                Payload {
+                    msg: &self.ciphertext.0,
+                    aad: &self.associated_data(),
+                },
+            )
+            .map_err(|_| EncryptionError::DecryptionFailed)?;
+        let value: serde_json::Value = serde_json::from_slice(&plaintext)?;
+        Ok(CanonicalJsonValue::try_from(value)?)
+    }
+
+    pub fn sender(&self) -> XOnlyPublicKey {
+        self.sender
+    }
+
+    pub fn recipients(&self) -> impl Iterator<Item = XOnlyPublicKey> + '_ {
+        self.recipients.iter().map(|r| r.to)
+    }
+
+    pub fn is_for(&self, key: &XOnlyPublicKey) -> bool {
+        self.recipients.iter().any(|r| r.to == *key)
+    }
+
+    /// everything but the ciphertext
+    fn associated_data(&self) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(32 + 33 + 12 + self.recipients.len() * 80);
+        aad.extend_from_slice(&self.sender.serialize());
+        aad.extend_from_slice(&self.ephemeral.serialize());
+        aad.extend_from_slice(&self.nonce.0);
+        for r in &self.recipients {
+            aad.extend_from_slice(&r.to.serialize());
+            aad.extend_from_slice(&r.key.0);
+        }
+        aad
+    }
+}
+
+impl AttestEnvelopable for EncryptedPayload {
+    type Ref = EncryptedPayload;
+    fn as_canonical(&self) -> Result<CanonicalJsonValue, ruma_serde::CanonicalJsonError> {
+        ruma_serde::to_canonical_value(self)
+    }
+}
+
+impl AsRef<EncryptedPayload> for EncryptedPayload {
+    fn as_ref(&self) -> &EncryptedPayload {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sapio_bitcoin::secp256k1::rand;
+    use serde_json::json;
+
+    #[test]
+    fn test_seal_and_open() {
+        let secp = Secp256k1::new();
+        let keys: Vec<KeyPair> = (0..4)
+            .map(|_| KeyPair::new(&secp, &mut rand::thread_rng()))
+            .collect();
+        let pks: Vec<_> = keys.iter().map(|k| k.x_only_public_key().0).collect();
+        let plaintext = CanonicalJsonValue::try_from(
+            json!({"offer": {"pair": ["Bitcoin", "ASIC"], "price": 21}}),
+        )
+        .unwrap();
+        let payload = EncryptedPayload::seal(&secp, pks[0], &pks[..3], &plaintext).unwrap();
+        // survives the trip through json an envelope takes
+        let payload: EncryptedPayload =
+            serde_json::from_str(&serde_json::to_string(&payload).unwrap()).unwrap();
+        for k in &keys[..3] {
+            assert_eq!(payload.open(&secp, k).unwrap(), plaintext);
+        }
+        assert!(matches!(
+            payload.open(&secp, &keys[3]),
+            Err(EncryptionError::NotARecipient)
+        ));
+        assert!(!serde_json::to_string(&payload).unwrap().contains("ASIC"));
+    }
+
+    #[test]
+    fn test_tampering_detected() {
+        let secp = Secp256k1::new();
+        let kp = KeyPair::new(&secp, &mut rand::thread_rng());
+        let other = KeyPair::new(&secp, &mut rand::thread_rng());
+        let me = kp.x_only_public_key().0;
+        let plaintext = CanonicalJsonValue::String("secret".into());
+        let payload = EncryptedPayload::seal(&secp, me, &[me], &plaintext).unwrap();
+
+        let mut bad = payload.clone();
+        bad.ciphertext.0[0] ^= 1;
+        assert!(matches!(
+            bad.open(&secp, &kp),
+            Err(EncryptionError::DecryptionFailed)
+        ));
+        // claiming someone else sealed it
+        let mut bad = payload.clone();
+        bad.sender = other.x_only_public_key().0;
+        assert!(matches!(
+            bad.open(&secp, &kp),
+            Err(EncryptionError::DecryptionFailed)
+        ));
+    }
+}
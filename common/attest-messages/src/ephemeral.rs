@@ -0,0 +1,291 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Envelopes whose message is only kept for a while.
+//!
+//! Things like heartbeats or chat don't need to outlive the game they were
+//! sent in, but the envelopes carrying them are still links in their chain.
+//! An ephemeral envelope commits to its message through the sha256 in its
+//! header, and its [`CanonicalEnvelopeHash`] is taken with the message left
+//! out. Once `ttl_ms` has passed since the envelope was sent its message may
+//! be pruned, replaced by `null`, without changing its hash or signature, so
+//! that children and tips referencing it stay valid.
+//!
+//! A pruned envelope still authenticates once it has expired, and is
+//! replicated instead of the full one from then on. Before that a missing
+//! message is refused, allowing for some skew between clocks, see
+//! [`MAX_CLOCK_SKEW_MS`]. Pruned messages read back as `null`, so only
+//! message types that accept it (e.g. [`WrappedJson`]) can read pruned
+//! envelopes.
+//!
+//! Ephemeral envelopes need at least [`crate::FormatVersion::V3`].
+//!
+//! [`CanonicalEnvelopeHash`]: crate::CanonicalEnvelopeHash
+
+use crate::{AttestEnvelopable, Authenticated, Envelope, GenericEnvelope, WrappedJson};
+use ruma_serde::CanonicalJsonValue;
+use sapio_bitcoin::hashes::{sha256, Hash};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How far ahead of ours a peer's clock may run. A pruned message is accepted
+/// once its envelope has expired by our clock plus this much, or plus half
+/// its `ttl_ms` if that is less, so that a short lived message can't be
+/// stripped as soon as it is sent.
+pub const MAX_CLOCK_SKEW_MS: i64 = 5 * 60 * 1000;
+
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Hash, JsonSchema,
+)]
+pub struct Ephemeral {
+    /// how long after `sent_time_ms` the message is kept
+    pub ttl_ms: u64,
+    /// sha256 of the canonical JSON of the message
+    #[schemars(with = "String")]
+    pub msg_hash: sha256::Hash,
+}
+
+impl Ephemeral {
+    /// Makes `msg` expire `ttl_ms` after it is sent.
+    pub fn of(ttl_ms: u64, msg: &CanonicalJsonValue) -> Ephemeral {
+        Ephemeral {
+            ttl_ms,
+            msg_hash: hash_msg(msg),
+        }
+    }
+
+    /// The clock skew allowed when pruned, see [`MAX_CLOCK_SKEW_MS`].
+    pub fn max_clock_skew_ms(&self) -> i64 {
+        MAX_CLOCK_SKEW_MS.min(i64::try_from(self.ttl_ms / 2).unwrap_or(i64::MAX))
+    }
+}
+
+pub fn hash_msg(msg: &CanonicalJsonValue) -> sha256::Hash {
+    sha256::Hash::hash(msg.to_string().as_bytes())
+}
+
+pub(crate) fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| i64::try_from(d.as_millis()).unwrap_or(i64::MAX))
+}
+
+impl<T> GenericEnvelope<T>
+where
+    T: AttestEnvelopable,
+{
+    /// When the message of this envelope may be pruned, if it is ephemeral.
+    pub fn expires_at_ms(&self) -> Option<i64> {
+        self.header.ephemeral.map(|e| {
+            self.header
+                .sent_time_ms
+                .saturating_add(i64::try_from(e.ttl_ms).unwrap_or(i64::MAX))
+        })
+    }
+
+    pub fn is_expired(&self, now_ms: i64) -> bool {
+        self.expires_at_ms().map_or(false, |t| t <= now_ms)
+    }
+
+    /// Whether the message has been pruned, i.e. is `null` in place of the one
+    /// committed to.
+    pub fn is_pruned(&self) -> bool {
+        match (&self.header.ephemeral, self.canonical_msg()) {
+            (Some(e), Ok(CanonicalJsonValue::Null)) => {
+                e.msg_hash != hash_msg(&CanonicalJsonValue::Null)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the message is the one the header commits to, or has been
+    /// pruned after expiring by `now_ms`, see [`MAX_CLOCK_SKEW_MS`]. Always
+    /// true for envelopes which aren't ephemeral.
+    pub(crate) fn check_ephemeral_msg(&self, now_ms: i64) -> bool {
+        match &self.header.ephemeral {
+            None => true,
+            Some(e) => match self.canonical_msg() {
+                Ok(m) if hash_msg(&m) == e.msg_hash => true,
+                Ok(CanonicalJsonValue::Null) => {
+                    self.is_expired(now_ms.saturating_add(e.max_clock_skew_ms()))
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+impl Envelope {
+    /// Drops the message of an ephemeral envelope, leaving its hash and
+    /// signature intact. Returns whether there was anything to drop.
+    pub fn prune(&mut self) -> bool {
+        if self.header.ephemeral.is_none() || self.is_pruned() {
+            return false;
+        }
+        self.msg = WrappedJson::from(CanonicalJsonValue::Null);
+        self.canonical = Some(CanonicalJsonValue::Null);
+        true
+    }
+
+    /// Like [`Self::prune`], but only once the envelope has expired.
+    pub fn prune_if_expired(&mut self, now_ms: i64) -> bool {
+        self.is_expired(now_ms) && self.prune()
+    }
+}
+
+impl Authenticated<Envelope> {
+    /// Pruning leaves the signature valid, so an authenticated envelope stays
+    /// authenticated, see [`Envelope::prune_if_expired`].
+    pub fn prune_if_expired(&mut self, now_ms: i64) -> bool {
+        self.0.prune_if_expired(now_ms)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nonce::PrecomittedNonce;
+    use crate::{Ancestors, AuthenticationError, FormatVersion, Header, Unsigned};
+    use sapio_bitcoin::secp256k1::{rand, Secp256k1};
+    use sapio_bitcoin::KeyPair;
+    use serde_json::json;
+
+    fn make(msg: CanonicalJsonValue, ttl_ms: Option<u64>) -> Envelope {
+        make_at(msg, ttl_ms, 1_000)
+    }
+
+    fn make_at(msg: CanonicalJsonValue, ttl_ms: Option<u64>, sent_time_ms: i64) -> Envelope {
+        let secp = Secp256k1::new();
+        let kp = KeyPair::new(&secp, &mut rand::thread_rng());
+        let header = Header::new(
+            kp.x_only_public_key().0,
+            PrecomittedNonce::new(&secp).get_public(&secp),
+            Some(Ancestors::new(
+                crate::CanonicalEnvelopeHash::genesis(),
+                crate::CanonicalEnvelopeHash::genesis(),
+            )),
+            vec![],
+            1,
+            sent_time_ms,
+            Unsigned::new(None),
+            Default::default(),
+            Default::default(),
+        );
+        let header = match ttl_ms {
+            Some(ttl) => header.with_ephemeral(Ephemeral::of(ttl, &msg)),
+            None => header,
+        };
+        let mut e = Envelope::new(header, WrappedJson::from(msg));
+        e.sign_with(&kp, &secp, PrecomittedNonce::new(&secp))
+            .unwrap();
+        e
+    }
+
+    fn chat() -> CanonicalJsonValue {
+        CanonicalJsonValue::try_from(json!({"chat": "gg"})).unwrap()
+    }
+
+    #[test]
+    fn test_prune_keeps_hash_and_signature() {
+        let secp = Secp256k1::new();
+        let mut e = make(chat(), Some(5_000));
+        let hash = e.canonicalized_hash_ref();
+        assert_eq!(e.expires_at_ms(), Some(6_000));
+        assert!(!e.prune_if_expired(5_999));
+        assert!(!e.is_pruned());
+        assert!(e.prune_if_expired(6_000));
+        assert!(e.is_pruned());
+        assert!(!e.prune());
+        assert_eq!(e.msg(), &CanonicalJsonValue::Null);
+
+        // the hash doesn't depend on the message, even when recomputed
+        let e: Envelope = serde_json::from_str(&serde_json::to_string(&e).unwrap()).unwrap();
+        assert_eq!(e.canonicalized_hash_ref(), hash);
+        let e = Envelope::from_binary(&e.to_binary().unwrap()).unwrap();
+        assert_eq!(e.canonicalized_hash_ref(), hash);
+        e.self_authenticate(&secp).unwrap();
+    }
+
+    #[test]
+    fn test_only_ephemeral_pruned() {
+        let mut e = make(chat(), None);
+        assert_eq!(e.expires_at_ms(), None);
+        assert!(!e.prune());
+        assert!(!e.prune_if_expired(i64::MAX));
+        assert_eq!(e.msg(), &chat());
+
+        // a null message isn't pruned, it's just null
+        let e = make(CanonicalJsonValue::Null, Some(0));
+        assert!(!e.is_pruned());
+        assert!(e.check_ephemeral_msg(0));
+    }
+
+    #[test]
+    fn test_rejects_swapped_msg() {
+        let secp = Secp256k1::new();
+        let e = make(chat(), Some(5_000));
+        let mut v = serde_json::to_value(&e).unwrap();
+        v["msg"] = json!({"chat": "not gg"});
+        let swapped: Envelope = serde_json::from_value(v).unwrap();
+        // the hash is the same, but the message isn't the one committed to
+        assert_eq!(swapped.canonicalized_hash_ref(), e.canonicalized_hash_ref());
+        assert!(matches!(
+            swapped.self_authenticate(&secp),
+            Err(AuthenticationError::InvalidEphemeral)
+        ));
+    }
+
+    #[test]
+    fn test_rejects_pruned_before_expiry() {
+        let secp = Secp256k1::new();
+        let hour = 60 * 60 * 1000;
+        let mut e = make(chat(), Some(hour as u64));
+        assert!(e.prune());
+        // expires an hour after 1_000, give or take the skew allowed
+        assert!(!e.check_ephemeral_msg(1_000 + hour - MAX_CLOCK_SKEW_MS - 1));
+        assert!(e.check_ephemeral_msg(1_000 + hour - MAX_CLOCK_SKEW_MS));
+
+        // a short lived message only gets half its ttl, rather than being
+        // prunable as soon as it is sent
+        let mut e = make(chat(), Some(5_000));
+        assert!(e.prune());
+        assert!(!e.check_ephemeral_msg(1_000));
+        assert!(!e.check_ephemeral_msg(6_000 - 2_500 - 1));
+        assert!(e.check_ephemeral_msg(6_000 - 2_500));
+        let mut e = make_at(chat(), Some(10_000), now_ms());
+        assert!(e.prune());
+        assert!(matches!(
+            e.self_authenticate(&secp),
+            Err(AuthenticationError::InvalidEphemeral)
+        ));
+
+        // a peer can't strip the message of an envelope that is still live
+        let mut e = make_at(chat(), Some(60 * 60 * 1000), now_ms());
+        assert!(e.prune());
+        assert!(matches!(
+            e.self_authenticate(&secp),
+            Err(AuthenticationError::InvalidEphemeral)
+        ));
+    }
+
+    #[test]
+    fn test_needs_v3() {
+        let secp = Secp256k1::new();
+        let e = make(chat(), Some(5_000));
+        // stamped with the lowest version that can carry it
+        assert_eq!(e.header().version(), FormatVersion::V3);
+        assert_eq!(make(chat(), None).header().version(), FormatVersion::V1);
+        let old = Envelope::new(
+            e.header().clone().with_version(FormatVersion::V2),
+            WrappedJson::from(chat()),
+        );
+        assert!(matches!(
+            old.self_authenticate(&secp),
+            Err(AuthenticationError::InvalidEphemeral)
+        ));
+    }
+}
@@ -4,7 +4,11 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use self::ancestry::AncestryPeaks;
+use self::authority::{AuthorityError, ChainAuthority, KeyGrant};
+use self::blobs::{BlobRef, MAX_ATTACHMENTS, MAX_BLOB_SIZE};
 use self::checkpoints::BitcoinCheckPoints;
+use self::ephemeral::Ephemeral;
 use crate::nonce::{PrecomittedNonce, PrecomittedPublicNonce};
 use ruma_serde::CanonicalJsonValue;
 use sapio_bitcoin::hashes::hex::ToHex;
@@ -20,8 +24,18 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::error::Error;
 use std::fmt::Display;
+pub mod anchor;
+pub mod ancestry;
 pub mod authenticated;
+pub mod authority;
+pub mod batch;
+pub mod binary;
+pub mod blobs;
+pub mod encrypted;
+pub mod ephemeral;
+pub mod musig;
 pub mod nonce;
+pub mod registry;
 pub use authenticated::*;
 pub mod checkpoints;
 #[cfg(feature = "rusqlite")]
@@ -62,8 +76,53 @@ impl Ancestors {
         self.genesis
     }
 }
+/// The layout and hashing rules an [`Envelope`] was made under.
+///
+/// Envelopes from before versions were introduced carry no version and read
+/// as [`FormatVersion::LEGACY`], which is left out of the canonical form so
+/// that their hashes and signatures are unchanged. Any other version is part
+/// of the canonical hash, and so of the signature.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Hash, JsonSchema,
+)]
+#[serde(transparent)]
+pub struct FormatVersion(pub u32);
+
+impl FormatVersion {
+    pub const LEGACY: FormatVersion = FormatVersion(0);
+    /// The version envelopes using none of the features below are made with
+    pub const V1: FormatVersion = FormatVersion(1);
+    /// Adds [`blobs`] attachments
+    pub const V2: FormatVersion = FormatVersion(2);
+    /// Adds [`ephemeral`] envelopes
+    pub const V3: FormatVersion = FormatVersion(3);
+    /// The newest version this build can check. New envelopes are made with
+    /// the lowest version that covers the features they use, so that peers
+    /// which predate a feature can still check envelopes that don't use it.
+    pub const CURRENT: FormatVersion = FormatVersion::V3;
+
+    pub fn is_legacy(&self) -> bool {
+        *self == Self::LEGACY
+    }
+
+    /// Whether envelopes of this version can be checked by this build.
+    pub fn is_supported(&self) -> bool {
+        *self <= Self::CURRENT
+    }
+}
+
+impl Default for FormatVersion {
+    fn default() -> Self {
+        Self::LEGACY
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Hash, JsonSchema)]
 pub struct Header {
+    /// see [`FormatVersion`]
+    #[serde(skip_serializing_if = "FormatVersion::is_legacy")]
+    #[serde(default)]
+    version: FormatVersion,
     #[schemars(with = "String")]
     key: sapio_bitcoin::secp256k1::XOnlyPublicKey,
     next_nonce: PrecomittedPublicNonce,
@@ -80,9 +139,27 @@ pub struct Header {
     sent_time_ms: i64,
     unsigned: Unsigned,
     checkpoints: BitcoinCheckPoints,
+    /// see [`ancestry`], empty for envelopes from before it was introduced
+    #[serde(skip_serializing_if = "AncestryPeaks::is_empty")]
+    #[serde(default)]
+    ancestry: AncestryPeaks,
+    /// see [`authority`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    grant: Option<KeyGrant>,
+    /// see [`blobs`]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    attachments: Vec<BlobRef>,
+    /// see [`ephemeral`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    ephemeral: Option<Ephemeral>,
 }
 
 impl Header {
+    /// Made under [`FormatVersion::V1`], raised as features needing a later
+    /// version are added.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         key: sapio_bitcoin::secp256k1::XOnlyPublicKey,
@@ -93,8 +170,10 @@ impl Header {
         sent_time_ms: i64,
         unsigned: Unsigned,
         checkpoints: BitcoinCheckPoints,
+        ancestry: AncestryPeaks,
     ) -> Self {
         Self {
+            version: FormatVersion::V1,
             key,
             next_nonce,
             ancestors,
@@ -103,9 +182,65 @@ impl Header {
             sent_time_ms,
             unsigned,
             checkpoints,
+            ancestry,
+            grant: None,
+            attachments: vec![],
+            ephemeral: None,
         }
     }
 
+    /// Makes this header under another [`FormatVersion`] than the one its
+    /// features need, e.g. to talk to peers which predate it.
+    pub fn with_version(mut self, version: FormatVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn version(&self) -> FormatVersion {
+        self.version
+    }
+
+    /// Attaches a [`KeyGrant`] to this header, only the chain's root key may
+    /// sign a header carrying one.
+    pub fn with_grant(mut self, grant: KeyGrant) -> Self {
+        self.grant = Some(grant);
+        self
+    }
+
+    pub fn ancestry(&self) -> &AncestryPeaks {
+        &self.ancestry
+    }
+
+    pub fn grant(&self) -> Option<&KeyGrant> {
+        self.grant.as_ref()
+    }
+
+    /// References `attachments` from this header, see [`blobs`]. Any
+    /// attachments need [`FormatVersion::V2`].
+    pub fn with_attachments(mut self, attachments: Vec<BlobRef>) -> Self {
+        if !attachments.is_empty() {
+            self.version = self.version.max(FormatVersion::V2);
+        }
+        self.attachments = attachments;
+        self
+    }
+
+    pub fn attachments(&self) -> &[BlobRef] {
+        &self.attachments
+    }
+
+    /// Makes the envelope ephemeral, see [`ephemeral`], which needs
+    /// [`FormatVersion::V3`].
+    pub fn with_ephemeral(mut self, ephemeral: Ephemeral) -> Self {
+        self.version = self.version.max(FormatVersion::V3);
+        self.ephemeral = Some(ephemeral);
+        self
+    }
+
+    pub fn ephemeral(&self) -> Option<&Ephemeral> {
+        self.ephemeral.as_ref()
+    }
+
     pub fn checkpoints(&self) -> &BitcoinCheckPoints {
         &self.checkpoints
     }
@@ -143,7 +278,7 @@ impl std::fmt::Debug for Header {
         f.write_str(&serde_json::to_string(self).unwrap())
     }
 }
-#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, JsonSchema)]
+#[derive(Deserialize, Clone, Eq, PartialEq, JsonSchema)]
 #[serde(try_from = "from_wrap::GenericErasedEnvelope")]
 #[serde(bound = "T: for<'a> Deserialize<'a> + Serialize + Clone")]
 pub struct GenericEnvelope<T = WrappedJson>
@@ -152,10 +287,32 @@ where
 {
     header: Header,
     msg: T,
+    /// `msg` as it is sent and hashed: exactly as it was read, or for new
+    /// envelopes as [`AttestEnvelopable::as_canonical`] gives it. Reading may
+    /// upgrade a message (see [`registry`]), this keeps the hash intact.
+    #[serde(skip)]
+    #[schemars(skip)]
+    canonical: Option<CanonicalJsonValue>,
     #[serde(skip)]
     cache: Option<CanonicalEnvelopeHash>,
 }
 
+impl<T> Serialize for GenericEnvelope<T>
+where
+    T: AttestEnvelopable,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct("GenericEnvelope", 2)?;
+        s.serialize_field("header", &self.header)?;
+        match &self.canonical {
+            Some(msg) => s.serialize_field("msg", msg)?,
+            None => s.serialize_field("msg", &self.msg)?,
+        }
+        s.end()
+    }
+}
+
 pub trait AttestEnvelopable
 where
     Self: JsonSchema
@@ -170,6 +327,11 @@ where
 {
     type Ref;
     fn as_canonical(&self) -> Result<CanonicalJsonValue, ruma_serde::CanonicalJsonError>;
+    /// Reads a message from its canonical JSON. Types in the
+    /// [`registry`] override this to validate and upgrade it first.
+    fn from_canonical(msg: CanonicalJsonValue) -> Result<Self, registry::RegistryError> {
+        Ok(serde_json::from_value(msg.into())?)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq, JsonSchema, Debug)]
@@ -216,10 +378,10 @@ mod from_wrap {
     where
         T: AttestEnvelopable + for<'a> Deserialize<'a> + Serialize + Clone,
     {
-        type Error = serde_json::Error;
+        type Error = crate::registry::RegistryError;
         fn try_from(e: GenericErasedEnvelope) -> Result<Self, Self::Error> {
-            let v: T = serde_json::from_value(e.msg.into())?;
-            Ok(super::GenericEnvelope::new(e.header, v))
+            let v = T::from_canonical(e.msg.clone())?;
+            Ok(super::GenericEnvelope::new_as_read(e.header, v, e.msg))
         }
     }
 }
@@ -232,9 +394,24 @@ where
     T: AttestEnvelopable,
 {
     pub fn new<It: Into<T>>(header: Header, msg: It) -> Self {
+        let msg = msg.into();
+        let canonical = msg.as_canonical().ok();
+        let mut s = Self {
+            header,
+            msg,
+            canonical,
+            cache: None,
+        };
+        s.cache = Some(s.canonicalized_hash_ref());
+        s
+    }
+
+    /// An envelope read from `canonical`, which `msg` was decoded from.
+    pub(crate) fn new_as_read(header: Header, msg: T, canonical: CanonicalJsonValue) -> Self {
         let mut s = Self {
             header,
-            msg: msg.into(),
+            msg,
+            canonical: Some(canonical),
             cache: None,
         };
         s.cache = Some(s.canonicalized_hash_ref());
@@ -259,11 +436,20 @@ pub enum AuthenticationError {
     HashingError,
     MissingAncestors,
     NoAncestorsForGenesis,
+    InvalidGrant(AuthorityError),
+    Unauthorized(AuthorityError),
+    UnsupportedVersion(FormatVersion),
+    InvalidAttachments,
+    InvalidEphemeral,
 }
 #[derive(Debug)]
 pub enum SigningError {
     SerializerError(serde_json::Error),
     HashingError,
+    UnsupportedVersion(FormatVersion),
+    /// the nonce has already signed an envelope, signing again with it would
+    /// leak the key
+    NonceAlreadyUsed(PrecomittedPublicNonce),
 }
 
 impl Display for SigningError {
@@ -299,6 +485,11 @@ impl ToHex for CanonicalEnvelopeHash {
         self.0.to_hex()
     }
 }
+impl AsRef<[u8]> for CanonicalEnvelopeHash {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
 
 pub struct SignatureDigest(SchnorrMessage);
 impl From<SignatureDigest> for SchnorrMessage {
@@ -344,12 +535,69 @@ where
         &self,
         secp: &Secp256k1<C>,
     ) -> Result<Authenticated<Self>, AuthenticationError> {
+        self.check_authentic(secp)?;
+        Ok(Authenticated(self.clone()))
+    }
+
+    /// Like [`Self::self_authenticate`], but consumes the envelope rather
+    /// than copying it.
+    pub fn into_authenticated<C: Verification>(
+        self,
+        secp: &Secp256k1<C>,
+    ) -> Result<Authenticated<Self>, AuthenticationError> {
+        self.check_authentic(secp)?;
+        Ok(Authenticated(self))
+    }
+
+    /// Like [`Self::self_authenticate`], but also checks that the signing key
+    /// holds authority over the chain given the grants before this envelope.
+    pub fn self_authenticate_in_chain<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        authority: &ChainAuthority,
+    ) -> Result<Authenticated<Self>, AuthenticationError> {
+        self.check_authentic(secp)?;
+        authority
+            .check(&self.header)
+            .map_err(AuthenticationError::Unauthorized)?;
+        Ok(Authenticated(self.clone()))
+    }
+
+    fn check_authentic<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+    ) -> Result<(), AuthenticationError> {
+        if !self.header.version.is_supported() {
+            return Err(AuthenticationError::UnsupportedVersion(self.header.version));
+        }
         if self.header.height == 0 && self.header.ancestors.is_some() {
             return Err(AuthenticationError::NoAncestorsForGenesis);
         }
         if self.header.height > 0 && self.header.ancestors.is_none() {
             return Err(AuthenticationError::MissingAncestors);
         }
+        if let Some(grant) = &self.header.grant {
+            grant
+                .check_well_formed(&self.header)
+                .map_err(AuthenticationError::InvalidGrant)?;
+        }
+        if !self.header.attachments.is_empty()
+            && (self.header.version < FormatVersion::V2
+                || self.header.attachments.len() > MAX_ATTACHMENTS
+                || self
+                    .header
+                    .attachments
+                    .iter()
+                    .any(|a| a.size > MAX_BLOB_SIZE))
+        {
+            return Err(AuthenticationError::InvalidAttachments);
+        }
+        if self.header.ephemeral.is_some()
+            && (self.header.version < FormatVersion::V3
+                || !self.check_ephemeral_msg(ephemeral::now_ms()))
+        {
+            return Err(AuthenticationError::InvalidEphemeral);
+        }
         let mut redacted = self.clone();
         let sig = redacted
             .header
@@ -362,8 +610,7 @@ where
             .signature_digest()
             .ok_or(AuthenticationError::HashingError)?;
         secp.verify_schnorr(&sig, &msg.0, &self.header.key)
-            .map_err(AuthenticationError::ValidationError)?;
-        Ok(Authenticated(self.clone()))
+            .map_err(AuthenticationError::ValidationError)
     }
 
     pub fn solemnly_swear_self_authenticated(
@@ -394,6 +641,9 @@ where
         secp: &Secp256k1<C>,
         nonce: PrecomittedNonce,
     ) -> Result<(), SigningError> {
+        if !self.header.version.is_supported() {
+            return Err(SigningError::UnsupportedVersion(self.header.version));
+        }
         self.header.unsigned.signature = None;
         self.cache = None;
 
@@ -409,8 +659,18 @@ where
     }
 
     fn compute_hash(&self) -> CanonicalEnvelopeHash {
-        let canonical =
+        // every FormatVersion so far hashes the same way, the version itself
+        // being part of the canonical form. One that doesn't must be
+        // dispatched on here.
+        let mut canonical =
             ruma_serde::to_canonical_value(self).expect("Canonicalization Must Succeed");
+        // ephemeral envelopes commit to their message from the header, so
+        // that it can be pruned without changing the hash
+        if self.header.ephemeral.is_some() {
+            if let CanonicalJsonValue::Object(o) = &mut canonical {
+                o.remove("msg");
+            }
+        }
         CanonicalEnvelopeHash(sapio_bitcoin::hashes::sha256::Hash::hash(
             canonical.to_string().as_bytes(),
         ))
@@ -464,6 +724,13 @@ where
         self.msg
     }
 
+    pub fn canonical_msg(&self) -> Result<CanonicalJsonValue, ruma_serde::CanonicalJsonError> {
+        match &self.canonical {
+            Some(msg) => Ok(msg.clone()),
+            None => self.msg.as_canonical(),
+        }
+    }
+
     pub fn header(&self) -> &Header {
         &self.header
     }
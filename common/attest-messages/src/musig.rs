@@ -0,0 +1,437 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Chains controlled by a group of keys.
+//!
+//! A [`SigningGroup`] aggregates its members' keys MuSig style into a single
+//! x-only key. The group's envelopes carry an ordinary BIP-340 signature under
+//! that key, so nothing about verifying them changes.
+//!
+//! Every envelope commits to the nonce of the next one in
+//! [`Header::next_nonce`](crate::Header::next_nonce). For a group that nonce
+//! is a [`GroupNonce`]: the sum of one [`PrecomittedNonce`] share per member,
+//! fixed before the next message is known. MuSig2's two nonces per signer
+//! can't be used here as they make the aggregate nonce depend on the message.
+//! Each member then contributes a [`PartialSignature`], and anyone holding all
+//! of them can combine them into the envelope's signature.
+//!
+//! Single precommitted nonces are only safe if a member never has two signing
+//! sessions open at once. A chain signs one height at a time, and a member
+//! must never partially sign a second digest with the same nonce share. With
+//! that rule the nonce reuse slashing of single key chains carries over:
+//!
+//! - the group signing two envelopes with one committed aggregate nonce
+//!   reveals the aggregate secret key, exactly as for a single signer.
+//! - a member partially signing two digests with one share reveals that
+//!   member's own key.
+
+use crate::nonce::{PrecomittedNonce, PrecomittedPublicNonce};
+use crate::{AttestEnvelopable, GenericEnvelope};
+use sapio_bitcoin::hashes::{sha256, Hash, HashEngine};
+use sapio_bitcoin::secp256k1::schnorr::Signature;
+use sapio_bitcoin::secp256k1::{
+    Message as SchnorrMessage, Parity, PublicKey, Secp256k1, SecretKey, Signing, Verification,
+};
+use sapio_bitcoin::{KeyPair, XOnlyPublicKey};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Display;
+
+const KEY_LIST_TAG: &[u8] = b"AttestGroup/keylist";
+const COEFFICIENT_TAG: &[u8] = b"AttestGroup/coefficient";
+const BIP340_CHALLENGE: &[u8] = b"BIP0340/challenge";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum GroupError {
+    NoMembers,
+    /// the envelope isn't keyed by the group
+    NotGroupKey,
+    DuplicateMember(XOnlyPublicKey),
+    NotAMember(XOnlyPublicKey),
+    /// the nonce shares must cover exactly the group's members
+    MissingNonceShare(XOnlyPublicKey),
+    /// the secret nonce given doesn't match the member's public share
+    NonceShareMismatch,
+    /// the aggregate nonce isn't the one the previous envelope committed to
+    UncommittedNonce,
+    /// the member already partially signed another digest with this nonce
+    NonceAlreadyUsed,
+    MissingPartialSignature(XOnlyPublicKey),
+    InvalidPartialSignature(XOnlyPublicKey),
+    Secp256k1Error(sapio_bitcoin::secp256k1::Error),
+}
+
+impl Display for GroupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for GroupError {}
+
+impl From<sapio_bitcoin::secp256k1::Error> for GroupError {
+    fn from(e: sapio_bitcoin::secp256k1::Error) -> Self {
+        GroupError::Secp256k1Error(e)
+    }
+}
+
+fn tagged_hash(tag: &[u8]) -> sha256::HashEngine {
+    let tag = sha256::Hash::hash(tag);
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    engine
+}
+
+fn scalar(engine: sha256::HashEngine) -> SecretKey {
+    // a hash is out of range with negligible probability
+    SecretKey::from_slice(&sha256::Hash::from_engine(engine)[..]).expect("Hash In Range")
+}
+
+fn even_point(key: &XOnlyPublicKey) -> Result<PublicKey, GroupError> {
+    let mut compressed = [2u8; 33];
+    compressed[1..].copy_from_slice(&key.serialize());
+    Ok(PublicKey::from_slice(&compressed)?)
+}
+
+fn is_odd(p: &PublicKey) -> bool {
+    p.x_only_public_key().1 == Parity::Odd
+}
+
+/// `sk` for the even point of its x-only key, negated if `negate`.
+fn even_secret<C: Signing>(secp: &Secp256k1<C>, mut sk: SecretKey, negate: bool) -> SecretKey {
+    if is_odd(&PublicKey::from_secret_key(secp, &sk)) != negate {
+        sk.negate_assign();
+    }
+    sk
+}
+
+/// A partial signature from one member, the `s` value of its share.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PartialSignature(#[schemars(with = "String")] pub SecretKey);
+
+/// The aggregate of one nonce share per member of a group.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GroupNonce {
+    shares: BTreeMap<XOnlyPublicKey, PrecomittedPublicNonce>,
+    aggregate: PublicKey,
+}
+
+impl GroupNonce {
+    /// The nonce as committed to in a header.
+    pub fn public(&self) -> PrecomittedPublicNonce {
+        PrecomittedPublicNonce(self.aggregate.x_only_public_key().0)
+    }
+
+    pub fn shares(&self) -> &BTreeMap<XOnlyPublicKey, PrecomittedPublicNonce> {
+        &self.shares
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SigningGroup {
+    /// sorted
+    members: Vec<XOnlyPublicKey>,
+    coefficients: Vec<SecretKey>,
+    key: XOnlyPublicKey,
+    /// the sum of the members' keys has an odd y, which members correct for
+    /// by negating their keys
+    negated: bool,
+}
+
+impl SigningGroup {
+    pub fn new<C: Verification>(
+        secp: &Secp256k1<C>,
+        mut members: Vec<XOnlyPublicKey>,
+    ) -> Result<Self, GroupError> {
+        members.sort();
+        if let Some(w) = members.windows(2).find(|w| w[0] == w[1]) {
+            return Err(GroupError::DuplicateMember(w[0]));
+        }
+        let mut list = tagged_hash(KEY_LIST_TAG);
+        for m in &members {
+            list.input(&m.serialize());
+        }
+        let list = sha256::Hash::from_engine(list);
+        let coefficients: Vec<SecretKey> = members
+            .iter()
+            .map(|m| {
+                let mut engine = tagged_hash(COEFFICIENT_TAG);
+                engine.input(&list[..]);
+                engine.input(&m.serialize());
+                scalar(engine)
+            })
+            .collect();
+        let mut aggregate: Option<PublicKey> = None;
+        for (m, a) in members.iter().zip(&coefficients) {
+            let mut p = even_point(m)?;
+            p.mul_assign(secp, &a.secret_bytes())?;
+            aggregate = Some(match aggregate {
+                None => p,
+                Some(q) => q.combine(&p)?,
+            });
+        }
+        let aggregate = aggregate.ok_or(GroupError::NoMembers)?;
+        Ok(SigningGroup {
+            members,
+            coefficients,
+            key: aggregate.x_only_public_key().0,
+            negated: is_odd(&aggregate),
+        })
+    }
+
+    /// The key the group's envelopes are signed under.
+    pub fn key(&self) -> XOnlyPublicKey {
+        self.key
+    }
+
+    pub fn members(&self) -> &[XOnlyPublicKey] {
+        &self.members
+    }
+
+    fn coefficient(&self, member: &XOnlyPublicKey) -> Result<&SecretKey, GroupError> {
+        self.members
+            .binary_search(member)
+            .map(|i| &self.coefficients[i])
+            .map_err(|_| GroupError::NotAMember(*member))
+    }
+
+    /// Sums one nonce share per member.
+    pub fn aggregate_nonce(
+        &self,
+        shares: BTreeMap<XOnlyPublicKey, PrecomittedPublicNonce>,
+    ) -> Result<GroupNonce, GroupError> {
+        if let Some(m) = shares
+            .keys()
+            .find(|m| self.members.binary_search(m).is_err())
+        {
+            return Err(GroupError::NotAMember(*m));
+        }
+        let mut aggregate: Option<PublicKey> = None;
+        for m in &self.members {
+            let share = shares.get(m).ok_or(GroupError::MissingNonceShare(*m))?;
+            let r = even_point(&share.0)?;
+            aggregate = Some(match aggregate {
+                None => r,
+                Some(a) => a.combine(&r)?,
+            });
+        }
+        Ok(GroupNonce {
+            shares,
+            aggregate: aggregate.ok_or(GroupError::NoMembers)?,
+        })
+    }
+
+    /// the BIP-340 challenge for the aggregate nonce and key
+    fn challenge(&self, nonce: &GroupNonce, msg: &SchnorrMessage) -> SecretKey {
+        let mut engine = tagged_hash(BIP340_CHALLENGE);
+        engine.input(&nonce.public().0.serialize());
+        engine.input(&self.key.serialize());
+        engine.input(msg.as_ref());
+        scalar(engine)
+    }
+
+    /// `member`'s share of the public key the partial signature is checked
+    /// against, multiplied by the challenge.
+    fn challenge_share<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        member: &XOnlyPublicKey,
+        e: &SecretKey,
+    ) -> Result<PublicKey, GroupError> {
+        let mut p = even_point(member)?;
+        if self.negated {
+            p.negate_assign(secp);
+        }
+        p.mul_assign(secp, &self.coefficient(member)?.secret_bytes())?;
+        p.mul_assign(secp, &e.secret_bytes())?;
+        Ok(p)
+    }
+
+    /// Partially signs `msg` as the member holding `keypair`, with the secret
+    /// for its share of `nonce`.
+    ///
+    /// Calling this twice with the same `secret_nonce` and different messages
+    /// leaks the member's key.
+    pub fn sign_partial<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        nonce: &GroupNonce,
+        msg: &SchnorrMessage,
+        keypair: &KeyPair,
+        secret_nonce: PrecomittedNonce,
+    ) -> Result<PartialSignature, GroupError> {
+        let member = keypair.x_only_public_key().0;
+        let a = self.coefficient(&member)?;
+        let share = nonce
+            .shares
+            .get(&member)
+            .ok_or(GroupError::MissingNonceShare(member))?;
+        if secret_nonce.get_public(secp) != *share {
+            return Err(GroupError::NonceShareMismatch);
+        }
+        let k = even_secret(secp, secret_nonce.0, is_odd(&nonce.aggregate));
+        let mut s = even_secret(secp, keypair.secret_key(), self.negated);
+        s.mul_assign(&a.secret_bytes())?;
+        s.mul_assign(&self.challenge(nonce, msg).secret_bytes())?;
+        s.add_assign(&k.secret_bytes())?;
+        Ok(PartialSignature(s))
+    }
+
+    /// Checks `member`'s partial signature, so that a bad share can be
+    /// blamed on whoever sent it.
+    pub fn verify_partial<C: Signing + Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        nonce: &GroupNonce,
+        msg: &SchnorrMessage,
+        member: &XOnlyPublicKey,
+        partial: &PartialSignature,
+    ) -> Result<(), GroupError> {
+        let share = nonce
+            .shares
+            .get(member)
+            .ok_or(GroupError::MissingNonceShare(*member))?;
+        let mut r = even_point(&share.0)?;
+        if is_odd(&nonce.aggregate) {
+            r.negate_assign(secp);
+        }
+        let expected = self
+            .challenge_share(secp, member, &self.challenge(nonce, msg))?
+            .combine(&r)?;
+        if PublicKey::from_secret_key(secp, &partial.0) != expected {
+            return Err(GroupError::InvalidPartialSignature(*member));
+        }
+        Ok(())
+    }
+
+    /// Combines every member's partial signature into a BIP-340 signature
+    /// under [`Self::key`].
+    pub fn combine<C: Signing + Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        nonce: &GroupNonce,
+        msg: &SchnorrMessage,
+        partials: &BTreeMap<XOnlyPublicKey, PartialSignature>,
+    ) -> Result<Signature, GroupError> {
+        let mut s: Option<SecretKey> = None;
+        for m in &self.members {
+            let partial = partials
+                .get(m)
+                .ok_or(GroupError::MissingPartialSignature(*m))?;
+            self.verify_partial(secp, nonce, msg, m, partial)?;
+            s = Some(match s {
+                None => partial.0,
+                Some(mut s) => {
+                    s.add_assign(&partial.0.secret_bytes())?;
+                    s
+                }
+            });
+        }
+        let s = s.ok_or(GroupError::NoMembers)?;
+        let mut sig = [0u8; 64];
+        sig[..32].copy_from_slice(&nonce.public().0.serialize());
+        sig[32..].copy_from_slice(&s.secret_bytes());
+        let sig = Signature::from_slice(&sig)?;
+        secp.verify_schnorr(&sig, msg, &self.key)?;
+        Ok(sig)
+    }
+
+    /// Signs `envelope`, which must be keyed by the group, with the combined
+    /// partial signatures.
+    pub fn sign_envelope<C: Signing + Verification, T: AttestEnvelopable>(
+        &self,
+        secp: &Secp256k1<C>,
+        envelope: &mut GenericEnvelope<T>,
+        nonce: &GroupNonce,
+        partials: &BTreeMap<XOnlyPublicKey, PartialSignature>,
+    ) -> Result<(), GroupError> {
+        if envelope.header.key != self.key {
+            return Err(GroupError::NotGroupKey);
+        }
+        let msg = envelope.signature_digest_mut().0;
+        let sig = self.combine(secp, nonce, &msg, partials)?;
+        envelope.header.unsigned.signature = Some(sig);
+        envelope.cache = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sapio_bitcoin::secp256k1::rand;
+
+    #[test]
+    fn test_group_signature() {
+        let secp = Secp256k1::new();
+        let keys: Vec<KeyPair> = (0..3)
+            .map(|_| KeyPair::new(&secp, &mut rand::thread_rng()))
+            .collect();
+        let group = SigningGroup::new(
+            &secp,
+            keys.iter().map(|k| k.x_only_public_key().0).collect(),
+        )
+        .unwrap();
+        let secrets: Vec<PrecomittedNonce> =
+            keys.iter().map(|_| PrecomittedNonce::new(&secp)).collect();
+        let nonce = group
+            .aggregate_nonce(
+                keys.iter()
+                    .zip(&secrets)
+                    .map(|(k, n)| (k.x_only_public_key().0, n.get_public(&secp)))
+                    .collect(),
+            )
+            .unwrap();
+        let msg = SchnorrMessage::from_slice(&[7u8; 32]).unwrap();
+        let mut partials: BTreeMap<_, _> = keys
+            .iter()
+            .zip(&secrets)
+            .map(|(k, n)| {
+                let p = group.sign_partial(&secp, &nonce, &msg, k, *n).unwrap();
+                (k.x_only_public_key().0, p)
+            })
+            .collect();
+        let sig = group.combine(&secp, &nonce, &msg, &partials).unwrap();
+        secp.verify_schnorr(&sig, &msg, &group.key()).unwrap();
+        assert_eq!(sig.as_ref()[..32], nonce.public().0.serialize());
+
+        // a corrupted share is blamed on its sender
+        let cheat = keys[1].x_only_public_key().0;
+        partials.insert(cheat, PartialSignature(keys[1].secret_key()));
+        assert_eq!(
+            group.combine(&secp, &nonce, &msg, &partials),
+            Err(GroupError::InvalidPartialSignature(cheat))
+        );
+        partials.remove(&cheat);
+        assert_eq!(
+            group.combine(&secp, &nonce, &msg, &partials),
+            Err(GroupError::MissingPartialSignature(cheat))
+        );
+    }
+
+    #[test]
+    fn test_group_membership() {
+        let secp = Secp256k1::new();
+        let k = KeyPair::new(&secp, &mut rand::thread_rng())
+            .x_only_public_key()
+            .0;
+        assert_eq!(
+            SigningGroup::new(&secp, vec![k, k]),
+            Err(GroupError::DuplicateMember(k))
+        );
+        assert_eq!(SigningGroup::new(&secp, vec![]), Err(GroupError::NoMembers));
+        // the aggregate doesn't depend on the order members are listed in
+        let k2 = KeyPair::new(&secp, &mut rand::thread_rng())
+            .x_only_public_key()
+            .0;
+        assert_eq!(
+            SigningGroup::new(&secp, vec![k, k2]).unwrap().key(),
+            SigningGroup::new(&secp, vec![k2, k]).unwrap().key()
+        );
+    }
+}
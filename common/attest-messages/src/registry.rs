@@ -0,0 +1,618 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A registry of the message types carried in envelopes.
+//!
+//! Each [`MessageType`] has a tag, a current version, and the schemas of its
+//! older versions together with functions upgrading each one to the next.
+//! Messages on the wire are either *tagged*:
+//!
+//! ```json
+//! {"@type": "ParticipantAction", "@version": 2, "@body": {...}}
+//! ```
+//!
+//! or untagged, which is how every message was sent before the registry
+//! existed, and are then read as version [`UNTAGGED_VERSION`] of the type the
+//! reader expects.
+//!
+//! Decoding a registered type checks the body against the schema of the
+//! version it claims, upgrades it step by step to the current version and
+//! checks it again, so a bad payload is rejected with the JSON path of the
+//! offending value rather than a generic deserialization failure.
+//!
+//! A node can only validate the types it knows about: tagged messages of an
+//! unregistered type, or of a version newer than the registered one, pass
+//! [`MessageRegistry::check`] untouched so that nodes aren't split by an
+//! upgrade. [`MessageRegistry::decode`] still refuses versions it can't read.
+//! Tagged
+//! messages may also be nested in untagged ones, e.g. a routing wrapper around
+//! a tagged payload, and are checked wherever they are found.
+
+use crate::AttestEnvelopable;
+use jsonschema::JSONSchema;
+use lazy_static::lazy_static;
+use ruma_serde::CanonicalJsonValue;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{RootSchema, Schema};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::RwLock;
+
+/// The version an untagged message is read as.
+pub const UNTAGGED_VERSION: u32 = 1;
+
+/// Turns a message of one version into the next version.
+pub type Upgrade = fn(CanonicalJsonValue) -> Result<CanonicalJsonValue, String>;
+
+/// A payload type known to the registry.
+pub trait MessageType: JsonSchema {
+    /// unique name of the type, stable across versions
+    const TAG: &'static str;
+    /// the version this type's Rust definition corresponds to
+    const VERSION: u32;
+    /// Versions before [`Self::VERSION`], oldest first, each with the function
+    /// upgrading it to the version after it.
+    fn previous_versions() -> Vec<PreviousVersion> {
+        vec![]
+    }
+}
+
+pub struct PreviousVersion {
+    pub version: u32,
+    pub schema: RootSchema,
+    pub upgrade: Upgrade,
+}
+
+/// The tagged form of a message.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Tagged {
+    #[serde(rename = "@type")]
+    pub tag: String,
+    #[serde(rename = "@version")]
+    pub version: u32,
+    #[serde(rename = "@body")]
+    #[schemars(with = "Value")]
+    pub body: CanonicalJsonValue,
+}
+
+impl Tagged {
+    /// Reads `msg` as a tagged message, `None` if it is untagged.
+    pub fn from_canonical(msg: &CanonicalJsonValue) -> Option<Tagged> {
+        match msg {
+            CanonicalJsonValue::Object(o) if o.len() == 3 && o.contains_key("@type") => {
+                serde_json::from_value(msg.clone().into()).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A value that doesn't match its schema, `path` is a JSON pointer into the
+/// message body.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SchemaViolation {
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    /// the tag has not been registered
+    UnknownType(String),
+    /// the tag is known but not at this version
+    UnsupportedVersion {
+        tag: String,
+        version: u32,
+        oldest: u32,
+        latest: u32,
+    },
+    /// a message of one type was read as another
+    WrongType {
+        expected: &'static str,
+        found: String,
+    },
+    /// the body doesn't match the schema of its version
+    Invalid {
+        tag: String,
+        version: u32,
+        violations: Vec<SchemaViolation>,
+    },
+    /// upgrading from version `from` failed
+    UpgradeFailed {
+        tag: String,
+        from: u32,
+        reason: String,
+    },
+    SerializerError(serde_json::Error),
+}
+
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for RegistryError {}
+
+impl From<serde_json::Error> for RegistryError {
+    fn from(e: serde_json::Error) -> Self {
+        RegistryError::SerializerError(e)
+    }
+}
+
+struct VersionEntry {
+    validator: JSONSchema,
+    /// to the next version, `None` for the latest
+    upgrade: Option<Upgrade>,
+}
+
+impl VersionEntry {
+    fn new(schema: &RootSchema, upgrade: Option<Upgrade>) -> Self {
+        let schema = serde_json::to_value(schema).expect("Schemas Serialize");
+        VersionEntry {
+            validator: JSONSchema::compile(&schema).expect("Generated Schemas Compile"),
+            upgrade,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MessageRegistry {
+    types: BTreeMap<String, BTreeMap<u32, VersionEntry>>,
+}
+
+impl MessageRegistry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Registers every version of `T`, replacing any earlier registration
+    /// under the same tag.
+    ///
+    /// Panics if the versions of `T` aren't consecutive and below
+    /// [`MessageType::VERSION`].
+    pub fn register<T: MessageType>(&mut self) {
+        let mut versions = BTreeMap::new();
+        let previous = T::previous_versions();
+        for (i, p) in previous.iter().enumerate() {
+            let next = previous.get(i + 1).map_or(T::VERSION, |n| n.version);
+            assert_eq!(
+                p.version + 1,
+                next,
+                "{} versions must be consecutive",
+                T::TAG
+            );
+            versions.insert(p.version, VersionEntry::new(&p.schema, Some(p.upgrade)));
+        }
+        versions.insert(
+            T::VERSION,
+            VersionEntry::new(&schemars::schema_for!(T), None),
+        );
+        self.types.insert(T::TAG.into(), versions);
+    }
+
+    pub fn is_registered(&self, tag: &str) -> bool {
+        self.types.contains_key(tag)
+    }
+
+    pub fn latest_version(&self, tag: &str) -> Option<u32> {
+        self.types.get(tag)?.keys().next_back().copied()
+    }
+
+    fn entry(&self, tag: &str, version: u32) -> Result<&VersionEntry, RegistryError> {
+        let versions = self
+            .types
+            .get(tag)
+            .ok_or_else(|| RegistryError::UnknownType(tag.into()))?;
+        versions
+            .get(&version)
+            .ok_or_else(|| RegistryError::UnsupportedVersion {
+                tag: tag.into(),
+                version,
+                oldest: *versions.keys().next().expect("Registered With A Version"),
+                latest: *versions
+                    .keys()
+                    .next_back()
+                    .expect("Registered With A Version"),
+            })
+    }
+
+    /// Checks `body` against the schema of `version` of `tag`.
+    pub fn validate(
+        &self,
+        tag: &str,
+        version: u32,
+        body: &CanonicalJsonValue,
+    ) -> Result<(), RegistryError> {
+        let entry = self.entry(tag, version)?;
+        let body: Value = body.clone().into();
+        entry.validator.validate(&body).map_err(|errors| {
+            let violations = errors
+                .map(|e| SchemaViolation {
+                    path: e.instance_path.to_string(),
+                    message: e.to_string(),
+                })
+                .collect();
+            RegistryError::Invalid {
+                tag: tag.into(),
+                version,
+                violations,
+            }
+        })
+    }
+
+    /// Validates every tagged message of a registered type within `msg`,
+    /// anything else passes. Versions newer than the registered one are
+    /// passed like unregistered types, they may be valid for their sender.
+    pub fn check(&self, msg: &CanonicalJsonValue) -> Result<(), RegistryError> {
+        if let Some(t) = Tagged::from_canonical(msg) {
+            return match self.latest_version(&t.tag) {
+                Some(latest) if t.version <= latest => self.validate(&t.tag, t.version, &t.body),
+                _ => self.check(&t.body),
+            };
+        }
+        match msg {
+            CanonicalJsonValue::Object(o) => o.values().try_for_each(|v| self.check(v)),
+            CanonicalJsonValue::Array(a) => a.iter().try_for_each(|v| self.check(v)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Brings `body` from `version` up to the latest version of `tag`,
+    /// validating it before and after each step.
+    pub fn upgrade(
+        &self,
+        tag: &str,
+        mut version: u32,
+        mut body: CanonicalJsonValue,
+    ) -> Result<CanonicalJsonValue, RegistryError> {
+        loop {
+            self.validate(tag, version, &body)?;
+            match self.entry(tag, version)?.upgrade {
+                None => return Ok(body),
+                Some(upgrade) => {
+                    body = upgrade(body).map_err(|reason| RegistryError::UpgradeFailed {
+                        tag: tag.into(),
+                        from: version,
+                        reason,
+                    })?;
+                    version += 1;
+                }
+            }
+        }
+    }
+
+    /// Reads `msg`, tagged or not, as the current version of `T`.
+    pub fn decode<T>(&self, msg: CanonicalJsonValue) -> Result<T, RegistryError>
+    where
+        T: MessageType + DeserializeOwned,
+    {
+        let (version, body) = match Tagged::from_canonical(&msg) {
+            Some(t) if t.tag == T::TAG => (t.version, t.body),
+            Some(t) => {
+                return Err(RegistryError::WrongType {
+                    expected: T::TAG,
+                    found: t.tag,
+                })
+            }
+            None => (UNTAGGED_VERSION, msg),
+        };
+        let body = self.upgrade(T::TAG, version, body)?;
+        Ok(serde_json::from_value(body.into())?)
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<MessageRegistry> = Default::default();
+}
+
+/// The registry used when decoding envelopes and on insert.
+pub fn global() -> &'static RwLock<MessageRegistry> {
+    &REGISTRY
+}
+
+/// Adds `T` to the [`global`] registry if it isn't there yet.
+pub fn register<T: MessageType>() {
+    if !global()
+        .read()
+        .expect("Registry Lock Not Poisoned")
+        .is_registered(T::TAG)
+    {
+        global()
+            .write()
+            .expect("Registry Lock Not Poisoned")
+            .register::<T>()
+    }
+}
+
+/// Decodes `msg` as `T` with the [`global`] registry, registering `T` first if
+/// needed.
+pub fn decode<T>(msg: CanonicalJsonValue) -> Result<T, RegistryError>
+where
+    T: MessageType + DeserializeOwned,
+{
+    register::<T>();
+    global()
+        .read()
+        .expect("Registry Lock Not Poisoned")
+        .decode(msg)
+}
+
+/// Sends a registered type in its tagged form, so that readers can upgrade it
+/// once newer versions exist.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Versioned<T>(pub T);
+
+impl<T: MessageType + Serialize> Versioned<T> {
+    pub fn to_tagged(&self) -> Result<Tagged, ruma_serde::CanonicalJsonError> {
+        Ok(Tagged {
+            tag: T::TAG.into(),
+            version: T::VERSION,
+            body: ruma_serde::to_canonical_value(&self.0)?,
+        })
+    }
+}
+
+/// The canonical tagged form of `msg`, for types which implement
+/// [`AttestEnvelopable`] themselves but should be sent tagged.
+pub fn to_tagged_canonical<T: MessageType + Serialize>(
+    msg: &T,
+) -> Result<CanonicalJsonValue, ruma_serde::CanonicalJsonError> {
+    ruma_serde::to_canonical_value(Tagged {
+        tag: T::TAG.into(),
+        version: T::VERSION,
+        body: ruma_serde::to_canonical_value(msg)?,
+    })
+}
+
+impl<T: MessageType + Serialize> Serialize for Versioned<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_tagged()
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de, T: MessageType + DeserializeOwned> Deserialize<'de> for Versioned<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let msg = CanonicalJsonValue::deserialize(deserializer)?;
+        decode(msg).map(Versioned).map_err(serde::de::Error::custom)
+    }
+}
+
+impl<T> JsonSchema for Versioned<T> {
+    fn schema_name() -> String {
+        Tagged::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        Tagged::json_schema(gen)
+    }
+}
+
+impl<T> AsRef<T> for Versioned<T> {
+    fn as_ref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> AttestEnvelopable for Versioned<T>
+where
+    T: MessageType + Serialize + DeserializeOwned + Clone + std::fmt::Debug + Send + Sync,
+{
+    type Ref = T;
+
+    fn as_canonical(&self) -> Result<CanonicalJsonValue, ruma_serde::CanonicalJsonError> {
+        ruma_serde::to_canonical_value(self.to_tagged()?)
+    }
+
+    fn from_canonical(msg: CanonicalJsonValue) -> Result<Self, RegistryError> {
+        decode(msg).map(Versioned)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// version 1 of `Greeting`, kept around for its schema
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    struct GreetingV1 {
+        #[allow(dead_code)]
+        name: String,
+    }
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Eq, PartialEq)]
+    #[serde(deny_unknown_fields)]
+    struct Greeting {
+        name: String,
+        times: u8,
+    }
+
+    fn add_times(v: CanonicalJsonValue) -> Result<CanonicalJsonValue, String> {
+        match v {
+            CanonicalJsonValue::Object(mut o) => {
+                o.insert("times".into(), CanonicalJsonValue::Integer(1u8.into()));
+                Ok(CanonicalJsonValue::Object(o))
+            }
+            _ => Err("not an object".into()),
+        }
+    }
+
+    impl MessageType for Greeting {
+        const TAG: &'static str = "Greeting";
+        const VERSION: u32 = 2;
+        fn previous_versions() -> Vec<PreviousVersion> {
+            vec![PreviousVersion {
+                version: 1,
+                schema: schemars::schema_for!(GreetingV1),
+                upgrade: add_times,
+            }]
+        }
+    }
+
+    fn json(v: Value) -> CanonicalJsonValue {
+        CanonicalJsonValue::try_from(v).unwrap()
+    }
+
+    #[test]
+    fn test_decode_and_upgrade() {
+        let mut registry = MessageRegistry::new();
+        registry.register::<Greeting>();
+        assert_eq!(registry.latest_version("Greeting"), Some(2));
+        let expected = Greeting {
+            name: "alice".into(),
+            times: 1,
+        };
+
+        // untagged messages are version 1
+        let g: Greeting = registry
+            .decode(json(serde_json::json!({"name": "alice"})))
+            .unwrap();
+        assert_eq!(g, expected);
+        let g: Greeting = registry
+            .decode(json(serde_json::json!({
+                "@type": "Greeting", "@version": 1, "@body": {"name": "alice"}
+            })))
+            .unwrap();
+        assert_eq!(g, expected);
+
+        // the tagged form of the current version round trips
+        let tagged = Versioned(expected.clone()).as_canonical().unwrap();
+        registry.check(&tagged).unwrap();
+        let g: Greeting = registry.decode(tagged).unwrap();
+        assert_eq!(g, expected);
+        let v: Versioned<Greeting> =
+            serde_json::from_str(&serde_json::to_string(&Versioned(expected.clone())).unwrap())
+                .unwrap();
+        assert_eq!(v.0, expected);
+    }
+
+    #[test]
+    fn test_rejections() {
+        let mut registry = MessageRegistry::new();
+        registry.register::<Greeting>();
+
+        match registry.decode::<Greeting>(json(serde_json::json!({
+            "@type": "Greeting", "@version": 2, "@body": {"name": "bob", "times": "twice"}
+        }))) {
+            Err(RegistryError::Invalid {
+                version: 2,
+                violations,
+                ..
+            }) => assert_eq!(violations[0].path, "/times"),
+            e => panic!("expected a schema violation, got {:?}", e.map(|_| ())),
+        }
+        assert!(matches!(
+            registry.decode::<Greeting>(json(serde_json::json!({
+                "@type": "Greeting", "@version": 3, "@body": {}
+            }))),
+            Err(RegistryError::UnsupportedVersion {
+                version: 3,
+                oldest: 1,
+                latest: 2,
+                ..
+            })
+        ));
+        assert!(matches!(
+            registry.decode::<Greeting>(json(serde_json::json!({
+                "@type": "Farewell", "@version": 1, "@body": {}
+            }))),
+            Err(RegistryError::WrongType { .. })
+        ));
+
+        // check only looks at registered tagged messages
+        let bad = json(serde_json::json!({
+            "@type": "Greeting", "@version": 1, "@body": {"name": 5}
+        }));
+        assert!(matches!(
+            registry.check(&bad),
+            Err(RegistryError::Invalid { .. })
+        ));
+        registry
+            .check(&json(serde_json::json!({
+                "@type": "Farewell", "@version": 1, "@body": {}
+            })))
+            .unwrap();
+        registry
+            .check(&json(serde_json::json!({"name": 5})))
+            .unwrap();
+        // or a version from after ours, which decode still refuses
+        let newer = json(serde_json::json!({
+            "@type": "Greeting", "@version": 3, "@body": {"name": "bob", "times": 2, "mood": "happy"}
+        }));
+        registry.check(&newer).unwrap();
+        assert!(matches!(
+            registry.decode::<Greeting>(newer),
+            Err(RegistryError::UnsupportedVersion { version: 3, .. })
+        ));
+        // but older versions are still checked
+        assert!(matches!(
+            registry.check(&json(serde_json::json!({
+                "@type": "Greeting", "@version": 0, "@body": {}
+            }))),
+            Err(RegistryError::UnsupportedVersion { version: 0, .. })
+        ));
+        // wherever it is nested
+        assert!(matches!(
+            registry.check(&json(serde_json::json!({
+                "channel": "default",
+                "data": [bad.clone()],
+            }))),
+            Err(RegistryError::Invalid { .. })
+        ));
+        assert!(matches!(
+            registry.check(&json(serde_json::json!({
+                "@type": "Farewell", "@version": 1, "@body": {"inner": bad}
+            }))),
+            Err(RegistryError::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn test_upgrade_keeps_hash() {
+        use crate::nonce::PrecomittedNonce;
+        use crate::{Envelope, GenericEnvelope, Header, Unsigned, WrappedJson};
+        use sapio_bitcoin::secp256k1::{rand, Secp256k1};
+        use sapio_bitcoin::KeyPair;
+        register::<Greeting>();
+        let secp = Secp256k1::new();
+        let kp = KeyPair::new(&secp, &mut rand::thread_rng());
+        let header = Header::new(
+            kp.x_only_public_key().0,
+            PrecomittedNonce::new(&secp).get_public(&secp),
+            None,
+            vec![],
+            0,
+            1_000,
+            Unsigned::new(None),
+            Default::default(),
+            Default::default(),
+        );
+        // an untagged message from before the registry, and an old version
+        for msg in [
+            serde_json::json!({"name": "alice"}),
+            serde_json::json!({"@type": "Greeting", "@version": 1, "@body": {"name": "alice"}}),
+        ] {
+            let mut e = Envelope::new(header.clone(), WrappedJson::from(json(msg)));
+            e.sign_with(&kp, &secp, PrecomittedNonce::new(&secp))
+                .unwrap();
+            let read: GenericEnvelope<Versioned<Greeting>> =
+                serde_json::from_str(&serde_json::to_string(&e).unwrap()).unwrap();
+            assert_eq!(read.msg().times, 1);
+            assert_eq!(read.canonicalized_hash_ref(), e.canonicalized_hash_ref());
+            read.self_authenticate(&secp).unwrap();
+            let read = GenericEnvelope::<Versioned<Greeting>>::from_binary(&e.to_binary().unwrap())
+                .unwrap();
+            assert_eq!(read.canonicalized_hash_ref(), e.canonicalized_hash_ref());
+        }
+    }
+}
@@ -4,9 +4,10 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::authority::Role;
 use crate::nonce::{PrecomittedNonce, PrecomittedPublicNonce};
 use crate::{AttestEnvelopable, Authenticated, CanonicalEnvelopeHash, GenericEnvelope};
-use rusqlite::types::{FromSql, FromSqlError, ToSqlOutput};
+use rusqlite::types::{FromSql, FromSqlError, ToSqlOutput, ValueRef};
 use rusqlite::ToSql;
 use sapio_bitcoin::hashes::hex::ToHex;
 use sapio_bitcoin::hashes::sha256;
@@ -28,8 +29,15 @@ where
     T: AttestEnvelopable,
 {
     fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
-        let s = value.as_str()?;
-        serde_json::from_str(s).map_err(|e| rusqlite::types::FromSqlError::Other(e.into()))
+        match value {
+            ValueRef::Blob(b) => {
+                GenericEnvelope::from_binary(b).map_err(|e| FromSqlError::Other(Box::new(e)))
+            }
+            _ => {
+                let s = value.as_str()?;
+                serde_json::from_str(s).map_err(|e| rusqlite::types::FromSqlError::Other(e.into()))
+            }
+        }
     }
 }
 
@@ -38,9 +46,7 @@ where
     T: AttestEnvelopable,
 {
     fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
-        let s = value.as_str()?;
-        let envelope: GenericEnvelope<T> =
-            serde_json::from_str(s).map_err(|e| rusqlite::types::FromSqlError::Other(e.into()))?;
+        let envelope = GenericEnvelope::<T>::column_result(value)?;
         Ok(Authenticated(envelope))
     }
 }
@@ -84,3 +90,22 @@ impl FromSql for CanonicalEnvelopeHash {
             .map_err(|e| FromSqlError::Other(Box::new(e)))
     }
 }
+
+impl ToSql for Role {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(match self {
+            Role::Root => "Root",
+            Role::Delegate => "Delegate",
+        }
+        .into())
+    }
+}
+impl FromSql for Role {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        match value.as_str()? {
+            "Root" => Ok(Role::Root),
+            "Delegate" => Ok(Role::Delegate),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
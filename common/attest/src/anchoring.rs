@@ -0,0 +1,442 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Periodically anchors chain tips into Bitcoin, see
+//! [`attest_messages::anchor`].
+//!
+//! Each round the tips that aren't anchored yet are committed to in a single
+//! transaction made by the node's wallet. Once it is buried deep enough the
+//! inclusion proof is stored, after which [`MsgDBHandle::get_anchor_proof`]
+//! returns proofs for those tips and [`verify_anchor`] checks them against the
+//! chain.
+//!
+//! [`MsgDBHandle::get_anchor_proof`]: attest_database::db_handle::MsgDBHandle::get_anchor_proof
+
+use crate::configuration::AnchorConfig;
+use crate::globals::Globals;
+use attest_database::connection::MsgDB;
+use attest_messages::anchor::{find_anchor, merkle_root, AnchorProof, ANCHOR_MAGIC};
+use attest_messages::{Envelope, WrappedJson};
+use attest_util::{AbstractResult, INFER_UNIT};
+use bitcoincore_rpc_async::{Client, RpcApi};
+use futures::future::BoxFuture;
+use sapio_bitcoin::consensus::encode::deserialize;
+use sapio_bitcoin::hashes::hex::{FromHex, ToHex};
+use sapio_bitcoin::hashes::sha256;
+use sapio_bitcoin::util::merkleblock::MerkleBlock;
+use sapio_bitcoin::{BlockHash, Transaction, Txid};
+use serde::Deserialize;
+use serde_json::json;
+use std::error::Error;
+use std::sync::Arc;
+use tokio::task::{spawn_blocking, JoinHandle};
+use tracing::{debug, info, warn};
+
+type AnchorResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Where a broadcast anchor transaction is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxStatus {
+    /// conflicted or evicted, it won't confirm without being rebroadcast
+    Dropped,
+    Pending,
+    Confirmed {
+        block: BlockHash,
+        height: u32,
+        confirmations: u32,
+    },
+}
+
+/// What the anchor service needs from a Bitcoin node, so that it can be run
+/// against something other than bitcoind in tests.
+pub trait AnchorBackend: Send + Sync {
+    /// Funds, signs and broadcasts a transaction with an output from
+    /// [`attest_messages::anchor::anchor_script`] of `root`.
+    fn broadcast(&self, root: sha256::Hash) -> BoxFuture<'_, AnchorResult<Transaction>>;
+    fn status(&self, txid: Txid) -> BoxFuture<'_, AnchorResult<TxStatus>>;
+    /// Proof that `txid` is in `block`
+    fn tx_proof(&self, txid: Txid, block: BlockHash) -> BoxFuture<'_, AnchorResult<MerkleBlock>>;
+    /// The height and confirmations of `block`, `None` if it is not in the
+    /// best chain.
+    fn block_depth(&self, block: BlockHash) -> BoxFuture<'_, AnchorResult<Option<(u32, u32)>>>;
+}
+
+#[derive(Deserialize)]
+struct HexResult {
+    hex: String,
+}
+
+#[derive(Deserialize)]
+struct WalletTx {
+    confirmations: i64,
+    blockhash: Option<BlockHash>,
+    blockheight: Option<u32>,
+}
+
+async fn wallet_broadcast(client: &Client, root: sha256::Hash) -> AnchorResult<Transaction> {
+    let mut data = ANCHOR_MAGIC.to_vec();
+    data.extend_from_slice(&root[..]);
+    let raw: String = client
+        .call(
+            "createrawtransaction",
+            &[json!([]), json!([{ "data": data.to_hex() }])],
+        )
+        .await?;
+    let funded: HexResult = client.call("fundrawtransaction", &[json!(raw)]).await?;
+    let signed: HexResult = client
+        .call("signrawtransactionwithwallet", &[json!(funded.hex)])
+        .await?;
+    let tx: Transaction = deserialize(&Vec::<u8>::from_hex(&signed.hex)?)?;
+    if find_anchor(&tx, &root).is_none() {
+        Err("Wallet Dropped Anchor Output")?;
+    }
+    let _txid: Txid = client
+        .call("sendrawtransaction", &[json!(signed.hex)])
+        .await?;
+    Ok(tx)
+}
+
+async fn wallet_status(client: &Client, txid: Txid) -> AnchorResult<TxStatus> {
+    let tx: WalletTx = client.call("gettransaction", &[json!(txid)]).await?;
+    Ok(match (tx.confirmations, tx.blockhash, tx.blockheight) {
+        (c, _, _) if c < 0 => TxStatus::Dropped,
+        (c, Some(block), Some(height)) if c > 0 => TxStatus::Confirmed {
+            block,
+            height,
+            confirmations: c as u32,
+        },
+        _ => TxStatus::Pending,
+    })
+}
+
+async fn node_tx_proof(client: &Client, txid: Txid, block: BlockHash) -> AnchorResult<MerkleBlock> {
+    let proof: String = client
+        .call("gettxoutproof", &[json!([txid]), json!(block)])
+        .await?;
+    Ok(deserialize(&Vec::<u8>::from_hex(&proof)?)?)
+}
+
+async fn node_block_depth(client: &Client, block: BlockHash) -> AnchorResult<Option<(u32, u32)>> {
+    let info = client.get_block_header_info(&block).await?;
+    // blocks off the best chain have -1 confirmations
+    Ok((info.confirmations > 0).then(|| (info.height as u32, info.confirmations as u32)))
+}
+
+impl AnchorBackend for Client {
+    fn broadcast(&self, root: sha256::Hash) -> BoxFuture<'_, AnchorResult<Transaction>> {
+        Box::pin(wallet_broadcast(self, root))
+    }
+    fn status(&self, txid: Txid) -> BoxFuture<'_, AnchorResult<TxStatus>> {
+        Box::pin(wallet_status(self, txid))
+    }
+    fn tx_proof(&self, txid: Txid, block: BlockHash) -> BoxFuture<'_, AnchorResult<MerkleBlock>> {
+        Box::pin(node_tx_proof(self, txid, block))
+    }
+    fn block_depth(&self, block: BlockHash) -> BoxFuture<'_, AnchorResult<Option<(u32, u32)>>> {
+        Box::pin(node_block_depth(self, block))
+    }
+}
+
+/// Anchors every tip not already part of an anchor in one transaction,
+/// returning its txid if there were any.
+pub async fn anchor_tips(
+    db: &MsgDB,
+    backend: &dyn AnchorBackend,
+    local_only: bool,
+) -> AnchorResult<Option<Txid>> {
+    let leaves = {
+        let handle = db.get_handle_read().await;
+        spawn_blocking(move || {
+            let tips: Vec<_> = if local_only {
+                handle
+                    .get_tip_for_known_keys::<WrappedJson>()?
+                    .into_iter()
+                    .map(|e| e.canonicalized_hash_ref())
+                    .collect()
+            } else {
+                handle
+                    .get_tips_for_all_users::<Envelope, WrappedJson>()?
+                    .into_iter()
+                    .map(|e| e.canonicalized_hash_ref())
+                    .collect()
+            };
+            let mut leaves = vec![];
+            for tip in tips {
+                if !handle.is_anchored(tip)? {
+                    leaves.push(tip);
+                }
+            }
+            leaves.sort_unstable();
+            Ok::<_, rusqlite::Error>(leaves)
+        })
+        .await??
+    };
+    let root = match merkle_root(&leaves) {
+        Some(root) => root,
+        None => return Ok(None),
+    };
+    let tx = backend.broadcast(root).await?;
+    let txid = tx.txid();
+    info!(?txid, n = leaves.len(), "Broadcast Anchor");
+    let mut handle = db.get_handle_all().await;
+    spawn_blocking(move || handle.save_anchor(&leaves, &tx)).await???;
+    Ok(Some(txid))
+}
+
+/// Stores the proofs of pending anchors that are now `min_confirmations` deep,
+/// and forgets those that were dropped so their tips get anchored again.
+/// Returns how many were confirmed.
+pub async fn confirm_anchors(
+    db: &MsgDB,
+    backend: &dyn AnchorBackend,
+    min_confirmations: u32,
+) -> AnchorResult<usize> {
+    let pending = {
+        let handle = db.get_handle_read().await;
+        spawn_blocking(move || handle.get_unconfirmed_anchors()).await??
+    };
+    let mut confirmed = 0;
+    for txid in pending {
+        match backend.status(txid).await? {
+            TxStatus::Pending => {}
+            TxStatus::Dropped => {
+                warn!(?txid, "Anchor Dropped");
+                let handle = db.get_handle_all().await;
+                spawn_blocking(move || handle.abandon_anchor(txid)).await??;
+            }
+            TxStatus::Confirmed {
+                block,
+                height,
+                confirmations,
+            } => {
+                if confirmations < min_confirmations {
+                    debug!(?txid, confirmations, "Anchor Not Yet Buried");
+                    continue;
+                }
+                let tx_proof = backend.tx_proof(txid, block).await?;
+                let handle = db.get_handle_all().await;
+                match spawn_blocking(move || handle.confirm_anchor(txid, &tx_proof, height))
+                    .await??
+                {
+                    Ok(()) => confirmed += 1,
+                    Err(e) => warn!(?txid, err=?e, "Node Gave Invalid Anchor Proof"),
+                }
+            }
+        }
+    }
+    Ok(confirmed)
+}
+
+/// Checks `proof` against the best chain, returning the height the envelope
+/// is known to have existed at.
+pub async fn verify_anchor(
+    backend: &dyn AnchorBackend,
+    proof: &AnchorProof,
+    min_confirmations: u32,
+) -> AnchorResult<u32> {
+    proof.verify()?;
+    match backend.block_depth(proof.block_hash()).await? {
+        Some((height, confirmations)) if confirmations >= min_confirmations => Ok(height),
+        Some(_) => Err("Anchor Not Buried Deep Enough")?,
+        None => Err("Anchor Not In Best Chain")?,
+    }
+}
+
+/// Starts the anchor service, if it is configured.
+pub fn run(
+    g: Arc<Globals>,
+    backend: Arc<dyn AnchorBackend>,
+) -> Option<JoinHandle<AbstractResult<()>>> {
+    let config: AnchorConfig = g.config.anchoring.clone()?;
+    Some(tokio::spawn(async move {
+        while !g.shutdown.should_quit() {
+            tokio::time::sleep(config.frequency).await;
+            confirm_anchors(&g.msg_db, &*backend, config.min_confirmations).await?;
+            anchor_tips(&g.msg_db, &*backend, config.local_only).await?;
+        }
+        INFER_UNIT
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use attest_database::{generate_new_user, setup_test_db};
+    use attest_messages::anchor::anchor_script;
+    use attest_messages::CanonicalEnvelopeHash;
+    use ruma_serde::CanonicalJsonValue;
+    use sapio_bitcoin::secp256k1::Secp256k1;
+    use sapio_bitcoin::{Block, BlockHeader, TxOut};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+    use test_log::test;
+
+    fn done<T: Send + 'static>(t: AnchorResult<T>) -> BoxFuture<'static, AnchorResult<T>> {
+        Box::pin(futures::future::ready(t))
+    }
+
+    /// A chain that mines whatever is in its mempool on demand
+    #[derive(Default)]
+    struct MockChain {
+        mempool: Mutex<Vec<Transaction>>,
+        blocks: Mutex<Vec<Block>>,
+        broadcasts: AtomicU32,
+    }
+
+    impl MockChain {
+        fn mine(&self) -> BlockHash {
+            let mut blocks = self.blocks.lock().unwrap();
+            let mut block = Block {
+                header: BlockHeader {
+                    version: 1,
+                    prev_blockhash: blocks.last().map(|b| b.block_hash()).unwrap_or_default(),
+                    merkle_root: Default::default(),
+                    time: blocks.len() as u32,
+                    bits: 0,
+                    nonce: 0,
+                },
+                txdata: std::mem::take(&mut *self.mempool.lock().unwrap()),
+            };
+            // empty blocks have no merkle root
+            block.header.merkle_root = block.compute_merkle_root().unwrap_or_default();
+            blocks.push(block);
+            blocks.last().unwrap().block_hash()
+        }
+        fn depth(blocks: &[Block], i: usize) -> (u32, u32) {
+            (i as u32, (blocks.len() - i) as u32)
+        }
+    }
+
+    impl AnchorBackend for MockChain {
+        fn broadcast(&self, root: sha256::Hash) -> BoxFuture<'_, AnchorResult<Transaction>> {
+            let tx = Transaction {
+                version: 2,
+                // like a wallet picking new coins, every attempt differs
+                lock_time: self.broadcasts.fetch_add(1, Ordering::Relaxed),
+                input: vec![],
+                output: vec![TxOut {
+                    value: 0,
+                    script_pubkey: anchor_script(&root),
+                }],
+            };
+            self.mempool.lock().unwrap().push(tx.clone());
+            done(Ok(tx))
+        }
+        fn status(&self, txid: Txid) -> BoxFuture<'_, AnchorResult<TxStatus>> {
+            let blocks = self.blocks.lock().unwrap();
+            let status = if let Some(i) = blocks
+                .iter()
+                .position(|b| b.txdata.iter().any(|t| t.txid() == txid))
+            {
+                let (height, confirmations) = Self::depth(&blocks, i);
+                TxStatus::Confirmed {
+                    block: blocks[i].block_hash(),
+                    height,
+                    confirmations,
+                }
+            } else if self
+                .mempool
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|t| t.txid() == txid)
+            {
+                TxStatus::Pending
+            } else {
+                TxStatus::Dropped
+            };
+            done(Ok(status))
+        }
+        fn tx_proof(
+            &self,
+            txid: Txid,
+            block: BlockHash,
+        ) -> BoxFuture<'_, AnchorResult<MerkleBlock>> {
+            let proof = self
+                .blocks
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|b| b.block_hash() == block)
+                .map(|b| MerkleBlock::from_block_with_predicate(b, |t| *t == txid));
+            done(proof.ok_or_else(|| "Unknown Block".into()))
+        }
+        fn block_depth(&self, block: BlockHash) -> BoxFuture<'_, AnchorResult<Option<(u32, u32)>>> {
+            let blocks = self.blocks.lock().unwrap();
+            let depth = blocks
+                .iter()
+                .position(|b| b.block_hash() == block)
+                .map(|i| Self::depth(&blocks, i));
+            done(Ok(depth))
+        }
+    }
+
+    async fn make_chains(db: &MsgDB, local: usize, remote: usize) -> Vec<CanonicalEnvelopeHash> {
+        let secp = Secp256k1::new();
+        let mut handle = db.get_handle_all().await;
+        let mut hashes = vec![];
+        for i in 0..local + remote {
+            let (kp, _nonce, genesis) =
+                generate_new_user::<_, WrappedJson, _>(&secp, CanonicalJsonValue::Null).unwrap();
+            if i < local {
+                handle.save_keypair(kp).unwrap();
+            }
+            hashes.push(genesis.canonicalized_hash_ref());
+            handle
+                .insert_user_by_genesis_envelope(
+                    format!("user-{}", i),
+                    genesis.self_authenticate(&secp).unwrap(),
+                )
+                .unwrap()
+                .unwrap();
+        }
+        hashes
+    }
+
+    #[test(tokio::test)]
+    async fn test_anchor_service() {
+        let db = setup_test_db().await;
+        let chain = MockChain::default();
+        let tips = make_chains(&db, 2, 1).await;
+        chain.mine();
+
+        let txid = anchor_tips(&db, &chain, true).await.unwrap().unwrap();
+        // nothing new to anchor while the first anchor is pending
+        assert_eq!(anchor_tips(&db, &chain, true).await.unwrap(), None);
+        assert_eq!(confirm_anchors(&db, &chain, 2).await.unwrap(), 0);
+
+        let block = chain.mine();
+        // not deep enough yet
+        assert_eq!(confirm_anchors(&db, &chain, 2).await.unwrap(), 0);
+        chain.mine();
+        assert_eq!(confirm_anchors(&db, &chain, 2).await.unwrap(), 1);
+
+        let handle = db.get_handle_read().await;
+        for tip in &tips[..2] {
+            let proof = handle.get_anchor_proof(*tip).unwrap().unwrap();
+            assert_eq!(proof.tx.txid(), txid);
+            assert_eq!(proof.block_hash(), block);
+            assert_eq!(verify_anchor(&chain, &proof, 2).await.unwrap(), 1);
+            assert!(verify_anchor(&chain, &proof, 3).await.is_err());
+        }
+        // only local chains were anchored
+        assert!(handle.get_anchor_proof(tips[2]).unwrap().is_none());
+        drop(handle);
+
+        // a dropped anchor gets redone
+        let dropped = anchor_tips(&db, &chain, false).await.unwrap().unwrap();
+        chain.mempool.lock().unwrap().clear();
+        assert_eq!(confirm_anchors(&db, &chain, 1).await.unwrap(), 0);
+        let redone = anchor_tips(&db, &chain, false).await.unwrap().unwrap();
+        assert_ne!(dropped, redone);
+        chain.mine();
+        assert_eq!(confirm_anchors(&db, &chain, 1).await.unwrap(), 1);
+        let handle = db.get_handle_read().await;
+        let proof = handle.get_anchor_proof(tips[2]).unwrap().unwrap();
+        assert_eq!(proof.tx.txid(), redone);
+        assert_eq!(verify_anchor(&chain, &proof, 1).await.unwrap(), 3);
+    }
+}
@@ -29,11 +29,41 @@ type SpecificTipsT = (
     protocol::SpecificTips,
     oneshot::Sender<protocol::SpecificTipsResponse>,
 );
+type ReconcileT = (
+    protocol::Reconcile,
+    oneshot::Sender<protocol::ReconcileResponse>,
+);
+type FetchBlobsT = (
+    protocol::FetchBlobs,
+    oneshot::Sender<protocol::FetchBlobsResponse>,
+);
+type FetchRangeT = (
+    protocol::FetchRange,
+    oneshot::Sender<protocol::FetchRangeResponse>,
+);
 
 pub enum AnySender {
     LatestTips(oneshot::Sender<protocol::LatestTipsResponse>),
     Post(oneshot::Sender<protocol::PostResponse>),
     SpecificTips(oneshot::Sender<protocol::SpecificTipsResponse>),
+    Reconcile(oneshot::Sender<protocol::ReconcileResponse>),
+    FetchBlobs(oneshot::Sender<protocol::FetchBlobsResponse>),
+    FetchRange(oneshot::Sender<protocol::FetchRangeResponse>),
+}
+impl From<oneshot::Sender<protocol::FetchRangeResponse>> for AnySender {
+    fn from(c: oneshot::Sender<protocol::FetchRangeResponse>) -> Self {
+        AnySender::FetchRange(c)
+    }
+}
+impl From<oneshot::Sender<protocol::FetchBlobsResponse>> for AnySender {
+    fn from(c: oneshot::Sender<protocol::FetchBlobsResponse>) -> Self {
+        AnySender::FetchBlobs(c)
+    }
+}
+impl From<oneshot::Sender<protocol::ReconcileResponse>> for AnySender {
+    fn from(c: oneshot::Sender<protocol::ReconcileResponse>) -> Self {
+        AnySender::Reconcile(c)
+    }
 }
 impl From<oneshot::Sender<protocol::SpecificTipsResponse>> for AnySender {
     fn from(c: oneshot::Sender<protocol::SpecificTipsResponse>) -> Self {
@@ -58,12 +88,20 @@ pub struct ProtocolChan {
     latest_tips: UnboundedSender<LatestTipsT>,
     specific_tips: UnboundedSender<SpecificTipsT>,
     post: UnboundedSender<PostT>,
+    reconcile: UnboundedSender<ReconcileT>,
+    fetch_blobs: UnboundedSender<FetchBlobsT>,
+    fetch_range: UnboundedSender<FetchRangeT>,
 }
 
 impl ProtocolChan {
     // if any is closed, they should all be dropped
     pub fn is_closed(&self) -> bool {
-        self.post.is_closed() || self.specific_tips.is_closed() || self.latest_tips.is_closed()
+        self.post.is_closed()
+            || self.specific_tips.is_closed()
+            || self.latest_tips.is_closed()
+            || self.reconcile.is_closed()
+            || self.fetch_blobs.is_closed()
+            || self.fetch_range.is_closed()
     }
     pub fn send_latest_tips(&self, value: LatestTipsT) -> Result<(), SendError<LatestTipsT>> {
         self.latest_tips.send(value)
@@ -74,12 +112,24 @@ impl ProtocolChan {
     pub fn send_post(&self, value: PostT) -> Result<(), SendError<PostT>> {
         self.post.send(value)
     }
+    pub fn send_reconcile(&self, value: ReconcileT) -> Result<(), SendError<ReconcileT>> {
+        self.reconcile.send(value)
+    }
+    pub fn send_fetch_blobs(&self, value: FetchBlobsT) -> Result<(), SendError<FetchBlobsT>> {
+        self.fetch_blobs.send(value)
+    }
+    pub fn send_fetch_range(&self, value: FetchRangeT) -> Result<(), SendError<FetchRangeT>> {
+        self.fetch_range.send(value)
+    }
 }
 
 pub struct ProtocolReceiverMut<'a> {
     pub latest_tips: &'a mut UnboundedReceiver<LatestTipsT>,
     pub specific_tips: &'a mut UnboundedReceiver<SpecificTipsT>,
     pub post: &'a mut UnboundedReceiver<PostT>,
+    pub reconcile: &'a mut UnboundedReceiver<ReconcileT>,
+    pub fetch_blobs: &'a mut UnboundedReceiver<FetchBlobsT>,
+    pub fetch_range: &'a mut UnboundedReceiver<FetchRangeT>,
 }
 impl Drop for ProtocolReceiver {
     fn drop(&mut self) {
@@ -90,6 +140,9 @@ pub struct ProtocolReceiver {
     pub latest_tips: UnboundedReceiver<LatestTipsT>,
     pub specific_tips: UnboundedReceiver<SpecificTipsT>,
     pub post: UnboundedReceiver<PostT>,
+    pub reconcile: UnboundedReceiver<ReconcileT>,
+    pub fetch_blobs: UnboundedReceiver<FetchBlobsT>,
+    pub fetch_range: UnboundedReceiver<FetchRangeT>,
 }
 
 impl ProtocolReceiver {
@@ -98,6 +151,9 @@ impl ProtocolReceiver {
             latest_tips: &mut self.latest_tips,
             specific_tips: &mut self.specific_tips,
             post: &mut self.post,
+            reconcile: &mut self.reconcile,
+            fetch_blobs: &mut self.fetch_blobs,
+            fetch_range: &mut self.fetch_range,
         }
     }
 }
@@ -106,16 +162,25 @@ pub fn new_protocol_chan(_p: usize) -> (ProtocolChan, ProtocolReceiver) {
     let (latest_tips_tx, latest_tips_rx) = unbounded_channel();
     let (specific_tips_tx, specific_tips_rx) = unbounded_channel();
     let (post_tx, post_rx) = unbounded_channel();
+    let (reconcile_tx, reconcile_rx) = unbounded_channel();
+    let (fetch_blobs_tx, fetch_blobs_rx) = unbounded_channel();
+    let (fetch_range_tx, fetch_range_rx) = unbounded_channel();
     (
         ProtocolChan {
             latest_tips: latest_tips_tx,
             specific_tips: specific_tips_tx,
             post: post_tx,
+            reconcile: reconcile_tx,
+            fetch_blobs: fetch_blobs_tx,
+            fetch_range: fetch_range_tx,
         },
         ProtocolReceiver {
             latest_tips: latest_tips_rx,
             specific_tips: specific_tips_rx,
             post: post_rx,
+            reconcile: reconcile_rx,
+            fetch_blobs: fetch_blobs_rx,
+            fetch_range: fetch_range_rx,
         },
     )
 }
@@ -251,9 +251,20 @@ impl AttestationClient {
                             );
                             tokio::time::sleep(Duration::from_secs(1)).await;
                         };
-                        let res =
-                            protocol::run_protocol(g, socket, gss, db, Role::Client, Some(svc))
-                                .await;
+                        let codec = protocol::compression::negotiate_client(
+                            &g.config.protocol,
+                            socket.peer_features(),
+                        );
+                        let res = protocol::run_protocol(
+                            g,
+                            socket,
+                            gss,
+                            db,
+                            Role::Client,
+                            Some(svc),
+                            codec,
+                        )
+                        .await;
                         trace!(?res, role=?Role::Client,"websocket terminated");
                     }));
                 }
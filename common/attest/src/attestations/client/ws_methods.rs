@@ -9,10 +9,19 @@ use super::super::query::Tips;
 use super::AttestationClient;
 use super::NotifyOnDrop;
 use super::ServiceUrl;
+use crate::attestations::server::protocol::reconcile;
+use crate::attestations::server::protocol::reconcile::RangePayload;
+use crate::attestations::server::protocol::reconcile::SyncKey;
+use crate::attestations::server::protocol::Blob;
+use crate::attestations::server::protocol::FetchBlobs;
+use crate::attestations::server::protocol::FetchRange;
 use crate::attestations::server::protocol::LatestTips;
 use crate::attestations::server::protocol::Post;
+use crate::attestations::server::protocol::Reconcile;
 use crate::attestations::server::protocol::SpecificTips;
 use crate::control::query::Outcome;
+use attest_messages::blobs::BlobRef;
+use attest_messages::CanonicalEnvelopeHash;
 use attest_messages::Envelope;
 use std::sync::Arc;
 use tokio::spawn;
@@ -21,6 +30,10 @@ use tokio::sync::Notify;
 use tracing::debug;
 use tracing::trace;
 use tracing::warn;
+/// Reconciliation normally settles in a handful of rounds, large differences
+/// take more as ranges wait for room in a message. Anything more means the
+/// peer is misbehaving.
+const MAX_RECONCILE_ROUNDS: usize = 256;
 impl AttestationClient {
     pub async fn get_latest_tips(&self, url: &ServiceUrl) -> Option<Vec<Envelope>> {
         let conn = self.get_conn(url).await;
@@ -116,4 +129,117 @@ impl AttestationClient {
             .ok()?;
         Some(resp.0)
     }
+
+    /// Asks the peer for the contents of attachments. The peer only returns
+    /// those it has and lets us see, and they still need to be checked
+    /// against `wanted`.
+    pub async fn fetch_blobs(&self, wanted: &[BlobRef], url: &ServiceUrl) -> Option<Vec<Blob>> {
+        let conn = self.get_conn(url).await;
+        let (tx, rx) = oneshot::channel();
+        conn.send_fetch_blobs((
+            FetchBlobs {
+                blobs: wanted.to_vec(),
+            },
+            tx,
+        ))
+        .map_err(|_| {
+            warn!("The channel to enqueue new requests is closed.");
+        })
+        .ok()?;
+
+        let resp = rx
+            .await
+            .map_err(|_| {
+                warn!("The oneshot::channel to get the reuslt closed without returning a response.")
+            })
+            .ok()?;
+        Some(resp.0)
+    }
+
+    /// Asks the peer for the envelopes of a chain between two heights
+    /// (inclusive). The peer may return fewer than asked for, and they still
+    /// need to be authenticated.
+    pub async fn fetch_range(
+        &self,
+        genesis: CanonicalEnvelopeHash,
+        first_height: i64,
+        last_height: i64,
+        url: &ServiceUrl,
+    ) -> Option<Vec<Envelope>> {
+        let conn = self.get_conn(url).await;
+        let (tx, rx) = oneshot::channel();
+        conn.send_fetch_range((
+            FetchRange {
+                genesis,
+                first_height,
+                last_height,
+            },
+            tx,
+        ))
+        .map_err(|_| {
+            warn!("The channel to enqueue new requests is closed.");
+        })
+        .ok()?;
+
+        let resp = rx
+            .await
+            .map_err(|_| {
+                warn!("The oneshot::channel to get the reuslt closed without returning a response.")
+            })
+            .ok()?;
+        Some(resp.0)
+    }
+
+    /// Runs set reconciliation against the peer, returning the keys we lack
+    /// and the keys the peer lacks. `mine` must be sorted.
+    pub async fn reconcile(
+        &self,
+        mine: &[SyncKey],
+        url: &ServiceUrl,
+    ) -> Option<(Vec<SyncKey>, Vec<SyncKey>)> {
+        let conn = self.get_conn(url).await;
+        let mut we_lack = vec![];
+        let mut they_lack = vec![];
+        // ranges beyond what fits in one message wait for a later round
+        let mut pending = reconcile::start(mine);
+        for round in 0..MAX_RECONCILE_ROUNDS {
+            if pending.is_empty() {
+                debug!(
+                    ?url,
+                    round,
+                    we_lack = we_lack.len(),
+                    they_lack = they_lack.len(),
+                    "reconciled"
+                );
+                return Some((we_lack, they_lack));
+            }
+            let ranges = pending
+                .drain(..pending.len().min(reconcile::MAX_RANGES))
+                .collect();
+            let (tx, rx) = oneshot::channel();
+            conn.send_reconcile((Reconcile { ranges }, tx))
+                .map_err(|_| {
+                    warn!("The channel to enqueue new requests is closed.");
+                })
+                .ok()?;
+            let resp = rx
+                .await
+                .map_err(|_| {
+                    warn!("The oneshot::channel to get the reuslt closed without returning a response.")
+                })
+                .ok()?;
+            let r = reconcile::reconcile(mine, &resp.0, usize::MAX);
+            we_lack.extend(r.we_lack);
+            they_lack.extend(r.they_lack);
+            // the peer keeps no state, so there's no point telling it the
+            // differences we found
+            pending.extend(
+                r.reply
+                    .into_iter()
+                    .filter(|r| !matches!(r.payload, RangePayload::Difference { .. })),
+            );
+        }
+        warn!(?url, "Reconciliation did not converge");
+        None
+    }
 }
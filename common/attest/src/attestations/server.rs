@@ -4,14 +4,15 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use self::protocol::compression::{self, Codec, ProtocolFeatures, FEATURES_HEADER};
 use self::protocol::GlobalSocketState;
 use crate::globals::Globals;
 use attest_database::connection::MsgDB;
 use attest_util::{AbstractResult, INFER_UNIT};
 use axum::{
     extract::{ws::WebSocket, WebSocketUpgrade},
-    http::Response,
     http::StatusCode,
+    http::{HeaderMap, HeaderValue, Response},
     routing::{get, post},
     Extension, Json, Router,
 };
@@ -23,21 +24,33 @@ pub mod generic_websocket;
 pub mod protocol;
 pub mod tungstenite_client_adaptor;
 
+/// Upgrades to the peer protocol, answering the client's offer of
+/// [`ProtocolFeatures`] if it made one.
 async fn handle_socket(
     ws: WebSocketUpgrade,
+    headers: HeaderMap,
     Extension(g): Extension<Arc<Globals>>,
     Extension(gss): Extension<GlobalSocketState>,
     Extension(db): Extension<MsgDB>,
 ) -> axum::response::Response {
-    ws.on_upgrade(|w| handle_socket_symmetric_server(g, w, gss, db))
+    let theirs = headers
+        .get(FEATURES_HEADER)
+        .and_then(|v| ProtocolFeatures::from_header(v.as_bytes()));
+    let (codec, answer) = compression::negotiate_server(&g.config.protocol, theirs.as_ref());
+    let mut response = ws.on_upgrade(move |w| handle_socket_symmetric_server(g, w, gss, db, codec));
+    if let Some(answer) = answer.and_then(|a| HeaderValue::from_str(&a.to_header()).ok()) {
+        response.headers_mut().insert(FEATURES_HEADER, answer);
+    }
+    response
 }
 async fn handle_socket_symmetric_server(
     g: Arc<Globals>,
     socket: WebSocket,
     gss: GlobalSocketState,
     db: MsgDB,
+    codec: Codec,
 ) {
-    let res = protocol::run_protocol(g, socket, gss, db, Role::Server, None).await;
+    let res = protocol::run_protocol(g, socket, gss, db, Role::Server, None, codec).await;
     trace!(?res, role=?Role::Server,"socket quit");
 }
 pub async fn handle_authenticate(
@@ -4,7 +4,9 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use self::authentication_handshake::MessageExt;
+use self::compression::Codec;
+use self::reconcile::Range;
+use self::reconcile::SyncKey;
 use super::super::query::Tips;
 use super::generic_websocket::WebSocketFunctionality;
 use crate::attestations::client::AnySender;
@@ -13,7 +15,13 @@ use crate::attestations::client::ServiceUrl;
 use crate::control::query::Outcome;
 use crate::globals::Globals;
 use attest_database::connection::MsgDB;
+use attest_database::db_handle::handle_type;
+use attest_database::db_handle::MsgDBHandle;
+use attest_messages::batch::authenticate_all;
+use attest_messages::blobs::BlobRef;
+use attest_messages::CanonicalEnvelopeHash;
 use attest_messages::Envelope;
+use attest_messages::WrappedJson;
 use axum::extract::ws::Message;
 use sapio_bitcoin::hashes::sha256;
 use sapio_bitcoin::hashes::Hash;
@@ -58,11 +66,32 @@ pub struct LatestTips {}
 pub struct SpecificTips {
     pub tips: Tips,
 }
+/// One round of set reconciliation, see [`reconcile`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Reconcile {
+    pub ranges: Vec<Range>,
+}
+/// Asks for the contents of attachments, see [`attest_messages::blobs`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchBlobs {
+    pub blobs: Vec<BlobRef>,
+}
+/// Asks for the envelopes of a chain between two heights (inclusive), e.g. to
+/// fill a gap, see [`attest_database::db_handle::get::gaps`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchRange {
+    pub genesis: CanonicalEnvelopeHash,
+    pub first_height: i64,
+    pub last_height: i64,
+}
 #[derive(Serialize, Deserialize, Debug)]
 pub enum AttestRequest {
     LatestTips(LatestTips),
     SpecificTips(SpecificTips),
     Post(Post),
+    Reconcile(Reconcile),
+    FetchBlobs(FetchBlobs),
+    FetchRange(FetchRange),
 }
 
 impl From<LatestTips> for AttestRequest {
@@ -80,6 +109,21 @@ impl From<SpecificTips> for AttestRequest {
         AttestRequest::SpecificTips(l)
     }
 }
+impl From<Reconcile> for AttestRequest {
+    fn from(l: Reconcile) -> Self {
+        AttestRequest::Reconcile(l)
+    }
+}
+impl From<FetchBlobs> for AttestRequest {
+    fn from(l: FetchBlobs) -> Self {
+        AttestRequest::FetchBlobs(l)
+    }
+}
+impl From<FetchRange> for AttestRequest {
+    fn from(l: FetchRange) -> Self {
+        AttestRequest::FetchRange(l)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LatestTipsResponse(pub Vec<Envelope>);
@@ -87,12 +131,43 @@ pub struct LatestTipsResponse(pub Vec<Envelope>);
 pub struct SpecificTipsResponse(pub Vec<Envelope>);
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PostResponse(pub Vec<Outcome>);
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReconcileResponse(pub Vec<Range>);
+/// The contents of an attachment, unchecked until matched to its [`BlobRef`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Blob {
+    pub hash: sha256::Hash,
+    #[serde(with = "hex_bytes")]
+    pub data: Vec<u8>,
+}
+/// The attachments the peer had and let us see, possibly fewer than asked for
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchBlobsResponse(pub Vec<Blob>);
+/// The envelopes of the range the peer had and let us see, lowest first and
+/// possibly cut short
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchRangeResponse(pub Vec<Envelope>);
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum AttestResponse {
     LatestTips(LatestTipsResponse),
     SpecificTips(SpecificTipsResponse),
     Post(PostResponse),
+    Reconcile(ReconcileResponse),
+    FetchBlobs(FetchBlobsResponse),
+    FetchRange(FetchRangeResponse),
+}
+
+mod hex_bytes {
+    use sapio_bitcoin::hashes::hex::{FromHex, ToHex};
+    use serde::{Deserialize, Deserializer, Serializer};
+    pub fn serialize<S: Serializer>(b: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&b.to_hex())
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        Vec::from_hex(&s).map_err(serde::de::Error::custom)
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -104,12 +179,31 @@ impl AttestRequest {
             AttestRequest::LatestTips(_) => 0,
             AttestRequest::SpecificTips(_) => 1,
             AttestRequest::Post(_) => 2,
+            AttestRequest::Reconcile(_) => 3,
+            AttestRequest::FetchBlobs(_) => 4,
+            AttestRequest::FetchRange(_) => 5,
         })
     }
-    pub(crate) fn into_protocol_and_log(self, seq: u64) -> Result<Message, serde_json::Error> {
+    pub(crate) fn into_protocol_and_log(
+        mut self,
+        seq: u64,
+        codec: &Codec,
+    ) -> Result<Message, AttestProtocolError> {
+        if let AttestRequest::Post(Post { envelopes }) = &mut self {
+            // outcomes are matched to envelopes by position, so stop at the
+            // first one the peer can't check rather than skipping it
+            if let Some(i) = envelopes.iter().position(|e| !codec.supports(e)) {
+                debug!(
+                    seq,
+                    skipped = envelopes.len() - i,
+                    "Peer Predates Envelope Version"
+                );
+                envelopes.truncate(i);
+            }
+        }
         let msg = &AttestSocketProtocol::Request(seq, self);
         trace!(?msg, seq, "Sending Request");
-        Ok(Message::Text(serde_json::to_string(msg)?))
+        codec.encode(msg)
     }
 }
 impl AttestResponse {
@@ -118,12 +212,25 @@ impl AttestResponse {
             AttestResponse::LatestTips(_) => 0,
             AttestResponse::SpecificTips(_) => 1,
             AttestResponse::Post(_) => 2,
+            AttestResponse::Reconcile(_) => 3,
+            AttestResponse::FetchBlobs(_) => 4,
+            AttestResponse::FetchRange(_) => 5,
         })
     }
-    pub(crate) fn into_protocol_and_log(self, seq: u64) -> Result<Message, serde_json::Error> {
+    pub(crate) fn into_protocol_and_log(
+        mut self,
+        seq: u64,
+        codec: &Codec,
+    ) -> Result<Message, AttestProtocolError> {
+        if let AttestResponse::LatestTips(LatestTipsResponse(envelopes))
+        | AttestResponse::SpecificTips(SpecificTipsResponse(envelopes))
+        | AttestResponse::FetchRange(FetchRangeResponse(envelopes)) = &mut self
+        {
+            envelopes.retain(|e| codec.supports(e));
+        }
         let msg = &AttestSocketProtocol::Response(seq, self);
         trace!(?msg, seq, "Sending Response");
-        Ok(Message::Text(serde_json::to_string(msg)?))
+        codec.encode(msg)
     }
 }
 
@@ -146,6 +253,11 @@ pub enum AttestProtocolError {
     UnrequestedResponse,
     InvalidChallengeHashString,
     SelfConnection,
+    MessageTooLarge,
+    CompressionError(String),
+    TooManyRanges,
+    TooManyBlobs,
+    BinaryEncodingError(String),
 }
 
 unsafe impl Send for AttestProtocolError {}
@@ -174,6 +286,12 @@ impl From<serde_json::Error> for AttestProtocolError {
     }
 }
 
+impl From<attest_messages::binary::BinaryError> for AttestProtocolError {
+    fn from(e: attest_messages::binary::BinaryError) -> Self {
+        AttestProtocolError::BinaryEncodingError(e.to_string())
+    }
+}
+
 impl std::error::Error for AttestProtocolError {}
 
 type ServiceIDBuilder = (String, u16);
@@ -224,6 +342,9 @@ impl GlobalSocketState {
 }
 
 pub mod authentication_handshake;
+pub mod binary;
+pub mod compression;
+pub mod reconcile;
 
 struct ResponseRouter {
     code: ResponseCode,
@@ -231,6 +352,11 @@ struct ResponseRouter {
 }
 // Only allow 10 outstanding messages
 pub const MAX_MESSAGE_DEFECIT: i64 = 10;
+/// Most attachments that may be asked for at once
+pub const MAX_BLOBS_PER_REQUEST: usize = 16;
+/// Most envelopes returned for one [`FetchRange`], the rest can be asked for
+/// again starting above the last one returned
+pub const MAX_ENVELOPES_PER_RANGE: u32 = 256;
 
 pub async fn run_protocol<W: WebSocketFunctionality>(
     g: Arc<Globals>,
@@ -239,8 +365,9 @@ pub async fn run_protocol<W: WebSocketFunctionality>(
     db: MsgDB,
     role: Role,
     peer_name_in: Option<ServiceUrl>,
+    codec: Codec,
 ) -> Result<&'static str, AttestProtocolError> {
-    let res = run_protocol_inner(g, &mut socket, gss, db, role, peer_name_in).await;
+    let res = run_protocol_inner(g, &mut socket, gss, db, role, peer_name_in, codec).await;
     // THis never runs I think because of the top recv
     trace!(error=?res, ?role, "websocket quit: Internal Connection Dropped");
     socket.t_close().await.ok();
@@ -253,6 +380,7 @@ pub async fn run_protocol_inner<W: WebSocketFunctionality>(
     mut db: MsgDB,
     role: Role,
     peer_name_in: Option<ServiceUrl>,
+    codec: Codec,
 ) -> Result<&'static str, AttestProtocolError> {
     let peer_name =
         authentication_handshake::handshake_protocol(g.clone(), socket, &mut gss, role).await?;
@@ -267,7 +395,6 @@ pub async fn run_protocol_inner<W: WebSocketFunctionality>(
             return Err(AttestProtocolError::InvalidSetup);
         }
     };
-
     let client = g.get_client().await?;
     let prefer_role = preferred_role(g, &peer_name).await?;
     let mut receiver = {
@@ -284,6 +411,9 @@ pub async fn run_protocol_inner<W: WebSocketFunctionality>(
         latest_tips,
         specific_tips,
         post,
+        reconcile,
+        fetch_blobs,
+        fetch_range,
     } = receiver.get_mut();
     let mut inflight_requests: BTreeMap<u64, ResponseRouter> = Default::default();
     let mut seq = 0;
@@ -301,6 +431,8 @@ pub async fn run_protocol_inner<W: WebSocketFunctionality>(
                         &mut db,
                         &mut inflight_requests,
                         role,
+                        &peer_name,
+                        &codec,
                         msg,
                     )
                     .await?;
@@ -315,6 +447,7 @@ pub async fn run_protocol_inner<W: WebSocketFunctionality>(
                     socket,
                     &mut inflight_requests,
                     seq,
+                    &codec,
                     request,
                     chan,
                 )
@@ -326,6 +459,7 @@ pub async fn run_protocol_inner<W: WebSocketFunctionality>(
                     socket,
                     &mut inflight_requests,
                     seq,
+                    &codec,
                     request,
                     chan,
                 )
@@ -337,6 +471,43 @@ pub async fn run_protocol_inner<W: WebSocketFunctionality>(
                     socket,
                     &mut inflight_requests,
                     seq,
+                    &codec,
+                    request,
+                    chan,
+                )
+                .await?;
+            }
+            Some((request, chan)) = reconcile.recv(), if defecit < MAX_MESSAGE_DEFECIT => {
+                handle_internal_request(
+                    &mut defecit,
+                    socket,
+                    &mut inflight_requests,
+                    seq,
+                    &codec,
+                    request,
+                    chan,
+                )
+                .await?;
+            }
+            Some((request, chan)) = fetch_blobs.recv(), if defecit < MAX_MESSAGE_DEFECIT => {
+                handle_internal_request(
+                    &mut defecit,
+                    socket,
+                    &mut inflight_requests,
+                    seq,
+                    &codec,
+                    request,
+                    chan,
+                )
+                .await?;
+            }
+            Some((request, chan)) = fetch_range.recv(), if defecit < MAX_MESSAGE_DEFECIT => {
+                handle_internal_request(
+                    &mut defecit,
+                    socket,
+                    &mut inflight_requests,
+                    seq,
+                    &codec,
                     request,
                     chan,
                 )
@@ -380,6 +551,7 @@ async fn handle_internal_request<W, IChan, IReq>(
     socket: &mut W,
     inflight_requests: &mut BTreeMap<u64, ResponseRouter>,
     seq: u64,
+    codec: &Codec,
     msg: IReq,
     response_chan: IChan,
 ) -> Result<(), AttestProtocolError>
@@ -399,7 +571,9 @@ where
         },
     );
     *defecit += 1;
-    socket.t_send(msg.into_protocol_and_log(seq)?).await?;
+    socket
+        .t_send(msg.into_protocol_and_log(seq, codec)?)
+        .await?;
 
     Ok(())
 }
@@ -411,23 +585,32 @@ async fn handle_message_from_peer<W: WebSocketFunctionality>(
     db: &mut MsgDB,
     inflight_requests: &mut BTreeMap<u64, ResponseRouter>,
     _role: Role,
+    peer: &ServiceUrl,
+    codec: &Codec,
     msg: Message,
 ) -> Result<(), AttestProtocolError> {
-    let a: AttestSocketProtocol = msg
-        .only_text("as a json encoded messages")
-        .and_then(|s| Ok(serde_json::from_str(&s)?))?;
+    let a = codec.decode(msg)?;
     match a {
         AttestSocketProtocol::Request(seq, m) => {
             trace!(request=?m, seq, "Processing Request...");
             match m {
                 AttestRequest::LatestTips(LatestTips {}) => {
-                    fetch_latest_tips(db, socket, seq).await
+                    fetch_latest_tips(db, socket, seq, peer, codec).await
                 }
                 AttestRequest::SpecificTips(SpecificTips { tips }) => {
-                    fetch_specific_tips(tips, db, socket, seq).await
+                    fetch_specific_tips(tips, db, socket, seq, peer, codec).await
                 }
                 AttestRequest::Post(Post { envelopes }) => {
-                    post_envelope(envelopes, db, socket, seq).await
+                    post_envelope(envelopes, db, socket, seq, codec).await
+                }
+                AttestRequest::Reconcile(Reconcile { ranges }) => {
+                    reconcile_ranges(ranges, db, socket, seq, peer, codec).await
+                }
+                AttestRequest::FetchBlobs(FetchBlobs { blobs }) => {
+                    fetch_blobs(blobs, db, socket, seq, peer, codec).await
+                }
+                AttestRequest::FetchRange(range) => {
+                    fetch_range(range, db, socket, seq, peer, codec).await
                 }
             }
         }
@@ -443,6 +626,9 @@ async fn handle_message_from_peer<W: WebSocketFunctionality>(
                     (AnySender::LatestTips(s), AttestResponse::LatestTips(m)) => s.send(m).ok(),
                     (AnySender::Post(s), AttestResponse::Post(m)) => s.send(m).ok(),
                     (AnySender::SpecificTips(s), AttestResponse::SpecificTips(m)) => s.send(m).ok(),
+                    (AnySender::Reconcile(s), AttestResponse::Reconcile(m)) => s.send(m).ok(),
+                    (AnySender::FetchBlobs(s), AttestResponse::FetchBlobs(m)) => s.send(m).ok(),
+                    (AnySender::FetchRange(s), AttestResponse::FetchRange(m)) => s.send(m).ok(),
                     _ => {
                         warn!("Message Mismatch");
                         return Err(AttestProtocolError::ResponseTypeIncorrect);
@@ -465,16 +651,22 @@ async fn post_envelope<W>(
     db: &mut MsgDB,
     socket: &mut W,
     seq: u64,
+    codec: &Codec,
 ) -> Result<(), AttestProtocolError>
 where
     W: WebSocketFunctionality,
 {
     info!(method = "POST", item = "/envelope/new");
-    let mut authed = Vec::with_capacity(envelopes.len());
-    for envelope in envelopes {
+    for envelope in &envelopes {
         info!(method="POST /msg",  envelope=?envelope.canonicalized_hash_ref(), "Envelope Received" );
         trace!(method="POST /msg",  envelope=?envelope, "Envelope Received" );
-        if let Ok(valid_envelope) = envelope.self_authenticate(&Secp256k1::new()) {
+    }
+    let results = spawn_blocking(move || authenticate_all(envelopes, &Secp256k1::new()))
+        .await
+        .expect("Verification Panic");
+    let mut authed = Vec::with_capacity(results.len());
+    for result in results {
+        if let Ok(valid_envelope) = result {
             authed.push(valid_envelope);
         } else {
             tracing::debug!("Invalid Message From Peer");
@@ -508,7 +700,7 @@ where
         }
     }
     if socket
-        .t_send(AttestResponse::Post(PostResponse(outcomes)).into_protocol_and_log(seq)?)
+        .t_send(AttestResponse::Post(PostResponse(outcomes)).into_protocol_and_log(seq, codec)?)
         .await
         .is_err()
     {
@@ -522,6 +714,8 @@ async fn fetch_specific_tips<W>(
     db: &mut MsgDB,
     socket: &mut W,
     seq: u64,
+    peer: &ServiceUrl,
+    codec: &Codec,
 ) -> Result<(), AttestProtocolError>
 where
     W: WebSocketFunctionality,
@@ -533,9 +727,17 @@ where
     trace!(method = "GET /tips", ?tips);
     let all_tips = {
         let handle = db.get_handle_read().await;
-        if let Ok(r) = spawn_blocking(move || handle.messages_by_hash(tips.tips.iter()))
-            .await
-            .expect("DB Panic")
+        let peer = peer.clone();
+        if let Ok(r) = spawn_blocking(move || {
+            let hidden = handle.get_chains_hidden_from(&peer.0, peer.1)?;
+            let mut r = handle.messages_by_hash::<_, Envelope, WrappedJson>(tips.tips.iter())?;
+            // act as if we don't have envelopes of chains the peer may not see
+            r.retain(|e| !hidden.contains(&e.get_genesis_hash()));
+            prune_expired(&mut r);
+            Ok::<_, rusqlite::Error>(r)
+        })
+        .await
+        .expect("DB Panic")
         {
             r
         } else {
@@ -545,7 +747,7 @@ where
     if socket
         .t_send(
             AttestResponse::SpecificTips(SpecificTipsResponse(all_tips))
-                .into_protocol_and_log(seq)?,
+                .into_protocol_and_log(seq, codec)?,
         )
         .await
         .is_err()
@@ -555,10 +757,21 @@ where
     Ok(())
 }
 
+/// Drops the messages of expired ephemeral envelopes we haven't pruned yet,
+/// peers have no use for them, see [`attest_messages::ephemeral`].
+fn prune_expired(envelopes: &mut [Envelope]) {
+    let now = attest_util::now();
+    for e in envelopes {
+        e.prune_if_expired(now);
+    }
+}
+
 async fn fetch_latest_tips<W>(
     db: &mut MsgDB,
     socket: &mut W,
     seq: u64,
+    peer: &ServiceUrl,
+    codec: &Codec,
 ) -> Result<(), AttestProtocolError>
 where
     W: WebSocketFunctionality,
@@ -566,12 +779,20 @@ where
     info!(method = "GET", item = "/latest_tips");
     let r = {
         let handle = db.get_handle_read().await;
-        spawn_blocking(move || handle.get_tips_for_all_users())
-            .await
-            .expect("DB Error")
+        let peer = peer.clone();
+        spawn_blocking(move || {
+            let hidden = handle.get_chains_hidden_from(&peer.0, peer.1)?;
+            let mut v = handle.get_tips_for_all_users::<Envelope, WrappedJson>()?;
+            v.retain(|e| !hidden.contains(&e.get_genesis_hash()));
+            prune_expired(&mut v);
+            Ok::<_, rusqlite::Error>(v)
+        })
+        .await
+        .expect("DB Error")
     };
     if let Ok(v) = r {
-        let msg = AttestResponse::LatestTips(LatestTipsResponse(v)).into_protocol_and_log(seq)?;
+        let msg =
+            AttestResponse::LatestTips(LatestTipsResponse(v)).into_protocol_and_log(seq, codec)?;
         if socket.t_send(msg).await.is_err() {
             trace!(seq, "peer rejected message");
             Err(AttestProtocolError::SocketClosed)
@@ -588,3 +809,243 @@ where
         Err(AttestProtocolError::DatabaseError)
     }
 }
+
+async fn reconcile_ranges<W>(
+    ranges: Vec<Range>,
+    db: &mut MsgDB,
+    socket: &mut W,
+    seq: u64,
+    peer: &ServiceUrl,
+    codec: &Codec,
+) -> Result<(), AttestProtocolError>
+where
+    W: WebSocketFunctionality,
+{
+    info!(method = "GET", item = "/reconcile", n = ranges.len());
+    if ranges.len() > reconcile::MAX_RANGES {
+        return Err(AttestProtocolError::TooManyRanges);
+    }
+    let keys = {
+        let handle = db.get_handle_read().await;
+        let peer = peer.clone();
+        spawn_blocking(move || sync_keys_visible_to(&handle, &peer))
+            .await
+            .expect("DB Panic")
+            .map_err(|_| AttestProtocolError::DatabaseError)?
+    };
+    let resp = reconcile::reconcile(&keys, &ranges, reconcile::MAX_RANGES);
+    if socket
+        .t_send(
+            AttestResponse::Reconcile(ReconcileResponse(resp.reply))
+                .into_protocol_and_log(seq, codec)?,
+        )
+        .await
+        .is_err()
+    {
+        return Err(AttestProtocolError::SocketClosed);
+    }
+    Ok(())
+}
+
+async fn fetch_blobs<W>(
+    blobs: Vec<BlobRef>,
+    db: &mut MsgDB,
+    socket: &mut W,
+    seq: u64,
+    peer: &ServiceUrl,
+    codec: &Codec,
+) -> Result<(), AttestProtocolError>
+where
+    W: WebSocketFunctionality,
+{
+    info!(method = "GET", item = "/blobs", n = blobs.len());
+    if blobs.len() > MAX_BLOBS_PER_REQUEST {
+        return Err(AttestProtocolError::TooManyBlobs);
+    }
+    // hex doubles the size, so stop well short of what a peer configured like
+    // us would accept and let it ask again for the rest
+    let budget = codec.max_message_size() / 2;
+    let found = {
+        let handle = db.get_handle_read().await;
+        let peer = peer.clone();
+        spawn_blocking(move || select_blobs(&handle, blobs, &peer, budget))
+            .await
+            .expect("DB Panic")
+            .map_err(|_| AttestProtocolError::DatabaseError)?
+    };
+    if socket
+        .t_send(
+            AttestResponse::FetchBlobs(FetchBlobsResponse(found))
+                .into_protocol_and_log(seq, codec)?,
+        )
+        .await
+        .is_err()
+    {
+        return Err(AttestProtocolError::SocketClosed);
+    }
+    Ok(())
+}
+
+/// The blobs visible to `peer` that fit in `budget` bytes, in the order asked
+/// for. Sizes are the ones stored, the sizes the peer claims aren't trusted.
+fn select_blobs<T: handle_type::Get>(
+    handle: &MsgDBHandle<T>,
+    blobs: Vec<BlobRef>,
+    peer: &ServiceUrl,
+    budget: u64,
+) -> Result<Vec<Blob>, rusqlite::Error> {
+    let mut found = vec![];
+    let mut size = 0;
+    for blob in blobs {
+        match handle.get_blob_size(blob.hash)? {
+            Some(stored) if size + stored <= budget => {}
+            _ => continue,
+        }
+        if let Some(data) = handle.get_blob_visible_to(blob.hash, &peer.0, peer.1)? {
+            size += data.len() as u64;
+            found.push(Blob {
+                hash: blob.hash,
+                data,
+            });
+        }
+    }
+    Ok(found)
+}
+
+async fn fetch_range<W>(
+    range: FetchRange,
+    db: &mut MsgDB,
+    socket: &mut W,
+    seq: u64,
+    peer: &ServiceUrl,
+    codec: &Codec,
+) -> Result<(), AttestProtocolError>
+where
+    W: WebSocketFunctionality,
+{
+    info!(
+        method = "GET",
+        item = "/range",
+        genesis = ?range.genesis,
+        first_height = range.first_height,
+        last_height = range.last_height
+    );
+    let envelopes = {
+        let handle = db.get_handle_read().await;
+        let peer = peer.clone();
+        spawn_blocking(move || {
+            let hidden = handle.get_chains_hidden_from(&peer.0, peer.1)?;
+            // act as if we don't have envelopes of chains the peer may not see
+            if hidden.contains(&range.genesis) {
+                return Ok(vec![]);
+            }
+            let mut r = handle.get_envelopes_in_range::<Envelope, WrappedJson>(
+                range.genesis,
+                range.first_height,
+                range.last_height,
+                MAX_ENVELOPES_PER_RANGE,
+            )?;
+            prune_expired(&mut r);
+            Ok::<_, rusqlite::Error>(r)
+        })
+        .await
+        .expect("DB Panic")
+        .map_err(|_| AttestProtocolError::DatabaseError)?
+    };
+    if socket
+        .t_send(
+            AttestResponse::FetchRange(FetchRangeResponse(envelopes))
+                .into_protocol_and_log(seq, codec)?,
+        )
+        .await
+        .is_err()
+    {
+        return Err(AttestProtocolError::SocketClosed);
+    }
+    Ok(())
+}
+
+/// The sorted sync keys of every envelope the peer is allowed to see
+pub(crate) fn sync_keys_visible_to<T: handle_type::Get>(
+    handle: &MsgDBHandle<T>,
+    peer: &ServiceUrl,
+) -> Result<Vec<SyncKey>, rusqlite::Error> {
+    let hidden = handle.get_chains_hidden_from(&peer.0, peer.1)?;
+    let mut keys: Vec<SyncKey> = handle
+        .get_all_sync_keys()?
+        .into_iter()
+        .map(SyncKey::from)
+        .filter(|k| !hidden.contains(&k.genesis))
+        .collect();
+    // sqlite should already return these in order
+    keys.sort_unstable();
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use attest_database::{generate_new_user, setup_test_db};
+    use ruma_serde::CanonicalJsonValue;
+    use sapio_bitcoin::secp256k1::rand::{thread_rng, RngCore};
+
+    #[tokio::test]
+    async fn test_blob_budget_uses_stored_size() {
+        let secp = Secp256k1::new();
+        let db = setup_test_db().await;
+        let mut handle = db.get_handle_all().await;
+        let (kp, nonce, genesis) =
+            generate_new_user::<_, WrappedJson, _>(&secp, CanonicalJsonValue::Null).unwrap();
+        handle.save_keypair(kp).unwrap();
+        handle
+            .insert_user_by_genesis_envelope(
+                "host".into(),
+                genesis.self_authenticate(&secp).unwrap(),
+            )
+            .unwrap()
+            .unwrap();
+        handle
+            .save_nonce_for_user_by_key(nonce, &secp, kp.x_only_public_key().0)
+            .unwrap();
+        let refs: Vec<BlobRef> = (0..3)
+            .map(|_| {
+                let mut data = vec![0u8; 1000];
+                thread_rng().fill_bytes(&mut data);
+                handle.save_blob(&data).unwrap().unwrap()
+            })
+            .collect();
+        let e = handle
+            .wrap_attachments_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                CanonicalJsonValue::Null,
+                &kp,
+                &secp,
+                None,
+                refs.clone(),
+            )
+            .unwrap()
+            .unwrap()
+            .self_authenticate(&secp)
+            .unwrap();
+        handle
+            .try_insert_authenticated_envelope(e, false)
+            .unwrap()
+            .unwrap();
+        let peer = ServiceUrl(Arc::new("peer".into()), 1);
+
+        let found = select_blobs(&handle, refs.clone(), &peer, 2500).unwrap();
+        assert_eq!(found.len(), 2);
+        // claiming the blobs are tiny doesn't get more of them sent
+        let understated = refs
+            .iter()
+            .map(|r| BlobRef {
+                hash: r.hash,
+                size: 1,
+            })
+            .collect();
+        let found = select_blobs(&handle, understated, &peer, 2500).unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().map(|b| b.data.len()).sum::<usize>() <= 2500);
+        // nor does asking for one larger than the whole budget
+        assert!(select_blobs(&handle, refs, &peer, 999).unwrap().is_empty());
+    }
+}
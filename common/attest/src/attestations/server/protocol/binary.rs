@@ -0,0 +1,83 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Binary frames for the messages that carry envelopes.
+//!
+//! Only `Post` requests and `LatestTips` / `SpecificTips` responses are worth
+//! encoding, everything else stays json. A frame is:
+//!
+//! ```text
+//! kind: u8 (0 = Request, 1 = Response)
+//! seq: varint
+//! variant: u8 (0 = Post, 1 = LatestTips, 2 = SpecificTips)
+//! envelopes: see attest_messages::binary::encode_envelopes
+//! ```
+
+use super::{
+    AttestRequest, AttestResponse, AttestSocketProtocol, LatestTipsResponse, Post,
+    SpecificTipsResponse,
+};
+use attest_messages::binary::{
+    decode_envelopes, encode_envelopes, get_varint, put_varint, BinaryError,
+};
+
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+
+const POST: u8 = 0;
+const LATEST_TIPS: u8 = 1;
+const SPECIFIC_TIPS: u8 = 2;
+
+/// Returns `None` for messages that have no binary form.
+pub(crate) fn encode(msg: &AttestSocketProtocol) -> Result<Option<Vec<u8>>, BinaryError> {
+    let (kind, seq, variant, envelopes) = match msg {
+        AttestSocketProtocol::Request(seq, AttestRequest::Post(Post { envelopes })) => {
+            (REQUEST, seq, POST, envelopes)
+        }
+        AttestSocketProtocol::Response(
+            seq,
+            AttestResponse::LatestTips(LatestTipsResponse(envelopes)),
+        ) => (RESPONSE, seq, LATEST_TIPS, envelopes),
+        AttestSocketProtocol::Response(
+            seq,
+            AttestResponse::SpecificTips(SpecificTipsResponse(envelopes)),
+        ) => (RESPONSE, seq, SPECIFIC_TIPS, envelopes),
+        _ => return Ok(None),
+    };
+    let mut out = Vec::with_capacity(64 + 512 * envelopes.len());
+    out.push(kind);
+    put_varint(&mut out, *seq);
+    out.push(variant);
+    encode_envelopes(envelopes, &mut out)?;
+    Ok(Some(out))
+}
+
+pub(crate) fn decode(mut input: &[u8]) -> Result<AttestSocketProtocol, BinaryError> {
+    let (kind, rest) = input.split_first().ok_or(BinaryError::UnexpectedEnd)?;
+    input = rest;
+    let seq = get_varint(&mut input)?;
+    let (variant, rest) = input.split_first().ok_or(BinaryError::UnexpectedEnd)?;
+    input = rest;
+    let envelopes = decode_envelopes(&mut input)?;
+    if !input.is_empty() {
+        return Err(BinaryError::TrailingBytes);
+    }
+    Ok(match (*kind, *variant) {
+        (REQUEST, POST) => {
+            AttestSocketProtocol::Request(seq, AttestRequest::Post(Post { envelopes }))
+        }
+        (RESPONSE, LATEST_TIPS) => AttestSocketProtocol::Response(
+            seq,
+            AttestResponse::LatestTips(LatestTipsResponse(envelopes)),
+        ),
+        (RESPONSE, SPECIFIC_TIPS) => AttestSocketProtocol::Response(
+            seq,
+            AttestResponse::SpecificTips(SpecificTipsResponse(envelopes)),
+        ),
+        (REQUEST | RESPONSE, v) => return Err(BinaryError::InvalidTag(v)),
+        (k, _) => return Err(BinaryError::InvalidTag(k)),
+    })
+}
@@ -0,0 +1,723 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::AttestProtocolError;
+use super::AttestSocketProtocol;
+use crate::configuration::ProtocolConfig;
+use attest_messages::{Envelope, FormatVersion};
+use axum::extract::ws::Message;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use tracing::debug;
+
+/// Messages smaller than this are always sent as plain text, compressing them
+/// costs more than it saves.
+pub const MIN_COMPRESS_SIZE: usize = 512;
+
+/// Leading byte flags of a Binary frame once [`Encoding::Binary`] is in use.
+const FLAG_COMPRESSED: u8 = 1;
+const FLAG_BINARY_ENVELOPES: u8 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Deflate,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// envelopes are sent using [`attest_messages::binary`], see
+    /// [`super::binary`]
+    Binary,
+}
+
+/// Header of the websocket upgrade carrying [`ProtocolFeatures`], offered by
+/// the client in its request and answered by the server in its response.
+///
+/// Peers which predate negotiation neither send nor answer it, and are
+/// spoken to in uncompressed json with legacy envelopes. Negotiating during
+/// the upgrade means they never see a frame their protocol loop doesn't
+/// expect, and no one waits for features that aren't coming.
+pub const FEATURES_HEADER: &str = "x-attest-features";
+
+/// What a peer supports, exchanged in [`FEATURES_HEADER`] to agree on an
+/// encoding.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProtocolFeatures {
+    pub compression: Vec<Compression>,
+    /// absent for peers which predate binary encodings
+    #[serde(default)]
+    pub encodings: Vec<Encoding>,
+    /// the newest [`FormatVersion`] the peer can check, absent (so legacy)
+    /// for peers which predate format versions
+    #[serde(default)]
+    pub envelope_version: FormatVersion,
+}
+
+impl ProtocolFeatures {
+    /// everything `config` allows us to offer
+    pub fn offered(config: &ProtocolConfig) -> Self {
+        ProtocolFeatures {
+            compression: if config.compression {
+                vec![Compression::Deflate]
+            } else {
+                vec![]
+            },
+            encodings: if config.binary_envelopes {
+                vec![Encoding::Binary]
+            } else {
+                vec![]
+            },
+            envelope_version: FormatVersion::CURRENT,
+        }
+    }
+
+    /// the value of [`FEATURES_HEADER`]
+    pub fn to_header(&self) -> String {
+        serde_json::to_string(self).expect("ProtocolFeatures always serialize")
+    }
+
+    /// reads a peer's [`FEATURES_HEADER`], `None` if it is malformed
+    pub fn from_header(value: &[u8]) -> Option<Self> {
+        serde_json::from_slice(value).ok()
+    }
+}
+
+/// Encodes and decodes [`AttestSocketProtocol`] messages for a single
+/// connection.
+///
+/// Plain json is always sent as a Text frame. Without an [`Encoding`] a Binary
+/// frame is compressed json; with [`Encoding::Binary`] a Binary frame starts
+/// with a flags byte saying whether the rest is compressed and whether it is
+/// json or binary envelopes. Either may be received regardless of what is
+/// chosen for sending. The size limit always applies to the decoded payload.
+///
+/// Envelopes newer than the peer's `envelope_version` are not sent at all,
+/// the peer could only reject them.
+#[derive(Debug, Clone, Copy)]
+pub struct Codec {
+    compression: Option<Compression>,
+    encoding: Option<Encoding>,
+    max_message_size: u64,
+    envelope_version: FormatVersion,
+}
+
+impl Codec {
+    pub fn new(
+        compression: Option<Compression>,
+        encoding: Option<Encoding>,
+        max_message_size: u64,
+    ) -> Self {
+        Self {
+            compression,
+            encoding,
+            max_message_size,
+            envelope_version: FormatVersion::CURRENT,
+        }
+    }
+
+    pub fn with_envelope_version(mut self, envelope_version: FormatVersion) -> Self {
+        self.envelope_version = envelope_version;
+        self
+    }
+
+    pub fn max_message_size(&self) -> u64 {
+        self.max_message_size
+    }
+    pub fn envelope_version(&self) -> FormatVersion {
+        self.envelope_version
+    }
+
+    /// Whether the peer can check `envelope`.
+    pub fn supports(&self, envelope: &Envelope) -> bool {
+        envelope.header().version() <= self.envelope_version
+    }
+
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.encoding
+    }
+
+    pub fn encode(&self, msg: &AttestSocketProtocol) -> Result<Message, AttestProtocolError> {
+        let binary = match self.encoding {
+            Some(Encoding::Binary) => super::binary::encode(msg)?,
+            None => None,
+        };
+        let (payload, flags) = match binary {
+            Some(b) => (b, FLAG_BINARY_ENVELOPES),
+            None => {
+                let s = serde_json::to_string(msg)?;
+                if self.compression.is_none() || s.len() < MIN_COMPRESS_SIZE {
+                    return Ok(Message::Text(s));
+                }
+                (s.into_bytes(), 0)
+            }
+        };
+        let (payload, flags) = match self.compression {
+            Some(Compression::Deflate) if payload.len() >= MIN_COMPRESS_SIZE => {
+                (self.deflate(&payload)?, flags | FLAG_COMPRESSED)
+            }
+            _ => (payload, flags),
+        };
+        match self.encoding {
+            Some(Encoding::Binary) => {
+                let mut frame = Vec::with_capacity(payload.len() + 1);
+                frame.push(flags);
+                frame.extend_from_slice(&payload);
+                Ok(Message::Binary(frame))
+            }
+            // only compressed json gets here
+            None => Ok(Message::Binary(payload)),
+        }
+    }
+
+    pub fn decode(&self, msg: Message) -> Result<AttestSocketProtocol, AttestProtocolError> {
+        match (self.encoding, msg) {
+            (_, Message::Text(s)) => {
+                if s.len() as u64 > self.max_message_size {
+                    return Err(AttestProtocolError::MessageTooLarge);
+                }
+                Ok(serde_json::from_str(&s)?)
+            }
+            (None, Message::Binary(b)) => {
+                if self.compression.is_none() {
+                    return Err(AttestProtocolError::IncorrectMessageOwned(
+                        "Binary frame without compression or encoding".into(),
+                    ));
+                }
+                Ok(serde_json::from_slice(&self.inflate(&b)?)?)
+            }
+            (Some(Encoding::Binary), Message::Binary(b)) => {
+                let (flags, rest) = b.split_first().ok_or_else(|| {
+                    AttestProtocolError::IncorrectMessageOwned("Empty binary frame".into())
+                })?;
+                if flags & !(FLAG_COMPRESSED | FLAG_BINARY_ENVELOPES) != 0 {
+                    return Err(AttestProtocolError::IncorrectMessageOwned(format!(
+                        "Unknown frame flags {}",
+                        flags
+                    )));
+                }
+                let inflated;
+                let payload = if flags & FLAG_COMPRESSED != 0 {
+                    if self.compression.is_none() {
+                        return Err(AttestProtocolError::CompressionError(
+                            "compression was not negotiated".into(),
+                        ));
+                    }
+                    inflated = self.inflate(rest)?;
+                    &inflated[..]
+                } else {
+                    if rest.len() as u64 > self.max_message_size {
+                        return Err(AttestProtocolError::MessageTooLarge);
+                    }
+                    rest
+                };
+                if flags & FLAG_BINARY_ENVELOPES != 0 {
+                    Ok(super::binary::decode(payload)?)
+                } else {
+                    Ok(serde_json::from_slice(payload)?)
+                }
+            }
+            (_, msg) => Err(AttestProtocolError::IncorrectMessageOwned(format!(
+                "Expected a Text or Binary frame, got {:?}",
+                msg
+            ))),
+        }
+    }
+
+    fn deflate(&self, b: &[u8]) -> Result<Vec<u8>, AttestProtocolError> {
+        let mut encoder = DeflateEncoder::new(
+            Vec::with_capacity(b.len() / 4),
+            flate2::Compression::default(),
+        );
+        encoder
+            .write_all(b)
+            .map_err(|e| AttestProtocolError::CompressionError(e.to_string()))?;
+        encoder
+            .finish()
+            .map_err(|e| AttestProtocolError::CompressionError(e.to_string()))
+    }
+
+    fn inflate(&self, b: &[u8]) -> Result<Vec<u8>, AttestProtocolError> {
+        let mut out = vec![];
+        // read at most one byte past the limit so that a compression
+        // bomb can't make us allocate unbounded memory.
+        let n = DeflateDecoder::new(b)
+            .take(self.max_message_size + 1)
+            .read_to_end(&mut out)
+            .map_err(|e| AttestProtocolError::CompressionError(e.to_string()))?;
+        if n as u64 > self.max_message_size {
+            return Err(AttestProtocolError::MessageTooLarge);
+        }
+        Ok(out)
+    }
+}
+
+/// Picks the [`Codec`] for a connection we accepted, given the features the
+/// client offered in its upgrade request, if any.
+///
+/// Also returns the features to answer with in the upgrade response, narrowed
+/// to what was agreed so that the client picks the same, or `None` for a
+/// client which didn't offer any.
+pub fn negotiate_server(
+    config: &ProtocolConfig,
+    theirs: Option<&ProtocolFeatures>,
+) -> (Codec, Option<ProtocolFeatures>) {
+    let agreed = theirs.map(|theirs| agree(&ProtocolFeatures::offered(config), theirs));
+    (codec_for(agreed.as_ref(), config.max_message_size), agreed)
+}
+
+/// Picks the [`Codec`] for a connection we opened, given the features the
+/// server answered our offer with, if any.
+pub fn negotiate_client(config: &ProtocolConfig, theirs: Option<&ProtocolFeatures>) -> Codec {
+    let agreed = theirs.map(|theirs| agree(&ProtocolFeatures::offered(config), theirs));
+    codec_for(agreed.as_ref(), config.max_message_size)
+}
+
+/// The first compression scheme and encoding of ours the peer supports too,
+/// and the older of our envelope versions.
+fn agree(mine: &ProtocolFeatures, theirs: &ProtocolFeatures) -> ProtocolFeatures {
+    ProtocolFeatures {
+        compression: mine
+            .compression
+            .iter()
+            .find(|c| theirs.compression.contains(c))
+            .into_iter()
+            .copied()
+            .collect(),
+        encodings: mine
+            .encodings
+            .iter()
+            .find(|e| theirs.encodings.contains(e))
+            .into_iter()
+            .copied()
+            .collect(),
+        envelope_version: mine.envelope_version.min(theirs.envelope_version),
+    }
+}
+
+fn codec_for(agreed: Option<&ProtocolFeatures>, max_message_size: u64) -> Codec {
+    let agreed = match agreed {
+        Some(agreed) => agreed,
+        None => {
+            debug!(
+                protocol = "features",
+                "Peer did not Negotiate, Falling Back to Legacy Encoding"
+            );
+            return Codec::new(None, None, max_message_size)
+                .with_envelope_version(FormatVersion::LEGACY);
+        }
+    };
+    let compression = agreed.compression.first().copied();
+    let encoding = agreed.encodings.first().copied();
+    let envelope_version = agreed.envelope_version;
+    debug!(
+        protocol = "features",
+        ?compression,
+        ?encoding,
+        ?envelope_version,
+        "Negotiated Encoding"
+    );
+    Codec::new(compression, encoding, max_message_size).with_envelope_version(envelope_version)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{
+        AttestRequest, AttestResponse, Blob, FetchBlobsResponse, LatestTips, LatestTipsResponse,
+        Post,
+    };
+    use super::*;
+    use crate::attestations::server::tungstenite_client_adaptor::{
+        answered_features, into_tungstenite, offer_features,
+    };
+    use attest_database::{db_handle::create::TipControl, generate_new_user, setup_test_db};
+    use attest_messages::{Envelope, WrappedJson};
+    use axum::extract::ws::WebSocket;
+    use axum::extract::WebSocketUpgrade;
+    use axum::routing::get;
+    use axum::Router;
+    use futures::{SinkExt, StreamExt};
+    use ruma_serde::CanonicalJsonValue;
+    use sapio_bitcoin::secp256k1::Secp256k1;
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite as ts;
+
+    const MAX: u64 = 16 * 1024 * 1024;
+
+    /// builds a few chains of heartbeat and trade moves like a running game
+    /// would.
+    async fn game_chains(players: usize, moves: usize) -> Vec<Vec<Envelope>> {
+        let secp = Secp256k1::new();
+        let db = setup_test_db().await;
+        let mut handle = db.get_handle_all().await;
+        let mut chains = vec![];
+        for p in 0..players {
+            let (kp, nonce, genesis) = generate_new_user::<_, WrappedJson, _>(
+                &secp,
+                CanonicalJsonValue::try_from(json!({"Ready": {"player": p}})).unwrap(),
+            )
+            .unwrap();
+            handle.save_keypair(kp).unwrap();
+            handle
+                .insert_user_by_genesis_envelope(
+                    format!("player-{}", p),
+                    genesis.self_authenticate(&secp).unwrap(),
+                )
+                .unwrap()
+                .unwrap();
+            handle
+                .save_nonce_for_user_by_key(nonce, &secp, kp.x_only_public_key().0)
+                .unwrap();
+            let mut chain = vec![genesis];
+            for i in 0..moves {
+                let m = if i % 4 == 0 {
+                    json!({"sequence": i, "from": p, "d": {"Trade": {"pair": ["Bitcoin", "ASIC"], "amount": 100 * i, "sell": i % 3 == 0}}})
+                } else {
+                    json!({"sequence": i, "from": p, "d": {"Heartbeat": null}})
+                };
+                let e = handle
+                    .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                        CanonicalJsonValue::try_from(m).unwrap(),
+                        &kp,
+                        &secp,
+                        None,
+                        None,
+                        TipControl::AllTips,
+                    )
+                    .unwrap()
+                    .unwrap()
+                    .self_authenticate(&secp)
+                    .unwrap();
+                handle
+                    .try_insert_authenticated_envelope(e.clone(), false)
+                    .unwrap()
+                    .unwrap();
+                chain.push(e.inner());
+            }
+            chains.push(chain);
+        }
+        chains
+    }
+
+    fn encoded_size(m: &Message) -> usize {
+        match m {
+            Message::Text(s) => s.len(),
+            Message::Binary(b) => b.len(),
+            _ => panic!("Unexpected Message Type"),
+        }
+    }
+
+    fn game_messages(chains: &[Vec<Envelope>]) -> Vec<AttestSocketProtocol> {
+        vec![
+            AttestSocketProtocol::Request(
+                1,
+                AttestRequest::Post(Post {
+                    envelopes: chains.iter().flatten().cloned().collect(),
+                }),
+            ),
+            AttestSocketProtocol::Response(
+                2,
+                AttestResponse::LatestTips(LatestTipsResponse(
+                    chains.iter().map(|c| c.last().unwrap().clone()).collect(),
+                )),
+            ),
+        ]
+    }
+
+    /// messages don't implement Eq, compare their json instead
+    fn same(a: &AttestSocketProtocol, b: &AttestSocketProtocol) -> bool {
+        serde_json::to_string(a).unwrap() == serde_json::to_string(b).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_savings() {
+        let chains = game_chains(5, 50).await;
+        let plain = Codec::new(None, None, MAX);
+        let deflate = Codec::new(Some(Compression::Deflate), None, MAX);
+        let binary = Codec::new(None, Some(Encoding::Binary), MAX);
+        let both = Codec::new(Some(Compression::Deflate), Some(Encoding::Binary), MAX);
+
+        for msg in game_messages(&chains) {
+            let json_size = serde_json::to_string(&msg).unwrap().len();
+            let uncompressed = plain.encode(&msg).unwrap();
+            assert!(matches!(uncompressed, Message::Text(_)));
+            assert_eq!(encoded_size(&uncompressed), json_size);
+            let sizes: Vec<usize> = [deflate, binary, both]
+                .iter()
+                .map(|codec| {
+                    let m = codec.encode(&msg).unwrap();
+                    assert!(matches!(m, Message::Binary(_)));
+                    let size = encoded_size(&m);
+                    assert!(same(&codec.decode(m).unwrap(), &msg));
+                    size
+                })
+                .collect();
+            // canonical json envelopes are mostly hex keys and field names,
+            // expect at least a 2x saving from either
+            assert!(sizes[0] * 2 < json_size);
+            assert!(sizes[1] * 2 < json_size);
+            assert!(sizes[2] * 2 < json_size);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_binary_preserves_hashes() {
+        let chains = game_chains(2, 10).await;
+        let codec = Codec::new(Some(Compression::Deflate), Some(Encoding::Binary), MAX);
+        let post = &game_messages(&chains)[0];
+        match codec.decode(codec.encode(post).unwrap()).unwrap() {
+            AttestSocketProtocol::Request(1, AttestRequest::Post(Post { envelopes })) => {
+                let sent: Vec<_> = chains.iter().flatten().collect();
+                assert_eq!(envelopes.len(), sent.len());
+                for (got, sent) in envelopes.iter().zip(sent) {
+                    assert_eq!(got.canonicalized_hash_ref(), sent.canonicalized_hash_ref());
+                }
+            }
+            _ => panic!("Wrong message decoded"),
+        }
+    }
+
+    #[test]
+    fn test_json_fallback_without_envelopes() {
+        let codec = Codec::new(None, Some(Encoding::Binary), MAX);
+        let msg = AttestSocketProtocol::Request(3, AttestRequest::LatestTips(LatestTips {}));
+        match codec.encode(&msg).unwrap() {
+            Message::Text(t) => assert_eq!(t, serde_json::to_string(&msg).unwrap()),
+            _ => panic!("Message without envelopes should be sent as json"),
+        }
+        // a peer that didn't negotiate binary can't be sent binary envelopes
+        let legacy = Codec::new(Some(Compression::Deflate), None, MAX);
+        let frame = codec
+            .encode(&AttestSocketProtocol::Response(
+                4,
+                AttestResponse::LatestTips(LatestTipsResponse(vec![])),
+            ))
+            .unwrap();
+        assert!(legacy.decode(frame).is_err());
+    }
+
+    #[test]
+    fn test_blobs_roundtrip() {
+        let data: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        let blob = attest_messages::blobs::BlobRef::of(&data).unwrap();
+        let codec = Codec::new(Some(Compression::Deflate), Some(Encoding::Binary), MAX);
+        let frame = codec
+            .encode(&AttestSocketProtocol::Response(
+                5,
+                AttestResponse::FetchBlobs(FetchBlobsResponse(vec![Blob {
+                    hash: blob.hash,
+                    data,
+                }])),
+            ))
+            .unwrap();
+        match codec.decode(frame).unwrap() {
+            AttestSocketProtocol::Response(
+                5,
+                AttestResponse::FetchBlobs(FetchBlobsResponse(b)),
+            ) => {
+                assert_eq!(b.len(), 1);
+                assert_eq!(b[0].hash, blob.hash);
+                blob.check(&b[0].data).unwrap();
+            }
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn test_small_messages_not_compressed() {
+        let codec = Codec::new(Some(Compression::Deflate), None, MAX);
+        let msg = AttestSocketProtocol::Request(0, AttestRequest::LatestTips(LatestTips {}));
+        match codec.encode(&msg).unwrap() {
+            Message::Text(t) => assert_eq!(t, serde_json::to_string(&msg).unwrap()),
+            _ => panic!("Small message should be sent as text"),
+        }
+    }
+
+    #[test]
+    fn test_compression_bomb_rejected() {
+        let codec = Codec::new(Some(Compression::Deflate), None, 1024 * 1024);
+        // 64 MiB of spaces compresses to a few tens of KiB
+        let bomb = codec
+            .deflate(" ".repeat(64 * 1024 * 1024).as_bytes())
+            .unwrap();
+        assert!(bomb.len() < 1024 * 1024);
+        assert!(matches!(
+            codec.decode(Message::Binary(bomb.clone())),
+            Err(AttestProtocolError::MessageTooLarge)
+        ));
+        // and inside a flagged frame
+        let mut frame = vec![FLAG_COMPRESSED | FLAG_BINARY_ENVELOPES];
+        frame.extend_from_slice(&bomb);
+        let binary = Codec::new(
+            Some(Compression::Deflate),
+            Some(Encoding::Binary),
+            1024 * 1024,
+        );
+        assert!(matches!(
+            binary.decode(Message::Binary(frame)),
+            Err(AttestProtocolError::MessageTooLarge)
+        ));
+        // limits apply to uncompressed messages too
+        assert!(matches!(
+            codec.decode(Message::Text(" ".repeat(1024 * 1024 + 1))),
+            Err(AttestProtocolError::MessageTooLarge)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_envelope_version_downgrade() {
+        // peers from before format versions don't say which they support
+        let legacy: ProtocolFeatures =
+            serde_json::from_str(r#"{"compression": [], "encodings": []}"#).unwrap();
+        assert_eq!(legacy.envelope_version, FormatVersion::LEGACY);
+
+        let chains = game_chains(2, 3).await;
+        let codec = Codec::new(None, None, MAX).with_envelope_version(FormatVersion::LEGACY);
+        assert!(chains.iter().flatten().all(|e| !codec.supports(e)));
+        let tips = AttestResponse::LatestTips(LatestTipsResponse(
+            chains.iter().map(|c| c.last().unwrap().clone()).collect(),
+        ));
+        match codec.decode(tips.into_protocol_and_log(1, &codec).unwrap()) {
+            Ok(AttestSocketProtocol::Response(
+                1,
+                AttestResponse::LatestTips(LatestTipsResponse(v)),
+            )) => assert!(v.is_empty()),
+            _ => panic!("Wrong message decoded"),
+        }
+        // a current peer gets everything
+        let current = Codec::new(None, None, MAX);
+        let post = AttestRequest::Post(Post {
+            envelopes: chains.iter().flatten().cloned().collect(),
+        });
+        match current.decode(post.into_protocol_and_log(2, &current).unwrap()) {
+            Ok(AttestSocketProtocol::Request(2, AttestRequest::Post(Post { envelopes }))) => {
+                assert_eq!(envelopes.len(), chains.iter().flatten().count())
+            }
+            _ => panic!("Wrong message decoded"),
+        }
+    }
+
+    fn config(binary_envelopes: bool) -> ProtocolConfig {
+        ProtocolConfig {
+            compression: true,
+            binary_envelopes,
+            max_message_size: MAX,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_negotiation() {
+        // a peer that negotiates gets what we both support, and picks the
+        // same from our answer
+        let offer = ProtocolFeatures::offered(&config(false));
+        let offer = ProtocolFeatures::from_header(offer.to_header().as_bytes()).unwrap();
+        let (codec, answer) = negotiate_server(&config(true), Some(&offer));
+        assert_eq!(codec.compression(), Some(Compression::Deflate));
+        assert_eq!(codec.encoding(), None);
+        assert_eq!(codec.envelope_version(), FormatVersion::CURRENT);
+        let client = negotiate_client(&config(false), answer.as_ref());
+        assert_eq!(client.compression(), codec.compression());
+        assert_eq!(client.encoding(), codec.encoding());
+        assert_eq!(client.envelope_version(), codec.envelope_version());
+
+        // a peer from before negotiation neither offers nor gets an answer
+        let (codec, answer) = negotiate_server(&config(true), None);
+        assert!(answer.is_none());
+        assert_eq!(codec.compression(), None);
+        assert_eq!(codec.encoding(), None);
+        assert_eq!(codec.envelope_version(), FormatVersion::LEGACY);
+        let codec = negotiate_client(&config(true), None);
+        assert_eq!(codec.compression(), None);
+        assert_eq!(codec.envelope_version(), FormatVersion::LEGACY);
+        assert!(ProtocolFeatures::from_header(b"not json").is_none());
+    }
+
+    /// The frame loop of a peer from before negotiation: every frame has to
+    /// be a json [`AttestSocketProtocol`] message, anything else drops the
+    /// connection.
+    async fn baseline_peer(mut socket: WebSocket) {
+        while let Some(Ok(msg)) = socket.recv().await {
+            let msg = match msg {
+                Message::Text(s) => serde_json::from_str::<AttestSocketProtocol>(&s),
+                _ => break,
+            };
+            let id = match msg {
+                Ok(AttestSocketProtocol::Request(id, AttestRequest::LatestTips(_))) => id,
+                _ => break,
+            };
+            let response = AttestSocketProtocol::Response(
+                id,
+                AttestResponse::LatestTips(LatestTipsResponse(vec![])),
+            );
+            let response = Message::Text(serde_json::to_string(&response).unwrap());
+            if socket.send(response).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_baseline_peer() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/socket",
+            get(|ws: WebSocketUpgrade| async move { ws.on_upgrade(baseline_peer) }),
+        );
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let config = config(true);
+        let request = offer_features(format!("ws://{}/socket", addr), &config).unwrap();
+        let (mut socket, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        let theirs = answered_features(&response);
+        assert!(theirs.is_none());
+        let codec = negotiate_client(&config, theirs.as_ref());
+        assert_eq!(codec.compression(), None);
+        assert_eq!(codec.encoding(), None);
+        assert_eq!(codec.envelope_version(), FormatVersion::LEGACY);
+
+        // nothing was sent ahead of the protocol, so the peer is still there
+        // to answer our first request
+        let request = AttestSocketProtocol::Request(1, AttestRequest::LatestTips(LatestTips {}));
+        socket
+            .send(into_tungstenite(codec.encode(&request).unwrap()))
+            .await
+            .unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(10), socket.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let reply = match reply {
+            ts::Message::Text(s) => Message::Text(s),
+            m => panic!("Unexpected frame {:?}", m),
+        };
+        match codec.decode(reply).unwrap() {
+            AttestSocketProtocol::Response(
+                1,
+                AttestResponse::LatestTips(LatestTipsResponse(v)),
+            ) => assert!(v.is_empty()),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn test_binary_rejected_without_compression() {
+        let codec = Codec::new(None, None, MAX);
+        assert!(codec.decode(Message::Binary(vec![0; 10])).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Range based set reconciliation over (genesis, height, hash).
+//!
+//! Both peers sort the keys of every envelope they hold. The initiator sends
+//! a fingerprint of the full range; whenever fingerprints differ, the side
+//! with many keys in the range splits it into sub-ranges at its own keys, and
+//! a side with few keys just lists them. A listing is answered with the exact
+//! difference, which ends that range. Matching ranges are dropped, so the
+//! conversation only continues where the sets actually differ and finishes in
+//! a logarithmic number of round trips.
+//!
+//! Processing is stateless: everything needed to answer a message is in the
+//! message, so the responding side keeps nothing between rounds.
+
+use attest_messages::CanonicalEnvelopeHash;
+use sapio_bitcoin::hashes::{sha256, Hash, HashEngine};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Ranges with at most this many of our keys are listed instead of split.
+pub const ITEM_THRESHOLD: usize = 32;
+/// How many sub-ranges a mismatched range is split into.
+pub const BRANCHING: usize = 16;
+/// Most ranges sent in one message, peers sending more are misbehaving.
+pub const MAX_RANGES: usize = 4096;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SyncKey {
+    pub genesis: CanonicalEnvelopeHash,
+    pub height: i64,
+    pub hash: CanonicalEnvelopeHash,
+}
+
+impl From<(CanonicalEnvelopeHash, i64, CanonicalEnvelopeHash)> for SyncKey {
+    fn from((genesis, height, hash): (CanonicalEnvelopeHash, i64, CanonicalEnvelopeHash)) -> Self {
+        SyncKey {
+            genesis,
+            height,
+            hash,
+        }
+    }
+}
+
+/// Summary of the keys in a range, equal fingerprints mean equal ranges.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub count: u64,
+    pub digest: sha256::Hash,
+}
+
+impl Fingerprint {
+    /// keys must be sorted
+    pub fn of(keys: &[SyncKey]) -> Self {
+        let mut engine = sha256::Hash::engine();
+        for k in keys {
+            engine.input(k.hash.as_ref());
+        }
+        Fingerprint {
+            count: keys.len() as u64,
+            digest: sha256::Hash::from_engine(engine),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RangePayload {
+    Fingerprint(Fingerprint),
+    /// Every key the sender has in the range
+    Items(Vec<SyncKey>),
+    /// The answer to an `Items` listing, nothing further is sent for the range
+    Difference {
+        you_lack: Vec<SyncKey>,
+        i_lack: Vec<SyncKey>,
+    },
+}
+
+/// The half open range `[lower, upper)`, `None` being unbounded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Range {
+    pub lower: Option<SyncKey>,
+    pub upper: Option<SyncKey>,
+    pub payload: RangePayload,
+}
+
+impl Range {
+    fn contains(&self, k: &SyncKey) -> bool {
+        let above_lower = self.lower.as_ref().map_or(true, |l| l <= k);
+        let below_upper = self.upper.as_ref().map_or(true, |u| k < u);
+        above_lower && below_upper
+    }
+    fn select<'a>(&self, mine: &'a [SyncKey]) -> &'a [SyncKey] {
+        let start = self
+            .lower
+            .as_ref()
+            .map_or(0, |l| mine.partition_point(|k| k < l));
+        let end = self
+            .upper
+            .as_ref()
+            .map_or(mine.len(), |u| mine.partition_point(|k| k < u));
+        &mine[start..end.max(start)]
+    }
+}
+
+/// The outcome of processing one message.
+#[derive(Default, Debug)]
+pub struct Reconciliation {
+    /// what to send back, empty once all ranges are settled
+    pub reply: Vec<Range>,
+    /// keys the peer has that we do not
+    pub we_lack: Vec<SyncKey>,
+    /// keys we have that the peer does not
+    pub they_lack: Vec<SyncKey>,
+}
+
+/// The first message of a reconciliation, `mine` must be sorted.
+pub fn start(mine: &[SyncKey]) -> Vec<Range> {
+    vec![Range {
+        lower: None,
+        upper: None,
+        payload: RangePayload::Fingerprint(Fingerprint::of(mine)),
+    }]
+}
+
+/// Answers every range in a message from the peer, `mine` must be sorted.
+///
+/// The reply holds at most `limit` ranges as long as `incoming` does. Splits
+/// get fewer branches once the limit is near, and a range with no room left
+/// is sent back whole with our fingerprint so that the peer splits it instead.
+pub fn reconcile(mine: &[SyncKey], incoming: &[Range], limit: usize) -> Reconciliation {
+    let mut out = Reconciliation::default();
+    // ranges to split, and where in the reply they go
+    let mut splits = vec![];
+    for range in incoming {
+        let ours = range.select(mine);
+        match &range.payload {
+            RangePayload::Fingerprint(fp) => {
+                let fingerprint = Fingerprint::of(ours);
+                if fingerprint == *fp {
+                    continue;
+                }
+                if ours.len() <= ITEM_THRESHOLD {
+                    out.reply.push(Range {
+                        lower: range.lower.clone(),
+                        upper: range.upper.clone(),
+                        payload: RangePayload::Items(ours.to_vec()),
+                    });
+                } else {
+                    splits.push((out.reply.len(), ours));
+                    out.reply.push(Range {
+                        lower: range.lower.clone(),
+                        upper: range.upper.clone(),
+                        payload: RangePayload::Fingerprint(fingerprint),
+                    });
+                }
+            }
+            RangePayload::Items(items) => {
+                let theirs: BTreeSet<&SyncKey> =
+                    items.iter().filter(|k| range.contains(k)).collect();
+                let ours: BTreeSet<&SyncKey> = ours.iter().collect();
+                let i_lack: Vec<SyncKey> = theirs.difference(&ours).map(|k| (*k).clone()).collect();
+                let you_lack: Vec<SyncKey> =
+                    ours.difference(&theirs).map(|k| (*k).clone()).collect();
+                out.we_lack.extend(i_lack.iter().cloned());
+                out.they_lack.extend(you_lack.iter().cloned());
+                if !(i_lack.is_empty() && you_lack.is_empty()) {
+                    out.reply.push(Range {
+                        lower: range.lower.clone(),
+                        upper: range.upper.clone(),
+                        payload: RangePayload::Difference { you_lack, i_lack },
+                    });
+                }
+            }
+            RangePayload::Difference { you_lack, i_lack } => {
+                // don't trust the peer's view of what we have
+                let ours: BTreeSet<&SyncKey> = ours.iter().collect();
+                out.we_lack.extend(
+                    you_lack
+                        .iter()
+                        .filter(|k| range.contains(k) && !ours.contains(k))
+                        .cloned(),
+                );
+                out.they_lack
+                    .extend(i_lack.iter().filter(|k| ours.contains(k)).cloned());
+            }
+        }
+    }
+    if splits.is_empty() {
+        return out;
+    }
+    let mut spare = limit.saturating_sub(out.reply.len());
+    let mut reply = Vec::with_capacity(out.reply.len() + spare.min(splits.len() * BRANCHING));
+    let mut splits = splits.into_iter().peekable();
+    for (i, range) in out.reply.into_iter().enumerate() {
+        match splits.next_if(|(at, _)| *at == i) {
+            Some((_, ours)) => {
+                let branches = BRANCHING.min(spare + 1);
+                if branches > 1 {
+                    spare -= branches - 1;
+                    split(&range, ours, branches, &mut reply);
+                } else {
+                    reply.push(range);
+                }
+            }
+            None => reply.push(range),
+        }
+    }
+    out.reply = reply;
+    out
+}
+
+/// Splits a range into up to `branches` sub-ranges with about the same
+/// number of our keys in each.
+fn split(range: &Range, ours: &[SyncKey], branches: usize, reply: &mut Vec<Range>) {
+    let chunk = (ours.len() + branches - 1) / branches;
+    let chunks: Vec<&[SyncKey]> = ours.chunks(chunk.max(1)).collect();
+    for (i, keys) in chunks.iter().enumerate() {
+        let lower = if i == 0 {
+            range.lower.clone()
+        } else {
+            Some(keys[0].clone())
+        };
+        let upper = match chunks.get(i + 1) {
+            Some(next) => Some(next[0].clone()),
+            None => range.upper.clone(),
+        };
+        reply.push(Range {
+            lower,
+            upper,
+            payload: RangePayload::Fingerprint(Fingerprint::of(keys)),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::collection::btree_set;
+    use proptest::prelude::*;
+    use sapio_bitcoin::hashes::hex::ToHex;
+
+    fn hash(b: [u8; 32]) -> CanonicalEnvelopeHash {
+        serde_json::from_value(serde_json::Value::String(b.to_hex())).unwrap()
+    }
+
+    /// keys spread over a handful of chains, like a real database
+    fn key() -> impl Strategy<Value = SyncKey> {
+        (0u8..8, 0i64..64, any::<[u8; 32]>()).prop_map(|(g, height, h)| SyncKey {
+            genesis: hash([g; 32]),
+            height,
+            hash: hash(h),
+        })
+    }
+
+    /// Runs the protocol the way a client drives it against a stateless
+    /// server, returning what the client learned and the round trips taken.
+    fn run(
+        client: &[SyncKey],
+        server: &[SyncKey],
+    ) -> (BTreeSet<SyncKey>, BTreeSet<SyncKey>, usize) {
+        let mut we_lack = BTreeSet::new();
+        let mut they_lack = BTreeSet::new();
+        let mut pending = start(client);
+        let mut rounds = 0;
+        while !pending.is_empty() {
+            rounds += 1;
+            let ranges: Vec<_> = pending.drain(..pending.len().min(MAX_RANGES)).collect();
+            let resp = reconcile(server, &ranges, MAX_RANGES);
+            assert!(resp.reply.len() <= MAX_RANGES);
+            let r = reconcile(client, &resp.reply, usize::MAX);
+            we_lack.extend(r.we_lack);
+            they_lack.extend(r.they_lack);
+            pending.extend(
+                r.reply
+                    .into_iter()
+                    .filter(|r| !matches!(r.payload, RangePayload::Difference { .. })),
+            );
+        }
+        (we_lack, they_lack, rounds)
+    }
+
+    proptest! {
+        #[test]
+        fn finds_symmetric_difference(
+            shared in btree_set(key(), 0..2000),
+            only_client in btree_set(key(), 0..100),
+            only_server in btree_set(key(), 0..100),
+        ) {
+            let client: Vec<_> = shared.union(&only_client).cloned().collect();
+            let server: Vec<_> = shared.union(&only_server).cloned().collect();
+            let client_set: BTreeSet<_> = client.iter().cloned().collect();
+            let server_set: BTreeSet<_> = server.iter().cloned().collect();
+            let (we_lack, they_lack, rounds) = run(&client, &server);
+            prop_assert_eq!(&we_lack, &server_set.difference(&client_set).cloned().collect());
+            prop_assert_eq!(&they_lack, &client_set.difference(&server_set).cloned().collect());
+            prop_assert!(rounds <= 6, "took {} rounds", rounds);
+        }
+    }
+
+    #[test]
+    fn identical_sets_take_one_round() {
+        let keys: Vec<_> = (0..1000u32)
+            .map(|i| {
+                let mut b = [0; 32];
+                b[..4].copy_from_slice(&i.to_be_bytes());
+                SyncKey {
+                    genesis: hash([0; 32]),
+                    height: i as i64,
+                    hash: hash(b),
+                }
+            })
+            .collect();
+        let resp = reconcile(&keys, &start(&keys), MAX_RANGES);
+        assert!(resp.reply.is_empty());
+        let (we_lack, they_lack, rounds) = run(&keys, &keys[..999]);
+        assert!(we_lack.is_empty());
+        assert_eq!(they_lack.into_iter().collect::<Vec<_>>(), keys[999..]);
+        assert!(rounds <= 3);
+    }
+
+    #[test]
+    fn empty_side() {
+        let keys: Vec<_> = (0..100u8)
+            .map(|i| SyncKey {
+                genesis: hash([1; 32]),
+                height: i as i64,
+                hash: hash([i; 32]),
+            })
+            .collect();
+        let (we_lack, they_lack, _) = run(&[], &keys);
+        assert_eq!(we_lack.into_iter().collect::<Vec<_>>(), keys);
+        assert!(they_lack.is_empty());
+        let (we_lack, they_lack, _) = run(&keys, &[]);
+        assert!(we_lack.is_empty());
+        assert_eq!(they_lack.into_iter().collect::<Vec<_>>(), keys);
+    }
+
+    /// `n` keys on one chain, numbered from `from`
+    fn numbered(from: u32, n: u32) -> Vec<SyncKey> {
+        (from..from + n)
+            .map(|i| {
+                let mut b = [0; 32];
+                b[..4].copy_from_slice(&i.to_be_bytes());
+                SyncKey {
+                    genesis: hash([2; 32]),
+                    height: i as i64,
+                    hash: hash(b),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reply_capped_at_limit() {
+        // every incoming range mismatches and has enough of our keys to split
+        let per_range = ITEM_THRESHOLD + 1;
+        let keys = numbered(0, (MAX_RANGES * per_range) as u32);
+        let chunks: Vec<_> = keys.chunks(per_range).collect();
+        let ranges: Vec<_> = chunks
+            .windows(2)
+            .map(|w| Range {
+                lower: Some(w[0][0].clone()),
+                upper: Some(w[1][0].clone()),
+                payload: RangePayload::Fingerprint(Fingerprint::of(&[])),
+            })
+            .collect();
+        assert_eq!(ranges.len(), MAX_RANGES - 1);
+        let resp = reconcile(&keys, &ranges, MAX_RANGES);
+        assert!(resp.reply.len() <= MAX_RANGES);
+        // the ranges still cover everything that was asked about
+        assert_eq!(
+            resp.reply.first().unwrap().lower,
+            ranges.first().unwrap().lower
+        );
+        assert_eq!(
+            resp.reply.last().unwrap().upper,
+            ranges.last().unwrap().upper
+        );
+        for w in resp.reply.windows(2) {
+            assert_eq!(w[0].upper, w[1].lower);
+        }
+        // and without a limit every range is split
+        let resp = reconcile(&keys, &ranges, usize::MAX);
+        assert!(resp.reply.len() > MAX_RANGES);
+    }
+
+    #[test]
+    fn large_difference() {
+        // interleaved so that every range differs at every level
+        let all = numbered(0, 200_000);
+        let client: Vec<_> = all.iter().step_by(2).cloned().collect();
+        let server: Vec<_> = all.iter().skip(1).step_by(2).cloned().collect();
+        let (we_lack, they_lack, rounds) = run(&client, &server);
+        assert_eq!(we_lack.into_iter().collect::<Vec<_>>(), server);
+        assert_eq!(they_lack.into_iter().collect::<Vec<_>>(), client);
+        assert!(rounds < 64, "took {} rounds", rounds);
+    }
+}
@@ -42,10 +42,13 @@ use tokio_tungstenite::tungstenite as ts;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::tungstenite::error::Error as TungstenError;
 use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::tungstenite::handshake::client::Request as ClientRequest;
 use tokio_tungstenite::tungstenite::handshake::client::Response as ClientResponse;
 use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
+use super::protocol::compression::{ProtocolFeatures, FEATURES_HEADER};
+use crate::configuration::ProtocolConfig;
 use crate::globals::Globals;
 
 use self::maybe_tor::MaybeTor;
@@ -130,6 +133,7 @@ mod maybe_tor {
 pub struct ClientWebSocket {
     inner: WebSocketStream<MaybeTlsStream<MaybeTor<TcpStream>>>,
     protocol: Option<HeaderValue>,
+    peer_features: Option<ProtocolFeatures>,
 }
 
 #[derive(Debug)]
@@ -161,10 +165,13 @@ impl ClientWebSocket {
         globals: &Arc<Globals>,
         url: String,
     ) -> Result<ClientWebSocket, TorWSError> {
-        let (ws_stream, _) = Self::connect_async_with_config_tor(globals, url, None).await?;
+        let request = offer_features(url, &globals.config.protocol)?;
+        let (ws_stream, response) =
+            Self::connect_async_with_config_tor(globals, request, None).await?;
         Ok(ClientWebSocket {
             inner: ws_stream,
             protocol: None,
+            peer_features: answered_features(&response),
         })
     }
 
@@ -247,6 +254,35 @@ impl ClientWebSocket {
     pub fn protocol(&self) -> Option<&HeaderValue> {
         self.protocol.as_ref()
     }
+
+    /// The features the server answered our offer with, `None` if it
+    /// predates negotiation.
+    pub fn peer_features(&self) -> Option<&ProtocolFeatures> {
+        self.peer_features.as_ref()
+    }
+}
+
+/// The upgrade request for `url`, offering the features `config` allows in
+/// [`FEATURES_HEADER`].
+pub fn offer_features(
+    url: String,
+    config: &ProtocolConfig,
+) -> Result<ClientRequest, TungstenError> {
+    let mut request = url.into_client_request()?;
+    let offer = ProtocolFeatures::offered(config).to_header();
+    let offer = offer
+        .parse()
+        .map_err(|e| TungstenError::HttpFormat(ts::http::Error::from(e)))?;
+    request.headers_mut().insert(FEATURES_HEADER, offer);
+    Ok(request)
+}
+
+/// The features the server answered with in its upgrade response.
+pub fn answered_features(response: &ClientResponse) -> Option<ProtocolFeatures> {
+    response
+        .headers()
+        .get(FEATURES_HEADER)
+        .and_then(|v| ProtocolFeatures::from_header(v.as_bytes()))
 }
 
 pub fn into_tungstenite(m: Message) -> ts::Message {
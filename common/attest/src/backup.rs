@@ -0,0 +1,125 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Periodically snapshots the database into rotating files, see
+//! [`attest_database::db_handle::backup`].
+
+use crate::configuration::BackupConfig;
+use crate::globals::Globals;
+use attest_database::connection::MsgDB;
+use attest_database::db_handle::backup::{rotate_snapshots, VerifiedSnapshot};
+use attest_messages::WrappedJson;
+use attest_util::{AbstractResult, INFER_UNIT};
+use sapio_bitcoin::secp256k1::{All, Secp256k1};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::task::{spawn_blocking, JoinHandle};
+use tracing::info;
+
+const SNAPSHOT_PREFIX: &str = "attestations-";
+
+/// Writes a new snapshot into `dir`, returning its path.
+async fn write_snapshot(db: &MsgDB, dir: &Path) -> AbstractResult<PathBuf> {
+    tokio::fs::create_dir_all(dir).await?;
+    // zero padded so that snapshots sort by age
+    let path = dir.join(format!(
+        "{}{:020}.sqlite3",
+        SNAPSHOT_PREFIX,
+        attest_util::now()
+    ));
+    // snapshots only read, so don't hold up writers
+    let handle = db.get_handle_read().await;
+    let dst = path.clone();
+    spawn_blocking(move || handle.backup_to(&dst)).await???;
+    Ok(path)
+}
+
+/// Writes a new snapshot into the backup directory and deletes the oldest
+/// beyond `config.keep`, returning the new snapshot's path.
+pub async fn backup_now(db: &MsgDB, config: &BackupConfig) -> AbstractResult<PathBuf> {
+    let path = write_snapshot(db, &config.backup_dir).await?;
+    let (dir, keep) = (config.backup_dir.clone(), config.keep);
+    let removed = spawn_blocking(move || rotate_snapshots(&dir, SNAPSHOT_PREFIX, keep)).await??;
+    if !removed.is_empty() {
+        info!(n = removed.len(), "Deleted Old Snapshots");
+    }
+    Ok(path)
+}
+
+/// The snapshot called `name` in the backup directory, if there is one. Only
+/// plain snapshot file names are accepted, so nothing outside the directory
+/// can be named.
+pub fn snapshot_path(config: &BackupConfig, name: &str) -> Option<PathBuf> {
+    let plain = Path::new(name).file_name().and_then(|n| n.to_str()) == Some(name);
+    if !plain || !name.starts_with(SNAPSHOT_PREFIX) || name.ends_with(".partial") {
+        return None;
+    }
+    let path = config.backup_dir.join(name);
+    path.is_file().then(|| path)
+}
+
+/// Replaces the database with a snapshot, once every envelope in it checks
+/// out. `snapshot` should come from [`snapshot_path`].
+///
+/// The current database is snapshotted first, so a restore can be undone.
+/// That snapshot doesn't count towards `config.keep` until the next rotation,
+/// so that it can't rotate out the snapshot being restored.
+pub async fn restore(
+    db: &MsgDB,
+    config: &BackupConfig,
+    secp: Arc<Secp256k1<All>>,
+    snapshot: PathBuf,
+) -> AbstractResult<VerifiedSnapshot> {
+    let before = write_snapshot(db, &config.backup_dir).await?;
+    info!(path=?before, "Snapshotted Database Before Restore");
+    let mut handle = db.get_handle_all().await;
+    let verified =
+        spawn_blocking(move || handle.restore_snapshot::<WrappedJson, _>(&secp, &snapshot))
+            .await???;
+    Ok(verified)
+}
+
+pub fn run(g: Arc<Globals>) -> Option<JoinHandle<AbstractResult<()>>> {
+    let config: BackupConfig = g.config.backup.clone()?;
+    Some(tokio::spawn(async move {
+        while !g.shutdown.should_quit() {
+            tokio::time::sleep(config.frequency).await;
+            let path = backup_now(&g.msg_db, &config).await?;
+            info!(?path, "Snapshotted Database");
+        }
+        INFER_UNIT
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_snapshot_path_stays_in_backup_dir() {
+        let backup_dir = std::env::temp_dir().join(format!("attest-backup-{}", std::process::id()));
+        std::fs::create_dir_all(&backup_dir).unwrap();
+        let name = format!("{}1", SNAPSHOT_PREFIX);
+        std::fs::write(backup_dir.join(&name), b"").unwrap();
+        std::fs::write(backup_dir.join(format!("{}.partial", name)), b"").unwrap();
+        let config = BackupConfig {
+            backup_dir: backup_dir.clone(),
+            frequency: Duration::from_secs(1),
+            keep: 1,
+        };
+        assert_eq!(snapshot_path(&config, &name), Some(backup_dir.join(&name)));
+        assert_eq!(snapshot_path(&config, &format!("{}.partial", name)), None);
+        assert_eq!(
+            snapshot_path(&config, &format!("{}2", SNAPSHOT_PREFIX)),
+            None
+        );
+        assert_eq!(snapshot_path(&config, &format!("../{}", name)), None);
+        assert_eq!(snapshot_path(&config, &format!("./{}", name)), None);
+        assert_eq!(snapshot_path(&config, "/etc/passwd"), None);
+        std::fs::remove_dir_all(backup_dir).unwrap();
+    }
+}
@@ -4,8 +4,9 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use attest_database::connection::MsgDB;
-use attest_database::setup_db;
+use attest_database::connection::{MsgDB, PoolConfig};
+use attest_database::db_handle::get::storage_encoding::StorageEncoding;
+use attest_database::setup_db_with;
 use attest_database::setup_test_db;
 use attest_util::bitcoin::BitcoinConfig;
 
@@ -58,6 +59,24 @@ pub struct PeerServicesTimers {
     pub attach_tip_while_busy_rate: Duration,
     pub tip_fetch_rate: Duration,
     pub entropy_range: Duration,
+    #[serde(default = "default_reconcile_rate")]
+    pub reconcile_rate: Duration,
+    #[serde(default = "default_blob_fetch_rate")]
+    pub blob_fetch_rate: Duration,
+    #[serde(default = "default_gap_fill_rate")]
+    pub gap_fill_rate: Duration,
+}
+
+fn default_reconcile_rate() -> Duration {
+    PeerServicesTimers::default().reconcile_rate
+}
+
+fn default_blob_fetch_rate() -> Duration {
+    PeerServicesTimers::default().blob_fetch_rate
+}
+
+fn default_gap_fill_rate() -> Duration {
+    PeerServicesTimers::default().gap_fill_rate
 }
 
 impl PeerServicesTimers {
@@ -68,6 +87,9 @@ impl PeerServicesTimers {
             attach_tip_while_busy_rate: Duration::from_millis((30000_f64 * scale) as u64),
             tip_fetch_rate: Duration::from_millis((15000_f64 * scale) as u64),
             entropy_range: Duration::from_millis((1000_f64 * scale) as u64),
+            reconcile_rate: Duration::from_millis((60000_f64 * scale) as u64),
+            blob_fetch_rate: Duration::from_millis((30000_f64 * scale) as u64),
+            gap_fill_rate: Duration::from_millis((20000_f64 * scale) as u64),
         }
     }
 }
@@ -95,6 +117,18 @@ impl PeerServicesTimers {
         let d = self.tip_fetch_rate + self.rand();
         tokio::time::sleep(d).await
     }
+    pub(crate) async fn reconcile_delay(&self) {
+        let d = self.reconcile_rate + self.rand();
+        tokio::time::sleep(d).await
+    }
+    pub(crate) async fn blob_fetch_delay(&self) {
+        let d = self.blob_fetch_rate + self.rand();
+        tokio::time::sleep(d).await
+    }
+    pub(crate) async fn gap_fill_delay(&self) {
+        let d = self.gap_fill_rate + self.rand();
+        tokio::time::sleep(d).await
+    }
     // todo: add randomization
     pub(crate) fn attach_tip_while_busy_interval(&self) -> Interval {
         let mut interval = tokio::time::interval(self.attach_tip_while_busy_rate);
@@ -103,10 +137,256 @@ impl PeerServicesTimers {
     }
 }
 
+pub(crate) const fn default_max_batch_envelopes() -> usize {
+    256
+}
+
+pub(crate) const fn default_max_batch_bytes() -> usize {
+    1024 * 1024
+}
+
+pub(crate) const fn default_max_queued_batches() -> usize {
+    4
+}
+
+/// Controls how envelopes are queued and batched when pushing to a peer.
+#[derive(Serialize, Deserialize)]
+pub struct PushConfig {
+    #[serde(default = "default_max_batch_envelopes")]
+    pub max_batch_envelopes: usize,
+    /// should stay well below the protocol's max_message_size
+    #[serde(default = "default_max_batch_bytes")]
+    pub max_batch_bytes: usize,
+    /// batches waiting per lane before we stop planning more for a slow peer
+    #[serde(default = "default_max_queued_batches")]
+    pub max_queued_batches: usize,
+    /// chain commit group names whose chains are sent ahead of everything
+    /// else, in order of importance
+    #[serde(default)]
+    pub priority_groups: Vec<String>,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_envelopes: default_max_batch_envelopes(),
+            max_batch_bytes: default_max_batch_bytes(),
+            max_queued_batches: default_max_queued_batches(),
+            priority_groups: vec![],
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Default)]
 pub struct PeerServiceConfig {
     #[serde(default)]
     pub timer_override: PeerServicesTimers,
+    #[serde(default)]
+    pub push: PushConfig,
+}
+
+pub(crate) const fn default_max_message_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+pub(crate) const fn default_compression() -> bool {
+    true
+}
+
+pub(crate) const fn default_binary_envelopes() -> bool {
+    true
+}
+
+pub(crate) const fn default_max_blob_size() -> u32 {
+    attest_messages::blobs::MAX_BLOB_SIZE
+}
+
+/// Settings for the attestation websocket protocol.
+#[derive(Serialize, Deserialize)]
+pub struct ProtocolConfig {
+    /// offer compression to peers, if they also support it it is used for
+    /// large messages.
+    #[serde(default = "default_compression")]
+    pub compression: bool,
+    /// offer to send envelopes in their binary encoding, used if the peer
+    /// supports it too.
+    #[serde(default = "default_binary_envelopes")]
+    pub binary_envelopes: bool,
+    /// largest message (after decompression) accepted from a peer
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: u64,
+    /// largest attachment (see attest_messages::blobs) fetched from peers,
+    /// larger ones are left missing
+    #[serde(default = "default_max_blob_size")]
+    pub max_blob_size: u32,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            compression: default_compression(),
+            binary_envelopes: default_binary_envelopes(),
+            max_message_size: default_max_message_size(),
+            max_blob_size: default_max_blob_size(),
+        }
+    }
+}
+
+fn default_anchor_frequency() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+const fn default_anchor_min_confirmations() -> u32 {
+    6
+}
+
+const fn default_anchor_local_only() -> bool {
+    true
+}
+
+/// Settings for anchoring chain tips into Bitcoin, see crate::anchoring.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AnchorConfig {
+    /// how often a new anchor transaction is made, if there are new tips
+    #[serde(default = "default_anchor_frequency")]
+    pub frequency: Duration,
+    /// how deep an anchor must be before its proofs are stored and accepted
+    #[serde(default = "default_anchor_min_confirmations")]
+    pub min_confirmations: u32,
+    /// only anchor the tips of chains we have the keys for
+    #[serde(default = "default_anchor_local_only")]
+    pub local_only: bool,
+}
+
+impl Default for AnchorConfig {
+    fn default() -> Self {
+        Self {
+            frequency: default_anchor_frequency(),
+            min_confirmations: default_anchor_min_confirmations(),
+            local_only: default_anchor_local_only(),
+        }
+    }
+}
+
+fn default_retention_archive_after() -> Duration {
+    Duration::from_secs(30 * 24 * 60 * 60)
+}
+
+const fn default_retention_finished_groups() -> bool {
+    true
+}
+
+const fn default_retention_keep() -> u64 {
+    16
+}
+
+fn default_retention_frequency() -> Duration {
+    Duration::from_secs(6 * 60 * 60)
+}
+
+const fn default_retention_compact() -> bool {
+    true
+}
+
+/// Settings for archiving old envelopes out of the database, see
+/// crate::retention.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RetentionConfig {
+    /// where archive files are written
+    pub archive_dir: PathBuf,
+    /// chains nothing was received for in this long are archived
+    #[serde(default = "default_retention_archive_after")]
+    pub archive_after: Duration,
+    /// also archive the members of finished chain commit groups right away
+    #[serde(default = "default_retention_finished_groups")]
+    pub finished_groups: bool,
+    /// how many of the latest envelopes of a chain are never archived
+    #[serde(default = "default_retention_keep")]
+    pub keep: u64,
+    /// how often to look for chains to archive
+    #[serde(default = "default_retention_frequency")]
+    pub frequency: Duration,
+    /// give the space freed by archiving back to the filesystem
+    #[serde(default = "default_retention_compact")]
+    pub compact: bool,
+}
+
+fn default_backup_frequency() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+const fn default_backup_keep() -> usize {
+    7
+}
+
+/// Settings for taking snapshots of the database while running, see
+/// crate::backup.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BackupConfig {
+    /// where snapshots are written
+    pub backup_dir: PathBuf,
+    /// how often to take a snapshot
+    #[serde(default = "default_backup_frequency")]
+    pub frequency: Duration,
+    /// how many snapshots to keep, the oldest are deleted first
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+}
+
+const fn default_nonce_pregenerate() -> usize {
+    16
+}
+
+fn default_nonce_frequency() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Settings for keeping nonces ready to sign with and erasing used ones, see
+/// crate::nonces.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NonceConfig {
+    /// how many nonces to keep pregenerated for each of our keys
+    #[serde(default = "default_nonce_pregenerate")]
+    pub pregenerate: usize,
+    /// how often to top up pregenerated nonces and erase used ones
+    #[serde(default = "default_nonce_frequency")]
+    pub frequency: Duration,
+    /// free the nonces of envelopes signed but never stored, e.g. as the
+    /// node died in between, once on start. Reusing one is only safe if the
+    /// envelope it signed never left the node, so it is off unless set
+    #[serde(default)]
+    pub release_unstored_on_start: bool,
+}
+
+impl Default for NonceConfig {
+    fn default() -> Self {
+        Self {
+            pregenerate: default_nonce_pregenerate(),
+            frequency: default_nonce_frequency(),
+            release_unstored_on_start: false,
+        }
+    }
+}
+
+fn default_prune_frequency() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Settings for pruning the messages of expired ephemeral envelopes, see
+/// crate::ephemeral.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EphemeralConfig {
+    /// how often to prune expired messages
+    #[serde(default = "default_prune_frequency")]
+    pub prune_frequency: Duration,
+}
+
+impl Default for EphemeralConfig {
+    fn default() -> Self {
+        Self {
+            prune_frequency: default_prune_frequency(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -121,6 +401,29 @@ pub struct Config {
     pub prefix: Option<PathBuf>,
     #[serde(default)]
     pub peer_service: PeerServiceConfig,
+    #[serde(default)]
+    pub protocol: ProtocolConfig,
+    /// how newly received envelopes are written to the database
+    #[serde(default)]
+    pub storage_encoding: StorageEncoding,
+    /// how many connections to the database to keep, and how long to wait
+    /// for one
+    #[serde(default)]
+    pub database: PoolConfig,
+    #[serde(default)]
+    pub nonces: NonceConfig,
+    #[serde(default)]
+    pub ephemeral: EphemeralConfig,
+    /// anchoring costs fees from the node's wallet, so it is off unless set
+    #[serde(default)]
+    pub anchoring: Option<AnchorConfig>,
+    /// archiving deletes envelopes from the database, so it is off unless set
+    #[serde(default)]
+    pub retention: Option<RetentionConfig>,
+    /// snapshots take disk space wherever they are written, so they are off
+    /// unless set
+    #[serde(default)]
+    pub backup: Option<BackupConfig>,
     #[serde(skip, default)]
     pub test_db: bool,
 }
@@ -132,14 +435,17 @@ pub(crate) fn get_config() -> Result<Arc<Config>, Box<dyn Error>> {
 
 impl Config {
     pub async fn setup_db(&self) -> Result<MsgDB, Box<dyn Error + Send + Sync>> {
-        if self.test_db {
-            Ok(setup_test_db().await)
+        let mdb = if self.test_db {
+            setup_test_db().await
         } else {
             let application = format!("attestations.{}", self.subname);
-            let mdb = setup_db(&application, self.prefix.clone())
+            setup_db_with(&application, self.prefix.clone(), self.database.clone())
                 .await
-                .map_err(|e| format!("{}", e))?;
-            Ok(mdb)
-        }
+                .map_err(|e| format!("{}", e))?
+        };
+        mdb.get_handle_all()
+            .await
+            .set_storage_encoding(self.storage_encoding)?;
+        Ok(mdb)
     }
 }
@@ -4,10 +4,20 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use attest_messages::Envelope;
+use attest_database::db_handle::backup::VerifiedSnapshot;
+use attest_database::db_handle::get::archive::ArchivedChain;
+use attest_database::db_handle::get::chain_commit_groups::ChainCommitGroupMembership;
+use attest_database::db_handle::get::chain_stats::ChainStats;
+use attest_database::db_handle::get::query::EnvelopeQuery;
+use attest_messages::{CanonicalEnvelopeHash, Envelope};
 use reqwest::Client;
+use sapio_bitcoin::XOnlyPublicKey;
 
-use super::query::{NewGenesis, Outcome, PushMsg, Subscribe};
+use super::query::{
+    AnchorStatus, ArchiveChain, Backup, ChainCommitGroupEdit, NewGenesis, Outcome, PushMsg,
+    RenameChainCommitGroup, ReplicationStatus, RestoreBackup, Restored, SetChainVisibility,
+    SetGroupTipPolicy, Subscribe,
+};
 
 #[derive(Clone)]
 pub struct ControlClient(pub Client);
@@ -67,4 +77,279 @@ impl ControlClient {
             .await?;
         Ok(resp)
     }
+    pub async fn replication_status(
+        &self,
+        genesis: &CanonicalEnvelopeHash,
+        url: &String,
+        port: u16,
+    ) -> Result<ReplicationStatus, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!("http://{}:{}/replication_status", url, port))
+            .json(genesis)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn chain_stats(
+        &self,
+        url: &String,
+        port: u16,
+    ) -> Result<Vec<ChainStats>, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .get(format!("http://{}:{}/chain_stats", url, port))
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn anchor_status(
+        &self,
+        envelope: &CanonicalEnvelopeHash,
+        url: &String,
+        port: u16,
+    ) -> Result<AnchorStatus, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!("http://{}:{}/anchor_status", url, port))
+            .json(envelope)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn query_envelopes(
+        &self,
+        q: &EnvelopeQuery,
+        url: &String,
+        port: u16,
+    ) -> Result<Vec<Envelope>, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!("http://{}:{}/query_envelopes", url, port))
+            .json(q)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn archived_chains(
+        &self,
+        url: &String,
+        port: u16,
+    ) -> Result<Vec<ArchivedChain>, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .get(format!("http://{}:{}/archived_chains", url, port))
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn archive_chain(
+        &self,
+        a: &ArchiveChain,
+        url: &String,
+        port: u16,
+    ) -> Result<Option<ArchivedChain>, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!("http://{}:{}/archive_chain", url, port))
+            .json(a)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    /// Puts an archived chain back in the database, see crate::retention.
+    pub async fn restore_chain(
+        &self,
+        genesis: &CanonicalEnvelopeHash,
+        url: &String,
+        port: u16,
+    ) -> Result<Restored, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!("http://{}:{}/restore_chain", url, port))
+            .json(genesis)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    /// Snapshots the database right away, see crate::backup.
+    pub async fn backup(&self, url: &String, port: u16) -> Result<Backup, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!("http://{}:{}/backup", url, port))
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    /// Replaces the database with a snapshot once it verifies, see
+    /// crate::backup.
+    pub async fn restore_backup(
+        &self,
+        b: &RestoreBackup,
+        url: &String,
+        port: u16,
+    ) -> Result<VerifiedSnapshot, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!("http://{}:{}/restore_backup", url, port))
+            .json(b)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn finish_chain_commit_group(
+        &self,
+        name: &String,
+        url: &String,
+        port: u16,
+    ) -> Result<Outcome, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!("http://{}:{}/finish_chain_commit_group", url, port))
+            .json(name)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn new_chain_commit_group(
+        &self,
+        name: &Option<String>,
+        url: &String,
+        port: u16,
+    ) -> Result<String, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!("http://{}:{}/new_chain_commit_group", url, port))
+            .json(name)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn add_to_chain_commit_group(
+        &self,
+        edit: &ChainCommitGroupEdit,
+        url: &String,
+        port: u16,
+    ) -> Result<Outcome, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!("http://{}:{}/add_to_chain_commit_group", url, port))
+            .json(edit)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn remove_from_chain_commit_group(
+        &self,
+        edit: &ChainCommitGroupEdit,
+        url: &String,
+        port: u16,
+    ) -> Result<Outcome, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!(
+                "http://{}:{}/remove_from_chain_commit_group",
+                url, port
+            ))
+            .json(edit)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn rename_chain_commit_group(
+        &self,
+        rename: &RenameChainCommitGroup,
+        url: &String,
+        port: u16,
+    ) -> Result<Outcome, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!("http://{}:{}/rename_chain_commit_group", url, port))
+            .json(rename)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn set_chain_commit_group_tip_policy(
+        &self,
+        policy: &SetGroupTipPolicy,
+        url: &String,
+        port: u16,
+    ) -> Result<Outcome, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!(
+                "http://{}:{}/set_chain_commit_group_tip_policy",
+                url, port
+            ))
+            .json(policy)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn chain_commit_group_memberships(
+        &self,
+        key: &XOnlyPublicKey,
+        url: &String,
+        port: u16,
+    ) -> Result<Vec<ChainCommitGroupMembership>, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!(
+                "http://{}:{}/chain_commit_group_memberships",
+                url, port
+            ))
+            .json(key)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
+    pub async fn set_chain_visibility(
+        &self,
+        v: &SetChainVisibility,
+        url: &String,
+        port: u16,
+    ) -> Result<Outcome, reqwest::Error> {
+        let resp = self
+            .as_ref()
+            .post(format!("http://{}:{}/chain_visibility", url, port))
+            .json(v)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(resp)
+    }
 }
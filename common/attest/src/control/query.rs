@@ -4,9 +4,15 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use attest_database::db_handle::get::chain_commit_groups::GroupTipPolicy;
+use attest_database::db_handle::get::chain_visibility::ChainVisibility;
+use attest_database::db_handle::get::peer_acknowledgements::PeerReplication;
+use attest_messages::anchor::AnchorProof;
+use attest_messages::CanonicalEnvelopeHash;
 use ruma_serde::CanonicalJsonValue;
 use sapio_bitcoin::XOnlyPublicKey;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 #[derive(Serialize, Deserialize)]
 pub struct PushMsg {
@@ -14,6 +20,9 @@ pub struct PushMsg {
     pub key: XOnlyPublicKey,
     #[serde(default)]
     pub equivocate: bool,
+    /// makes the envelope ephemeral, see [`attest_messages::ephemeral`]
+    #[serde(default)]
+    pub ttl_ms: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -33,6 +42,29 @@ pub struct Outcome {
     pub success: bool,
 }
 
+/// Adds a chain to, or removes it from, a chain commit group.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChainCommitGroupEdit {
+    pub group: String,
+    pub genesis: CanonicalEnvelopeHash,
+    /// as a subscriber, committing to the members' tips, rather than as a
+    /// member
+    #[serde(default)]
+    pub subscriber: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenameChainCommitGroup {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetGroupTipPolicy {
+    pub group: String,
+    pub tip_policy: GroupTipPolicy,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct NewGenesis {
     pub nickname: String,
@@ -40,3 +72,59 @@ pub struct NewGenesis {
     #[serde(default)]
     pub danger_extended_private_key: Option<String>,
 }
+
+/// How many of the peers we push to have acknowledged our latest envelope on a
+/// chain, e.g. "my latest move has reached 4/5 players".
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplicationStatus {
+    pub genesis: CanonicalEnvelopeHash,
+    pub height: i64,
+    pub hash: CanonicalEnvelopeHash,
+    pub reached: usize,
+    pub out_of: usize,
+    pub peers: Vec<PeerReplication>,
+}
+
+/// Whether an envelope was anchored into Bitcoin, see crate::anchoring.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AnchorStatus {
+    pub proof: Option<AnchorProof>,
+    /// the height of the anchor's block, if the proof checks out against our
+    /// node and is buried deep enough
+    pub verified_height: Option<u32>,
+}
+
+/// Archives a chain out of the database, see crate::retention.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchiveChain {
+    pub genesis: CanonicalEnvelopeHash,
+    /// how many of the latest envelopes to keep, defaults to the configured
+    /// amount
+    #[serde(default)]
+    pub keep: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Restored {
+    pub restored: usize,
+}
+
+/// A snapshot of the database, see crate::backup.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Backup {
+    pub path: PathBuf,
+}
+
+/// A snapshot to restore, by its file name in the backup directory, see
+/// crate::backup::snapshot_path.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RestoreBackup {
+    pub name: String,
+}
+
+/// Restricts which peers a chain is shared with.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetChainVisibility {
+    pub genesis: CanonicalEnvelopeHash,
+    pub visibility: ChainVisibility,
+}
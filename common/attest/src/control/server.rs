@@ -5,12 +5,23 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{
+    anchoring::{verify_anchor, AnchorBackend},
+    backup,
     globals::Globals,
     peer_services::{PeerQuery, TaskID},
+    retention,
 };
 use attest_database::{
-    connection::MsgDB,
-    db_handle::{create::TipControl, get::PeerInfo},
+    connection::{MsgDB, PoolStats},
+    db_handle::{
+        backup::VerifiedSnapshot,
+        create::TipControl,
+        get::{
+            archive::ArchivedChain, chain_commit_groups::ChainCommitGroupMembership,
+            chain_stats::ChainStats, gaps::ChainGaps, query::EnvelopeQuery, PeerInfo,
+        },
+        handle_type, ChainCommitGroupID, MsgDBHandle,
+    },
     generate_new_user, generate_new_user_keypair,
 };
 use attest_messages::{Authenticated, CanonicalEnvelopeHash, Envelope, WrappedJson};
@@ -38,7 +49,11 @@ use tokio::{
 };
 use tower_http::cors::{Any, CorsLayer};
 
-use super::query::{NewGenesis, Outcome, PushMsg, Subscribe};
+use super::query::{
+    AnchorStatus, ArchiveChain, Backup, ChainCommitGroupEdit, NewGenesis, Outcome, PushMsg,
+    RenameChainCommitGroup, ReplicationStatus, RestoreBackup, Restored, SetChainVisibility,
+    SetGroupTipPolicy, Subscribe,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct TipData {
@@ -52,6 +67,9 @@ pub struct Status {
     peer_connections: Vec<TaskID>,
     all_users: Vec<(XOnlyPublicKey, String, bool)>,
     hidden_service_url: Option<(String, u16)>,
+    /// the missing ranges of chains we hold envelopes we can't connect for
+    gaps: Vec<ChainGaps>,
+    db_pool: PoolStats,
 }
 
 async fn get_expensive_db_snapshot(
@@ -121,12 +139,113 @@ async fn chain_commit_groups(
         Json(resp),
     ))
 }
+async fn replication_status(
+    Json(genesis): Json<CanonicalEnvelopeHash>,
+    db: Extension<MsgDB>,
+) -> Result<(Response<()>, Json<ReplicationStatus>), (StatusCode, String)> {
+    let handle = db.0.get_handle_read().await;
+    let resp = spawn_blocking(move || {
+        let genesis_envelope =
+            match handle.messages_by_hash::<_, Envelope, _>(std::iter::once(&genesis)) {
+                Ok(envelopes) => envelopes.first().cloned(),
+                Err(rusqlite::Error::QueryReturnedNoRows) => None,
+                Err(e) => return Err(e),
+            };
+        let genesis_envelope = match genesis_envelope {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let tip = handle.get_tip_for_user_by_key::<WrappedJson>(genesis_envelope.header().key())?;
+        let peers = handle.get_replication_status_for_chain(genesis)?;
+        let height = tip.header().height();
+        let reached = peers
+            .iter()
+            .filter(|p| matches!(p.acknowledged, Some((h, _)) if h >= height))
+            .count();
+        Ok::<_, rusqlite::Error>(Some(ReplicationStatus {
+            genesis,
+            height,
+            hash: tip.canonicalized_hash_ref(),
+            reached,
+            out_of: peers.len(),
+            peers,
+        }))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Unknown chain {:?}", genesis),
+        )
+    })?;
+
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(resp),
+    ))
+}
+async fn chain_stats(
+    db: Extension<MsgDB>,
+) -> Result<(Response<()>, Json<Vec<ChainStats>>), (StatusCode, String)> {
+    let handle = db.0.get_handle_read().await;
+    let stats = spawn_blocking(move || handle.get_chain_stats())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(stats),
+    ))
+}
+async fn anchor_status(
+    Json(envelope): Json<CanonicalEnvelopeHash>,
+    g: Extension<Arc<Globals>>,
+    db: Extension<MsgDB>,
+    backend: Extension<Arc<dyn AnchorBackend>>,
+) -> Result<(Response<()>, Json<AnchorStatus>), (StatusCode, String)> {
+    let handle = db.0.get_handle_read().await;
+    let proof = spawn_blocking(move || handle.get_anchor_proof(envelope))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let min_confirmations = g
+        .config
+        .anchoring
+        .clone()
+        .unwrap_or_default()
+        .min_confirmations;
+    let verified_height = match &proof {
+        Some(p) => verify_anchor(&**backend, p, min_confirmations).await.ok(),
+        None => None,
+    };
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(AnchorStatus {
+            proof,
+            verified_height,
+        }),
+    ))
+}
 async fn get_status(
     g: Extension<Arc<Globals>>,
     db: Extension<MsgDB>,
     peer_status: Extension<Sender<PeerQuery>>,
 ) -> Result<(Response<()>, Json<Status>), (StatusCode, String)> {
-    let (tips, peers, all_users) = {
+    let (tips, peers, all_users, gaps) = {
         let handle = db.0.get_handle_read().await;
         spawn_blocking(move || {
             let peers = handle
@@ -158,7 +277,13 @@ async fn get_status(
                 .into_iter()
                 .map(|(k, v)| (k, v, known_keys.contains_key(&k)))
                 .collect();
-            Ok::<_, (StatusCode, String)>((tips, peers, all_users))
+            let gaps = handle.get_chain_gaps().map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Gap query failed: {}", e),
+                )
+            })?;
+            Ok::<_, (StatusCode, String)>((tips, peers, all_users, gaps))
         })
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??
@@ -188,6 +313,8 @@ async fn get_status(
         peer_connections,
         all_users,
         hidden_service_url,
+        gaps,
+        db_pool: db.0.stats(),
     };
 
     Ok((
@@ -229,6 +356,333 @@ async fn listen_to_service(
     ))
 }
 
+async fn set_chain_visibility(
+    db: Extension<MsgDB>,
+    Json(SetChainVisibility {
+        genesis,
+        visibility,
+    }): Json<SetChainVisibility>,
+) -> Result<(Response<()>, Json<Outcome>), (StatusCode, String)> {
+    let mut h = db.0.get_handle_all().await;
+    spawn_blocking(move || h.set_chain_visibility(genesis, &visibility))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(Outcome { success: true }),
+    ))
+}
+
+async fn query_envelopes(
+    db: Extension<MsgDB>,
+    Json(query): Json<EnvelopeQuery>,
+) -> Result<(Response<()>, Json<Vec<Envelope>>), (StatusCode, String)> {
+    let handle = db.0.get_handle_read().await;
+    let envelopes = spawn_blocking(move || {
+        handle.query_envelopes::<Authenticated<Envelope>, WrappedJson>(&query)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(envelopes.into_iter().map(|e| e.inner()).collect()),
+    ))
+}
+
+async fn archived_chains(
+    db: Extension<MsgDB>,
+) -> Result<(Response<()>, Json<Vec<ArchivedChain>>), (StatusCode, String)> {
+    let handle = db.0.get_handle_read().await;
+    let archives = spawn_blocking(move || handle.get_archived_chains())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(archives),
+    ))
+}
+
+async fn archive_chain(
+    g: Extension<Arc<Globals>>,
+    db: Extension<MsgDB>,
+    Json(ArchiveChain { genesis, keep }): Json<ArchiveChain>,
+) -> Result<(Response<()>, Json<Option<ArchivedChain>>), (StatusCode, String)> {
+    let config = g.config.retention.clone().ok_or((
+        StatusCode::BAD_REQUEST,
+        "No Archive Directory Configured".to_string(),
+    ))?;
+    let archive = retention::archive_chain(
+        &db.0,
+        genesis,
+        keep.unwrap_or(config.keep),
+        config.archive_dir,
+    )
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(archive),
+    ))
+}
+
+async fn restore_chain(
+    g: Extension<Arc<Globals>>,
+    db: Extension<MsgDB>,
+    Json(genesis): Json<CanonicalEnvelopeHash>,
+) -> Result<(Response<()>, Json<Restored>), (StatusCode, String)> {
+    let restored = retention::restore_chain(&db.0, g.secp.clone(), genesis)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(Restored { restored }),
+    ))
+}
+
+async fn backup(
+    g: Extension<Arc<Globals>>,
+    db: Extension<MsgDB>,
+) -> Result<(Response<()>, Json<Backup>), (StatusCode, String)> {
+    let config = g.config.backup.clone().ok_or((
+        StatusCode::BAD_REQUEST,
+        "No Backup Directory Configured".to_string(),
+    ))?;
+    let path = backup::backup_now(&db.0, &config)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(Backup { path }),
+    ))
+}
+
+async fn restore_backup(
+    g: Extension<Arc<Globals>>,
+    db: Extension<MsgDB>,
+    Json(RestoreBackup { name }): Json<RestoreBackup>,
+) -> Result<(Response<()>, Json<VerifiedSnapshot>), (StatusCode, String)> {
+    let config = g.config.backup.clone().ok_or((
+        StatusCode::BAD_REQUEST,
+        "No Backup Directory Configured".to_string(),
+    ))?;
+    // never a path, anyone who can reach this may call it
+    let path = backup::snapshot_path(&config, &name).ok_or((
+        StatusCode::BAD_REQUEST,
+        "No Such Snapshot in the Backup Directory".to_string(),
+    ))?;
+    let verified = backup::restore(&db.0, &config, g.secp.clone(), path)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(verified),
+    ))
+}
+
+async fn finish_chain_commit_group(
+    db: Extension<MsgDB>,
+    Json(name): Json<String>,
+) -> Result<(Response<()>, Json<Outcome>), (StatusCode, String)> {
+    let h = db.0.get_handle_all().await;
+    spawn_blocking(move || h.finish_chain_commit_group(&name))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(Outcome { success: true }),
+    ))
+}
+
+/// the id of the chain commit group called `name`, for the endpoints that
+/// manage groups by name
+fn chain_commit_group_id<T: handle_type::Get>(
+    handle: &MsgDBHandle<T>,
+    name: &str,
+) -> Result<ChainCommitGroupID, (StatusCode, String)> {
+    handle
+        .get_chain_commit_group_by_name(name)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(|g| g.id)
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("No chain commit group named {}", name),
+            )
+        })
+}
+
+async fn new_chain_commit_group(
+    db: Extension<MsgDB>,
+    Json(name): Json<Option<String>>,
+) -> Result<(Response<()>, Json<String>), (StatusCode, String)> {
+    let h = db.0.get_handle_all().await;
+    let (name, _) = spawn_blocking(move || h.new_chain_commit_group(name))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(name),
+    ))
+}
+
+async fn add_to_chain_commit_group(
+    db: Extension<MsgDB>,
+    Json(edit): Json<ChainCommitGroupEdit>,
+) -> Result<(Response<()>, Json<Outcome>), (StatusCode, String)> {
+    let h = db.0.get_handle_all().await;
+    spawn_blocking(move || {
+        let id = chain_commit_group_id(&h, &edit.group)?;
+        if edit.subscriber {
+            h.add_subscriber_to_chain_commit_group(id, edit.genesis)
+        } else {
+            h.add_member_to_chain_commit_group(id, edit.genesis)
+        }
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(Outcome { success: true }),
+    ))
+}
+
+/// e.g. to drop a kicked player from a game's group, succeeds only if the
+/// chain was in the group
+async fn remove_from_chain_commit_group(
+    db: Extension<MsgDB>,
+    Json(edit): Json<ChainCommitGroupEdit>,
+) -> Result<(Response<()>, Json<Outcome>), (StatusCode, String)> {
+    let h = db.0.get_handle_all().await;
+    let success = spawn_blocking(move || {
+        let id = chain_commit_group_id(&h, &edit.group)?;
+        if edit.subscriber {
+            h.remove_subscriber_from_chain_commit_group(id, edit.genesis)
+        } else {
+            h.remove_member_from_chain_commit_group(id, edit.genesis)
+        }
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(Outcome { success }),
+    ))
+}
+
+async fn rename_chain_commit_group(
+    db: Extension<MsgDB>,
+    Json(RenameChainCommitGroup { from, to }): Json<RenameChainCommitGroup>,
+) -> Result<(Response<()>, Json<Outcome>), (StatusCode, String)> {
+    let h = db.0.get_handle_all().await;
+    let success = spawn_blocking(move || {
+        let id = chain_commit_group_id(&h, &from)?;
+        h.rename_chain_commit_group(id, &to)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(Outcome { success }),
+    ))
+}
+
+async fn set_chain_commit_group_tip_policy(
+    db: Extension<MsgDB>,
+    Json(SetGroupTipPolicy { group, tip_policy }): Json<SetGroupTipPolicy>,
+) -> Result<(Response<()>, Json<Outcome>), (StatusCode, String)> {
+    let h = db.0.get_handle_all().await;
+    let success = spawn_blocking(move || {
+        let id = chain_commit_group_id(&h, &group)?;
+        h.set_chain_commit_group_tip_policy(id, tip_policy)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))??;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(Outcome { success }),
+    ))
+}
+
+async fn chain_commit_group_memberships(
+    db: Extension<MsgDB>,
+    Json(key): Json<XOnlyPublicKey>,
+) -> Result<(Response<()>, Json<Vec<ChainCommitGroupMembership>>), (StatusCode, String)> {
+    let h = db.0.get_handle_read().await;
+    let memberships = spawn_blocking(move || h.get_chain_commit_group_memberships_for_key(key))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(memberships),
+    ))
+}
+
 async fn push_message_dangerous(
     db: Extension<MsgDB>,
     secp: Extension<Secp256k1<All>>,
@@ -237,6 +691,7 @@ async fn push_message_dangerous(
         mut msg,
         key,
         equivocate,
+        ttl_ms,
     }): Json<PushMsg>,
 ) -> Result<(Response<()>, Json<Outcome>), (StatusCode, String)> {
     let mut handle = db.0.get_handle_all().await;
@@ -257,32 +712,36 @@ async fn push_message_dangerous(
                 ruma_serde::CanonicalJsonValue::Array(mut a) if a.len() == 2 => {
                     let dirty1 = a.pop().unwrap();
                     let dirty2 = a.pop().unwrap();
+                    let err = |e: &dyn std::fmt::Display| {
+                        (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            format!("Wrapping Message and Inserting failed: {}", e),
+                        )
+                    };
                     let tip = handle
                         .get_tip_for_user_by_key(kp.x_only_public_key().0)
                         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("No Tip: {}", e)))?
                         .inner();
-                    for dirty in [dirty1, dirty2] {
-                        let m: Envelope = handle
-                            .wrap_message_in_envelope_for_user_by_key(
-                                dirty,
-                                &kp,
-                                &secp,
-                                Some(tips.clone()),
-                                Some(tip.clone()),
-                                TipControl::NoTips,
-                            )
-                            .map_err(|e| {
-                                (
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                    format!("Wrapping Message and Inserting failed: {}", e),
-                                )
-                            })?
-                            .map_err(|e| {
-                                (
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                    format!("Wrapping Message and Inserting failed: {}", e),
-                                )
-                            })?;
+                    // wrapping marks the nonce used, so the second envelope
+                    // has to be signed by hand before the first is stored and
+                    // the secret erased
+                    let secret = handle
+                        .get_secret_for_public_nonce(tip.header().next_nonce())
+                        .map_err(|e| err(&e))?;
+                    let m1: Envelope = handle
+                        .wrap_message_in_envelope_for_user_by_key(
+                            dirty1,
+                            &kp,
+                            &secp,
+                            Some(tips),
+                            Some(tip),
+                            TipControl::NoTips,
+                        )
+                        .map_err(|e| err(&e))?
+                        .map_err(|e| err(&e))?;
+                    let mut m2 = Envelope::new(m1.header().clone(), WrappedJson::from(dirty2));
+                    m2.sign_with(&kp, &secp, secret).map_err(|e| err(&e))?;
+                    for m in [m1, m2] {
                         handle.try_insert_authenticated_envelope(
                             m.self_authenticate(&secp).unwrap(),
                             false,
@@ -291,6 +750,32 @@ async fn push_message_dangerous(
                 }
                 _ => {}
             }
+        } else if let Some(ttl_ms) = ttl_ms {
+            let err = |e: &dyn std::fmt::Display| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Wrapping Message and Inserting failed: {}", e),
+                )
+            };
+            let m: Envelope = handle
+                .wrap_ephemeral_in_envelope_for_user_by_key(msg, &kp, &secp, Some(tips), ttl_ms)
+                .map_err(|e| err(&e))?
+                .map_err(|e| err(&e))?;
+            let nonce = m.extract_used_nonce();
+            let m = match m.self_authenticate(&secp) {
+                Ok(m) => m,
+                Err(e) => {
+                    // never stored, so the nonce may sign another
+                    if let Some(nonce) = nonce {
+                        handle.release_unstored_nonce(nonce).map_err(|e| err(&e))?;
+                    }
+                    return Err(err(&e));
+                }
+            };
+            handle
+                .try_insert_authenticated_envelope(m, true)
+                .map_err(|e| err(&e))?
+                .map_err(|(e, _)| err(&format!("{:?}", e)))?;
         } else {
             handle
                 .retry_insert_authenticated_envelope_atomic::<WrappedJson, _, _>(
@@ -387,6 +872,7 @@ pub async fn run(
     db: MsgDB,
     peer_status: Sender<PeerQuery>,
     bitcoin_tipcache: Arc<BitcoinCheckPointCache>,
+    anchor_backend: Arc<dyn AnchorBackend>,
 ) -> tokio::task::JoinHandle<AbstractResult<()>> {
     tokio::spawn(async move {
         // build our application with a route
@@ -416,6 +902,30 @@ pub async fn run(
                         .allow_origin(Any),
                 ),
             )
+            .route(
+                "/replication_status",
+                post(replication_status).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/chain_stats",
+                get(chain_stats).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::GET, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
             .route(
                 "/expensive_db_snapshot",
                 get(get_expensive_db_snapshot).layer(
@@ -440,6 +950,186 @@ pub async fn run(
                         .allow_origin(Any),
                 ),
             )
+            .route(
+                "/anchor_status",
+                post(anchor_status).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/chain_visibility",
+                post(set_chain_visibility).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/query_envelopes",
+                post(query_envelopes).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/archived_chains",
+                get(archived_chains).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::GET, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/archive_chain",
+                post(archive_chain).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/restore_chain",
+                post(restore_chain).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/backup",
+                post(backup).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/restore_backup",
+                post(restore_backup).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/finish_chain_commit_group",
+                post(finish_chain_commit_group).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/new_chain_commit_group",
+                post(new_chain_commit_group).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/add_to_chain_commit_group",
+                post(add_to_chain_commit_group).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/remove_from_chain_commit_group",
+                post(remove_from_chain_commit_group).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/rename_chain_commit_group",
+                post(rename_chain_commit_group).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/set_chain_commit_group_tip_policy",
+                post(set_chain_commit_group_tip_policy).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
+            .route(
+                "/chain_commit_group_memberships",
+                post(chain_commit_group_memberships).layer(
+                    CorsLayer::new()
+                        .allow_methods([Method::POST, Method::OPTIONS])
+                        .allow_headers([
+                            reqwest::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                            reqwest::header::CONTENT_TYPE,
+                        ])
+                        .allow_origin(Any),
+                ),
+            )
             .route(
                 "/push_message_dangerous",
                 post(push_message_dangerous).layer(
@@ -469,6 +1159,7 @@ pub async fn run(
             .layer(Extension(peer_status))
             .layer(Extension(Secp256k1::new()))
             .layer(Extension(bitcoin_tipcache))
+            .layer(Extension(anchor_backend))
             .layer(tower_http::trace::TraceLayer::new_for_http());
 
         // run our app with hyper
@@ -0,0 +1,38 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Periodically prunes the messages of expired ephemeral envelopes, see
+//! [`attest_messages::ephemeral`].
+
+use crate::configuration::EphemeralConfig;
+use crate::globals::Globals;
+use attest_database::connection::MsgDB;
+use attest_util::{AbstractResult, INFER_UNIT};
+use std::sync::Arc;
+use tokio::task::{spawn_blocking, JoinHandle};
+use tracing::info;
+
+/// Prunes every message expired by now, returning how many were pruned.
+pub async fn prune(db: &MsgDB) -> AbstractResult<usize> {
+    let mut handle = db.get_handle_all().await;
+    let pruned =
+        spawn_blocking(move || handle.prune_expired_messages(attest_util::now())).await??;
+    Ok(pruned)
+}
+
+pub fn run(g: Arc<Globals>) -> JoinHandle<AbstractResult<()>> {
+    let config: EphemeralConfig = g.config.ephemeral.clone();
+    tokio::spawn(async move {
+        while !g.shutdown.should_quit() {
+            let pruned = prune(&g.msg_db).await?;
+            if pruned > 0 {
+                info!(n = pruned, "Pruned Expired Messages");
+            }
+            tokio::time::sleep(config.prune_frequency).await;
+        }
+        INFER_UNIT
+    })
+}
@@ -5,6 +5,7 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use attest_database::connection::MsgDB;
+use attest_messages::registry;
 use attest_util::INFER_UNIT;
 use bitcoin_header_checkpoints::BitcoinCheckPointCache;
 use globals::{AppShutdown, Globals};
@@ -16,12 +17,18 @@ use std::sync::Arc;
 use tokio::sync::mpsc::channel;
 use tokio::task::JoinHandle;
 
+use crate::anchoring::AnchorBackend;
 use crate::attestations::server::protocol::GlobalSocketState;
+mod anchoring;
 mod attestations;
+mod backup;
 mod configuration;
 mod control;
+mod ephemeral;
 mod globals;
+mod nonces;
 mod peer_services;
+mod retention;
 mod tor;
 
 #[tokio::main]
@@ -54,10 +61,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     });
     init_main(g).await
 }
+/// Registers the message types this node knows of, so that tagged messages of
+/// those types are checked when inserted, see [`attest_messages::registry`].
+fn register_message_types() {
+    registry::register::<game_host_messages::BroadcastByHost>();
+    registry::register::<game_player_messages::ParticipantAction>();
+}
+
 async fn init_main(g: Arc<Globals>) -> Result<(), Box<dyn Error + Send + Sync>> {
     tracing::debug!("Config Loaded");
+    register_message_types();
+    // before any service could sign with them
+    if g.config.nonces.release_unstored_on_start {
+        nonces::release_unstored(&g.msg_db).await?;
+    }
     let bitcoin_client = g.config.bitcoin.get_new_client().await?;
     tracing::debug!("Bitcoin Client Loaded");
+    let anchor_backend: Arc<dyn AnchorBackend> = bitcoin_client.clone();
     let bitcoin_checkpoints = Arc::new(
         BitcoinCheckPointCache::new(bitcoin_client, None, (*g.shutdown.clone()).clone()).await,
     );
@@ -65,6 +85,11 @@ async fn init_main(g: Arc<Globals>) -> Result<(), Box<dyn Error + Send + Sync>>
         .run_cache_service()
         .ok_or("Checkpoint service already started")?;
     tracing::debug!("Checkpoint Service Started");
+    let mut anchor_service = anchoring::run(g.clone(), anchor_backend.clone());
+    let mut retention_service = retention::run(g.clone());
+    let mut backup_service = backup::run(g.clone());
+    let mut nonce_service = nonces::run(g.clone());
+    let mut ephemeral_service = ephemeral::run(g.clone());
     let mut attestation_server = attestations::server::run(g.clone(), g.msg_db.clone()).await;
     let mut tor_service = tor::start(g.clone()).await?;
     let (tx_peer_status, rx_peer_status) = channel(1);
@@ -74,6 +99,7 @@ async fn init_main(g: Arc<Globals>) -> Result<(), Box<dyn Error + Send + Sync>>
         g.msg_db.clone(),
         tx_peer_status,
         bitcoin_checkpoints,
+        anchor_backend,
     )
     .await;
 
@@ -99,16 +125,41 @@ async fn init_main(g: Arc<Globals>) -> Result<(), Box<dyn Error + Send + Sync>>
     e = &mut control_server => {
         tracing::debug!("Error From Control Server: {:?}", e);
         skip.replace("control");
+    }
+    j = &mut nonce_service => {
+        tracing::debug!("Error From Nonce Server: {:?}", j);
+        skip.replace("nonces");
+    }
+    k = &mut ephemeral_service => {
+        tracing::debug!("Error From Ephemeral Server: {:?}", k);
+        skip.replace("ephemeral");
+    }
+    f = async { anchor_service.as_mut().expect("Checked by Guard").await }, if anchor_service.is_some() => {
+        tracing::debug!("Error From Anchor Server: {:?}", f);
+        skip.replace("anchor");
+    }
+    h = async { retention_service.as_mut().expect("Checked by Guard").await }, if retention_service.is_some() => {
+        tracing::debug!("Error From Retention Server: {:?}", h);
+        skip.replace("retention");
+    }
+    i = async { backup_service.as_mut().expect("Checked by Guard").await }, if backup_service.is_some() => {
+        tracing::debug!("Error From Backup Server: {:?}", i);
+        skip.replace("backup");
     });
     tracing::debug!("Shutting Down Subservices");
     g.shutdown.begin_shutdown();
-    let svcs = [
+    let mut svcs = vec![
         ("tor", tor_service),
         ("attest", attestation_server),
         ("fetch", fetching_client),
         ("checkpoint", checkpoint_service),
         ("control", control_server),
+        ("nonces", nonce_service),
+        ("ephemeral", ephemeral_service),
     ];
+    svcs.extend(anchor_service.map(|a| ("anchor", a)));
+    svcs.extend(retention_service.map(|r| ("retention", r)));
+    svcs.extend(backup_service.map(|b| ("backup", b)));
     for svc in &svcs {
         tracing::debug!("Abort Subservice: {}", svc.0);
         svc.1.abort();
@@ -0,0 +1,77 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Periodically tops up the nonces pregenerated for our keys, and erases the
+//! secrets of used ones, see [`attest_database::db_handle::get::nonces`].
+
+use crate::configuration::NonceConfig;
+use crate::globals::Globals;
+use attest_database::connection::MsgDB;
+use attest_util::{AbstractResult, INFER_UNIT};
+use sapio_bitcoin::secp256k1::{All, Secp256k1};
+use std::sync::Arc;
+use tokio::task::{spawn_blocking, JoinHandle};
+use tracing::{debug, info, warn};
+
+/// Pregenerates nonces for each of our keys until there are `config.pregenerate`
+/// of them, returning how many were made.
+pub async fn pregenerate(
+    db: &MsgDB,
+    config: &NonceConfig,
+    secp: Arc<Secp256k1<All>>,
+) -> AbstractResult<usize> {
+    let target = config.pregenerate;
+    let mut handle = db.get_handle_all().await;
+    let made = spawn_blocking(move || {
+        let mut made = 0;
+        for key in handle.get_keymap()?.into_keys() {
+            let n = target.saturating_sub(handle.get_pregenerated_nonce_count(key)?);
+            if n > 0 {
+                handle.pregenerate_nonces_for_user_by_key(&secp, key, n)?;
+                made += n;
+            }
+        }
+        Ok::<_, rusqlite::Error>(made)
+    })
+    .await??;
+    Ok(made)
+}
+
+/// Erases the secrets of nonces whose envelopes are stored, returning how
+/// many were erased.
+pub async fn collect(db: &MsgDB) -> AbstractResult<usize> {
+    let handle = db.get_handle_all().await;
+    let erased = spawn_blocking(move || handle.collect_used_nonces()).await??;
+    Ok(erased)
+}
+
+/// Frees the nonces of envelopes that were signed but never stored, returning
+/// how many were freed. Must run before anything signs, see
+/// [`NonceConfig::release_unstored_on_start`].
+pub async fn release_unstored(db: &MsgDB) -> AbstractResult<usize> {
+    let handle = db.get_handle_all().await;
+    let released = spawn_blocking(move || handle.release_unstored_nonces()).await??;
+    warn!(n = released, "Released Nonces of Envelopes Never Stored");
+    Ok(released)
+}
+
+pub fn run(g: Arc<Globals>) -> JoinHandle<AbstractResult<()>> {
+    let config: NonceConfig = g.config.nonces.clone();
+    tokio::spawn(async move {
+        while !g.shutdown.should_quit() {
+            let made = pregenerate(&g.msg_db, &config, g.secp.clone()).await?;
+            if made > 0 {
+                debug!(n = made, "Pregenerated Nonces");
+            }
+            let erased = collect(&g.msg_db).await?;
+            if erased > 0 {
+                info!(n = erased, "Erased Used Nonces");
+            }
+            tokio::time::sleep(config.frequency).await;
+        }
+        INFER_UNIT
+    })
+}
@@ -9,11 +9,16 @@ use crate::attestations::client::AttestationClient;
 use crate::attestations::client::NotifyOnDrop;
 use crate::attestations::client::ServiceUrl;
 use crate::attestations::query::Tips;
+use crate::attestations::server::protocol::sync_keys_visible_to;
+use crate::attestations::server::protocol::MAX_BLOBS_PER_REQUEST;
 use attest_database::sql_error::SqliteFail;
+use attest_messages::batch::authenticate_all_ref;
 use attest_messages::CanonicalEnvelopeHash;
 use attest_messages::Envelope;
 use attest_util::now;
 use attest_util::INFER_UNIT;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 
 use tokio::sync::mpsc::UnboundedSender;
 use tracing::info;
@@ -39,6 +44,27 @@ pub(crate) async fn fetch_from_peer(
         service,
         envelopes_to_process.clone(),
     );
+    // Periodically diffs our database against the peer's and requests
+    // whatever we are missing
+    let mut reconciler = reconciler(
+        g.clone(),
+        client.clone(),
+        service,
+        conn.clone(),
+        request_tips.clone(),
+        allow_unsolicited_tips,
+    );
+    // Periodically fetches the attachments of envelopes we have
+    let mut blob_fetcher = blob_fetcher(g.clone(), client.clone(), service, conn.clone());
+    // Periodically requests the missing ranges of chains with gaps that the
+    // peer has envelopes above
+    let mut gap_filler = gap_filler(
+        g.clone(),
+        client.clone(),
+        service,
+        conn.clone(),
+        envelopes_to_process.clone(),
+    );
     // Reads from next_envelope, processes results, and then requests to resolve unknown tips
     let mut envelope_processor = envelope_processor(
         g.clone(),
@@ -61,18 +87,54 @@ pub(crate) async fn fetch_from_peer(
             warn!(?service, task="FETCH", subtask="Envelope Processor", event="SHUTDOWN", err=?a);
             latest_tip_fetcher.abort();
             missing_envelope_fetcher.abort();
+            reconciler.abort();
+            blob_fetcher.abort();
+            gap_filler.abort();
             a??
         }
         a = &mut latest_tip_fetcher => {
             warn!(?service, task="FETCH", subtask="Latest Tip Fetcher", event="SHUTDOWN", err=?a);
             envelope_processor.abort();
             missing_envelope_fetcher.abort();
+            reconciler.abort();
+            blob_fetcher.abort();
+            gap_filler.abort();
             a??
         }
         a = &mut missing_envelope_fetcher => {
             warn!(?service, task="FETCH", subtask="Missing Envelope Fetcher", event="SHUTDOWN", err=?a);
             envelope_processor.abort();
             latest_tip_fetcher.abort();
+            reconciler.abort();
+            blob_fetcher.abort();
+            gap_filler.abort();
+            a??
+        }
+        a = &mut reconciler => {
+            warn!(?service, task="FETCH", subtask="Reconciler", event="SHUTDOWN", err=?a);
+            envelope_processor.abort();
+            latest_tip_fetcher.abort();
+            missing_envelope_fetcher.abort();
+            blob_fetcher.abort();
+            gap_filler.abort();
+            a??
+        }
+        a = &mut blob_fetcher => {
+            warn!(?service, task="FETCH", subtask="Blob Fetcher", event="SHUTDOWN", err=?a);
+            envelope_processor.abort();
+            latest_tip_fetcher.abort();
+            missing_envelope_fetcher.abort();
+            reconciler.abort();
+            gap_filler.abort();
+            a??
+        }
+        a = &mut gap_filler => {
+            warn!(?service, task="FETCH", subtask="Gap Filler", event="SHUTDOWN", err=?a);
+            envelope_processor.abort();
+            latest_tip_fetcher.abort();
+            missing_envelope_fetcher.abort();
+            reconciler.abort();
+            blob_fetcher.abort();
             a??
         }
     };
@@ -80,6 +142,9 @@ pub(crate) async fn fetch_from_peer(
     envelope_processor.abort();
     latest_tip_fetcher.abort();
     missing_envelope_fetcher.abort();
+    reconciler.abort();
+    blob_fetcher.abort();
+    gap_filler.abort();
 
     INFER_UNIT
 }
@@ -125,7 +190,22 @@ async fn handle_envelope(
     _cancel_inflight: NotifyOnDrop,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut all_tips = Vec::new();
-    for envelope in resp {
+    let secp = g.secp.clone();
+    let (resp, results) = spawn_blocking(move || {
+        let results = authenticate_all_ref(&resp, &secp);
+        (resp, results)
+    })
+    .await?;
+    // the peer has whatever it served us, however it was asked for, which is
+    // what gap_filler picks the gaps to ask it for by. Only properly signed
+    // envelopes count.
+    record_acknowledgements(
+        conn,
+        service,
+        results.iter().flatten().map(|a| a.inner_ref()),
+    )
+    .await;
+    for (envelope, result) in resp.into_iter().zip(results) {
         if g.shutdown.should_quit() {
             break;
         }
@@ -135,7 +215,7 @@ async fn handle_envelope(
                         ?service,
                         "Processing this envelope");
         tracing::trace!(?envelope, ?service, "Processing this envelope");
-        match envelope.self_authenticate(&g.secp) {
+        match result {
             Ok(authentic) => {
                 tracing::debug!(?service, "Authentic Tip: {:?}", authentic);
                 if authentic.inner_ref().header().ancestors().is_none()
@@ -246,6 +326,7 @@ pub(crate) fn latest_tip_fetcher(
                 .get_latest_tips(&service)
                 .await
                 .ok_or("Latest Tips Not Received")?;
+            // acknowledged once processed, see handle_envelope
             envelopes_to_process.send((resp, NotifyOnDrop::empty()))?;
             g.config.peer_service.timer_override.tip_fetch_delay().await;
         }
@@ -253,6 +334,166 @@ pub(crate) fn latest_tip_fetcher(
     })
 }
 
+/// reconciler periodically runs set reconciliation with a peer and forwards
+/// the hashes of envelopes we lack to the missing_envelope_fetcher.
+pub(crate) fn reconciler(
+    g: Arc<Globals>,
+    client: AttestationClient,
+    service: &ServiceUrl,
+    conn: MsgDB,
+    request_tips: UnboundedSender<Vec<CanonicalEnvelopeHash>>,
+    allow_unsolicited_tips: bool,
+) -> JoinHandle<Result<(), Box<dyn Error + Send + Sync>>> {
+    let service = service.clone();
+    tokio::spawn(async move {
+        while !g.shutdown.should_quit() {
+            g.config.peer_service.timer_override.reconcile_delay().await;
+            let mine = {
+                let handle = conn.get_handle_read().await;
+                let service = service.clone();
+                spawn_blocking(move || sync_keys_visible_to(&handle, &service)).await??
+            };
+            let (we_lack, they_lack) = client
+                .reconcile(&mine, &service)
+                .await
+                .ok_or("Reconciliation Failed")?;
+            info!(
+                ?service,
+                we_lack = we_lack.len(),
+                they_lack = they_lack.len(),
+                "Reconciled"
+            );
+            let known: BTreeSet<_> = if allow_unsolicited_tips {
+                Default::default()
+            } else {
+                mine.iter().map(|k| k.genesis).collect()
+            };
+            let tips: Vec<_> = we_lack
+                .into_iter()
+                .filter(|k| allow_unsolicited_tips || known.contains(&k.genesis))
+                .map(|k| k.hash)
+                .collect();
+            if !tips.is_empty() {
+                request_tips.send(tips)?;
+            }
+        }
+        INFER_UNIT
+    })
+}
+
+/// blob_fetcher periodically asks a peer for the attachments of envelopes we
+/// have but whose attachments we lack, keeping only those that match.
+pub(crate) fn blob_fetcher(
+    g: Arc<Globals>,
+    client: AttestationClient,
+    service: &ServiceUrl,
+    conn: MsgDB,
+) -> JoinHandle<Result<(), Box<dyn Error + Send + Sync>>> {
+    let service = service.clone();
+    tokio::spawn(async move {
+        while !g.shutdown.should_quit() {
+            g.config
+                .peer_service
+                .timer_override
+                .blob_fetch_delay()
+                .await;
+            let max_size = g.config.protocol.max_blob_size;
+            let wanted = {
+                let handle = conn.get_handle_read().await;
+                spawn_blocking(move || {
+                    handle.get_missing_blobs(max_size, MAX_BLOBS_PER_REQUEST as u32)
+                })
+                .await??
+            };
+            if wanted.is_empty() {
+                continue;
+            }
+            let blobs = client
+                .fetch_blobs(&wanted, &service)
+                .await
+                .ok_or("Blobs Not Fetched")?;
+            let handle = conn.get_handle_all().await;
+            let service = service.clone();
+            let saved = spawn_blocking(move || {
+                let mut saved = 0;
+                for blob in blobs {
+                    if let Some(blob_ref) = wanted.iter().find(|w| w.hash == blob.hash) {
+                        match handle.save_fetched_blob(blob_ref, &blob.data)? {
+                            Ok(()) => saved += 1,
+                            // TODO: Ban peer?
+                            Err(e) => warn!(?service, err=?e, "Attachment Validation Failed"),
+                        }
+                    }
+                }
+                Ok::<_, rusqlite::Error>(saved)
+            })
+            .await??;
+            info!(?service, saved, "Fetched Attachments");
+        }
+        INFER_UNIT
+    })
+}
+
+/// gap_filler periodically asks a peer for the missing ranges of our chains,
+/// see [`attest_database::db_handle::get::gaps`], and sends what it returns
+/// for processing. Only gaps below the highest envelope the peer is known to
+/// have on a chain are asked for, whether it acknowledged our push of it or
+/// served it to us, see [`handle_envelope`].
+pub(crate) fn gap_filler(
+    g: Arc<Globals>,
+    client: AttestationClient,
+    service: &ServiceUrl,
+    conn: MsgDB,
+    envelopes_to_process: UnboundedSender<(Vec<Envelope>, NotifyOnDrop)>,
+) -> JoinHandle<Result<(), Box<dyn Error + Send + Sync>>> {
+    let service = service.clone();
+    tokio::spawn(async move {
+        while !g.shutdown.should_quit() {
+            g.config.peer_service.timer_override.gap_fill_delay().await;
+            let wanted = {
+                let handle = conn.get_handle_read().await;
+                let service = service.clone();
+                spawn_blocking(move || {
+                    let advertised: BTreeMap<_, _> = handle
+                        .get_peer_acknowledgements(&service.0, service.1)?
+                        .into_iter()
+                        .map(|a| (a.genesis, a.height))
+                        .collect();
+                    let mut wanted = vec![];
+                    for chain in handle.get_chain_gaps()? {
+                        if let Some(&height) = advertised.get(&chain.genesis) {
+                            for gap in chain.gaps {
+                                if gap.last_height <= height {
+                                    wanted.push((chain.genesis, gap));
+                                }
+                            }
+                        }
+                    }
+                    Ok::<_, rusqlite::Error>(wanted)
+                })
+                .await??
+            };
+            for (genesis, gap) in wanted {
+                let envelopes = client
+                    .fetch_range(genesis, gap.first_height, gap.last_height, &service)
+                    .await
+                    .ok_or("Range Not Fetched")?;
+                trace!(
+                    ?service,
+                    ?genesis,
+                    ?gap,
+                    n = envelopes.len(),
+                    "Fetched Range"
+                );
+                if !envelopes.is_empty() {
+                    envelopes_to_process.send((envelopes, NotifyOnDrop::empty()))?;
+                }
+            }
+        }
+        INFER_UNIT
+    })
+}
+
 /// missing_envelope_fetcher ingests a Vec<Hash> and queries a service for the envelope
 /// of those hashes, then sends those envelopers for processing.
 pub(crate) fn missing_envelope_fetcher(
@@ -11,8 +11,9 @@ use tokio::{
     task::spawn_blocking,
 };
 
+use attest_messages::Envelope;
 use attest_util::INFER_UNIT;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use crate::attestations::client::{AttestationClient, ServiceUrl};
 
@@ -170,6 +171,40 @@ pub fn startup(
     })
 }
 
+/// Saves that a peer is known to hold the given envelopes, advancing the
+/// per-chain high-water marks used for resending and replication status.
+pub(crate) async fn record_acknowledgements<'a, I>(conn: &MsgDB, service: &ServiceUrl, envelopes: I)
+where
+    I: Iterator<Item = &'a Envelope>,
+{
+    let acks: Vec<_> = envelopes
+        .map(|e| {
+            (
+                e.get_genesis_hash(),
+                e.header().height(),
+                e.canonicalized_hash_ref(),
+            )
+        })
+        .collect();
+    if acks.is_empty() {
+        return;
+    }
+    let handle = conn.get_handle_all().await;
+    let service = service.clone();
+    let res = spawn_blocking(move || {
+        for (genesis, height, hash) in acks {
+            handle.upsert_peer_acknowledgement(&service.0, service.1, genesis, height, hash)?;
+        }
+        Ok::<_, rusqlite::Error>(())
+    })
+    .await;
+    match res {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => warn!(?error, "Failed to Record Peer Acknowledgements"),
+        Err(error) => warn!(?error, "Failed to Record Peer Acknowledgements"),
+    }
+}
+
 mod push_peer;
 
 mod fetch_peer;
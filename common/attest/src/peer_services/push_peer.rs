@@ -4,32 +4,197 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
-use attest_messages::{Authenticated, CanonicalEnvelopeHash, Envelope};
+use attest_messages::batch::authenticate_all;
+use attest_messages::{Authenticated, CanonicalEnvelopeHash, Envelope, WrappedJson};
+use attest_util::AbstractResult;
 use tokio::{
     spawn,
-    sync::{Mutex, Notify},
-    task::spawn_blocking,
+    sync::{
+        mpsc::{channel, Receiver},
+        Mutex, Notify,
+    },
+    task::{spawn_blocking, JoinError},
 };
 use tracing::{trace, warn};
 
 use super::*;
+use crate::configuration::PushConfig;
+use crate::control::query::Outcome;
+
+#[derive(Hash, PartialEq, PartialOrd, Ord, Eq, Debug, Copy, Clone)]
+struct GenesisHash(CanonicalEnvelopeHash);
+impl From<&Envelope> for GenesisHash {
+    fn from(e: &Envelope) -> Self {
+        GenesisHash(e.get_genesis_hash())
+    }
+}
+type TipTracker = HashMap<GenesisHash, Authenticated<Envelope>>;
+
+/// Loads the envelopes the peer acknowledged in a previous session so that
+/// after a reconnect we only resend what is missing.
+async fn load_acknowledged_tips(
+    conn: &MsgDB,
+    service: &ServiceUrl,
+) -> Result<TipTracker, Box<dyn Error + Send + Sync + 'static>> {
+    let handle = conn.get_handle_read().await;
+    let service = service.clone();
+    let tips = spawn_blocking(move || {
+        let acks = handle.get_peer_acknowledgements(&service.0, service.1)?;
+        let mut tips = TipTracker::new();
+        for ack in acks {
+            // we might not have the envelope a peer told us about, skip those
+            if let Ok(mut envs) = handle
+                .messages_by_hash::<_, Authenticated<Envelope>, WrappedJson>(std::iter::once(
+                    &ack.hash,
+                ))
+            {
+                if let Some(e) = envs.pop() {
+                    tips.insert(GenesisHash(ack.genesis), e);
+                }
+            }
+        }
+        Ok::<_, rusqlite::Error>(tips)
+    })
+    .await??;
+    Ok(tips)
+}
+
+/// Given a batch we posted and the peer's per-envelope outcomes, returns for
+/// each chain the highest envelope such that it and every lower envelope of
+/// that chain in the batch were accepted.
+fn highest_contiguous_accepted(
+    posted: Vec<Authenticated<Envelope>>,
+    outcomes: &[Outcome],
+) -> Vec<Authenticated<Envelope>> {
+    let mut by_chain: BTreeMap<GenesisHash, Vec<(Authenticated<Envelope>, bool)>> =
+        Default::default();
+    // outcomes may be shorter than posted if the peer stopped early, treat
+    // those as not accepted.
+    let outcomes = outcomes
+        .iter()
+        .map(|o| o.success)
+        .chain(std::iter::repeat(false));
+    for (e, success) in posted.into_iter().zip(outcomes) {
+        by_chain
+            .entry(GenesisHash::from(e.inner_ref()))
+            .or_default()
+            .push((e, success));
+    }
+    by_chain
+        .into_values()
+        .filter_map(|mut envs| {
+            envs.sort_by_key(|(e, _)| e.header().height());
+            envs.into_iter()
+                .take_while(|(_, success)| *success)
+                .last()
+                .map(|(e, _)| e)
+        })
+        .collect()
+}
+
+/// Maps the genesis of every chain in one of the priority groups to the
+/// position of the first group it appears in, lower is more urgent.
+async fn load_priority_ranks(
+    conn: &MsgDB,
+    groups: &[String],
+) -> Result<BTreeMap<CanonicalEnvelopeHash, usize>, Box<dyn Error + Send + Sync + 'static>> {
+    if groups.is_empty() {
+        return Ok(Default::default());
+    }
+    let handle = conn.get_handle_read().await;
+    let groups = groups.to_vec();
+    let rank = spawn_blocking(move || {
+        let mut rank = BTreeMap::new();
+        for (i, name) in groups.iter().enumerate() {
+            for genesis in handle.get_chain_commit_group_member_chains_by_name(name)? {
+                rank.entry(genesis).or_insert(i);
+            }
+        }
+        Ok::<_, rusqlite::Error>(rank)
+    })
+    .await??;
+    Ok(rank)
+}
+
+/// Sorts envelopes so that every envelope comes after its ancestors, with
+/// chains from higher priority groups first.
+fn ancestor_first(
+    envelopes: &mut [Authenticated<Envelope>],
+    rank: &BTreeMap<CanonicalEnvelopeHash, usize>,
+) {
+    envelopes.sort_by_cached_key(|e| {
+        let genesis = e.get_genesis_hash();
+        (
+            rank.get(&genesis).copied().unwrap_or(usize::MAX),
+            e.header().height(),
+            genesis,
+        )
+    });
+}
+
+/// Splits envelopes, in order, into batches bounded by count and by
+/// serialized size. An envelope bigger than the size limit is sent alone.
+fn make_batches(
+    envelopes: Vec<Authenticated<Envelope>>,
+    config: &PushConfig,
+) -> Vec<Vec<Authenticated<Envelope>>> {
+    let mut batches = vec![];
+    let mut batch = vec![];
+    let mut bytes = 0;
+    for e in envelopes {
+        let size = serde_json::to_vec(e.inner_ref()).map_or(0, |v| v.len());
+        if !batch.is_empty()
+            && (batch.len() >= config.max_batch_envelopes || bytes + size > config.max_batch_bytes)
+        {
+            batches.push(std::mem::take(&mut batch));
+            bytes = 0;
+        }
+        bytes += size;
+        batch.push(e);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Takes the next batch to send, always preferring the priority lane.
+async fn next_batch(
+    priority: &mut Receiver<Vec<Authenticated<Envelope>>>,
+    bulk: &mut Receiver<Vec<Authenticated<Envelope>>>,
+) -> Option<Vec<Authenticated<Envelope>>> {
+    tokio::select! {
+        biased;
+        Some(batch) = priority.recv() => Some(batch),
+        Some(batch) = bulk.recv() => Some(batch),
+        else => None,
+    }
+}
+
+fn log_shutdown(service: &ServiceUrl, task: &str, a: &Result<AbstractResult<String>, JoinError>) {
+    match a {
+        Ok(Ok(msg)) => {
+            info!(?service, task, event = "SHUTDOWN", msg);
+        }
+        Ok(Err(e)) => {
+            warn!(?service, task, event="SHUTDOWN", err=?e, "Fail");
+        }
+        Err(e) => {
+            warn!(?service, task, event="SHUTDOWN", err=?e, "Fail");
+        }
+    }
+}
+
 pub async fn push_to_peer(
     g: Arc<Globals>,
     client: AttestationClient,
     service: &ServiceUrl,
     conn: MsgDB,
 ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
-    #[derive(Hash, PartialEq, PartialOrd, Ord, Eq, Debug, Copy, Clone)]
-    struct GenesisHash(CanonicalEnvelopeHash);
-    impl From<&Envelope> for GenesisHash {
-        fn from(e: &Envelope) -> Self {
-            GenesisHash(e.get_genesis_hash())
-        }
-    }
-    let tip_tracker: Arc<Mutex<HashMap<GenesisHash, Authenticated<Envelope>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+    let tip_tracker: Arc<Mutex<TipTracker>> =
+        Arc::new(Mutex::new(load_acknowledged_tips(&conn, service).await?));
     let new_tips = Arc::new(Notify::new());
     let mut t1 = spawn({
         let g = g.clone();
@@ -37,15 +202,19 @@ pub async fn push_to_peer(
         let client = client.clone();
         let new_tips = new_tips.clone();
         let service = service.clone();
+        let conn = conn.clone();
         async move {
             while !g.shutdown.should_quit() {
                 // Get the tips this client claims to have
-                let tips: Vec<_> = client
+                let resp = client
                     .get_latest_tips(&service)
                     .await
-                    .ok_or("Failed to Fetch Latest Tips")?
-                    .iter()
-                    .flat_map(|e| e.self_authenticate(&g.secp))
+                    .ok_or("Failed to Fetch Latest Tips")?;
+                let secp = g.secp.clone();
+                let tips: Vec<_> = spawn_blocking(move || authenticate_all(resp, &secp))
+                    .await?
+                    .into_iter()
+                    .flatten()
                     .collect();
                 trace!(
                     ?service,
@@ -57,6 +226,8 @@ pub async fn push_to_peer(
                     ?service,
                         task = "PUSH::tip_tracker",
                     tips=?tips.iter().map(|t| (t.get_genesis_hash(), t.canonicalized_hash_ref())).collect::<Vec<_>>(), "Fetching saw peer at state");
+                // The peer has everything it advertises as a tip
+                record_acknowledgements(&conn, &service, tips.iter().map(|t| t.inner_ref())).await;
                 {
                     let mut tip_tracker = tip_tracker.lock().await;
 
@@ -116,16 +287,25 @@ pub async fn push_to_peer(
             INFER_UNIT.map(|_| format!("Shutdown Graceful: {}", g.shutdown.should_quit()))
         }
     });
+    let queued: Arc<Mutex<BTreeSet<CanonicalEnvelopeHash>>> = Default::default();
+    let push_config = &g.config.peer_service.push;
+    let (priority_tx, mut priority_rx) =
+        channel::<Vec<Authenticated<Envelope>>>(push_config.max_queued_batches.max(1));
+    let (bulk_tx, mut bulk_rx) =
+        channel::<Vec<Authenticated<Envelope>>>(push_config.max_queued_batches.max(1));
+    // Plans what to send: finds envelopes the peer is missing, orders and
+    // batches them, and queues them for t3
     let mut t2 = spawn({
         let g = g.clone();
         let tip_tracker = tip_tracker.clone();
-        let client = client.clone();
         let new_tips = new_tips.clone();
         let service = service.clone();
+        let conn = conn.clone();
+        let queued = queued.clone();
         async move {
             while !g.shutdown.should_quit() {
                 new_tips.notified().await;
-                let to_broadcast = {
+                let mut to_broadcast = {
                     // get the DB first as it is more contended, and we're OK
                     // waiting on the other thread later
                     let handle = conn.get_handle_read().await;
@@ -161,32 +341,115 @@ pub async fn push_to_peer(
                         }
                         debug!(unknown_chains = ?genesis);
                         msgs.extend(genesis.into_iter().flatten().map(|(_a, b)| b));
+                        // never push chains this peer isn't allowed to see
+                        let hidden = {
+                            let handle = conn.get_handle_read().await;
+                            let service = service.clone();
+                            spawn_blocking(move || {
+                                handle.get_chains_hidden_from(&service.0, service.1)
+                            })
+                            .await
+                            .expect("Panic Free")?
+                        };
+                        msgs.retain(|e| !hidden.contains(&e.get_genesis_hash()));
+                        // nor messages which have expired
+                        let now = attest_util::now();
+                        for e in msgs.iter_mut() {
+                            e.prune_if_expired(now);
+                        }
                         msgs
                     } else {
                         continue;
                     }
                 };
-                if !to_broadcast.is_empty() {
-                    info!(?service, task = "PUSH::broadcast", n = to_broadcast.len());
-                    trace!(?service, task="PUSH::broadcast", msgs = ?to_broadcast);
-
-                    let l = to_broadcast.len();
-                    let res = client
-                        .post_messages(
-                            &to_broadcast.into_iter().map(|x| x.inner()).collect(),
-                            &service,
-                        )
-                        .await
-                        .ok_or("Messages Failed To Post")?;
-
-                    info!(
-                        accepted = res.iter().filter(|s| s.success).count(),
-                        out_of = l,
-                        task = "PUSH",
-                        ?service
-                    );
-                } else {
+                // anything still waiting in the queue will be sent already
+                {
+                    let queued = queued.lock().await;
+                    to_broadcast.retain(|e| !queued.contains(&e.canonicalized_hash_ref()));
+                }
+                if to_broadcast.is_empty() {
                     info!(?service, task = "PUSH", "No Work to Do");
+                    continue;
+                }
+                let push_config = &g.config.peer_service.push;
+                let rank = load_priority_ranks(&conn, &push_config.priority_groups).await?;
+                ancestor_first(&mut to_broadcast, &rank);
+                let (priority, bulk): (Vec<_>, Vec<_>) = to_broadcast
+                    .into_iter()
+                    .partition(|e| rank.contains_key(&e.get_genesis_hash()));
+                info!(
+                    ?service,
+                    task = "PUSH::plan",
+                    priority = priority.len(),
+                    bulk = bulk.len()
+                );
+                for (lane, envelopes) in [(&priority_tx, priority), (&bulk_tx, bulk)] {
+                    for batch in make_batches(envelopes, push_config) {
+                        queued
+                            .lock()
+                            .await
+                            .extend(batch.iter().map(|e| e.canonicalized_hash_ref()));
+                        // Waits while the lane is full, so a slow peer stalls
+                        // planning rather than piling up work.
+                        lane.send(batch).await?;
+                    }
+                }
+            }
+            INFER_UNIT.map(|_| format!("Shutdown Graceful: {}", g.shutdown.should_quit()))
+        }
+    });
+    // Sends queued batches one at a time, always preferring the priority lane.
+    let mut t3 = spawn({
+        let g = g.clone();
+        let tip_tracker = tip_tracker.clone();
+        let client = client.clone();
+        let service = service.clone();
+        async move {
+            while !g.shutdown.should_quit() {
+                let batch = match next_batch(&mut priority_rx, &mut bulk_rx).await {
+                    Some(batch) => batch,
+                    None => break,
+                };
+                info!(?service, task = "PUSH::broadcast", n = batch.len());
+                trace!(?service, task="PUSH::broadcast", msgs = ?batch);
+
+                let hashes: Vec<_> = batch.iter().map(|e| e.canonicalized_hash_ref()).collect();
+                let l = batch.len();
+                let res = client
+                    .post_messages(
+                        &batch.iter().map(|x| x.inner_ref().clone()).collect(),
+                        &service,
+                    )
+                    .await
+                    .ok_or("Messages Failed To Post")?;
+
+                info!(
+                    accepted = res.iter().filter(|s| s.success).count(),
+                    out_of = l,
+                    task = "PUSH",
+                    ?service
+                );
+                let accepted = highest_contiguous_accepted(batch, &res[..]);
+                record_acknowledgements(&conn, &service, accepted.iter().map(|e| e.inner_ref()))
+                    .await;
+                // Advance what we think the peer has so we don't resend
+                // before the next tip scan.
+                {
+                    let mut tip_tracker = tip_tracker.lock().await;
+                    for e in accepted {
+                        let genesis = GenesisHash::from(e.inner_ref());
+                        match tip_tracker.get(&genesis) {
+                            Some(prev) if prev.header().height() >= e.header().height() => {}
+                            _ => {
+                                tip_tracker.insert(genesis, e);
+                            }
+                        }
+                    }
+                }
+                // rejected envelopes become eligible to be planned again
+                let mut queued = queued.lock().await;
+                for h in hashes {
+                    queued.remove(&h);
                 }
             }
             INFER_UNIT.map(|_| format!("Shutdown Graceful: {}", g.shutdown.should_quit()))
@@ -196,38 +459,198 @@ pub async fn push_to_peer(
     tokio::select! {
         a = &mut t1 => {
             t2.abort();
-            match &a {
-                Ok(r) => match r {
-                    Ok(msg) => {
-                        info!(?service, task = "PUSH::tip_tracker", event = "SHUTDOWN", msg);
-                    },
-                    Err(e) => {
-                        warn!(?service, task="PUSH::tip_tracker", event="SHUTDOWN", err=?e, "Fail");
-                    },
-                },
-                Err(e) => {
-                    warn!(?service, task="PUSH::tip_tracker", event="SHUTDOWN", err=?e, "Fail");
-                },
-            };
+            t3.abort();
+            log_shutdown(&service, "PUSH::tip_tracker", &a);
             a??;
         },
         a = &mut t2 => {
             t1.abort();
-            match &a {
-                Ok(r) => match r {
-                    Ok(msg) => {
-                        info!(?service, task = "PUSH::broadcast", event = "SHUTDOWN", msg);
-                    },
-                    Err(e) => {
-                        warn!(?service, task="PUSH::broadcast", event="SHUTDOWN", err=?e, "Fail");
-                    },
-                },
-                Err(e) => {
-                    warn!(?service, task="PUSH::broadcast", event="SHUTDOWN", err=?e, "Fail");
-                },
-            }
+            t3.abort();
+            log_shutdown(&service, "PUSH::plan", &a);
+            a??;
+        }
+        a = &mut t3 => {
+            t1.abort();
+            t2.abort();
+            log_shutdown(&service, "PUSH::broadcast", &a);
             a??;
         }
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use attest_database::{db_handle::create::TipControl, generate_new_user, setup_test_db};
+    use ruma_serde::CanonicalJsonValue;
+    use sapio_bitcoin::secp256k1::Secp256k1;
+    use serde_json::json;
+
+    /// builds `n` chains of `len` messages after the genesis, message `i`
+    /// padded with `pad(i)` bytes.
+    async fn chains(
+        n: usize,
+        len: usize,
+        pad: impl Fn(usize) -> usize,
+    ) -> Vec<Vec<Authenticated<Envelope>>> {
+        let secp = Secp256k1::new();
+        let db = setup_test_db().await;
+        let mut handle = db.get_handle_all().await;
+        let mut chains = vec![];
+        for c in 0..n {
+            let (kp, nonce, genesis) = generate_new_user::<_, WrappedJson, _>(
+                &secp,
+                CanonicalJsonValue::try_from(json!({ "chain": c })).unwrap(),
+            )
+            .unwrap();
+            let genesis = genesis.self_authenticate(&secp).unwrap();
+            handle.save_keypair(kp).unwrap();
+            handle
+                .insert_user_by_genesis_envelope(format!("chain-{}", c), genesis.clone())
+                .unwrap()
+                .unwrap();
+            handle
+                .save_nonce_for_user_by_key(nonce, &secp, kp.x_only_public_key().0)
+                .unwrap();
+            let mut chain = vec![genesis];
+            for i in 0..len {
+                let e = handle
+                    .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                        CanonicalJsonValue::try_from(json!({ "pad": "x".repeat(pad(i)) })).unwrap(),
+                        &kp,
+                        &secp,
+                        None,
+                        None,
+                        TipControl::NoTips,
+                    )
+                    .unwrap()
+                    .unwrap()
+                    .self_authenticate(&secp)
+                    .unwrap();
+                handle
+                    .try_insert_authenticated_envelope(e.clone(), false)
+                    .unwrap()
+                    .unwrap();
+                chain.push(e);
+            }
+            chains.push(chain);
+        }
+        chains
+    }
+
+    fn size(e: &Authenticated<Envelope>) -> usize {
+        serde_json::to_vec(e.inner_ref()).unwrap().len()
+    }
+
+    fn hashes(envelopes: &[Authenticated<Envelope>]) -> Vec<CanonicalEnvelopeHash> {
+        envelopes
+            .iter()
+            .map(|e| e.canonicalized_hash_ref())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_batches_bounded_by_count() {
+        let envelopes: Vec<_> = chains(2, 10, |_| 0).await.concat();
+        let config = PushConfig {
+            max_batch_envelopes: 4,
+            max_batch_bytes: usize::MAX,
+            ..Default::default()
+        };
+        let batches = make_batches(envelopes.clone(), &config);
+        assert_eq!(batches.len(), 6);
+        assert!(batches.iter().all(|b| !b.is_empty() && b.len() <= 4));
+        // nothing is dropped or reordered
+        assert_eq!(hashes(&batches.concat()), hashes(&envelopes));
+    }
+
+    #[tokio::test]
+    async fn test_batches_bounded_by_size() {
+        let envelopes: Vec<_> = chains(1, 20, |i| 100 * (i % 5)).await.concat();
+        let largest = envelopes.iter().map(size).max().unwrap();
+        let config = PushConfig {
+            max_batch_envelopes: usize::MAX,
+            max_batch_bytes: 3 * largest,
+            ..Default::default()
+        };
+        let batches = make_batches(envelopes.clone(), &config);
+        assert!(batches.len() > 1);
+        for b in &batches {
+            assert!(b.iter().map(size).sum::<usize>() <= config.max_batch_bytes);
+        }
+        assert_eq!(hashes(&batches.concat()), hashes(&envelopes));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_envelope_sent_alone() {
+        let envelopes: Vec<_> = chains(1, 5, |i| if i == 2 { 10_000 } else { 0 })
+            .await
+            .concat();
+        let big = envelopes[3].canonicalized_hash_ref();
+        let config = PushConfig {
+            max_batch_envelopes: usize::MAX,
+            max_batch_bytes: 5_000,
+            ..Default::default()
+        };
+        let batches = make_batches(envelopes.clone(), &config);
+        assert_eq!(batches.len(), 3);
+        assert_eq!(hashes(&batches[1]), vec![big]);
+        assert_eq!(hashes(&batches.concat()), hashes(&envelopes));
+    }
+
+    #[tokio::test]
+    async fn test_ancestor_first() {
+        let chains = chains(3, 8, |_| 0).await;
+        let mut envelopes: Vec<_> = chains.concat();
+        envelopes.reverse();
+        // the last chain is most urgent, then the first, the middle one is
+        // in no group
+        let rank: BTreeMap<_, _> = [
+            (chains[2][0].get_genesis_hash(), 0),
+            (chains[0][0].get_genesis_hash(), 1),
+        ]
+        .into_iter()
+        .collect();
+        ancestor_first(&mut envelopes, &rank);
+
+        let position: BTreeMap<_, _> = hashes(&envelopes)
+            .into_iter()
+            .enumerate()
+            .map(|(i, h)| (h, i))
+            .collect();
+        for (i, e) in envelopes.iter().enumerate() {
+            if let Some(ancestors) = e.header().ancestors() {
+                assert!(position[&ancestors.prev_msg()] < i, "parent after child");
+            }
+        }
+        let order: Vec<_> = envelopes.iter().map(|e| e.get_genesis_hash()).collect();
+        let expected: Vec<_> = [2, 0, 1]
+            .iter()
+            .flat_map(|c| std::iter::repeat(chains[*c][0].get_genesis_hash()).take(9))
+            .collect();
+        assert_eq!(order, expected);
+    }
+
+    #[tokio::test]
+    async fn test_priority_lane_sent_first() {
+        let chains = chains(2, 1, |_| 0).await;
+        let (priority_tx, mut priority_rx) = channel(4);
+        let (bulk_tx, mut bulk_rx) = channel(4);
+        // bulk work queued first still waits for the priority lane
+        bulk_tx.send(chains[0].clone()).await.unwrap();
+        bulk_tx.send(chains[0].clone()).await.unwrap();
+        priority_tx.send(chains[1].clone()).await.unwrap();
+        priority_tx.send(chains[1].clone()).await.unwrap();
+        let mut sent = vec![];
+        drop((priority_tx, bulk_tx));
+        while let Some(batch) = next_batch(&mut priority_rx, &mut bulk_rx).await {
+            sent.push(batch[0].get_genesis_hash());
+        }
+        let (p, b) = (
+            chains[1][0].get_genesis_hash(),
+            chains[0][0].get_genesis_hash(),
+        );
+        assert_eq!(sent, vec![p, p, b, b]);
+    }
+}
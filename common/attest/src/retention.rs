@@ -0,0 +1,92 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Periodically archives old chains out of the database, see
+//! [`attest_database::db_handle::archive`].
+
+use crate::configuration::RetentionConfig;
+use crate::globals::Globals;
+use attest_database::connection::MsgDB;
+use attest_database::db_handle::get::archive::ArchivedChain;
+use attest_messages::{CanonicalEnvelopeHash, WrappedJson};
+use attest_util::{AbstractResult, INFER_UNIT};
+use sapio_bitcoin::secp256k1::{All, Secp256k1};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::task::{spawn_blocking, JoinHandle};
+use tracing::{info, warn};
+
+/// Archives a chain down to its latest `keep` envelopes, if there is anything
+/// to archive.
+pub async fn archive_chain(
+    db: &MsgDB,
+    genesis: CanonicalEnvelopeHash,
+    keep: u64,
+    dir: PathBuf,
+) -> AbstractResult<Option<ArchivedChain>> {
+    let mut handle = db.get_handle_all().await;
+    let archived = spawn_blocking(move || handle.archive_chain::<WrappedJson>(genesis, keep, &dir))
+        .await???;
+    Ok(archived)
+}
+
+/// Puts all archived envelopes of a chain back in the database, returning how
+/// many there were.
+pub async fn restore_chain(
+    db: &MsgDB,
+    secp: Arc<Secp256k1<All>>,
+    genesis: CanonicalEnvelopeHash,
+) -> AbstractResult<usize> {
+    let mut handle = db.get_handle_all().await;
+    let restored =
+        spawn_blocking(move || handle.restore_chain::<WrappedJson, _>(&secp, genesis)).await???;
+    Ok(restored)
+}
+
+/// Archives every chain that is due according to `config`.
+///
+/// A chain that fails to archive is skipped, so that one bad chain doesn't
+/// hold up the rest.
+pub async fn archive_due(
+    db: &MsgDB,
+    config: &RetentionConfig,
+) -> AbstractResult<Vec<ArchivedChain>> {
+    let older_than = attest_util::now() - config.archive_after.as_millis() as i64;
+    let (finished_groups, keep) = (config.finished_groups, config.keep);
+    let due = {
+        let handle = db.get_handle_read().await;
+        spawn_blocking(move || handle.get_archive_candidates(older_than, finished_groups, keep))
+            .await??
+    };
+    let mut archived = vec![];
+    for genesis in due {
+        match archive_chain(db, genesis, keep, config.archive_dir.clone()).await {
+            Ok(Some(a)) => archived.push(a),
+            Ok(None) => {}
+            Err(e) => warn!(?genesis, err=?e, "Failed to Archive Chain"),
+        }
+    }
+    Ok(archived)
+}
+
+pub fn run(g: Arc<Globals>) -> Option<JoinHandle<AbstractResult<()>>> {
+    let config: RetentionConfig = g.config.retention.clone()?;
+    Some(tokio::spawn(async move {
+        while !g.shutdown.should_quit() {
+            tokio::time::sleep(config.frequency).await;
+            let archived = archive_due(&g.msg_db, &config).await?;
+            if archived.is_empty() {
+                continue;
+            }
+            info!(n = archived.len(), "Archived Chains");
+            if config.compact {
+                let handle = g.msg_db.get_handle_all().await;
+                spawn_blocking(move || handle.compact()).await??;
+            }
+        }
+        INFER_UNIT
+    }))
+}
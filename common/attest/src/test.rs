@@ -16,13 +16,17 @@ use crate::{
         query::{NewGenesis, Outcome, PushMsg, Subscribe},
     },
     globals::Globals,
-    init_main, AppShutdown,
+    init_main, register_message_types, AppShutdown,
 };
-use attest_messages::{CanonicalEnvelopeHash, Envelope};
+use attest_database::{
+    db_handle::create::TipControl, generate_new_user, setup_test_db, sql_error::SqliteFail,
+};
+use attest_messages::{AttestEnvelopable, CanonicalEnvelopeHash, Envelope, WrappedJson};
 use attest_util::bitcoin::BitcoinConfig;
 use attest_util::CrossPlatformPermissions;
 use bitcoincore_rpc_async::Auth;
 use futures::{future::join_all, stream::FuturesUnordered, Future, StreamExt};
+use game_host_messages::{BroadcastByHost, Channelized};
 
 use ruma_serde::CanonicalJsonValue;
 use sapio_bitcoin::{
@@ -119,7 +123,18 @@ async fn create_test_config(quits: &mut Vec<AppShutdown>, test_id: u8) -> (AppSh
             port: 14556 + test_id as u16,
         },
         prefix: Some(dir),
-        peer_service: PeerServiceConfig { timer_override },
+        peer_service: PeerServiceConfig {
+            timer_override,
+            push: Default::default(),
+        },
+        protocol: Default::default(),
+        storage_encoding: Default::default(),
+        database: Default::default(),
+        nonces: Default::default(),
+        ephemeral: Default::default(),
+        anchoring: None,
+        retention: None,
+        backup: None,
         test_db: true,
     };
     (shutdown, config)
@@ -318,6 +333,40 @@ async fn connect_and_test_nodes() {
         check_synched(11, true).await;
         tokio::time::sleep(Duration::from_millis(20)).await;
         test_envelope_inner_tips(ports.clone(), client.clone(), old_tips).await;
+
+        // Every node should learn that all of its peers have its latest message
+        for ((_port, ctrl), genesis) in ports.iter().zip(genesis_envelopes.iter()) {
+            loop {
+                let status = control_client
+                    .replication_status(&genesis.get_genesis_hash(), &HOME.into(), *ctrl)
+                    .await
+                    .unwrap();
+                assert_eq!(status.height, 11);
+                assert_eq!(status.out_of, ports.len() - 1);
+                if status.reached == status.out_of {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }
+        info!(checkpoint = "Replication Status reports all peers up to date");
+
+        // Every node counts the genesis and 11 messages of every chain
+        for (_port, ctrl) in ports.iter() {
+            loop {
+                let stats = control_client
+                    .chain_stats(&HOME.into(), *ctrl)
+                    .await
+                    .unwrap();
+                assert_eq!(stats.len(), ports.len());
+                assert!(stats.iter().all(|s| s.messages <= 12));
+                if stats.iter().all(|s| s.messages == 12) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }
+        info!(checkpoint = "Chain Stats count every message");
     })
     .await
 }
@@ -399,6 +448,7 @@ async fn make_nth(
                         key,
                         msg: nth_msg_per_port(port, n),
                         equivocate: false,
+                        ttl_ms: None,
                     },
                     &HOME.into(),
                     ctrl,
@@ -476,3 +526,62 @@ async fn check_synched(
 
     info!(n, "Synchronization success");
 }
+
+#[test(tokio::test)]
+async fn test_known_message_types_checked_on_insert() {
+    register_message_types();
+    let secp = Secp256k1::new();
+    let db = setup_test_db().await;
+    let mut handle = db.get_handle_all().await;
+    let (kp, nonce, genesis) =
+        generate_new_user::<_, WrappedJson, _>(&secp, CanonicalJsonValue::Null).unwrap();
+    handle.save_keypair(kp).unwrap();
+    handle
+        .insert_user_by_genesis_envelope("host".into(), genesis.self_authenticate(&secp).unwrap())
+        .unwrap()
+        .unwrap();
+    handle
+        .save_nonce_for_user_by_key(nonce, &secp, kp.x_only_public_key().0)
+        .unwrap();
+    let mut post = |msg: CanonicalJsonValue| {
+        let e = handle
+            .wrap_message_in_envelope_for_user_by_key::<_, WrappedJson, _>(
+                msg,
+                &kp,
+                &secp,
+                None,
+                None,
+                TipControl::NoTips,
+            )
+            .unwrap()
+            .unwrap()
+            .self_authenticate(&secp)
+            .unwrap();
+        handle.try_insert_authenticated_envelope(e, false).unwrap()
+    };
+    let json = |v: serde_json::Value| CanonicalJsonValue::try_from(v).unwrap();
+
+    // what the producers send goes in
+    let heartbeat = Channelized {
+        data: BroadcastByHost::Heartbeat,
+        channel: "default".into(),
+    };
+    post(heartbeat.as_canonical().unwrap()).unwrap();
+    // a malformed move is refused
+    assert!(matches!(
+        post(json(serde_json::json!({
+            "@type": "ParticipantAction",
+            "@version": 1,
+            "@body": {"MoveEnvelope": {"sequence": "first"}}
+        }))),
+        Err((SqliteFail::SqliteConstraintCheck, _))
+    ));
+    // as is a malformed broadcast inside its channel
+    assert!(matches!(
+        post(json(serde_json::json!({
+            "channel": "default",
+            "data": {"@type": "BroadcastByHost", "@version": 1, "@body": {"NewPeer": {"port": "80"}}}
+        }))),
+        Err((SqliteFail::SqliteConstraintCheck, _))
+    ));
+}
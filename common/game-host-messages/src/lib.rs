@@ -4,6 +4,7 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use attest_messages::registry::{self, MessageType, RegistryError};
 use attest_messages::{AttestEnvelopable, CanonicalEnvelopeHash};
 use mine_with_friends_board::game::{game_move::GameMove, GameSetup};
 use ruma_serde::CanonicalJsonValue;
@@ -100,6 +101,11 @@ pub enum BroadcastByHost {
     Heartbeat,
 }
 
+impl MessageType for BroadcastByHost {
+    const TAG: &'static str = "BroadcastByHost";
+    const VERSION: u32 = 1;
+}
+
 impl BroadcastByHost {
     pub fn is_sequence(&self) -> bool {
         matches!(self, BroadcastByHost::Sequence(_))
@@ -126,12 +132,31 @@ impl<T> AsRef<Channelized<T>> for Channelized<T> {
 
 impl<T> AttestEnvelopable for Channelized<T>
 where
-    T: Send + Sync + std::fmt::Debug + Clone + JsonSchema + Serialize + for<'de> Deserialize<'de>,
+    T: Send + Sync + std::fmt::Debug + Clone + MessageType + Serialize + for<'de> Deserialize<'de>,
 {
     type Ref = Self;
 
+    /// `data` is sent tagged, see [`registry`]
     fn as_canonical(&self) -> Result<CanonicalJsonValue, ruma_serde::CanonicalJsonError> {
-        ruma_serde::to_canonical_value(self)
+        ruma_serde::to_canonical_value(serde_json::json!({
+            "data": registry::to_tagged_canonical(&self.data)?,
+            "channel": self.channel,
+        }))
+    }
+
+    /// the channel is plain routing data, only `data` goes through the
+    /// registry
+    fn from_canonical(msg: CanonicalJsonValue) -> Result<Self, RegistryError> {
+        #[derive(Deserialize)]
+        struct Raw {
+            data: CanonicalJsonValue,
+            channel: ChannelID,
+        }
+        let raw: Raw = serde_json::from_value(msg.into())?;
+        Ok(Channelized {
+            data: registry::decode(raw.data)?,
+            channel: raw.channel,
+        })
     }
 }
 
@@ -242,6 +242,36 @@ pub async fn add_chain_to_group(
     ))
 }
 
+/// Drops a chain, e.g. a kicked player's, from a group so the host stops
+/// committing to it
+pub async fn remove_chain_from_group(
+    Json(j): Json<AddChainToGroup>,
+    Extension(db): Extension<MsgDB>,
+) -> Result<(Response<()>, Json<bool>), (StatusCode, &'static str)> {
+    let handle = db.get_handle_all().await;
+    let removed = spawn_blocking(move || {
+        let id = handle
+            .get_chain_commit_group_by_name(&j.group)
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, ""))?
+            .ok_or((StatusCode::NOT_FOUND, "No Such Group"))?
+            .id;
+        handle
+            .remove_member_from_chain_commit_group(id, j.genesis_hash)
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, ""))
+    })
+    .await
+    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, ""))??;
+
+    Ok((
+        Response::builder()
+            .status(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body(())
+            .expect("Response<()> should always be valid"),
+        Json(removed),
+    ))
+}
+
 pub type CompilerModule =
     Arc<Mutex<dyn PluginHandle<Input = CreateArgs<Value>, Output = Compiled> + Send>>;
 pub fn run(
@@ -266,6 +296,10 @@ pub fn run(
                 "/attestation_chain/commit_group/add_member",
                 post(add_chain_to_group),
             )
+            .route(
+                "/attestation_chain/commit_group/remove_member",
+                post(remove_chain_from_group),
+            )
             .layer(TraceLayer::new_for_http())
             .layer(Extension(db))
             .layer(Extension(secp))
@@ -4,6 +4,8 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use attest_messages::encrypted::EncryptedPayload;
+use attest_messages::registry::{self, MessageType, RegistryError};
 use attest_messages::AttestEnvelopable;
 use mine_with_friends_board::{game::game_move::GameMove, sanitize::Unsanitized, MoveEnvelope};
 use ruma_serde::CanonicalJsonValue;
@@ -48,6 +50,8 @@ pub enum ParticipantAction {
     MoveEnvelope(MoveEnvelope),
     Custom(#[schemars(with = "serde_json::Value")] CanonicalJsonValue),
     PsbtSigningCoordination(Multiplexed<PsbtString>),
+    /// readable only by the recipients, e.g. a private offer to an opponent
+    Encrypted(EncryptedPayload),
 }
 
 impl AsRef<ParticipantAction> for ParticipantAction {
@@ -58,9 +62,19 @@ impl AsRef<ParticipantAction> for ParticipantAction {
 impl AttestEnvelopable for ParticipantAction {
     type Ref = ParticipantAction;
 
+    /// sent tagged, see [`registry`]
     fn as_canonical(&self) -> Result<CanonicalJsonValue, ruma_serde::CanonicalJsonError> {
-        ruma_serde::to_canonical_value(self.clone())
+        registry::to_tagged_canonical(self)
     }
+
+    fn from_canonical(msg: CanonicalJsonValue) -> Result<Self, RegistryError> {
+        registry::decode(msg)
+    }
+}
+
+impl MessageType for ParticipantAction {
+    const TAG: &'static str = "ParticipantAction";
+    const VERSION: u32 = 1;
 }
 
 impl From<MoveEnvelope> for ParticipantAction {
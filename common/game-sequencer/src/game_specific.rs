@@ -77,6 +77,7 @@ fn read_move(
         ParticipantAction::MoveEnvelope(me) => Ok(Some((me, m.header().key()))),
         ParticipantAction::Custom(_) => Ok(None),
         ParticipantAction::PsbtSigningCoordination(_) => Ok(None),
+        ParticipantAction::Encrypted(_) => Ok(None),
     }
 }
 
@@ -677,6 +677,7 @@ mod test {
                                 sent_time_ms,
                                 unsigned,
                                 checkpoints,
+                                Default::default(),
                             ),
                             ParticipantAction::new(
                                 Unsanitized(GameMove::Heartbeat(Heartbeat())),
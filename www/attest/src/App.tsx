@@ -41,6 +41,13 @@ type Status = {
   peer_connections: Array<TaskID>,
   all_users: Array<[string, string, boolean]>,
   hidden_service_url: [string, number] | null;
+  gaps: Array<{ genesis: string, gaps: Array<{ first_height: number, last_height: number, needed: string }> }>,
+  db_pool: {
+    readers: number, idle_readers: number,
+    read_checkouts: number, read_wait_us: number, max_read_wait_us: number,
+    write_checkouts: number, write_wait_us: number, max_write_wait_us: number,
+    timeouts: number, busy_waits: number, busy_errors: number, checkpoints: number,
+  },
   Error: undefined,
   IsNull: undefined,
 }